@@ -74,6 +74,16 @@ fn linking_closure_detached(c: &mut Criterion) {
       });
     }
   );
+  group.bench_function(
+    "prepared",
+    |b| {
+      let p = PageSize::get().unwrap();
+      let s = SafeStack::new(8192, p).unwrap();
+      b.iter(|| {
+        black_box(unsafe { link_closure_prepared(s.end(), closure) });
+      });
+    }
+  );
 }
 
 fn switching(c: &mut Criterion) {
@@ -104,6 +114,33 @@ fn switching(c: &mut Criterion) {
       })
     }
   );
+  group.bench_function(
+    "safe_suspended",
+    |b| {
+      let p = PageSize::get().unwrap();
+      let mut s = SafeStack::new(8192, p).unwrap();
+      let c = Suspended::link_detached(&mut s, |mut back, mut n| {
+        loop { (back, n) = back.switch(n) }
+      });
+      let mut ret = Some((c, 0));
+      b.iter(|| {
+        let (c, n) = ret.take().unwrap();
+        ret = Some(c.switch(n));
+      })
+    }
+  );
+  group.bench_function(
+    "safe_full",
+    |b| {
+      let p = PageSize::get().unwrap();
+      let s = SafeStack::new(8192, p).unwrap();
+      let c = unsafe { link_closure_detached(s.end(), closure) };
+      let mut ret = Switch { stack: c, arg: 0 };
+      b.iter(|| {
+        ret = unsafe { switch_full(ret.stack, ret.arg) };
+      })
+    }
+  );
   group.bench_function(
     "paranoid",
     |b| {
@@ -0,0 +1,341 @@
+//! A safe coroutine layer on top of [`switch`].
+//!
+//! Everything a coroutine needs (its control block, its closure and the [`Stack`] itself) lives in
+//! the top few bytes of its own stack, so a [`Coroutine`] is a single pointer and spawning one
+//! allocates nothing the stack didn't already.
+mod scope;
+pub use scope::*;
+mod send;
+pub use send::*;
+
+use crate::stack::Stack;
+#[cfg(not(feature="chained-backtraces"))]
+use crate::switch::link_closure_prepared;
+#[cfg(feature="chained-backtraces")]
+use crate::switch::link_closure_prepared_chained;
+use crate::switch::{switch, Switch, PREPARED_RESERVE};
+#[cfg(unix)]
+use crate::switch::{switch_masked, SigMask};
+use core::cell::Cell;
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem::{align_of, align_of_val, size_of, size_of_val, ManuallyDrop};
+use core::ptr::{self, null_mut, NonNull};
+#[cfg(feature="std")]
+use crate::local::{Enter, Locals};
+#[cfg(feature="std")]
+use std::{any::Any, panic::{self, AssertUnwindSafe}};
+
+/// What a coroutine did when it was resumed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CoroutineResult<Y, R> {
+  /// It suspended itself with a value.
+  Yield(Y),
+  /// It finished with a value.
+  Return(R),
+}
+
+// `switch` args from the resumer.
+const RESUME: usize = 0;
+const CANCEL: usize = 1;
+// `switch` args from the coroutine.
+const YIELD:  usize = 0;
+const RETURN: usize = 1;
+#[cfg(feature="std")]
+const PANIC:  usize = 2;
+
+/// The stack was too small for a coroutine's control block and closure.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StackTooSmall {
+  /// How many bytes they needed.
+  pub needed: usize,
+  /// How many bytes the stack had.
+  pub size:   usize,
+}
+
+impl fmt::Display for StackTooSmall {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "a coroutine needs {} bytes of stack to start, but the stack has {}", self.needed, self.size)
+  }
+}
+
+#[cfg(feature="std")]
+impl std::error::Error for StackTooSmall {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+  Fresh,
+  Suspended,
+  Running,
+  Complete,
+}
+
+/// Handed to a coroutine's closure so that it may suspend itself.
+pub struct Yielder<I, Y> {
+  // The other side: the resumer's stack while we're running, ours while we're suspended.
+  sp:      Cell<*mut usize>,
+  input:   Cell<Option<I>>,
+  yielded: Cell<Option<Y>>,
+}
+
+impl<I, Y> Yielder<I, Y> {
+  /// Suspends the coroutine, handing `value` to whoever resumed it. Returns the input it is next
+  /// resumed with.
+  pub fn suspend(&self, value: Y) -> I {
+    self.yielded.set(Some(value));
+    let ret = unsafe { switch(self.sp.get(), YIELD) };
+    self.sp.set(ret.stack);
+    if ret.arg == CANCEL { cancelled() }
+    self.input.take().expect("resumed without an input")
+  }
+}
+
+/// The payload we unwind a cancelled coroutine with. Let it pass if you catch it.
+#[cfg(feature="std")]
+struct ForcedUnwind;
+
+#[cfg(feature="std")]
+fn cancelled() -> ! { panic::resume_unwind(Box::new(ForcedUnwind)) }
+
+// Without unwinding, we never ask a started coroutine to cancel.
+#[cfg(not(feature="std"))]
+fn cancelled() -> ! { unreachable!("a suspended coroutine was cancelled") }
+
+/// The control block, which lives at the very top of the coroutine's stack.
+#[repr(C)]
+struct Block<I, Y, R, S> {
+  // First, so a scope can find us from it.
+  link:    Link,
+  yielder: Yielder<I, Y>,
+  state:   Cell<State>,
+  result:  Cell<Option<R>>,
+  #[cfg(feature="std")]
+  panic:   Cell<Option<Box<dyn Any + Send>>>,
+  #[cfg(feature="std")]
+  locals:  Locals,
+  stack:   ManuallyDrop<S>,
+}
+
+/// A coroutine taking `I`s, yielding `Y`s, returning an `R` and running on a stack `S`.
+///
+/// Dropping a coroutine that has not completed unwinds it first (with the `std` feature), so
+/// everything on its stack is dropped properly. Without `std`, it's simply forgotten.
+pub struct Coroutine<I, Y, R, S: Stack> {
+  block:   NonNull<Block<I, Y, R, S>>,
+  _marker: PhantomData<Block<I, Y, R, S>>,
+}
+
+impl<I, Y, R, S: Stack> Coroutine<I, Y, R, S> {
+  /// Prepares a coroutine to run `fun` on `stack`. Nothing runs until the first resume, whose
+  /// input is passed to `fun`.
+  ///
+  /// Fails if the control block and `fun` don't fit on the stack with room to start.
+  pub fn new<F>(stack: S, fun: F) -> Result<Self, StackTooSmall>
+  where F: FnOnce(&Yielder<I, Y>, I) -> R + 'static {
+    unsafe { Self::new_unchecked(stack, fun) }
+  }
+
+  /// As [`Coroutine::new`], but `fun` may borrow things.
+  ///
+  /// # Safety
+  ///
+  /// The coroutine must not be resumed once anything `fun` borrows is gone.
+  pub(crate) unsafe fn new_unchecked<F>(stack: S, fun: F) -> Result<Self, StackTooSmall>
+  where F: FnOnce(&Yielder<I, Y>, I) -> R {
+    let end = stack.end() as usize;
+    let align = align_of::<Block<I, Y, R, S>>().max(16);
+    let block = (end.wrapping_sub(size_of::<Block<I, Y, R, S>>()) & !(align - 1)) as *mut Block<I, Y, R, S>;
+    // The closure goes just below the control block, placed as `link_closure_prepared` will.
+    let closure = move |sp, arg| start(block, fun, sp, arg);
+    let align = align_of_val(&closure).max(16);
+    let top = (block as usize).wrapping_sub(size_of_val(&closure)) & !(align - 1);
+    let needed = end.wrapping_sub(top) + PREPARED_RESERVE;
+    if needed > stack.size() {
+      return Err(StackTooSmall { needed, size: stack.size() });
+    }
+    block.write(Block {
+      link:    Link::new(destroy::<I, Y, R, S>),
+      yielder: Yielder { sp: Cell::new(null_mut()), input: Cell::new(None), yielded: Cell::new(None) },
+      state:   Cell::new(State::Fresh),
+      result:  Cell::new(None),
+      #[cfg(feature="std")]
+      panic:   Cell::new(None),
+      #[cfg(feature="std")]
+      locals:  Locals::inherit(),
+      stack:   ManuallyDrop::new(stack),
+    });
+    #[cfg(not(feature="chained-backtraces"))]
+    let sp = link_closure_prepared(block.cast(), closure);
+    // While it runs, the yielder holds the resumer's stack, so backtraces continue there.
+    #[cfg(feature="chained-backtraces")]
+    let sp = link_closure_prepared_chained(block.cast(), closure, (*block).yielder.sp.as_ptr());
+    (*block).yielder.sp.set(sp);
+    Ok(Coroutine { block: NonNull::new_unchecked(block), _marker: PhantomData })
+  }
+
+  /// Runs the coroutine until it yields or returns.
+  ///
+  /// # Panics
+  ///
+  /// * If the coroutine has already completed.
+  /// * If the coroutine panics, with its panic.
+  pub fn resume(&mut self, input: I) -> CoroutineResult<Y, R> {
+    self.resume_by(input, |sp| unsafe { switch(sp, RESUME) })
+  }
+
+  /// Like [`Coroutine::resume`], but the coroutine runs with `mask` as the thread's signal mask
+  /// (see [`switch_masked`]), leaving ours alone. Whatever mask it leaves in place when it
+  /// suspends is saved back into `mask`.
+  ///
+  /// # Panics
+  ///
+  /// As [`Coroutine::resume`].
+  #[cfg(unix)]
+  pub fn resume_masked(&mut self, input: I, mask: &mut SigMask) -> CoroutineResult<Y, R> {
+    self.resume_by(input, |sp| unsafe { switch_masked(sp, RESUME, mask) })
+  }
+
+  fn resume_by(&mut self, input: I, switch: impl FnOnce(*mut usize) -> Switch) -> CoroutineResult<Y, R> {
+    let block = self.block();
+    match block.state.get() {
+      State::Fresh | State::Suspended => (),
+      _ => panic!("resumed a completed coroutine"),
+    }
+    block.yielder.input.set(Some(input));
+    block.state.set(State::Running);
+    #[cfg(feature="std")]
+    let enter = Enter::new(&block.locals);
+    let ret = switch(block.yielder.sp.get());
+    #[cfg(feature="std")]
+    drop(enter);
+    block.yielder.sp.set(ret.stack);
+    match ret.arg {
+      YIELD => {
+        block.state.set(State::Suspended);
+        CoroutineResult::Yield(block.yielder.yielded.take().expect("yielded without a value"))
+      }
+      RETURN => {
+        self.completed();
+        CoroutineResult::Return(block.result.take().expect("returned without a value"))
+      }
+      #[cfg(feature="std")]
+      _ => {
+        self.completed();
+        panic::resume_unwind(block.panic.take().expect("panicked without a payload"))
+      }
+      #[cfg(not(feature="std"))]
+      _ => unreachable!(),
+    }
+  }
+
+  /// Has the coroutine been resumed yet?
+  pub fn started(&self) -> bool { self.block().state.get() != State::Fresh }
+
+  /// Has the coroutine returned (or panicked)?
+  pub fn done(&self) -> bool { self.block().state.get() == State::Complete }
+
+  /// Where the coroutine is suspended, if it is. See [`crate::trace`] for the caveats.
+  #[cfg(feature="backtrace")]
+  pub fn backtrace(&self) -> Option<crate::trace::PausedBacktrace> {
+    let block = self.block();
+    match block.state.get() {
+      State::Fresh | State::Suspended => unsafe {
+        Some(crate::trace::PausedBacktrace::capture(block.yielder.sp.get(), block.stack.end()))
+      }
+      _ => None,
+    }
+  }
+
+  fn block(&self) -> &Block<I, Y, R, S> { unsafe { self.block.as_ref() } }
+
+  /// Is anything tying the coroutine to this thread? See [`SendCoroutine::migrate`].
+  #[cfg(feature="std")]
+  pub(crate) fn affinity(&self) -> Option<crate::local::Affinity> { self.block().locals.affinity() }
+
+  fn completed(&self) {
+    let block = self.block();
+    block.state.set(State::Complete);
+    block.yielder.sp.set(null_mut()); // there's nothing left to resume.
+  }
+
+  /// Brings the coroutine to completion without running any more of it.
+  fn finish(&mut self) {
+    let block = self.block();
+    #[cfg(feature="std")]
+    let _enter = Enter::new(&block.locals);
+    match block.state.get() {
+      // It will drop the closure and return immediately.
+      State::Fresh => unsafe { switch(block.yielder.sp.get(), CANCEL); },
+      // Unwind it. If it insists on yielding while we're at it, keep going.
+      #[cfg(feature="std")]
+      State::Suspended => loop {
+        let ret = unsafe { switch(block.yielder.sp.get(), CANCEL) };
+        block.yielder.sp.set(ret.stack);
+        if ret.arg != YIELD { break }
+      }
+      _ => (),
+    }
+    self.completed();
+  }
+}
+
+impl<I, Y, R, S: Stack> Drop for Coroutine<I, Y, R, S> {
+  fn drop(&mut self) {
+    self.finish();
+    self.block().link.unlink();
+    unsafe {
+      let block = self.block.as_ptr();
+      // The stack must go last, because everything else is on it.
+      let stack = ManuallyDrop::take(&mut (*block).stack);
+      ptr::drop_in_place(block);
+      drop(stack);
+    }
+  }
+}
+
+/// A scope's way of dropping a coroutine whose handle it can't see.
+unsafe fn destroy<I, Y, R, S: Stack>(link: *const Link) {
+  drop(Coroutine::<I, Y, R, S> { block: NonNull::new_unchecked(link as *mut Block<I, Y, R, S>), _marker: PhantomData });
+}
+
+unsafe fn start<I, Y, R, S, F>(block: *mut Block<I, Y, R, S>, fun: F, sp: *mut usize, arg: usize) -> !
+where F: FnOnce(&Yielder<I, Y>, I) -> R {
+  let block = &*block;
+  block.yielder.sp.set(sp);
+  let ret = if arg == CANCEL {
+    drop(fun);
+    RETURN
+  } else {
+    let input = block.yielder.input.take().expect("resumed without an input");
+    run(block, fun, input)
+  };
+  // Fiber-local destructors run here, while we're still the current context.
+  #[cfg(feature="std")]
+  block.locals.clear();
+  switch(block.yielder.sp.get(), ret);
+  unreachable!("a completed coroutine was resumed")
+}
+
+#[cfg(feature="std")]
+fn run<I, Y, R, S, F>(block: &Block<I, Y, R, S>, fun: F, input: I) -> usize
+where F: FnOnce(&Yielder<I, Y>, I) -> R {
+  match panic::catch_unwind(AssertUnwindSafe(|| fun(&block.yielder, input))) {
+    Ok(value) => {
+      block.result.set(Some(value));
+      RETURN
+    }
+    // We were cancelled, nobody is listening.
+    Err(payload) if payload.is::<ForcedUnwind>() => RETURN,
+    Err(payload) => {
+      block.panic.set(Some(payload));
+      PANIC
+    }
+  }
+}
+
+#[cfg(not(feature="std"))]
+fn run<I, Y, R, S, F>(block: &Block<I, Y, R, S>, fun: F, input: I) -> usize
+where F: FnOnce(&Yielder<I, Y>, I) -> R {
+  block.result.set(Some(fun(&block.yielder, input)));
+  RETURN
+}
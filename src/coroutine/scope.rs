@@ -0,0 +1,146 @@
+//! Coroutines that may borrow from the frame that spawned them, in the spirit of
+//! `std::thread::scope`.
+//!
+//! ```
+//! use stackle::{coroutine::*, stack::*};
+//!
+//! let mut total = 0;
+//! let words = vec!["one", "two", "three"];
+//! stackle::scope(|s| {
+//!   let stack = SafeStack::new(64 * 1024, PageSize::get().unwrap()).unwrap();
+//!   let mut c = s.spawn(stack, |y: &Yielder<(), &str>, ()| {
+//!     for word in &words { y.suspend(word) }
+//!   }).unwrap();
+//!   while let CoroutineResult::Yield(word) = c.resume(()) { total += word.len() }
+//! });
+//! assert_eq!(11, total);
+//! ```
+//!
+//! A scoped coroutine can't escape its scope:
+//!
+//! ```compile_fail
+//! # use stackle::{coroutine::*, stack::*};
+//! let words = vec!["one"];
+//! let c = stackle::scope(|s| {
+//!   let stack = SafeStack::new(64 * 1024, PageSize::get().unwrap()).unwrap();
+//!   s.spawn(stack, |_: &Yielder<(), ()>, ()| words.len()).unwrap()
+//! });
+//! ```
+use super::{Coroutine, CoroutineResult, StackTooSmall, Yielder};
+use crate::stack::Stack;
+use core::cell::Cell;
+use core::marker::PhantomData;
+use core::ptr::null;
+
+/// Where a coroutine hangs in its scope's list, which is circular through the scope itself.
+/// Unscoped coroutines are never linked.
+pub(super) struct Link {
+  prev:    Cell<*const Link>,
+  next:    Cell<*const Link>,
+  // Drops the coroutine this is the link of.
+  destroy: unsafe fn(*const Link),
+}
+
+impl Link {
+  pub(super) const fn new(destroy: unsafe fn(*const Link)) -> Self {
+    Link { prev: Cell::new(null()), next: Cell::new(null()), destroy }
+  }
+
+  fn insert_after(&self, head: &Link) {
+    let next = head.next.get();
+    self.prev.set(head);
+    self.next.set(next);
+    unsafe { (*next).prev.set(self) };
+    head.next.set(self);
+  }
+
+  pub(super) fn unlink(&self) {
+    let (prev, next) = (self.prev.get(), self.next.get());
+    if next.is_null() { return }
+    unsafe {
+      (*prev).next.set(next);
+      (*next).prev.set(prev);
+    }
+    self.prev.set(null());
+    self.next.set(null());
+  }
+}
+
+unsafe fn destroy_nothing(_: *const Link) {}
+
+/// Spawns coroutines that may borrow anything that outlives the scope. See [`scope`].
+pub struct Scope<'scope, 'env: 'scope> {
+  head:   Link,
+  _scope: PhantomData<&'scope mut &'scope ()>,
+  _env:   PhantomData<&'env mut &'env ()>,
+}
+
+/// Calls `f` with a [`Scope`] whose coroutines may borrow from the enclosing frame.
+///
+/// Every coroutine spawned in the scope has finished, or been unwound (with `std`), by the time
+/// this returns, even if its handle was leaked.
+pub fn scope<'env, F, T>(f: F) -> T
+where F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T {
+  let scope = Scope { head: Link::new(destroy_nothing), _scope: PhantomData, _env: PhantomData };
+  scope.head.prev.set(&scope.head);
+  scope.head.next.set(&scope.head);
+  // Runs even if `f` panics.
+  let _cleanup = Cleanup(&scope.head);
+  f(&scope)
+}
+
+struct Cleanup<'a>(&'a Link);
+
+impl Drop for Cleanup<'_> {
+  fn drop(&mut self) {
+    // Whatever's still here had its handle leaked. Dropping one unlinks it.
+    loop {
+      let link = self.0.next.get();
+      if core::ptr::eq(link, self.0) { break }
+      unsafe { ((*link).destroy)(link) }
+    }
+  }
+}
+
+impl<'scope> Scope<'scope, '_> {
+  /// Like [`Coroutine::new`], except `fun` may borrow anything that outlives the scope.
+  pub fn spawn<I, Y, R, S, F>(
+    &'scope self, stack: S, fun: F
+  ) -> Result<ScopedCoroutine<'scope, I, Y, R, S>, StackTooSmall>
+  where I: 'scope, Y: 'scope, R: 'scope, S: Stack + 'scope,
+        F: FnOnce(&Yielder<I, Y>, I) -> R + 'scope {
+    // The handle can't outlive the scope and the scope destroys it if it's leaked, so it's never
+    // resumed once anything `fun` borrows is gone.
+    let inner = unsafe { Coroutine::new_unchecked(stack, fun) }?;
+    inner.block().link.insert_after(&self.head);
+    Ok(ScopedCoroutine { inner, _scope: PhantomData })
+  }
+}
+
+/// A coroutine that may borrow from its scope. See [`Coroutine`] for the methods.
+pub struct ScopedCoroutine<'scope, I, Y, R, S: Stack> {
+  // Not exposed mutably, or it could be swapped out of the scope.
+  inner:  Coroutine<I, Y, R, S>,
+  _scope: PhantomData<&'scope ()>,
+}
+
+impl<I, Y, R, S: Stack> ScopedCoroutine<'_, I, Y, R, S> {
+  /// See [`Coroutine::resume`].
+  pub fn resume(&mut self, input: I) -> CoroutineResult<Y, R> { self.inner.resume(input) }
+
+  /// See [`Coroutine::resume_masked`].
+  #[cfg(unix)]
+  pub fn resume_masked(&mut self, input: I, mask: &mut crate::switch::SigMask) -> CoroutineResult<Y, R> {
+    self.inner.resume_masked(input, mask)
+  }
+
+  /// See [`Coroutine::started`].
+  pub fn started(&self) -> bool { self.inner.started() }
+
+  /// See [`Coroutine::done`].
+  pub fn done(&self) -> bool { self.inner.done() }
+
+  /// See [`Coroutine::backtrace`].
+  #[cfg(feature="backtrace")]
+  pub fn backtrace(&self) -> Option<crate::trace::PausedBacktrace> { self.inner.backtrace() }
+}
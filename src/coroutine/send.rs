@@ -0,0 +1,104 @@
+//! Coroutines that may be moved between threads while suspended.
+//!
+//! A closure's captures are easy to check for `Send`, but whatever it holds on its stack when it
+//! suspends isn't: the compiler never sees a stackful coroutine's locals as a type. An `Rc`, a
+//! `std` mutex guard or a reference into a `thread_local!` held across a suspend will happily come
+//! along to the other thread. So a [`SendCoroutine`] isn't `Send` itself. You move one by calling
+//! [`SendCoroutine::migrate`], which is `unsafe` for exactly that reason, and
+//! [`Migrating::arrive`] on the other side.
+//!
+//! Debug builds (with `std`) check what they can when migrating: fiber-local values that aren't
+//! `Send`, and anything wrapped with [`crate::local::bound`].
+//!
+//! ```
+//! use stackle::{coroutine::*, stack::*};
+//!
+//! let stack = SafeStack::new(64 * 1024, PageSize::get().unwrap()).unwrap();
+//! let mut c = SendCoroutine::new(stack, |y: &Yielder<(), std::thread::ThreadId>, ()| {
+//!   loop { y.suspend(std::thread::current().id()) }
+//! }).unwrap();
+//! let here = c.resume(());
+//! let moving = unsafe { c.migrate() }.ok().expect("tied to this thread");
+//! let there = std::thread::spawn(move || moving.arrive().resume(())).join().unwrap();
+//! assert_ne!(here, there);
+//! ```
+use super::{Coroutine, CoroutineResult, StackTooSmall, Yielder};
+use crate::stack::Stack;
+#[cfg(feature="std")]
+use crate::local::Affinity;
+#[cfg(unix)]
+use crate::switch::SigMask;
+
+/// A coroutine whose closure, stack and values are all `Send`, so it may be
+/// [migrated](SendCoroutine::migrate) to another thread while suspended.
+pub struct SendCoroutine<I, Y, R, S: Stack> {
+  inner: Coroutine<I, Y, R, S>,
+}
+
+impl<I: Send, Y: Send, R: Send, S: Stack + Send> SendCoroutine<I, Y, R, S> {
+  /// See [`Coroutine::new`].
+  pub fn new<F>(stack: S, fun: F) -> Result<Self, StackTooSmall>
+  where F: FnOnce(&Yielder<I, Y>, I) -> R + Send + 'static {
+    Ok(SendCoroutine { inner: Coroutine::new(stack, fun)? })
+  }
+}
+
+impl<I, Y, R, S: Stack> SendCoroutine<I, Y, R, S> {
+  /// See [`Coroutine::resume`].
+  pub fn resume(&mut self, input: I) -> CoroutineResult<Y, R> { self.inner.resume(input) }
+
+  /// See [`Coroutine::resume_masked`].
+  #[cfg(unix)]
+  pub fn resume_masked(&mut self, input: I, mask: &mut SigMask) -> CoroutineResult<Y, R> {
+    self.inner.resume_masked(input, mask)
+  }
+
+  /// See [`Coroutine::started`].
+  pub fn started(&self) -> bool { self.inner.started() }
+
+  /// See [`Coroutine::done`].
+  pub fn done(&self) -> bool { self.inner.done() }
+
+  /// See [`Coroutine::backtrace`].
+  #[cfg(feature="backtrace")]
+  pub fn backtrace(&self) -> Option<crate::trace::PausedBacktrace> { self.inner.backtrace() }
+
+  /// Gets the coroutine ready to move to another thread.
+  ///
+  /// Debug builds with `std` hand it back, along with the reason, if it has fiber-local values that
+  /// aren't `Send` or holds a [`ThreadBound`](crate::local::ThreadBound). Release builds don't check.
+  ///
+  /// # Safety
+  ///
+  /// Nothing the coroutine holds across its current suspension may be tied to this thread: no
+  /// `!Send` values, no `std` mutex guards, no references to thread-locals.
+  #[cfg(feature="std")]
+  pub unsafe fn migrate(self) -> Result<Migrating<I, Y, R, S>, (Self, Affinity)> {
+    #[cfg(debug_assertions)]
+    if let Some(affinity) = self.inner.affinity() { return Err((self, affinity)) }
+    Ok(Migrating { inner: self.inner })
+  }
+
+  /// Gets the coroutine ready to move to another thread.
+  ///
+  /// # Safety
+  ///
+  /// Nothing the coroutine holds across its current suspension may be tied to this thread: no
+  /// `!Send` values, no references to thread-locals.
+  #[cfg(not(feature="std"))]
+  pub unsafe fn migrate(self) -> Migrating<I, Y, R, S> { Migrating { inner: self.inner } }
+}
+
+/// A [`SendCoroutine`] on its way to another thread.
+pub struct Migrating<I, Y, R, S: Stack> {
+  inner: Coroutine<I, Y, R, S>,
+}
+
+// `SendCoroutine::new` made sure of everything the compiler can see and `migrate`'s caller vouched
+// for the rest.
+unsafe impl<I: Send, Y: Send, R: Send, S: Stack + Send> Send for Migrating<I, Y, R, S> {}
+
+impl<I, Y, R, S: Stack> Migrating<I, Y, R, S> {
+  /// Takes the coroutine back on the current thread.
+  pub fn arrive(self) -> SendCoroutine<I, Y, R, S> { SendCoroutine { inner: self.inner } }
+}
@@ -4,5 +4,15 @@
 #[cfg(all(feature="alloc", not(feature="std")))]
 extern crate alloc;
 
+pub mod coroutine;
+pub use coroutine::scope;
+#[cfg(feature="std")]
+pub mod local;
+#[cfg(feature="std")]
+pub mod parking_lot;
+#[cfg(all(unix, feature="std"))]
+pub mod runtime;
 pub mod stack;
 pub mod switch;
+#[cfg(feature="backtrace")]
+pub mod trace;
@@ -0,0 +1,394 @@
+//! Fiber-local storage: like `thread_local!`, but each coroutine gets its own values.
+//!
+//! Every context (each coroutine, plus the thread itself for code outside of one) has a table of
+//! values. The coroutine layer points the thread at the table of whichever coroutine it resumes and
+//! back again when it suspends, so lookups just go through that pointer.
+//!
+//! ```
+//! use stackle::fiber_local;
+//! use std::cell::Cell;
+//!
+//! fiber_local! {
+//!   static REQUESTS: Cell<usize> = Cell::new(0);
+//!   // Copied into coroutines created while this one is running.
+//!   #[inherit]
+//!   static TRACE_ID: Cell<u64> = Cell::new(0);
+//! }
+//!
+//! REQUESTS.with(|r| r.set(r.get() + 1));
+//! ```
+//!
+//! Thread-local state we don't own (`errno`, or whatever some C library keeps per thread) can't be
+//! redirected like that, so instead a registered [`TlsSlot`] is saved when a coroutine suspends
+//! and put back when it's resumed.
+//!
+//! Some state can't follow a coroutine to another thread at all, like a held `std` mutex guard.
+//! Wrap it with [`bound`] and debug builds will refuse to migrate the coroutine holding it (see
+//! [`SendCoroutine::migrate`](crate::coroutine::SendCoroutine::migrate)).
+use core::any::Any;
+use core::cell::{Cell, RefCell};
+use core::marker::PhantomData;
+use core::mem::take;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{null, null_mut};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+/// Declares fiber-local statics of type [`FiberLocalKey`].
+///
+/// Put `#[inherit]` first to have a key's value cloned into coroutines created while it is set.
+#[macro_export]
+macro_rules! fiber_local {
+  () => {};
+  (#[inherit] $(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr $(; $($rest:tt)*)?) => {
+    $(#[$attr])* $vis static $name: $crate::local::FiberLocalKey<$t> = {
+      fn __init() -> $t { $init }
+      #[allow(unused_imports)]
+      use $crate::local::NotSend as _;
+      $crate::local::FiberLocalKey::inherited(__init, $crate::local::IsSend::<$t>::SEND)
+    };
+    $($crate::fiber_local!($($rest)*);)?
+  };
+  ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr $(; $($rest:tt)*)?) => {
+    $(#[$attr])* $vis static $name: $crate::local::FiberLocalKey<$t> = {
+      fn __init() -> $t { $init }
+      #[allow(unused_imports)]
+      use $crate::local::NotSend as _;
+      $crate::local::FiberLocalKey::new(__init, $crate::local::IsSend::<$t>::SEND)
+    };
+    $($crate::fiber_local!($($rest)*);)?
+  };
+}
+
+type InheritFn = fn(&dyn Any) -> Box<dyn Any>;
+
+// `IsSend::<T>::SEND` is true for `Send` types, otherwise it falls back to `NotSend`'s. It needs a
+// concrete `T`, which `fiber_local!` has.
+#[doc(hidden)]
+pub struct IsSend<T: ?Sized>(PhantomData<T>);
+
+#[doc(hidden)]
+pub trait NotSend { const SEND: bool = false; }
+
+impl<T: ?Sized> NotSend for IsSend<T> {}
+
+impl<T: ?Sized + Send> IsSend<T> {
+  #[doc(hidden)]
+  pub const SEND: bool = true;
+}
+
+/// A key for a fiber-local value. Create them with [`fiber_local!`].
+pub struct FiberLocalKey<T: 'static> {
+  // One more than our index into the tables, or 0 if we haven't been given one yet.
+  index:   AtomicUsize,
+  init:    fn() -> T,
+  inherit: Option<InheritFn>,
+  // Whether values can follow a coroutine to another thread.
+  send:    bool,
+}
+
+// Hands out indices into the tables.
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+impl<T: 'static> FiberLocalKey<T> {
+  #[doc(hidden)]
+  pub const fn new(init: fn() -> T, send: bool) -> Self {
+    FiberLocalKey { index: AtomicUsize::new(0), init, inherit: None, send }
+  }
+
+  #[doc(hidden)]
+  pub const fn inherited(init: fn() -> T, send: bool) -> Self where T: Clone {
+    FiberLocalKey { index: AtomicUsize::new(0), init, inherit: Some(clone_any::<T>), send }
+  }
+
+  /// Calls `f` with the current context's value, initialising it first if need be.
+  pub fn with<R>(&'static self, f: impl FnOnce(&T) -> R) -> R {
+    let index = self.index();
+    with_current(|locals| {
+      let value = match locals.get(index) {
+        Some(value) => value,
+        None => {
+          // No borrows are held while we run `init`, so it may use other fiber locals.
+          let value = Box::new((self.init)());
+          locals.insert(index, value, self.inherit, self.send)
+        }
+      };
+      // Values are boxed, so they stay put even if the table grows while `f` runs.
+      f(unsafe { &*value }.downcast_ref().expect("fiber local of the wrong type"))
+    })
+  }
+
+  fn index(&self) -> usize {
+    match self.index.load(Ordering::Acquire) {
+      0 => {
+        let fresh = NEXT_INDEX.fetch_add(1, Ordering::Relaxed) + 1;
+        // If somebody beat us to it, theirs wins.
+        match self.index.compare_exchange(0, fresh, Ordering::AcqRel, Ordering::Acquire) {
+          Ok(_) => fresh - 1,
+          Err(theirs) => theirs - 1,
+        }
+      }
+      index => index - 1,
+    }
+  }
+}
+
+fn clone_any<T: Clone + 'static>(value: &dyn Any) -> Box<dyn Any> {
+  Box::new(value.downcast_ref::<T>().expect("fiber local of the wrong type").clone())
+}
+
+struct Slot {
+  value:   Box<dyn Any>,
+  inherit: Option<InheritFn>,
+  send:    bool,
+}
+
+/// A word of thread-local state that belongs to whichever coroutine is running, accessed through
+/// a pair of functions.
+///
+/// Once registered, the coroutine layer saves its value when a coroutine suspends and restores it
+/// when it's next resumed, putting the resumer's value back in between. A coroutine starts out
+/// seeing whatever its first resumer had.
+pub struct TlsSlot {
+  get:        fn() -> usize,
+  set:        fn(usize),
+  registered: AtomicBool,
+  index:      AtomicUsize,
+  next:       AtomicPtr<TlsSlot>,
+}
+
+// The registered slots, as a list that only ever grows, so walking it needs no locks.
+static TLS_SLOTS: AtomicPtr<TlsSlot> = AtomicPtr::new(null_mut());
+static TLS_SLOT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+impl TlsSlot {
+  pub const fn new(get: fn() -> usize, set: fn(usize)) -> Self {
+    TlsSlot {
+      get, set,
+      registered: AtomicBool::new(false),
+      index:      AtomicUsize::new(0),
+      next:       AtomicPtr::new(null_mut()),
+    }
+  }
+
+  /// Starts preserving this slot across switches, on every thread. Registering twice does nothing.
+  ///
+  /// Registered inside a coroutine, whoever resumed it gets back the value as it is now.
+  pub fn register(&'static self) {
+    if self.registered.swap(true, Ordering::AcqRel) { return }
+    let index = TLS_SLOT_COUNT.fetch_add(1, Ordering::Relaxed);
+    self.index.store(index, Ordering::Relaxed);
+    // Nothing was saved for the resumers on our thread when they switched in, so it's still
+    // theirs. Those on other threads are out of reach.
+    let value = (self.get)();
+    let mut locals = CURRENT.with(Cell::get);
+    while let Some(l) = unsafe { locals.as_ref() } {
+      let mut tls = l.tls.borrow_mut();
+      if tls.len() <= index { tls.resize(index + 1, None) }
+      tls[index].get_or_insert(value);
+      locals = l.resumer.get();
+    }
+    let this = self as *const TlsSlot as *mut TlsSlot;
+    let mut head = TLS_SLOTS.load(Ordering::Acquire);
+    loop {
+      self.next.store(head, Ordering::Relaxed);
+      match TLS_SLOTS.compare_exchange_weak(head, this, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => break,
+        Err(actual) => head = actual,
+      }
+    }
+  }
+}
+
+fn for_each_tls_slot(mut f: impl FnMut(&TlsSlot)) {
+  let mut slot = TLS_SLOTS.load(Ordering::Acquire);
+  while let Some(s) = unsafe { slot.as_ref() } {
+    f(s);
+    slot = s.next.load(Ordering::Acquire);
+  }
+}
+
+#[cfg(any(target_os="linux", target_os="android", target_os="macos", target_os="ios",
+          target_os="freebsd", target_os="openbsd", target_os="netbsd"))]
+fn errno_location() -> *mut libc::c_int {
+  #[cfg(target_os="linux")]
+  unsafe { libc::__errno_location() }
+  #[cfg(any(target_os="android", target_os="openbsd", target_os="netbsd"))]
+  unsafe { libc::__errno() }
+  #[cfg(any(target_os="macos", target_os="ios", target_os="freebsd"))]
+  unsafe { libc::__error() }
+}
+
+/// `errno`. Register it if your coroutines make libc calls and check `errno` afterwards.
+#[cfg(any(target_os="linux", target_os="android", target_os="macos", target_os="ios",
+          target_os="freebsd", target_os="openbsd", target_os="netbsd"))]
+pub static ERRNO: TlsSlot = TlsSlot::new(
+  || unsafe { *errno_location() as usize },
+  |value| unsafe { *errno_location() = value as libc::c_int },
+);
+
+/// A context's table of fiber-local values and saved TLS slots.
+#[derive(Default)]
+pub(crate) struct Locals {
+  slots: RefCell<Vec<Option<Slot>>>,
+  // While we're running, the resumer's values. Otherwise, ours.
+  tls:   RefCell<Vec<Option<usize>>>,
+  // How many `ThreadBound`s are alive in this context.
+  bound: Cell<usize>,
+  // While we're running, the table of whoever resumed us (null for the thread's).
+  resumer: Cell<*const Locals>,
+}
+
+impl Locals {
+  /// A table holding copies of the current context's inheritable values.
+  pub(crate) fn inherit() -> Self {
+    with_current(|parent| {
+      let slots = parent.slots.borrow().iter().map(|slot| {
+        slot.as_ref().and_then(|slot| slot.inherit.map(|inherit| {
+          Slot { value: inherit(&*slot.value), inherit: Some(inherit), send: slot.send }
+        }))
+      }).collect();
+      Locals { slots: RefCell::new(slots), ..Locals::default() }
+    })
+  }
+
+  fn get(&self, index: usize) -> Option<*const dyn Any> {
+    let slots = self.slots.borrow();
+    slots.get(index)?.as_ref().map(|slot| &*slot.value as *const dyn Any)
+  }
+
+  fn insert(
+    &self, index: usize, value: Box<dyn Any>, inherit: Option<InheritFn>, send: bool
+  ) -> *const dyn Any {
+    let mut slots = self.slots.borrow_mut();
+    if slots.len() <= index { slots.resize_with(index + 1, || None) }
+    // If `init` recursed into this key, keep the value it already stored.
+    let slot = slots[index].get_or_insert(Slot { value, inherit, send });
+    &*slot.value as *const dyn Any
+  }
+
+  /// Swaps our saved TLS slot values with the thread's, going either way.
+  fn swap_tls(&self) {
+    let mut tls = self.tls.borrow_mut();
+    for_each_tls_slot(|slot| {
+      let index = slot.index.load(Ordering::Relaxed);
+      if tls.len() <= index { tls.resize(index + 1, None) }
+      let current = (slot.get)();
+      if let Some(saved) = tls[index] { (slot.set)(saved) }
+      tls[index] = Some(current);
+    })
+  }
+
+  /// Does anything tie this context to the thread it's on? Saved TLS slots don't count, they're
+  /// just words, and neither do `Send` values.
+  pub(crate) fn affinity(&self) -> Option<Affinity> {
+    if self.bound.get() != 0 { return Some(Affinity::ThreadBound) }
+    if self.slots.borrow().iter().flatten().any(|slot| !slot.send) {
+      return Some(Affinity::FiberLocals)
+    }
+    None
+  }
+
+  /// Drops every value. Destructors may create new values, so we go until none are left.
+  pub(crate) fn clear(&self) {
+    loop {
+      let slots = take(&mut *self.slots.borrow_mut());
+      if slots.iter().all(Option::is_none) { break }
+      drop(slots);
+    }
+  }
+}
+
+std::thread_local! {
+  // The table of whichever coroutine is running, or null outside of one.
+  static CURRENT: Cell<*const Locals> = const { Cell::new(null()) };
+  // The table for the thread itself.
+  static THREAD: Locals = Locals::default();
+}
+
+fn with_current<R>(f: impl FnOnce(&Locals) -> R) -> R {
+  let current = CURRENT.with(Cell::get);
+  if current.is_null() { THREAD.with(f) } else { f(unsafe { &*current }) }
+}
+
+/// Makes `locals` current and swaps in its TLS slots until dropped.
+pub(crate) struct Enter<'a> {
+  locals: &'a Locals,
+}
+
+impl<'a> Enter<'a> {
+  pub(crate) fn new(locals: &'a Locals) -> Self {
+    locals.swap_tls();
+    locals.resumer.set(CURRENT.with(|c| c.replace(locals)));
+    Enter { locals }
+  }
+}
+
+impl Drop for Enter<'_> {
+  fn drop(&mut self) {
+    CURRENT.with(|c| c.set(self.locals.resumer.get()));
+    self.locals.swap_tls();
+  }
+}
+
+/// Why a context can't move to another thread.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Affinity {
+  /// It has fiber-local values that aren't `Send`.
+  FiberLocals,
+  /// It holds a [`ThreadBound`].
+  ThreadBound,
+}
+
+/// Ties the current context to its thread until dropped. Drop it in the context that made it.
+#[must_use]
+pub struct ThreadBound {
+  _not_send: PhantomData<*const ()>,
+}
+
+impl ThreadBound {
+  pub fn new() -> Self {
+    with_current(|locals| locals.bound.set(locals.bound.get() + 1));
+    ThreadBound { _not_send: PhantomData }
+  }
+}
+
+impl Default for ThreadBound {
+  fn default() -> Self { Self::new() }
+}
+
+impl Drop for ThreadBound {
+  fn drop(&mut self) {
+    with_current(|locals| locals.bound.set(locals.bound.get().saturating_sub(1)));
+  }
+}
+
+/// A guard (or anything else) that must stay on this thread, along with a [`ThreadBound`].
+pub struct Bound<G> {
+  guard:  G,
+  _bound: ThreadBound,
+}
+
+/// Wraps `guard` so the current context is tied to its thread for as long as it's held.
+///
+/// ```
+/// use std::sync::Mutex;
+///
+/// let m = Mutex::new(0);
+/// let mut count = stackle::local::bound(m.lock().unwrap());
+/// *count += 1;
+/// ```
+pub fn bound<G>(guard: G) -> Bound<G> { Bound { guard, _bound: ThreadBound::new() } }
+
+impl<G> Bound<G> {
+  /// Unwraps the guard, untying the context.
+  pub fn into_inner(self) -> G { self.guard }
+}
+
+impl<G: Deref> Deref for Bound<G> {
+  type Target = G::Target;
+  fn deref(&self) -> &G::Target { &self.guard }
+}
+
+impl<G: DerefMut> DerefMut for Bound<G> {
+  fn deref_mut(&mut self) -> &mut G::Target { &mut self.guard }
+}
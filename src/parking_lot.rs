@@ -0,0 +1,225 @@
+//! A parking lot: somewhere to wait on an address until someone wakes you, for building your own
+//! blocking primitives out of a word or two of atomics.
+//!
+//! Waiters [`park`] on a key, usually the address of the primitive they're waiting on, and
+//! [`unpark_one`] or [`unpark_all`] wakes them. The lot keeps the queues, so the primitive itself
+//! needn't hold anything but its own state. Parking checks a condition under the queue's lock
+//! first, so as long as whoever changes that condition unparks afterwards, nobody can miss a
+//! wakeup.
+//!
+//! Waiting doesn't have to block a thread. Whatever runs coroutines can [install](with_scheduler)
+//! a [`Scheduler`] while it runs one, and parking then suspends just that coroutine.
+//! [`runtime`](crate::runtime) tasks do, and anything else falls back to parking the thread.
+//!
+//! ```
+//! use stackle::parking_lot::{self, ParkResult};
+//! use std::sync::atomic::{AtomicBool, Ordering};
+//! use std::sync::Arc;
+//!
+//! /// Something you can wait for, which stays set once it is.
+//! struct Event(AtomicBool);
+//!
+//! impl Event {
+//!   fn key(&self) -> usize { self as *const Self as usize }
+//!
+//!   fn wait(&self) {
+//!     while !self.0.load(Ordering::Acquire) {
+//!       parking_lot::park(self.key(), || !self.0.load(Ordering::Acquire), || (), None);
+//!     }
+//!   }
+//!
+//!   fn set(&self) {
+//!     self.0.store(true, Ordering::Release);
+//!     parking_lot::unpark_all(self.key());
+//!   }
+//! }
+//!
+//! let event = Arc::new(Event(AtomicBool::new(false)));
+//! let setter = event.clone();
+//! std::thread::spawn(move || setter.set());
+//! event.wait();
+//! ```
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, Thread};
+use std::time::Instant;
+
+/// Something that can wake a parked context. It can go to other threads.
+pub trait Unpark: Send + Sync {
+  /// Wakes it, or if it hasn't got as far as parking yet, makes sure it won't stay parked.
+  fn unpark(&self);
+}
+
+impl Unpark for Thread {
+  fn unpark(&self) { Thread::unpark(self) }
+}
+
+/// How the lot suspends whatever's running, and wakes it again.
+pub trait Scheduler: Sync {
+  /// Something to wake the current context with.
+  fn unparker(&self) -> Box<dyn Unpark>;
+
+  /// Suspends the current context until it's unparked, or `deadline` passes if there is one. If
+  /// it was unparked since it last parked, this returns straight away. It may also return for no
+  /// reason at all.
+  fn park(&self, deadline: Option<Instant>);
+
+  /// The time by the clock that `park` goes by.
+  fn now(&self) -> Instant { Instant::now() }
+}
+
+/// Parks the thread. What the lot uses when nobody's installed anything else.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ThreadScheduler;
+
+impl Scheduler for ThreadScheduler {
+  fn unparker(&self) -> Box<dyn Unpark> { Box::new(thread::current()) }
+
+  fn park(&self, deadline: Option<Instant>) {
+    match deadline {
+      Some(deadline) => thread::park_timeout(deadline.saturating_duration_since(Instant::now())),
+      None => thread::park(),
+    }
+  }
+}
+
+std::thread_local! {
+  static SCHEDULER: Cell<Option<&'static dyn Scheduler>> = const { Cell::new(None) };
+}
+
+/// Runs `f` with `scheduler` parking whatever parks in it, then puts back the one before.
+///
+/// Executors wrap resuming a coroutine in this, so that it's the coroutine that gets suspended.
+/// The scheduler is `'static` because a coroutine may be resumed with another one installed (by
+/// another call to this) than the one it parked with, and must still be able to use it.
+pub fn with_scheduler<R>(scheduler: &'static dyn Scheduler, f: impl FnOnce() -> R) -> R {
+  struct Restore(Option<&'static dyn Scheduler>);
+  impl Drop for Restore {
+    fn drop(&mut self) { SCHEDULER.with(|s| s.set(self.0)) }
+  }
+  let _restore = Restore(SCHEDULER.with(|s| s.replace(Some(scheduler))));
+  f()
+}
+
+fn scheduler() -> &'static dyn Scheduler { SCHEDULER.with(Cell::get).unwrap_or(&ThreadScheduler) }
+
+/// How a [`park`] ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParkResult {
+  /// Somebody unparked us.
+  Unparked,
+  /// `validate` said not to wait.
+  Invalid,
+  /// The deadline passed first.
+  TimedOut,
+}
+
+/// What an [`unpark_one`] did.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UnparkResult {
+  /// Whether there was anyone to unpark.
+  pub unparked:  bool,
+  /// Whether there's anyone else still parked on the key. It may have changed by the time you
+  /// look, of course.
+  pub have_more: bool,
+}
+
+struct Parked {
+  key:      usize,
+  unparker: Box<dyn Unpark>,
+  // Set, under the bucket's lock, when it's taken off the queue to be woken.
+  unparked: AtomicBool,
+}
+
+// Keys hash to one of these. Different keys may share a queue, so every lookup checks the key.
+const BUCKETS: usize = 64;
+static QUEUES: [Mutex<VecDeque<Arc<Parked>>>; BUCKETS] = [const { Mutex::new(VecDeque::new()) }; BUCKETS];
+
+fn queue(key: usize) -> &'static Mutex<VecDeque<Arc<Parked>>> {
+  // Fibonacci hashing: addresses are mostly alike at the bottom, so use the top.
+  let hash = (key as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (64 - BUCKETS.trailing_zeros());
+  &QUEUES[hash as usize]
+}
+
+/// Parks on `key` until it's unparked, or `timeout` passes if there is one, by the installed
+/// scheduler's [clock](Scheduler::now).
+///
+/// `validate` is called first, with the key's queue locked, so nobody can unpark it meanwhile. If
+/// it returns false, we return [`ParkResult::Invalid`] without parking. `before_sleep` is called
+/// once we're on the queue and it's been unlocked, just before we actually park, for anything
+/// that'd otherwise keep whoever unparks us from getting to it (like unlocking something).
+///
+/// Neither may park on the lot themselves. Unlike [`Scheduler::park`], this doesn't return
+/// without a reason.
+pub fn park(key: usize, validate: impl FnOnce() -> bool, before_sleep: impl FnOnce(), timeout: Option<Instant>) -> ParkResult {
+  let scheduler = scheduler();
+  let queue = queue(key);
+  let mut waiters = queue.lock().unwrap();
+  if !validate() { return ParkResult::Invalid }
+  let me = Arc::new(Parked { key, unparker: scheduler.unparker(), unparked: AtomicBool::new(false) });
+  waiters.push_back(me.clone());
+  drop(waiters);
+
+  // If we're unwound while parked, say because our task was cancelled, nobody else can take us
+  // off the queue. And if somebody already has, to wake us, the wakeup goes to whoever's next
+  // instead, or they might wait for good on something we'll never use.
+  struct Leave<'a> {
+    queue: &'a Mutex<VecDeque<Arc<Parked>>>,
+    me:    &'a Arc<Parked>,
+  }
+  impl Drop for Leave<'_> {
+    fn drop(&mut self) {
+      let mut waiters = self.queue.lock().unwrap();
+      if self.me.unparked.load(Ordering::Acquire) {
+        drop(waiters);
+        unpark_one(self.me.key);
+      } else if let Some(index) = waiters.iter().position(|w| Arc::ptr_eq(w, self.me)) {
+        waiters.remove(index);
+      }
+    }
+  }
+  let leave = Leave { queue, me: &me };
+  before_sleep();
+  let result = loop {
+    scheduler.park(timeout);
+    if me.unparked.load(Ordering::Acquire) { break ParkResult::Unparked }
+    if timeout.is_some_and(|deadline| scheduler.now() >= deadline) {
+      let mut waiters = queue.lock().unwrap();
+      // Somebody may have got to us on the way.
+      if me.unparked.load(Ordering::Acquire) { break ParkResult::Unparked }
+      if let Some(index) = waiters.iter().position(|w| Arc::ptr_eq(w, &me)) { waiters.remove(index); }
+      break ParkResult::TimedOut
+    }
+  };
+  std::mem::forget(leave);
+  result
+}
+
+/// Unparks whoever's been parked on `key` longest.
+pub fn unpark_one(key: usize) -> UnparkResult {
+  let mut waiters = queue(key).lock().unwrap();
+  let Some(index) = waiters.iter().position(|w| w.key == key) else { return UnparkResult::default() };
+  let next = waiters.remove(index).expect("just found it");
+  next.unparked.store(true, Ordering::Release);
+  let have_more = waiters.iter().skip(index).any(|w| w.key == key);
+  drop(waiters);
+  next.unparker.unpark();
+  UnparkResult { unparked: true, have_more }
+}
+
+/// Unparks everyone parked on `key`, returning how many there were.
+pub fn unpark_all(key: usize) -> usize {
+  let mut waiters = queue(key).lock().unwrap();
+  let mut woken = Vec::new();
+  waiters.retain(|w| {
+    if w.key != key { return true }
+    w.unparked.store(true, Ordering::Release);
+    woken.push(w.clone());
+    false
+  });
+  drop(waiters);
+  for waiter in &woken { waiter.unparker.unpark() }
+  woken.len()
+}
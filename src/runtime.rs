@@ -0,0 +1,35 @@
+//! A green thread runtime built on the [coroutine](crate::coroutine) layer.
+//!
+//! Tasks are coroutines on pooled [`SafeStack`](crate::stack::SafeStack)s. They're plain
+//! closures: no futures, no `async`, so any blocking-style code can run in one as long as it
+//! [yields](yield_now) or [parks](park) instead of blocking the thread.
+//!
+//! There's a [`LocalExecutor`] for running them on the current thread, an [`Executor`] that
+//! spreads them over a pool of worker threads and, on Linux, a [`ThreadPerCore`] whose workers
+//! are pinned to a CPU each and never share tasks. The functions here, the [`channel`]s, the
+//! [`sync`] primitives and the [`time`] functions work in all of them, as do the sockets in `net`
+//! and the io_uring ops in `uring` on Linux.
+pub mod channel;
+mod local;
+pub use local::*;
+#[cfg(any(target_os="linux", target_os="android"))]
+pub mod net;
+#[cfg(any(target_os="linux", target_os="android"))]
+mod per_core;
+#[cfg(any(target_os="linux", target_os="android"))]
+pub use per_core::*;
+mod pool;
+pub use pool::*;
+#[cfg(any(target_os="linux", target_os="android"))]
+mod reactor;
+mod stacks;
+pub mod sync;
+mod task;
+pub use task::{current, park, yield_now, JoinHandle, Task};
+pub mod time;
+#[cfg(any(target_os="linux", target_os="android"))]
+pub mod uring;
+mod wait;
+
+/// Plenty for most code. The pages are only touched as they're used.
+pub const DEFAULT_STACK_SIZE: u32 = 256 * 1024;
@@ -0,0 +1,319 @@
+//! Channels for handing values between tasks, and between tasks and plain threads.
+//!
+//! [`channel`] and [`unbounded`] make multi-producer, single-consumer queues, [`oneshot`] carries
+//! a single value and [`broadcast`] gives every receiver a copy of everything sent. A `send` that
+//! finds no room or a `recv` that finds nothing parks just the task it's called from, and whoever
+//! makes room or sends something unparks it through its executor. Each has a `try_` variant that
+//! gives up instead.
+//!
+//! A channel is closed once everyone on the other end is gone: sending then hands the value back,
+//! and receiving drains what's left and then fails.
+//!
+//! ```
+//! use stackle::runtime::{channel, LocalExecutor};
+//!
+//! let exec = LocalExecutor::new();
+//! let (tx, rx) = channel::channel(2);
+//! exec.spawn(move || for i in 0..10 { tx.send(i).unwrap() });
+//! let sum = exec.spawn(move || rx.iter().sum::<i32>());
+//! exec.run_until_idle();
+//! assert_eq!(45, sum.join().unwrap());
+//! ```
+//!
+//! Either end can just as well be a plain thread, outside of any task, which blocks (on a futex,
+//! where there is one) until a task unblocks it. Going the other way, unblocking a task goes
+//! through its executor's usual wakeup, so a task on an [`Executor`](super::Executor) or
+//! [`ThreadPerCore`](super::ThreadPerCore) worker carries on by itself. A
+//! [`LocalExecutor`](super::LocalExecutor)'s tasks only run while its thread runs them, so a
+//! thread that blocks on a channel (or [joins](super::JoinHandle::join) a task) runs its own
+//! executors' tasks as they become ready, and only sleeps when there are none.
+//!
+//! ```
+//! use stackle::runtime::{channel, Executor};
+//! use std::thread;
+//!
+//! let exec = Executor::new(2);
+//! let (jobs, incoming) = channel::channel(8);
+//! let (done, results) = channel::unbounded();
+//! unsafe { exec.spawn(move || for job in &incoming { done.send(job * 2).unwrap() }) };
+//! let feeder = thread::spawn(move || for job in 0..100 { jobs.send(job).unwrap() });
+//! assert_eq!(9900, results.iter().sum::<i32>());
+//! feeder.join().unwrap();
+//! ```
+use super::wait::{wait_in, Waiters};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+pub mod broadcast;
+pub mod oneshot;
+
+/// The receiving side has gone away. Here's your value back.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+/// Why a [`try_send`](Sender::try_send) didn't. Either way, here's your value back.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+  /// There's no room for it right now.
+  Full(T),
+  /// The receiving side has gone away.
+  Closed(T),
+}
+
+/// The sending side has gone away and there's nothing left to receive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecvError;
+
+/// Why a [`try_recv`](Receiver::try_recv) didn't.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TryRecvError {
+  /// There's nothing to receive right now.
+  Empty,
+  /// The sending side has gone away and there's nothing left to receive.
+  Closed,
+}
+
+// Like std's, these don't print the value, so `unwrap` works whatever it is.
+impl<T> fmt::Debug for SendError<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("SendError(..)") }
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      TrySendError::Full(_) => f.write_str("Full(..)"),
+      TrySendError::Closed(_) => f.write_str("Closed(..)"),
+    }
+  }
+}
+
+impl<T> fmt::Display for SendError<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("sending on a closed channel") }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      TrySendError::Full(_) => f.write_str("sending on a full channel"),
+      TrySendError::Closed(_) => f.write_str("sending on a closed channel"),
+    }
+  }
+}
+
+impl fmt::Display for RecvError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("receiving on a closed channel") }
+}
+
+impl fmt::Display for TryRecvError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      TryRecvError::Empty => f.write_str("receiving on an empty channel"),
+      TryRecvError::Closed => f.write_str("receiving on a closed channel"),
+    }
+  }
+}
+
+impl<T> std::error::Error for SendError<T> {}
+impl<T> std::error::Error for TrySendError<T> {}
+impl std::error::Error for RecvError {}
+impl std::error::Error for TryRecvError {}
+
+impl<T> TrySendError<T> {
+  /// The value that wasn't sent.
+  pub fn into_inner(self) -> T {
+    match self {
+      TrySendError::Full(value) | TrySendError::Closed(value) => value,
+    }
+  }
+}
+
+struct State<T> {
+  queue:     VecDeque<T>,
+  // `None` for unbounded.
+  capacity:  Option<usize>,
+  senders:   usize,
+  receiver:  bool,
+  receiving: Waiters,
+  // Senders waiting for room.
+  sending:   Waiters,
+}
+
+impl<T> State<T> {
+  fn has_room(&self) -> bool { self.capacity.is_none_or(|capacity| self.queue.len() < capacity) }
+}
+
+/// Sends values to a [`Receiver`]. Clone it for more senders.
+pub struct Sender<T> {
+  shared: Arc<Mutex<State<T>>>,
+}
+
+/// Receives the values sent by any of the [`Sender`]s, in the order they were sent.
+pub struct Receiver<T> {
+  shared: Arc<Mutex<State<T>>>,
+}
+
+/// A channel holding up to `capacity` values. Sending more parks the sender until the receiver
+/// catches up.
+///
+/// # Panics
+///
+/// If `capacity` is 0.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+  assert!(capacity > 0, "a channel needs room for at least one value");
+  with_capacity(Some(capacity))
+}
+
+/// A channel that holds however many values it's sent, so sending never parks.
+pub fn unbounded<T>() -> (Sender<T>, Receiver<T>) { with_capacity(None) }
+
+fn with_capacity<T>(capacity: Option<usize>) -> (Sender<T>, Receiver<T>) {
+  let shared = Arc::new(Mutex::new(State {
+    queue:     VecDeque::new(),
+    capacity,
+    senders:   1,
+    receiver:  true,
+    receiving: Waiters::default(),
+    sending:   Waiters::default(),
+  }));
+  (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+impl<T> Sender<T> {
+  /// Sends `value`, parking until there's room for it.
+  ///
+  /// Fails if the receiver is gone, now or while we wait.
+  pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+    let mut state = self.shared.lock().unwrap();
+    loop {
+      if !state.receiver { return Err(SendError(value)) }
+      if state.has_room() { break }
+      let me = state.sending.push_current();
+      drop(state);
+      wait_in(&self.shared, &me, |state| &mut state.sending);
+      state = self.shared.lock().unwrap();
+      state.sending.remove(&me);
+    }
+    state.queue.push_back(value);
+    let receiver = state.receiving.pop();
+    // Someone else may have got our wakeup and then not used the room.
+    let sender = if state.has_room() { state.sending.pop() } else { None };
+    drop(state);
+    receiver.iter().chain(&sender).for_each(|waiter| waiter.unpark());
+    Ok(())
+  }
+
+  /// Sends `value` if there's room for it right now.
+  pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+    let mut state = self.shared.lock().unwrap();
+    if !state.receiver { return Err(TrySendError::Closed(value)) }
+    if !state.has_room() { return Err(TrySendError::Full(value)) }
+    state.queue.push_back(value);
+    let receiver = state.receiving.pop();
+    drop(state);
+    if let Some(receiver) = receiver { receiver.unpark() }
+    Ok(())
+  }
+
+  /// Has the receiver gone away?
+  pub fn is_closed(&self) -> bool { !self.shared.lock().unwrap().receiver }
+}
+
+impl<T> Clone for Sender<T> {
+  fn clone(&self) -> Self {
+    self.shared.lock().unwrap().senders += 1;
+    Sender { shared: self.shared.clone() }
+  }
+}
+
+impl<T> Drop for Sender<T> {
+  fn drop(&mut self) {
+    let mut state = self.shared.lock().unwrap();
+    state.senders -= 1;
+    if state.senders > 0 { return }
+    let receivers = state.receiving.take();
+    drop(state);
+    receivers.iter().for_each(|waiter| waiter.unpark());
+  }
+}
+
+impl<T> Receiver<T> {
+  /// The next value, parking until there is one.
+  ///
+  /// Fails once every sender is gone and everything they sent has been received.
+  pub fn recv(&self) -> Result<T, RecvError> {
+    let mut state = self.shared.lock().unwrap();
+    loop {
+      if let Some(value) = state.queue.pop_front() {
+        let sender = state.sending.pop();
+        drop(state);
+        if let Some(sender) = sender { sender.unpark() }
+        return Ok(value)
+      }
+      if state.senders == 0 { return Err(RecvError) }
+      let me = state.receiving.push_current();
+      drop(state);
+      wait_in(&self.shared, &me, |state| &mut state.receiving);
+      state = self.shared.lock().unwrap();
+      state.receiving.remove(&me);
+    }
+  }
+
+  /// The next value, if there is one right now.
+  pub fn try_recv(&self) -> Result<T, TryRecvError> {
+    let mut state = self.shared.lock().unwrap();
+    match state.queue.pop_front() {
+      Some(value) => {
+        let sender = state.sending.pop();
+        drop(state);
+        if let Some(sender) = sender { sender.unpark() }
+        Ok(value)
+      }
+      None if state.senders == 0 => Err(TryRecvError::Closed),
+      None => Err(TryRecvError::Empty),
+    }
+  }
+
+  /// Receives values until the channel is closed.
+  pub fn iter(&self) -> Iter<'_, T> { Iter { receiver: self } }
+
+  /// Are all the senders gone? There may still be values to receive.
+  pub fn is_closed(&self) -> bool { self.shared.lock().unwrap().senders == 0 }
+}
+
+impl<T> Drop for Receiver<T> {
+  fn drop(&mut self) {
+    let mut state = self.shared.lock().unwrap();
+    state.receiver = false;
+    // Dropped once we've let go of the lock, in case that does anything with the channel.
+    let unreceived = std::mem::take(&mut state.queue);
+    let senders = state.sending.take();
+    drop(state);
+    drop(unreceived);
+    senders.iter().for_each(|waiter| waiter.unpark());
+  }
+}
+
+/// An iterator that [receives](Receiver::recv) until the channel is closed.
+pub struct Iter<'a, T> {
+  receiver: &'a Receiver<T>,
+}
+
+impl<T> Iterator for Iter<'_, T> {
+  type Item = T;
+  fn next(&mut self) -> Option<T> { self.receiver.recv().ok() }
+}
+
+impl<'a, T> IntoIterator for &'a Receiver<T> {
+  type Item = T;
+  type IntoIter = Iter<'a, T>;
+  fn into_iter(self) -> Iter<'a, T> { self.iter() }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("Sender { .. }") }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("Receiver { .. }") }
+}
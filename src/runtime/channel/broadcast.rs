@@ -0,0 +1,244 @@
+//! A channel where every receiver gets its own copy of every value.
+//!
+//! It holds up to its capacity in values that some receiver hasn't got to yet, and sending any
+//! more parks until the slowest one catches up. Nobody misses anything, but one stuck receiver
+//! holds everyone up, so drop the ones you're done with.
+//!
+//! ```
+//! use stackle::runtime::{channel::broadcast, LocalExecutor};
+//!
+//! let exec = LocalExecutor::new();
+//! let (tx, rx) = broadcast::channel(4);
+//! let listeners: Vec<_> = (0..3).map(|_| {
+//!   let mut rx = rx.clone();
+//!   exec.spawn(move || rx.recv().unwrap())
+//! }).collect();
+//! drop(rx);
+//! tx.send("hello").unwrap();
+//! exec.run_until_idle();
+//! for listener in listeners { assert_eq!("hello", listener.join().unwrap()) }
+//! ```
+use crate::runtime::wait::{wait_in, Waiters};
+use super::{RecvError, SendError, TryRecvError, TrySendError};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+struct Slot<T> {
+  value:  T,
+  // How many receivers have yet to see it.
+  unseen: usize,
+}
+
+struct State<T> {
+  queue:     VecDeque<Slot<T>>,
+  // Where the front of the queue is in the sequence of everything sent.
+  head:      u64,
+  capacity:  usize,
+  senders:   usize,
+  receivers: usize,
+  receiving: Waiters,
+  sending:   Waiters,
+}
+
+impl<T> State<T> {
+  fn tail(&self) -> u64 { self.head + self.queue.len() as u64 }
+
+  /// One fewer receiver will see everything from `next` on. Returns what nobody will see again,
+  /// for dropping once the lock's released.
+  fn unsee(&mut self, next: u64) -> Vec<T> {
+    let start = (next - self.head) as usize;
+    for slot in self.queue.range_mut(start..) { slot.unseen -= 1 }
+    let mut seen = Vec::new();
+    while self.queue.front().is_some_and(|slot| slot.unseen == 0) {
+      seen.extend(self.queue.pop_front().map(|slot| slot.value));
+      self.head += 1;
+    }
+    seen
+  }
+}
+
+/// Sends values to every [`Receiver`]. Clone it for more senders.
+pub struct Sender<T> {
+  shared: Arc<Mutex<State<T>>>,
+}
+
+/// Receives a copy of every value sent after it was made. Clone it, or
+/// [subscribe](Sender::subscribe), for more receivers.
+pub struct Receiver<T> {
+  shared: Arc<Mutex<State<T>>>,
+  // Where we're up to in the sequence of everything sent.
+  next:   u64,
+}
+
+/// A broadcast channel holding up to `capacity` values that not every receiver has seen.
+///
+/// # Panics
+///
+/// If `capacity` is 0.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+  assert!(capacity > 0, "a channel needs room for at least one value");
+  let shared = Arc::new(Mutex::new(State {
+    queue:     VecDeque::new(),
+    head:      0,
+    capacity,
+    senders:   1,
+    receivers: 1,
+    receiving: Waiters::default(),
+    sending:   Waiters::default(),
+  }));
+  (Sender { shared: shared.clone() }, Receiver { shared, next: 0 })
+}
+
+impl<T> Sender<T> {
+  /// Sends `value` to every receiver, parking until there's room for it.
+  ///
+  /// Fails if there are no receivers, now or while we wait.
+  pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+    let mut state = self.shared.lock().unwrap();
+    loop {
+      if state.receivers == 0 { return Err(SendError(value)) }
+      if state.queue.len() < state.capacity { break }
+      let me = state.sending.push_current();
+      drop(state);
+      wait_in(&self.shared, &me, |state| &mut state.sending);
+      state = self.shared.lock().unwrap();
+      state.sending.remove(&me);
+    }
+    let unseen = state.receivers;
+    state.queue.push_back(Slot { value, unseen });
+    let receivers = state.receiving.take();
+    // Someone else may have got our wakeup and then not used the room.
+    let sender = if state.queue.len() < state.capacity { state.sending.pop() } else { None };
+    drop(state);
+    receivers.iter().chain(&sender).for_each(|waiter| waiter.unpark());
+    Ok(())
+  }
+
+  /// Sends `value` to every receiver if there's room for it right now.
+  pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+    let mut state = self.shared.lock().unwrap();
+    if state.receivers == 0 { return Err(TrySendError::Closed(value)) }
+    if state.queue.len() >= state.capacity { return Err(TrySendError::Full(value)) }
+    let unseen = state.receivers;
+    state.queue.push_back(Slot { value, unseen });
+    let receivers = state.receiving.take();
+    drop(state);
+    receivers.iter().for_each(|waiter| waiter.unpark());
+    Ok(())
+  }
+
+  /// A new receiver, which gets everything sent from now on.
+  pub fn subscribe(&self) -> Receiver<T> {
+    let mut state = self.shared.lock().unwrap();
+    state.receivers += 1;
+    Receiver { shared: self.shared.clone(), next: state.tail() }
+  }
+
+  /// How many receivers there are.
+  pub fn receiver_count(&self) -> usize { self.shared.lock().unwrap().receivers }
+}
+
+impl<T> Clone for Sender<T> {
+  fn clone(&self) -> Self {
+    self.shared.lock().unwrap().senders += 1;
+    Sender { shared: self.shared.clone() }
+  }
+}
+
+impl<T> Drop for Sender<T> {
+  fn drop(&mut self) {
+    let mut state = self.shared.lock().unwrap();
+    state.senders -= 1;
+    if state.senders > 0 { return }
+    let receivers = state.receiving.take();
+    drop(state);
+    receivers.iter().for_each(|waiter| waiter.unpark());
+  }
+}
+
+impl<T: Clone> Receiver<T> {
+  /// The next value, parking until there is one.
+  ///
+  /// Fails once every sender is gone and we've received everything they sent.
+  pub fn recv(&mut self) -> Result<T, RecvError> {
+    let mut state = self.shared.lock().unwrap();
+    loop {
+      if self.next < state.tail() { return Ok(take(state, &mut self.next)) }
+      if state.senders == 0 { return Err(RecvError) }
+      let me = state.receiving.push_current();
+      drop(state);
+      wait_in(&self.shared, &me, |state| &mut state.receiving);
+      state = self.shared.lock().unwrap();
+      state.receiving.remove(&me);
+    }
+  }
+
+  /// The next value, if there is one right now.
+  pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+    let state = self.shared.lock().unwrap();
+    if self.next < state.tail() { return Ok(take(state, &mut self.next)) }
+    match state.senders {
+      0 => Err(TryRecvError::Closed),
+      _ => Err(TryRecvError::Empty),
+    }
+  }
+}
+
+/// Takes the value at `next` off a receiver's hands and moves it along.
+fn take<T: Clone>(mut state: MutexGuard<State<T>>, next: &mut u64) -> T {
+  let index = (*next - state.head) as usize;
+  *next += 1;
+  let slot = &mut state.queue[index];
+  slot.unseen -= 1;
+  if slot.unseen > 0 { return slot.value.clone() }
+  // We were the last to see it, and so are the last to see everything before it, which makes it
+  // the front. Nobody else wants it, so it's ours.
+  let value = state.queue.pop_front().expect("the last one to see it").value;
+  state.head += 1;
+  let sender = state.sending.pop();
+  drop(state);
+  if let Some(sender) = sender { sender.unpark() }
+  value
+}
+
+impl<T> Receiver<T> {
+  /// Are all the senders gone? There may still be values to receive.
+  pub fn is_closed(&self) -> bool { self.shared.lock().unwrap().senders == 0 }
+}
+
+impl<T> Clone for Receiver<T> {
+  /// Another receiver, that's up to where we are.
+  fn clone(&self) -> Self {
+    let mut state = self.shared.lock().unwrap();
+    state.receivers += 1;
+    let start = (self.next - state.head) as usize;
+    for slot in state.queue.range_mut(start..) { slot.unseen += 1 }
+    Receiver { shared: self.shared.clone(), next: self.next }
+  }
+}
+
+impl<T> Drop for Receiver<T> {
+  fn drop(&mut self) {
+    let mut state = self.shared.lock().unwrap();
+    state.receivers -= 1;
+    let seen = state.unsee(self.next);
+    // Without receivers, senders just fail.
+    let senders = match (state.receivers, seen.is_empty()) {
+      (0, _) => state.sending.take(),
+      (_, false) => state.sending.pop().into_iter().collect(),
+      (_, true) => Default::default(),
+    };
+    drop(state);
+    drop(seen);
+    senders.iter().for_each(|waiter| waiter.unpark());
+  }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("Sender { .. }") }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("Receiver { .. }") }
+}
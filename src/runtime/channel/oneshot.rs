@@ -0,0 +1,112 @@
+//! A channel for sending a single value, say a reply.
+//!
+//! ```
+//! use stackle::runtime::{channel::oneshot, LocalExecutor};
+//!
+//! let exec = LocalExecutor::new();
+//! let (tx, rx) = oneshot::channel();
+//! let reply = exec.spawn(move || rx.recv().unwrap() + 1);
+//! exec.spawn(move || tx.send(41).unwrap());
+//! exec.run_until_idle();
+//! assert_eq!(42, reply.join().unwrap());
+//! ```
+use crate::runtime::task::Unparker;
+use crate::runtime::wait::wait;
+use super::{RecvError, SendError, TryRecvError};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+struct State<T> {
+  value:     Option<T>,
+  sender:    bool,
+  receiver:  bool,
+  receiving: Option<Unparker>,
+}
+
+/// Sends the one value to a [`Receiver`].
+pub struct Sender<T> {
+  shared: Arc<Mutex<State<T>>>,
+}
+
+/// Receives the one value from a [`Sender`].
+pub struct Receiver<T> {
+  shared: Arc<Mutex<State<T>>>,
+}
+
+/// A channel for one value. Sending never parks.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+  let shared = Arc::new(Mutex::new(State { value: None, sender: true, receiver: true, receiving: None }));
+  (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+impl<T> Sender<T> {
+  /// Sends `value`. Fails if the receiver is gone.
+  pub fn send(self, value: T) -> Result<(), SendError<T>> {
+    let mut state = self.shared.lock().unwrap();
+    if !state.receiver { return Err(SendError(value)) }
+    state.value = Some(value);
+    // Our drop does the waking.
+    Ok(())
+  }
+
+  /// Has the receiver gone away?
+  pub fn is_closed(&self) -> bool { !self.shared.lock().unwrap().receiver }
+}
+
+impl<T> Drop for Sender<T> {
+  fn drop(&mut self) {
+    let mut state = self.shared.lock().unwrap();
+    state.sender = false;
+    let receiver = state.receiving.take();
+    drop(state);
+    if let Some(receiver) = receiver { receiver.unpark() }
+  }
+}
+
+impl<T> Receiver<T> {
+  /// The value, parking until it's sent. Fails if the sender went away without sending it.
+  pub fn recv(self) -> Result<T, RecvError> {
+    let mut state = self.shared.lock().unwrap();
+    loop {
+      if let Some(value) = state.value.take() { return Ok(value) }
+      if !state.sender { return Err(RecvError) }
+      state.receiving = Some(Unparker::current());
+      drop(state);
+      wait();
+      state = self.shared.lock().unwrap();
+    }
+  }
+
+  /// The value, if it's been sent. Fails with [`TryRecvError::Closed`] if it never will be,
+  /// including once it's been received.
+  pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+    let mut state = self.shared.lock().unwrap();
+    match state.value.take() {
+      Some(value) => {
+        // Make sure we say closed from now on.
+        state.sender = false;
+        Ok(value)
+      }
+      None if state.sender => Err(TryRecvError::Empty),
+      None => Err(TryRecvError::Closed),
+    }
+  }
+}
+
+impl<T> Drop for Receiver<T> {
+  fn drop(&mut self) {
+    let mut state = self.shared.lock().unwrap();
+    state.receiver = false;
+    let unreceived = state.value.take();
+    drop(state);
+    drop(unreceived);
+  }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("Sender { .. }") }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("Receiver { .. }") }
+}
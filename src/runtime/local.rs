@@ -0,0 +1,216 @@
+use super::stacks::StackPool;
+use super::task::{JoinHandle, Ran, Schedule, Task, TaskList};
+use super::time::Clock;
+use super::DEFAULT_STACK_SIZE;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, Thread};
+
+/// The guts of a [`LocalExecutor`], also used by each worker of a
+/// [`ThreadPerCore`](super::ThreadPerCore).
+pub(crate) struct Core {
+  ready:      Mutex<VecDeque<Task>>,
+  // Every task that hasn't finished.
+  tasks:      Mutex<TaskList>,
+  stacks:     Arc<StackPool>,
+  stack_size: u32,
+  running:    AtomicBool,
+  // The thread we belong to, woken when tasks are unparked from elsewhere.
+  owner:      Thread,
+  // Where our tasks get the time from.
+  clock:      Clock,
+}
+
+std::thread_local! {
+  // The local executor running on this thread, if any.
+  static LOCAL: RefCell<Option<Arc<Core>>> = const { RefCell::new(None) };
+  // Every core belonging to this thread, so code blocking outside of a task can keep them going.
+  static OWNED: RefCell<Vec<Weak<Core>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Runs green threads ("tasks") on the current thread, one at a time, switching only when they
+/// [`yield_now`](super::yield_now), [`park`](super::park) or finish.
+///
+/// ```
+/// use stackle::runtime::{self, LocalExecutor};
+///
+/// let exec = LocalExecutor::new();
+/// let ping = exec.spawn(|| {
+///   for _ in 0..3 { runtime::yield_now() }
+///   "done"
+/// });
+/// assert_eq!(0, exec.run_until_idle());
+/// assert_eq!("done", ping.join().unwrap());
+/// ```
+///
+/// Dropping it cancels whatever tasks are left, unwinding their stacks.
+pub struct LocalExecutor {
+  core:      Arc<Core>,
+  // The tasks' coroutines needn't be `Send`, so neither are we.
+  _not_send: PhantomData<*const ()>,
+}
+
+impl LocalExecutor {
+  /// An executor whose tasks get stacks of [`DEFAULT_STACK_SIZE`].
+  pub fn new() -> Self { Self::with_stack_size(DEFAULT_STACK_SIZE) }
+
+  /// An executor whose tasks get stacks of at least `size` bytes by default.
+  pub fn with_stack_size(size: u32) -> Self {
+    LocalExecutor { core: Core::new(size, Clock::real()), _not_send: PhantomData }
+  }
+
+  /// An executor whose tasks tell the time by `clock`, say a [manual](Clock::manual) one for
+  /// tests.
+  pub fn with_clock(clock: Clock) -> Self {
+    LocalExecutor { core: Core::new(DEFAULT_STACK_SIZE, clock), _not_send: PhantomData }
+  }
+
+  /// Queues `f` to run as a new task.
+  ///
+  /// # Panics
+  ///
+  /// If we can't map a stack for it.
+  pub fn spawn<F, T>(&self, f: F) -> JoinHandle<T>
+  where F: FnOnce() -> T + 'static, T: 'static {
+    self.core.spawn(self.core.stack_size, f)
+  }
+
+  /// Like [`LocalExecutor::spawn`], but the task gets a stack of at least `stack_size` bytes.
+  /// Stacks are pooled by size, rounded up to a power of two, unless they're over 2GiB.
+  pub fn spawn_sized<F, T>(&self, stack_size: u32, f: F) -> JoinHandle<T>
+  where F: FnOnce() -> T + 'static, T: 'static {
+    self.core.spawn(stack_size, f)
+  }
+
+  /// Runs tasks until none are ready, returning how many are left parked.
+  ///
+  /// # Panics
+  ///
+  /// If called from one of our own tasks.
+  pub fn run_until_idle(&self) -> usize { self.core.run_until_idle() }
+
+  /// Whether the kernel has io_uring. See [`uring::is_supported`](super::uring::is_supported).
+  #[cfg(any(target_os="linux", target_os="android"))]
+  pub fn uring_supported(&self) -> bool { super::uring::is_supported() }
+}
+
+impl Default for LocalExecutor {
+  fn default() -> Self { Self::new() }
+}
+
+impl Drop for LocalExecutor {
+  fn drop(&mut self) { self.core.cancel_all() }
+}
+
+impl Core {
+  /// A core belonging to the current thread.
+  pub(crate) fn new(stack_size: u32, clock: Clock) -> Arc<Self> {
+    #[cfg(any(target_os="linux", target_os="android"))]
+    super::uring::is_supported();
+    let core = Arc::new(Core {
+      ready:      Mutex::new(VecDeque::new()),
+      tasks:      Mutex::new(TaskList::default()),
+      stacks:     Arc::new(StackPool::new()),
+      stack_size,
+      running:    AtomicBool::new(false),
+      owner:      thread::current(),
+      clock,
+    });
+    OWNED.with(|o| {
+      let mut owned = o.borrow_mut();
+      owned.retain(|core| core.strong_count() > 0);
+      owned.push(Arc::downgrade(&core));
+    });
+    core
+  }
+
+  pub(crate) fn stack_size(&self) -> u32 { self.stack_size }
+
+  fn spawn<F, T>(self: &Arc<Self>, stack_size: u32, f: F) -> JoinHandle<T>
+  where F: FnOnce() -> T + 'static, T: 'static {
+    let (task, handle) = Task::new(self.stacks.take(stack_size), self.clone(), f);
+    self.admit(task);
+    handle
+  }
+
+  /// Queues `f` to run as a new task, from any thread. Its coroutine is made on ours, when it
+  /// first runs.
+  pub(crate) fn spawn_remote<F, T>(self: &Arc<Self>, stack_size: u32, f: F) -> JoinHandle<T>
+  where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+    let (task, handle) = Task::lazy(self.stacks.clone(), stack_size, self.clone(), f);
+    self.admit(task);
+    handle
+  }
+
+  fn admit(&self, task: Task) {
+    let admitted = self.tasks.lock().unwrap().insert(&task);
+    if admitted { self.schedule(task) } else { task.cancel() }
+  }
+
+  /// Is anything ready to run?
+  pub(crate) fn has_ready(&self) -> bool { !self.ready.lock().unwrap().is_empty() }
+
+  /// Unwinds every task that hasn't finished. Only on our own thread.
+  pub(crate) fn cancel_all(&self) {
+    let tasks = self.tasks.lock().unwrap().close();
+    for task in tasks { task.cancel() }
+    // Cancelling may have unparked some, whose references would keep us alive.
+    self.ready.lock().unwrap().clear();
+  }
+
+  pub(crate) fn run_until_idle(self: &Arc<Self>) -> usize {
+    assert!(!self.running.swap(true, Ordering::Acquire), "run_until_idle called from one of its own tasks");
+    let previous = LOCAL.with(|l| l.replace(Some(self.clone())));
+    loop {
+      let next = self.ready.lock().unwrap().pop_front();
+      let Some(task) = next else { break };
+      match task.run() {
+        Ran::Ready => self.ready.lock().unwrap().push_back(task),
+        Ran::Parked => (),
+        Ran::Finished => self.tasks.lock().unwrap().remove(&task),
+      }
+    }
+    LOCAL.with(|l| l.replace(previous));
+    self.running.store(false, Ordering::Release);
+    self.tasks.lock().unwrap().len()
+  }
+}
+
+impl Schedule for Core {
+  fn schedule(&self, task: Task) {
+    self.ready.lock().unwrap().push_back(task);
+    // In case it came from another thread and ours is waiting for it.
+    self.owner.unpark();
+  }
+
+  fn drive(self: Arc<Self>) -> bool {
+    if thread::current().id() != self.owner.id() || self.running.load(Ordering::Acquire) { return false }
+    self.run_until_idle();
+    true
+  }
+
+  fn clock(&self) -> Clock { self.clock.clone() }
+}
+
+/// Runs every executor belonging to this thread until none of them has anything ready. For when
+/// we're about to block the thread outside of a task, quite possibly waiting on one of theirs.
+pub(crate) fn drive_owned() {
+  let cores: Vec<_> = OWNED.with(|o| o.borrow().iter().filter_map(Weak::upgrade).collect());
+  for core in cores {
+    if !core.running.load(Ordering::Acquire) { core.run_until_idle(); }
+  }
+}
+
+/// Queues `f` to run as a new task on the [`LocalExecutor`] we're running in.
+///
+/// # Panics
+///
+/// Outside of a `LocalExecutor`'s task, or if we can't map a stack for it.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where F: FnOnce() -> T + 'static, T: 'static {
+  let core = LOCAL.with(|l| l.borrow().clone()).expect("spawn called outside of a local executor");
+  core.spawn(core.stack_size, f)
+}
@@ -0,0 +1,128 @@
+//! Sockets whose blocking calls park the task rather than the thread.
+//!
+//! They look like `std::net`'s and `std::os::unix::net`'s: `read` waits for data, `accept` for a
+//! connection and so on. Under the hood the socket's non-blocking, and when it isn't ready, the
+//! task parks until an epoll reactor says it might be. Outside of a task they block the thread,
+//! running its own executors' tasks meanwhile, so a plain thread can talk to a task over one.
+//!
+//! ```
+//! use stackle::runtime::net::{TcpListener, TcpStream};
+//! use stackle::runtime::LocalExecutor;
+//! use std::io::{Read, Write};
+//!
+//! let exec = LocalExecutor::new();
+//! let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+//! let addr = listener.local_addr().unwrap();
+//! exec.spawn(move || {
+//!   let (mut stream, _) = listener.accept().unwrap();
+//!   let mut name = String::new();
+//!   stream.read_to_string(&mut name).unwrap();
+//!   stream.write_all(format!("hello, {}", name).as_bytes()).unwrap();
+//! });
+//! let greeting = exec.spawn(move || {
+//!   let mut stream = TcpStream::connect(addr).unwrap();
+//!   stream.write_all(b"world").unwrap();
+//!   stream.shutdown(std::net::Shutdown::Write).unwrap();
+//!   let mut greeting = String::new();
+//!   stream.read_to_string(&mut greeting).unwrap();
+//!   greeting
+//! });
+//! assert_eq!("hello, world", greeting.join().unwrap());
+//! ```
+//!
+//! Resolving names with [`ToSocketAddrs`](std::net::ToSocketAddrs) still blocks the thread, as
+//! it does in `std`.
+use super::reactor::{cvt, Registration};
+use super::time;
+use std::io;
+use std::mem::{size_of, zeroed};
+use std::net::SocketAddr;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd};
+use std::time::Duration;
+
+mod tcp;
+pub use tcp::*;
+mod udp;
+pub use udp::*;
+mod unix;
+pub use unix::*;
+
+/// A new non-blocking socket.
+fn socket(domain: libc::c_int, kind: libc::c_int) -> io::Result<OwnedFd> {
+  let fd = cvt(unsafe { libc::socket(domain, kind | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC, 0) })?;
+  Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Connects a new stream socket to `addr`, parking until it's done. `wrap` makes it into whatever
+/// it'll be, and `connected` says whether that's connected yet, each time it becomes writable.
+fn connect<S: AsFd>(
+  domain: libc::c_int,
+  addr: &libc::sockaddr_storage,
+  len: libc::socklen_t,
+  wrap: impl FnOnce(OwnedFd) -> S,
+  connected: impl Fn(&S) -> io::Result<()>,
+) -> io::Result<(S, Registration)> {
+  let socket = socket(domain, libc::SOCK_STREAM)?;
+  let fd = socket.as_raw_fd();
+  let socket = wrap(socket);
+  let registration = Registration::new(socket.as_fd())?;
+  loop {
+    match cvt(unsafe { libc::connect(fd, addr as *const _ as *const libc::sockaddr, len) }) {
+      Ok(_) => break,
+      Err(err) if err.raw_os_error() == Some(libc::EINTR) => continue,
+      Err(err) if err.raw_os_error() == Some(libc::EINPROGRESS) => {
+        registration.write(|| connected(&socket))?;
+        break
+      }
+      // A Unix socket whose listener's backlog is full. Nothing tells us when it isn't.
+      Err(err) if err.raw_os_error() == Some(libc::EAGAIN) => time::sleep(Duration::from_millis(1)),
+      Err(err) => return Err(err),
+    }
+  }
+  Ok((socket, registration))
+}
+
+/// `connected` for sockets that know `take_error` and `peer_addr`.
+fn check_connected<A>(error: io::Result<Option<io::Error>>, peer: io::Result<A>) -> io::Result<()> {
+  if let Some(err) = error? { return Err(err) }
+  match peer {
+    Ok(_) => Ok(()),
+    Err(err) if err.kind() == io::ErrorKind::NotConnected => Err(io::ErrorKind::WouldBlock.into()),
+    Err(err) => Err(err),
+  }
+}
+
+fn sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+  let mut storage: libc::sockaddr_storage = unsafe { zeroed() };
+  let len = match addr {
+    SocketAddr::V4(addr) => {
+      let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+      sin.sin_family = libc::AF_INET as libc::sa_family_t;
+      sin.sin_port = addr.port().to_be();
+      sin.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+      size_of::<libc::sockaddr_in>()
+    }
+    SocketAddr::V6(addr) => {
+      let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+      sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+      sin6.sin6_port = addr.port().to_be();
+      sin6.sin6_flowinfo = addr.flowinfo();
+      sin6.sin6_addr.s6_addr = addr.ip().octets();
+      sin6.sin6_scope_id = addr.scope_id();
+      size_of::<libc::sockaddr_in6>()
+    }
+  };
+  (storage, len as libc::socklen_t)
+}
+
+/// Tries `f` on each of `addrs`, returning the first success or the last failure.
+fn each_addr<A: std::net::ToSocketAddrs, T>(addrs: A, mut f: impl FnMut(&SocketAddr) -> io::Result<T>) -> io::Result<T> {
+  let mut last = None;
+  for addr in addrs.to_socket_addrs()? {
+    match f(&addr) {
+      Ok(value) => return Ok(value),
+      Err(err) => last = Some(err),
+    }
+  }
+  Err(last.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses")))
+}
@@ -0,0 +1,141 @@
+use super::{check_connected, connect, each_addr, sockaddr, Registration};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{self, Shutdown, SocketAddr, ToSocketAddrs};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+
+/// A TCP server socket, whose [`accept`](TcpListener::accept) parks the task until someone
+/// connects.
+pub struct TcpListener {
+  // Dropped first, while the socket's still open.
+  registration: Registration,
+  inner:        net::TcpListener,
+}
+
+/// The connections coming into a [`TcpListener`], forever. See [`TcpListener::incoming`].
+#[derive(Debug)]
+pub struct Incoming<'a> {
+  listener: &'a TcpListener,
+}
+
+impl TcpListener {
+  /// A listener bound to the first of `addrs` that works.
+  pub fn bind<A: ToSocketAddrs>(addrs: A) -> io::Result<Self> {
+    each_addr(addrs, |addr| Self::from_std(net::TcpListener::bind(addr)?))
+  }
+
+  /// Takes over a `std` listener, making it non-blocking.
+  pub fn from_std(inner: net::TcpListener) -> io::Result<Self> {
+    inner.set_nonblocking(true)?;
+    Ok(TcpListener { registration: Registration::new(inner.as_fd())?, inner })
+  }
+
+  /// The next connection, parking until there is one.
+  pub fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+    let (stream, addr) = self.registration.read(|| self.inner.accept())?;
+    Ok((TcpStream::from_std(stream)?, addr))
+  }
+
+  /// An iterator over [`accept`](TcpListener::accept)'s results.
+  pub fn incoming(&self) -> Incoming<'_> { Incoming { listener: self } }
+
+  /// The address we're listening on.
+  pub fn local_addr(&self) -> io::Result<SocketAddr> { self.inner.local_addr() }
+}
+
+impl<'a> Iterator for Incoming<'a> {
+  type Item = io::Result<TcpStream>;
+
+  fn next(&mut self) -> Option<Self::Item> { Some(self.listener.accept().map(|(stream, _)| stream)) }
+}
+
+impl AsFd for TcpListener {
+  fn as_fd(&self) -> BorrowedFd<'_> { self.inner.as_fd() }
+}
+
+impl AsRawFd for TcpListener {
+  fn as_raw_fd(&self) -> RawFd { self.inner.as_raw_fd() }
+}
+
+impl fmt::Debug for TcpListener {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { self.inner.fmt(f) }
+}
+
+/// A TCP connection, whose reads and writes park the task until the socket's ready.
+///
+/// Like `std`'s, it's [`Read`] and [`Write`] by reference too, so one task can read while another
+/// writes.
+pub struct TcpStream {
+  registration: Registration,
+  inner:        net::TcpStream,
+}
+
+impl TcpStream {
+  /// Connects to the first of `addrs` that'll have us, parking while we wait for each.
+  pub fn connect<A: ToSocketAddrs>(addrs: A) -> io::Result<Self> {
+    each_addr(addrs, |addr| {
+      let domain = if addr.is_ipv4() { libc::AF_INET } else { libc::AF_INET6 };
+      let (addr, len) = sockaddr(addr);
+      let (inner, registration) = connect(domain, &addr, len, net::TcpStream::from, |stream| {
+        check_connected(stream.take_error(), stream.peer_addr())
+      })?;
+      Ok(TcpStream { registration, inner })
+    })
+  }
+
+  /// Takes over a connected `std` stream, making it non-blocking.
+  pub fn from_std(inner: net::TcpStream) -> io::Result<Self> {
+    inner.set_nonblocking(true)?;
+    Ok(TcpStream { registration: Registration::new(inner.as_fd())?, inner })
+  }
+
+  /// Reads into `buf` without taking it out of the queue, parking until there's something to read.
+  pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> { self.registration.read(|| self.inner.peek(buf)) }
+
+  /// Shuts down reading, writing or both. The other end sees the end of the stream.
+  pub fn shutdown(&self, how: Shutdown) -> io::Result<()> { self.inner.shutdown(how) }
+
+  /// The address of the other end.
+  pub fn peer_addr(&self) -> io::Result<SocketAddr> { self.inner.peer_addr() }
+
+  /// Our end's address.
+  pub fn local_addr(&self) -> io::Result<SocketAddr> { self.inner.local_addr() }
+
+  /// Turns Nagle's algorithm off, or on again.
+  pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> { self.inner.set_nodelay(nodelay) }
+
+  /// Is Nagle's algorithm off?
+  pub fn nodelay(&self) -> io::Result<bool> { self.inner.nodelay() }
+}
+
+impl Read for &TcpStream {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.registration.read(|| (&self.inner).read(buf)) }
+}
+
+impl Write for &TcpStream {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.registration.write(|| (&self.inner).write(buf)) }
+
+  fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+impl Read for TcpStream {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { (&*self).read(buf) }
+}
+
+impl Write for TcpStream {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> { (&*self).write(buf) }
+
+  fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+impl AsFd for TcpStream {
+  fn as_fd(&self) -> BorrowedFd<'_> { self.inner.as_fd() }
+}
+
+impl AsRawFd for TcpStream {
+  fn as_raw_fd(&self) -> RawFd { self.inner.as_raw_fd() }
+}
+
+impl fmt::Debug for TcpStream {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { self.inner.fmt(f) }
+}
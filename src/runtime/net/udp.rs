@@ -0,0 +1,70 @@
+use super::{each_addr, Registration};
+use std::fmt;
+use std::io;
+use std::net::{self, SocketAddr, ToSocketAddrs};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+
+/// A UDP socket, whose sends and receives park the task until it's ready.
+pub struct UdpSocket {
+  registration: Registration,
+  inner:        net::UdpSocket,
+}
+
+impl UdpSocket {
+  /// A socket bound to the first of `addrs` that works.
+  pub fn bind<A: ToSocketAddrs>(addrs: A) -> io::Result<Self> {
+    each_addr(addrs, |addr| Self::from_std(net::UdpSocket::bind(addr)?))
+  }
+
+  /// Takes over a `std` socket, making it non-blocking.
+  pub fn from_std(inner: net::UdpSocket) -> io::Result<Self> {
+    inner.set_nonblocking(true)?;
+    Ok(UdpSocket { registration: Registration::new(inner.as_fd())?, inner })
+  }
+
+  /// Sends `buf` to `addr`, parking until there's room to.
+  pub fn send_to<A: ToSocketAddrs>(&self, buf: &[u8], addr: A) -> io::Result<usize> {
+    each_addr(addr, |addr| self.registration.write(|| self.inner.send_to(buf, addr)))
+  }
+
+  /// The next datagram and who sent it, parking until there is one. Whatever doesn't fit in `buf`
+  /// is thrown away.
+  pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    self.registration.read(|| self.inner.recv_from(buf))
+  }
+
+  /// Like [`UdpSocket::recv_from`], but leaves the datagram to be received again.
+  pub fn peek_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    self.registration.read(|| self.inner.peek_from(buf))
+  }
+
+  /// Sends to, and only receives from, the first of `addrs` that works, from now on.
+  pub fn connect<A: ToSocketAddrs>(&self, addrs: A) -> io::Result<()> { self.inner.connect(addrs) }
+
+  /// Sends `buf` to whoever we're [connected](UdpSocket::connect) to.
+  pub fn send(&self, buf: &[u8]) -> io::Result<usize> { self.registration.write(|| self.inner.send(buf)) }
+
+  /// The next datagram from whoever we're [connected](UdpSocket::connect) to.
+  pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> { self.registration.read(|| self.inner.recv(buf)) }
+
+  /// Our address.
+  pub fn local_addr(&self) -> io::Result<SocketAddr> { self.inner.local_addr() }
+
+  /// The address we're [connected](UdpSocket::connect) to.
+  pub fn peer_addr(&self) -> io::Result<SocketAddr> { self.inner.peer_addr() }
+
+  /// Lets us send to broadcast addresses, or stops us.
+  pub fn set_broadcast(&self, broadcast: bool) -> io::Result<()> { self.inner.set_broadcast(broadcast) }
+}
+
+impl AsFd for UdpSocket {
+  fn as_fd(&self) -> BorrowedFd<'_> { self.inner.as_fd() }
+}
+
+impl AsRawFd for UdpSocket {
+  fn as_raw_fd(&self) -> RawFd { self.inner.as_raw_fd() }
+}
+
+impl fmt::Debug for UdpSocket {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { self.inner.fmt(f) }
+}
@@ -0,0 +1,143 @@
+use super::{check_connected, connect, Registration};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem::{offset_of, size_of, zeroed};
+use std::net::Shutdown;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{self, SocketAddr};
+use std::path::Path;
+
+/// A Unix domain socket server, whose [`accept`](UnixListener::accept) parks the task until
+/// someone connects.
+pub struct UnixListener {
+  registration: Registration,
+  inner:        net::UnixListener,
+}
+
+/// The connections coming into a [`UnixListener`], forever. See [`UnixListener::incoming`].
+#[derive(Debug)]
+pub struct UnixIncoming<'a> {
+  listener: &'a UnixListener,
+}
+
+impl UnixListener {
+  /// A listener bound to `path`, which mustn't exist yet.
+  pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<Self> { Self::from_std(net::UnixListener::bind(path)?) }
+
+  /// Takes over a `std` listener, making it non-blocking.
+  pub fn from_std(inner: net::UnixListener) -> io::Result<Self> {
+    inner.set_nonblocking(true)?;
+    Ok(UnixListener { registration: Registration::new(inner.as_fd())?, inner })
+  }
+
+  /// The next connection, parking until there is one.
+  pub fn accept(&self) -> io::Result<(UnixStream, SocketAddr)> {
+    let (stream, addr) = self.registration.read(|| self.inner.accept())?;
+    Ok((UnixStream::from_std(stream)?, addr))
+  }
+
+  /// An iterator over [`accept`](UnixListener::accept)'s results.
+  pub fn incoming(&self) -> UnixIncoming<'_> { UnixIncoming { listener: self } }
+
+  /// The address we're listening on.
+  pub fn local_addr(&self) -> io::Result<SocketAddr> { self.inner.local_addr() }
+}
+
+impl<'a> Iterator for UnixIncoming<'a> {
+  type Item = io::Result<UnixStream>;
+
+  fn next(&mut self) -> Option<Self::Item> { Some(self.listener.accept().map(|(stream, _)| stream)) }
+}
+
+impl AsFd for UnixListener {
+  fn as_fd(&self) -> BorrowedFd<'_> { self.inner.as_fd() }
+}
+
+impl AsRawFd for UnixListener {
+  fn as_raw_fd(&self) -> RawFd { self.inner.as_raw_fd() }
+}
+
+impl fmt::Debug for UnixListener {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { self.inner.fmt(f) }
+}
+
+/// A Unix domain stream socket, whose reads and writes park the task until it's ready.
+pub struct UnixStream {
+  registration: Registration,
+  inner:        net::UnixStream,
+}
+
+impl UnixStream {
+  /// Connects to the socket at `path`, parking until it's accepted us.
+  pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+    let path = path.as_ref().as_os_str().as_bytes();
+    let mut addr: libc::sockaddr_storage = unsafe { zeroed() };
+    let sun = unsafe { &mut *(&mut addr as *mut _ as *mut libc::sockaddr_un) };
+    // Room for a nul on the end.
+    if path.len() >= sun.sun_path.len() {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "path must be shorter than SUN_LEN"))
+    }
+    sun.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (to, &from) in sun.sun_path.iter_mut().zip(path) { *to = from as libc::c_char }
+    let len = offset_of!(libc::sockaddr_un, sun_path) + path.len() + 1;
+    debug_assert!(len <= size_of::<libc::sockaddr_un>());
+    let (inner, registration) = connect(libc::AF_UNIX, &addr, len as libc::socklen_t, net::UnixStream::from, |stream| {
+      check_connected(stream.take_error(), stream.peer_addr())
+    })?;
+    Ok(UnixStream { registration, inner })
+  }
+
+  /// A pair of sockets connected to each other.
+  pub fn pair() -> io::Result<(UnixStream, UnixStream)> {
+    let (a, b) = net::UnixStream::pair()?;
+    Ok((Self::from_std(a)?, Self::from_std(b)?))
+  }
+
+  /// Takes over a connected `std` stream, making it non-blocking.
+  pub fn from_std(inner: net::UnixStream) -> io::Result<Self> {
+    inner.set_nonblocking(true)?;
+    Ok(UnixStream { registration: Registration::new(inner.as_fd())?, inner })
+  }
+
+  /// Shuts down reading, writing or both. The other end sees the end of the stream.
+  pub fn shutdown(&self, how: Shutdown) -> io::Result<()> { self.inner.shutdown(how) }
+
+  /// The address of the other end.
+  pub fn peer_addr(&self) -> io::Result<SocketAddr> { self.inner.peer_addr() }
+
+  /// Our end's address.
+  pub fn local_addr(&self) -> io::Result<SocketAddr> { self.inner.local_addr() }
+}
+
+impl Read for &UnixStream {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.registration.read(|| (&self.inner).read(buf)) }
+}
+
+impl Write for &UnixStream {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.registration.write(|| (&self.inner).write(buf)) }
+
+  fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+impl Read for UnixStream {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { (&*self).read(buf) }
+}
+
+impl Write for UnixStream {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> { (&*self).write(buf) }
+
+  fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+impl AsFd for UnixStream {
+  fn as_fd(&self) -> BorrowedFd<'_> { self.inner.as_fd() }
+}
+
+impl AsRawFd for UnixStream {
+  fn as_raw_fd(&self) -> RawFd { self.inner.as_raw_fd() }
+}
+
+impl fmt::Debug for UnixStream {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { self.inner.fmt(f) }
+}
@@ -0,0 +1,162 @@
+use super::local::Core;
+use super::task::JoinHandle;
+use super::time::Clock;
+use super::DEFAULT_STACK_SIZE;
+use std::io;
+use std::mem::{size_of, zeroed};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+/// One worker thread per CPU, each pinned to its CPU and running its own tasks, which never move.
+///
+/// A worker is a [`LocalExecutor`](super::LocalExecutor) of its own: its tasks' coroutines are
+/// made, run and dropped on it, on stacks from its own pool. So unlike
+/// [`Executor::spawn`](super::Executor::spawn), [`spawn_on`](ThreadPerCore::spawn_on) is safe, and
+/// tasks may hold whatever they like across a suspension. The only way in from other threads is
+/// to send a worker a closure to run as a new task (or to wake one of its tasks), which is
+/// queued until the worker gets to it.
+///
+/// ```
+/// use stackle::runtime::{self, ThreadPerCore};
+/// use std::rc::Rc;
+///
+/// let cpus = runtime::available_cpus().unwrap();
+/// let workers = ThreadPerCore::new(&cpus[..1]).unwrap();
+/// let handle = workers.spawn_on(0, || {
+///   // not `Send`, but it never leaves the worker.
+///   let shared = Rc::new(5);
+///   runtime::yield_now();
+///   *shared * 2
+/// });
+/// assert_eq!(10, handle.join().unwrap());
+/// ```
+///
+/// Dropping it has each worker cancel its remaining tasks and stop.
+pub struct ThreadPerCore {
+  workers:  Vec<Worker>,
+  shutdown: Arc<AtomicBool>,
+}
+
+struct Worker {
+  core:   Arc<Core>,
+  thread: thread::JoinHandle<()>,
+}
+
+/// A handle for sending tasks to one of a [`ThreadPerCore`]'s workers from anywhere.
+#[derive(Clone)]
+pub struct CoreHandle {
+  core: Arc<Core>,
+}
+
+impl ThreadPerCore {
+  /// Starts a worker pinned to each of `cpus`, whose tasks get stacks of [`DEFAULT_STACK_SIZE`].
+  pub fn new(cpus: &[usize]) -> io::Result<Self> { Self::with_stack_size(cpus, DEFAULT_STACK_SIZE) }
+
+  /// Starts a worker pinned to each of `cpus`, whose tasks get stacks of at least `size` bytes by
+  /// default.
+  ///
+  /// Fails if we can't start a thread or pin it to its CPU, say because it doesn't exist or isn't
+  /// in our affinity mask.
+  pub fn with_stack_size(cpus: &[usize], size: u32) -> io::Result<Self> {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let mut this = ThreadPerCore { workers: Vec::with_capacity(cpus.len()), shutdown: shutdown.clone() };
+    for &cpu in cpus {
+      let (started, start) = mpsc::channel();
+      let shutdown = shutdown.clone();
+      let thread = thread::Builder::new()
+        .name(format!("stackle-core-{}", cpu))
+        .spawn(move || {
+          if let Err(err) = pin(cpu) { return started.send(Err(err)).unwrap() }
+          let core = Core::new(size, Clock::real());
+          started.send(Ok(core.clone())).unwrap();
+          work(core, &shutdown);
+        })?;
+      match start.recv().unwrap() {
+        Ok(core) => this.workers.push(Worker { core, thread }),
+        Err(err) => {
+          let _ = thread.join();
+          // Dropping `this` stops the ones we've started so far.
+          return Err(err)
+        }
+      }
+    }
+    Ok(this)
+  }
+
+  /// How many workers there are.
+  pub fn cores(&self) -> usize { self.workers.len() }
+
+  /// Whether the kernel has io_uring. See [`uring::is_supported`](super::uring::is_supported).
+  pub fn uring_supported(&self) -> bool { super::uring::is_supported() }
+
+  /// Queues `f` to run as a new task on worker `core`, with the default stack size.
+  ///
+  /// # Panics
+  ///
+  /// If there's no such worker.
+  pub fn spawn_on<F, T>(&self, core: usize, f: F) -> JoinHandle<T>
+  where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+    self.handle(core).spawn(f)
+  }
+
+  /// A handle for sending tasks to worker `core`.
+  ///
+  /// # Panics
+  ///
+  /// If there's no such worker.
+  pub fn handle(&self, core: usize) -> CoreHandle { CoreHandle { core: self.workers[core].core.clone() } }
+}
+
+impl Drop for ThreadPerCore {
+  fn drop(&mut self) {
+    self.shutdown.store(true, Ordering::Release);
+    for worker in &self.workers { worker.thread.thread().unpark() }
+    for worker in self.workers.drain(..) { let _ = worker.thread.join(); }
+  }
+}
+
+impl CoreHandle {
+  /// Queues `f` to run as a new task on our worker, with the default stack size. Inside one of
+  /// the worker's own tasks, [`spawn`](super::spawn) does without the `Send`.
+  pub fn spawn<F, T>(&self, f: F) -> JoinHandle<T>
+  where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+    self.core.spawn_remote(self.core.stack_size(), f)
+  }
+
+  /// Like [`CoreHandle::spawn`], but the task gets a stack of at least `stack_size` bytes.
+  pub fn spawn_sized<F, T>(&self, stack_size: u32, f: F) -> JoinHandle<T>
+  where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+    self.core.spawn_remote(stack_size, f)
+  }
+}
+
+fn work(core: Arc<Core>, shutdown: &AtomicBool) {
+  loop {
+    core.run_until_idle();
+    if shutdown.load(Ordering::Acquire) { break }
+    // Anything queued from now on unparks us, so we can't miss it.
+    if !core.has_ready() { thread::park() }
+  }
+  core.cancel_all();
+}
+
+/// The CPUs this process may run on, to pick from for [`ThreadPerCore::new`].
+pub fn available_cpus() -> io::Result<Vec<usize>> {
+  let mut set: libc::cpu_set_t = unsafe { zeroed() };
+  if unsafe { libc::sched_getaffinity(0, size_of::<libc::cpu_set_t>(), &mut set) } != 0 {
+    return Err(io::Error::last_os_error())
+  }
+  Ok((0..libc::CPU_SETSIZE as usize).filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) }).collect())
+}
+
+/// Pins the calling thread to `cpu`.
+fn pin(cpu: usize) -> io::Result<()> {
+  if cpu >= libc::CPU_SETSIZE as usize { return Err(io::Error::from_raw_os_error(libc::EINVAL)) }
+  let mut set: libc::cpu_set_t = unsafe { zeroed() };
+  unsafe { libc::CPU_SET(cpu, &mut set) };
+  match unsafe { libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &set) } {
+    0 => Ok(()),
+    _ => Err(io::Error::last_os_error()),
+  }
+}
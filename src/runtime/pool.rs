@@ -0,0 +1,271 @@
+use super::stacks::StackPool;
+use super::task::{JoinHandle, Ran, Schedule, Task, TaskList};
+use super::DEFAULT_STACK_SIZE;
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::ptr::null;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+struct Core {
+  // One run queue per worker. Its owner works from the front, thieves take from the back.
+  queues:     Box<[Mutex<VecDeque<Task>>]>,
+  // Where tasks spawned or unparked from outside the workers go.
+  injector:   Mutex<VecDeque<Task>>,
+  // How many workers are asleep, or about to be.
+  sleeping:   AtomicUsize,
+  idle:       Mutex<()>,
+  wake:       Condvar,
+  shutdown:   AtomicBool,
+  tasks:      Mutex<TaskList>,
+  stacks:     Arc<StackPool>,
+  stack_size: u32,
+}
+
+std::thread_local! {
+  // The pool this thread is a worker of, and which worker.
+  static WORKER: Cell<(*const Core, usize)> = const { Cell::new((null(), 0)) };
+}
+
+/// Runs green threads ("tasks") on a pool of worker threads, moving them between workers to keep
+/// everyone busy.
+///
+/// Each worker has its own run queue and takes half of somebody else's when it runs dry. Tasks
+/// spawned or woken from outside the pool go on a shared queue that workers check in between.
+/// Idle workers sleep until there's something to do.
+///
+/// ```
+/// use stackle::runtime::{self, Executor};
+///
+/// let exec = Executor::new(4);
+/// let handles: Vec<_> = (0..16).map(|i| unsafe {
+///   exec.spawn(move || {
+///     runtime::yield_now();
+///     i * 2
+///   })
+/// }).collect();
+/// let total: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+/// assert_eq!(240, total);
+/// ```
+///
+/// Dropping it stops the workers once they're done with whatever they're running and then cancels
+/// the tasks that are left, unwinding their stacks.
+pub struct Executor {
+  core:    Arc<Core>,
+  workers: Vec<thread::JoinHandle<()>>,
+}
+
+/// A handle for spawning onto an [`Executor`] from anywhere, including its own tasks.
+#[derive(Clone)]
+pub struct Handle {
+  core: Arc<Core>,
+}
+
+impl Executor {
+  /// An executor with `workers` threads, whose tasks get stacks of [`DEFAULT_STACK_SIZE`].
+  ///
+  /// # Panics
+  ///
+  /// If `workers` is 0 or we can't start the threads.
+  pub fn new(workers: usize) -> Self { Self::with_stack_size(workers, DEFAULT_STACK_SIZE) }
+
+  /// An executor with `workers` threads, whose tasks get stacks of at least `size` bytes by
+  /// default.
+  ///
+  /// # Panics
+  ///
+  /// If `workers` is 0 or we can't start the threads.
+  pub fn with_stack_size(workers: usize, size: u32) -> Self {
+    assert!(workers > 0, "an executor needs at least one worker");
+    #[cfg(any(target_os="linux", target_os="android"))]
+    super::uring::is_supported();
+    let core = Arc::new(Core {
+      queues:     (0..workers).map(|_| Mutex::new(VecDeque::new())).collect(),
+      injector:   Mutex::new(VecDeque::new()),
+      sleeping:   AtomicUsize::new(0),
+      idle:       Mutex::new(()),
+      wake:       Condvar::new(),
+      shutdown:   AtomicBool::new(false),
+      tasks:      Mutex::new(TaskList::default()),
+      stacks:     Arc::new(StackPool::new()),
+      stack_size: size,
+    });
+    let workers = (0..workers).map(|index| {
+      let core = core.clone();
+      thread::Builder::new()
+        .name(format!("stackle-worker-{}", index))
+        .spawn(move || core.work(index))
+        .expect("couldn't start a worker")
+    }).collect();
+    Executor { core, workers }
+  }
+
+  /// A handle for spawning from elsewhere.
+  pub fn handle(&self) -> Handle { Handle { core: self.core.clone() } }
+
+  /// Whether the kernel has io_uring. See [`uring::is_supported`](super::uring::is_supported).
+  #[cfg(any(target_os="linux", target_os="android"))]
+  pub fn uring_supported(&self) -> bool { super::uring::is_supported() }
+
+  /// Queues `f` to run as a new task. See [`Handle::spawn`].
+  ///
+  /// # Safety
+  ///
+  /// As [`Handle::spawn`].
+  pub unsafe fn spawn<F, T>(&self, f: F) -> JoinHandle<T>
+  where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+    self.core.spawn(self.core.stack_size, f)
+  }
+
+  /// Queues `f` to run as a new task with a stack of at least `stack_size` bytes. See
+  /// [`Handle::spawn_sized`].
+  ///
+  /// # Safety
+  ///
+  /// As [`Handle::spawn`].
+  pub unsafe fn spawn_sized<F, T>(&self, stack_size: u32, f: F) -> JoinHandle<T>
+  where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+    self.core.spawn(stack_size, f)
+  }
+}
+
+impl Drop for Executor {
+  fn drop(&mut self) {
+    self.core.shutdown.store(true, Ordering::SeqCst);
+    {
+      let _idle = self.core.idle.lock().unwrap();
+      self.core.wake.notify_all();
+    }
+    for worker in self.workers.drain(..) { let _ = worker.join(); }
+    // Nobody's running anything now, so the rest can be unwound here.
+    let tasks = self.core.tasks.lock().unwrap().close();
+    for task in tasks { task.cancel() }
+    self.core.injector.lock().unwrap().clear();
+    for queue in self.core.queues.iter() { queue.lock().unwrap().clear() }
+  }
+}
+
+impl Handle {
+  /// Queues `f` to run as a new task.
+  ///
+  /// Debug builds won't move a task that has fiber-local values that aren't `Send` or holds a
+  /// [`ThreadBound`](crate::local::ThreadBound) to another worker: it waits for the one it last
+  /// ran on. Release builds don't check.
+  ///
+  /// # Safety
+  ///
+  /// The task may move to another thread whenever it suspends, which includes parking and
+  /// anything that might park. Whatever it holds at those points must be fine with that: no
+  /// `!Send` values, no `std` mutex guards, no references to thread-locals. See
+  /// [`SendCoroutine::migrate`](crate::coroutine::SendCoroutine::migrate).
+  ///
+  /// # Panics
+  ///
+  /// If we can't map a stack for it.
+  pub unsafe fn spawn<F, T>(&self, f: F) -> JoinHandle<T>
+  where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+    self.core.spawn(self.core.stack_size, f)
+  }
+
+  /// Like [`Handle::spawn`], but the task gets a stack of at least `stack_size` bytes. Stacks are
+  /// pooled by size, rounded up to a power of two, unless they're over 2GiB.
+  ///
+  /// # Safety
+  ///
+  /// As [`Handle::spawn`].
+  pub unsafe fn spawn_sized<F, T>(&self, stack_size: u32, f: F) -> JoinHandle<T>
+  where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+    self.core.spawn(stack_size, f)
+  }
+}
+
+impl Core {
+  fn spawn<F, T>(self: &Arc<Self>, stack_size: u32, f: F) -> JoinHandle<T>
+  where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+    let (task, handle) = Task::new(self.stacks.take(stack_size), self.clone(), f);
+    let admitted = self.tasks.lock().unwrap().insert(&task);
+    if admitted { self.schedule(task) } else { task.cancel() }
+    handle
+  }
+
+  fn work(self: Arc<Self>, index: usize) {
+    WORKER.with(|w| w.set((Arc::as_ptr(&self), index)));
+    while !self.shutdown.load(Ordering::Acquire) {
+      match self.find(index) {
+        Some(task) => self.run(index, task),
+        None => self.sleep(index),
+      }
+    }
+  }
+
+  fn run(&self, index: usize, task: Task) {
+    task.0.home.store(index, Ordering::Relaxed);
+    match task.run() {
+      Ran::Ready => self.queues[index].lock().unwrap().push_back(task),
+      Ran::Parked => (),
+      Ran::Finished => self.tasks.lock().unwrap().remove(&task),
+    }
+  }
+
+  /// Something to run: from our own queue, the injector, or somebody else's queue, in that order.
+  /// Tied tasks are only ever in their own worker's queue, and stay there.
+  fn find(&self, index: usize) -> Option<Task> {
+    if let Some(task) = self.queues[index].lock().unwrap().pop_front() { return Some(task) }
+    if let Some(task) = self.injector.lock().unwrap().pop_front() { return Some(task) }
+    let count = self.queues.len();
+    for victim in (1..count).map(|i| (index + i) % count) {
+      let stolen: VecDeque<Task> = {
+        let mut theirs = self.queues[victim].lock().unwrap();
+        let keep = theirs.len() / 2;
+        let (tied, stolen) = theirs.split_off(keep).into_iter().partition(Task::tied);
+        theirs.extend::<VecDeque<Task>>(tied);
+        stolen
+      };
+      if stolen.is_empty() { continue }
+      let mut ours = self.queues[index].lock().unwrap();
+      ours.extend(stolen);
+      return ours.pop_front();
+    }
+    None
+  }
+
+  /// Is there anything worker `index` could run?
+  fn has_work(&self, index: usize) -> bool {
+    !self.queues[index].lock().unwrap().is_empty() ||
+      !self.injector.lock().unwrap().is_empty() ||
+      self.queues.iter().any(|queue| queue.lock().unwrap().iter().any(|task| !task.tied()))
+  }
+
+  fn sleep(&self, index: usize) {
+    let idle = self.idle.lock().unwrap();
+    // Anyone queueing work after this sees us and wakes us up, so check once more first.
+    self.sleeping.fetch_add(1, Ordering::SeqCst);
+    if !self.has_work(index) && !self.shutdown.load(Ordering::SeqCst) {
+      drop(self.wake.wait(idle).unwrap());
+    }
+    self.sleeping.fetch_sub(1, Ordering::SeqCst);
+  }
+}
+
+impl Schedule for Core {
+  fn schedule(&self, task: Task) {
+    if task.tied() {
+      // Straight back to the only worker that may run it, which mightn't be the one we'd wake.
+      let home = task.0.home.load(Ordering::Relaxed);
+      self.queues[home].lock().unwrap().push_back(task);
+      let _idle = self.idle.lock().unwrap();
+      return self.wake.notify_all();
+    }
+    let (pool, index) = WORKER.with(Cell::get);
+    if std::ptr::eq(pool, self) {
+      self.queues[index].lock().unwrap().push_back(task);
+    } else {
+      self.injector.lock().unwrap().push_back(task);
+    }
+    if self.sleeping.load(Ordering::SeqCst) > 0 {
+      let _idle = self.idle.lock().unwrap();
+      self.wake.notify_one();
+    }
+  }
+}
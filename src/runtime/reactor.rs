@@ -0,0 +1,136 @@
+use super::wait::{wait_in, Waiters};
+use std::collections::HashMap;
+use std::io;
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+
+// Waits for file descriptors to become ready, and wakes whoever's waiting on them. It's all
+// edge-triggered: each fd is registered once, for everything, and we only hear when it changes.
+struct Reactor {
+  epoll:   OwnedFd,
+  // By token, which is what epoll hands back to us. Tokens are never reused, so an event for a
+  // source that's since gone away finds nothing.
+  sources: Mutex<HashMap<u64, Arc<Source>>>,
+  next:    AtomicU64,
+}
+
+#[derive(Default)]
+struct Source {
+  read:  Mutex<Readiness>,
+  write: Mutex<Readiness>,
+}
+
+#[derive(Default)]
+struct Readiness {
+  // Has it become ready since somebody last found it wasn't?
+  ready:   bool,
+  waiters: Waiters,
+}
+
+static REACTOR: OnceLock<Result<Reactor, io::ErrorKind>> = OnceLock::new();
+
+// Starts the reactor the first time it's wanted. If it can't be, for want of an epoll fd or a
+// thread, we remember and fail from then on, rather than have I/O park for a thread that isn't
+// there.
+fn reactor() -> io::Result<&'static Reactor> {
+  let reactor = REACTOR.get_or_init(|| {
+    let epoll = cvt(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) }).map_err(|err| err.kind())?;
+    let epoll = unsafe { OwnedFd::from_raw_fd(epoll) };
+    thread::Builder::new().name("stackle-reactor".into()).spawn(|| {
+      REACTOR.wait().as_ref().expect("started once the reactor's up").run()
+    }).map_err(|err| err.kind())?;
+    Ok(Reactor { epoll, sources: Mutex::new(HashMap::new()), next: AtomicU64::new(0) })
+  });
+  reactor.as_ref().map_err(|&kind| kind.into())
+}
+
+impl Reactor {
+  fn run(&self) {
+    let mut events = vec![libc::epoll_event { events: 0, u64: 0 }; 256];
+    loop {
+      let count = unsafe { libc::epoll_wait(self.epoll.as_raw_fd(), events.as_mut_ptr(), events.len() as i32, -1) };
+      if count < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::Interrupted { continue }
+        panic!("epoll_wait failed: {}", err);
+      }
+      for event in &events[..count as usize] {
+        let (token, flags) = (event.u64, event.events as i32);
+        let Some(source) = self.sources.lock().unwrap().get(&token).cloned() else { continue };
+        let closed = libc::EPOLLHUP | libc::EPOLLERR;
+        if flags & (libc::EPOLLIN | libc::EPOLLPRI | libc::EPOLLRDHUP | closed) != 0 { wake(&source.read) }
+        if flags & (libc::EPOLLOUT | closed) != 0 { wake(&source.write) }
+      }
+    }
+  }
+}
+
+fn wake(readiness: &Mutex<Readiness>) {
+  let mut state = readiness.lock().unwrap();
+  state.ready = true;
+  let everyone = state.waiters.take();
+  drop(state);
+  for waiter in everyone { waiter.unpark() }
+}
+
+/// A file descriptor the reactor's watching. It must be non-blocking, and outlive this.
+pub(crate) struct Registration {
+  fd:     RawFd,
+  token:  u64,
+  source: Arc<Source>,
+}
+
+impl Registration {
+  pub(crate) fn new(fd: BorrowedFd) -> io::Result<Self> {
+    let reactor = reactor()?;
+    let token = reactor.next.fetch_add(1, Ordering::Relaxed);
+    let source = Arc::new(Source::default());
+    reactor.sources.lock().unwrap().insert(token, source.clone());
+    let flags = libc::EPOLLIN | libc::EPOLLOUT | libc::EPOLLPRI | libc::EPOLLRDHUP | libc::EPOLLET;
+    let mut event = libc::epoll_event { events: flags as u32, u64: token };
+    let added = cvt(unsafe { libc::epoll_ctl(reactor.epoll.as_raw_fd(), libc::EPOLL_CTL_ADD, fd.as_raw_fd(), &mut event) });
+    if let Err(err) = added {
+      reactor.sources.lock().unwrap().remove(&token);
+      return Err(err)
+    }
+    Ok(Registration { fd: fd.as_raw_fd(), token, source })
+  }
+
+  /// Runs `op` until it doesn't say it would block, parking until the fd's readable in between.
+  pub(crate) fn read<T>(&self, op: impl FnMut() -> io::Result<T>) -> io::Result<T> { retry(&self.source.read, op) }
+
+  /// Like [`Registration::read`], for writing.
+  pub(crate) fn write<T>(&self, op: impl FnMut() -> io::Result<T>) -> io::Result<T> { retry(&self.source.write, op) }
+}
+
+fn retry<T>(readiness: &Mutex<Readiness>, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+  loop {
+    match op() {
+      Err(err) if err.kind() == io::ErrorKind::WouldBlock => (),
+      result => return result,
+    }
+    let mut state = readiness.lock().unwrap();
+    // It became ready again since we tried, so try again.
+    if std::mem::take(&mut state.ready) { continue }
+    let me = state.waiters.push_current();
+    drop(state);
+    wait_in(readiness, &me, |state| &mut state.waiters);
+    readiness.lock().unwrap().waiters.remove(&me);
+  }
+}
+
+impl Drop for Registration {
+  fn drop(&mut self) {
+    let reactor = reactor().expect("registered with the reactor");
+    // Closing the fd would do, but only if nobody's duplicated it.
+    unsafe { libc::epoll_ctl(reactor.epoll.as_raw_fd(), libc::EPOLL_CTL_DEL, self.fd, std::ptr::null_mut()) };
+    reactor.sources.lock().unwrap().remove(&self.token);
+  }
+}
+
+/// Turns a libc-style -1 into the error it means.
+pub(crate) fn cvt(result: libc::c_int) -> io::Result<libc::c_int> {
+  if result < 0 { Err(io::Error::last_os_error()) } else { Ok(result) }
+}
@@ -0,0 +1,65 @@
+use crate::stack::{PageSize, SafeStack, Stack};
+use std::mem::ManuallyDrop;
+use std::sync::{Arc, Mutex};
+
+// Stacks come in powers of two from here up, so similar sizes share a free list.
+const MIN_CLASS: u32 = 16 * 1024;
+
+/// Keeps the stacks of finished tasks around for the next ones, since mapping a fresh one costs a
+/// couple of syscalls. Never holds more than the most tasks that were ever alive at once.
+pub(crate) struct StackPool {
+  page:    PageSize,
+  // (class, free stacks of that class), in no particular order. There are only ever a few.
+  classes: Mutex<Vec<(u32, Vec<SafeStack>)>>,
+}
+
+impl StackPool {
+  pub(crate) fn new() -> Self {
+    let page = PageSize::get().expect("couldn't get the page size");
+    StackPool { page, classes: Mutex::new(Vec::new()) }
+  }
+
+  /// A stack of at least `size` bytes, from the pool if there's one free. Stacks over 2GiB have no
+  /// class to round up to, so they're mapped to size and never pooled.
+  ///
+  /// # Panics
+  ///
+  /// If we have to map a stack and can't.
+  pub(crate) fn take(self: &Arc<Self>, size: u32) -> PooledStack {
+    let Some(class) = size.max(MIN_CLASS).checked_next_power_of_two() else {
+      let stack = SafeStack::new(size, self.page).expect("couldn't map a stack");
+      return PooledStack { stack: ManuallyDrop::new(stack), class: None, pool: self.clone() }
+    };
+    let free = {
+      let mut classes = self.classes.lock().unwrap();
+      classes.iter_mut().find(|(c, _)| *c == class).and_then(|(_, free)| free.pop())
+    };
+    let stack = free.unwrap_or_else(|| SafeStack::new(class, self.page).expect("couldn't map a stack"));
+    PooledStack { stack: ManuallyDrop::new(stack), class: Some(class), pool: self.clone() }
+  }
+}
+
+/// A stack that goes back to its pool when dropped.
+pub(crate) struct PooledStack {
+  stack: ManuallyDrop<SafeStack>,
+  // None if it's too big to pool.
+  class: Option<u32>,
+  pool:  Arc<StackPool>,
+}
+
+unsafe impl Stack for PooledStack {
+  fn end(&self) -> *mut usize { self.stack.end() }
+  fn size(&self) -> usize { self.stack.size() }
+}
+
+impl Drop for PooledStack {
+  fn drop(&mut self) {
+    let stack = unsafe { ManuallyDrop::take(&mut self.stack) };
+    let Some(class) = self.class else { return };
+    let mut classes = self.pool.classes.lock().unwrap();
+    match classes.iter_mut().find(|(c, _)| *c == class) {
+      Some((_, free)) => free.push(stack),
+      None => classes.push((class, vec![stack])),
+    }
+  }
+}
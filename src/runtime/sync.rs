@@ -0,0 +1,111 @@
+//! Synchronisation primitives for tasks.
+//!
+//! A `std` mutex held across a suspension blocks the whole worker thread for whoever wants it
+//! next, and deadlocks outright if that's another task on the same thread. These park just the
+//! waiting task instead, and plain threads can use them too (they block, like with channels).
+//!
+//! Waiters queue up first come, first served, and whatever they're waiting for is handed over to
+//! the front of the queue rather than left for anyone to grab, so nobody starves. Nothing here is
+//! poisoned by a panic.
+//!
+//! ```
+//! use stackle::runtime::{self, sync::Mutex, LocalExecutor};
+//! use std::rc::Rc;
+//!
+//! let exec = LocalExecutor::new();
+//! let count = Rc::new(Mutex::new(0));
+//! for _ in 0..3 {
+//!   let count = count.clone();
+//!   exec.spawn(move || {
+//!     let mut count = count.lock();
+//!     // Holding it across a yield is fine: the others just wait their turn.
+//!     runtime::yield_now();
+//!     *count += 1;
+//!   });
+//! }
+//! exec.run_until_idle();
+//! assert_eq!(3, *count.lock());
+//! ```
+use super::time;
+use super::wait::{wait, wait_until, Waiter, Waiters};
+use std::sync::{Arc, Mutex as StdMutex, MutexGuard as StdMutexGuard};
+use std::time::Instant;
+
+mod barrier;
+pub use barrier::*;
+mod condvar;
+pub use condvar::*;
+mod mutex;
+pub use mutex::*;
+mod once;
+pub use once::*;
+mod rwlock;
+pub use rwlock::*;
+mod semaphore;
+pub use semaphore::*;
+mod wait_group;
+pub use wait_group::*;
+
+/// The state behind one of our primitives, guarded by a `std` mutex, with a queue of waiters.
+trait Queue {
+  fn waiters(&mut self) -> &mut Waiters;
+
+  /// `waiter` is unwinding out of [`wait_for`], say because its task was cancelled, after being
+  /// `notified` or not. If it was, pass on whatever it was given. If not, it's already been taken
+  /// off the queue.
+  fn abandon(&mut self, _waiter: &Waiter, _notified: bool) {}
+}
+
+// For condvars, whose state is just who's waiting. A notification the waiter can't use goes to
+// the next one.
+impl Queue for Waiters {
+  fn waiters(&mut self) -> &mut Waiters { self }
+
+  fn abandon(&mut self, _waiter: &Waiter, notified: bool) {
+    if notified { unpark_all(self.pop()) }
+  }
+}
+
+/// Waits for `waiter` to be notified, with `lock` released meanwhile, or until `deadline` if
+/// there is one. Returns the lock again and whether we were notified. If we weren't, we're off
+/// the queue.
+fn wait_for<'a, S: Queue>(
+  lock: &'a StdMutex<S>,
+  state: StdMutexGuard<'a, S>,
+  waiter: &Arc<Waiter>,
+  deadline: Option<Instant>,
+) -> (StdMutexGuard<'a, S>, bool) {
+  struct Abandon<'a, S: Queue> {
+    lock:   &'a StdMutex<S>,
+    waiter: &'a Arc<Waiter>,
+  }
+  impl<S: Queue> Drop for Abandon<'_, S> {
+    fn drop(&mut self) {
+      let mut state = self.lock.lock().unwrap();
+      let notified = self.waiter.notified();
+      if !notified { state.waiters().remove(self.waiter) }
+      state.abandon(self.waiter, notified);
+    }
+  }
+  let abandon = Abandon { lock, waiter };
+  drop(state);
+  let result = loop {
+    match deadline {
+      Some(deadline) => wait_until(deadline),
+      None => wait(),
+    }
+    let mut state = lock.lock().unwrap();
+    if waiter.notified() { break (state, true) }
+    if deadline.is_some_and(|deadline| time::now() >= deadline) {
+      state.waiters().remove(waiter);
+      break (state, false)
+    }
+  };
+  std::mem::forget(abandon);
+  result
+}
+
+/// Unparks everyone in `woken`, for after the lock's been released.
+fn unpark_all(woken: impl IntoIterator<Item = Arc<Waiter>>) {
+  for waiter in woken { waiter.unpark() }
+}
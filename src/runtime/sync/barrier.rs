@@ -0,0 +1,63 @@
+use super::{unpark_all, wait_for, Queue, Waiter, Waiters};
+use std::fmt;
+use std::sync::Mutex as StdMutex;
+
+struct BarrierState {
+  // How many are waiting in this round.
+  arrived: usize,
+  waiters: Waiters,
+}
+
+impl Queue for BarrierState {
+  fn waiters(&mut self) -> &mut Waiters { &mut self.waiters }
+
+  // Unwinding before the round's up, we were never here. After, it doesn't matter.
+  fn abandon(&mut self, _waiter: &Waiter, notified: bool) {
+    if !notified { self.arrived -= 1 }
+  }
+}
+
+/// Holds tasks up until a set number of them are waiting, then lets them all go. It can be used
+/// again straight away, for another round.
+pub struct Barrier {
+  state: StdMutex<BarrierState>,
+  count: usize,
+}
+
+/// What a [`Barrier::wait`] returned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BarrierWaitResult(bool);
+
+impl BarrierWaitResult {
+  /// Were we the last to arrive? Exactly one per round is.
+  pub fn is_leader(&self) -> bool { self.0 }
+}
+
+impl Barrier {
+  /// A barrier for `count` tasks at a time. With 0 or 1, nobody waits.
+  pub const fn new(count: usize) -> Self {
+    Barrier { state: StdMutex::new(BarrierState { arrived: 0, waiters: Waiters::new() }), count }
+  }
+
+  /// Parks until `count` tasks (ourselves included) have called this.
+  pub fn wait(&self) -> BarrierWaitResult {
+    let mut state = self.state.lock().unwrap();
+    state.arrived += 1;
+    if state.arrived >= self.count {
+      state.arrived = 0;
+      let everyone = state.waiters.take();
+      drop(state);
+      unpark_all(everyone);
+      return BarrierWaitResult(true)
+    }
+    let me = state.waiters.push_current();
+    drop(wait_for(&self.state, state, &me, None));
+    BarrierWaitResult(false)
+  }
+}
+
+impl fmt::Debug for Barrier {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("Barrier").field("count", &self.count).finish_non_exhaustive()
+  }
+}
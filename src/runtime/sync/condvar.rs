@@ -0,0 +1,89 @@
+use super::{unpark_all, wait_for, MutexGuard, Waiters};
+use crate::runtime::time;
+use std::fmt;
+use std::sync::Mutex as StdMutex;
+use std::time::{Duration, Instant};
+
+/// A condition variable for use with a [`Mutex`](super::Mutex), that parks the task waiting on
+/// it rather than its thread.
+///
+/// Waiters are notified in the order they started waiting. Like `std`'s, a wait can return
+/// without a notification, so check what you're waiting for in a loop, or use
+/// [`wait_while`](Condvar::wait_while).
+#[derive(Default)]
+pub struct Condvar {
+  waiters: StdMutex<Waiters>,
+}
+
+/// Whether a [`Condvar::wait_timeout`] gave up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+  /// Did we run out of time?
+  pub fn timed_out(&self) -> bool { self.0 }
+}
+
+impl Condvar {
+  /// A condvar nobody is waiting on.
+  pub const fn new() -> Self { Condvar { waiters: StdMutex::new(Waiters::new()) } }
+
+  /// Unlocks `guard`'s mutex and parks until notified, then locks it again.
+  pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+    self.wait_until(guard, None).0
+  }
+
+  /// Waits until `condition` is false, checking it each time we're notified.
+  pub fn wait_while<'a, T: ?Sized, F>(&self, mut guard: MutexGuard<'a, T>, mut condition: F) -> MutexGuard<'a, T>
+  where F: FnMut(&mut T) -> bool {
+    while condition(&mut guard) { guard = self.wait(guard) }
+    guard
+  }
+
+  /// Like [`Condvar::wait`], but gives up after `timeout`.
+  pub fn wait_timeout<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>, timeout: Duration)
+  -> (MutexGuard<'a, T>, WaitTimeoutResult) {
+    let (guard, notified) = self.wait_until(guard, Some(time::now() + timeout));
+    (guard, WaitTimeoutResult(!notified))
+  }
+
+  /// Like [`Condvar::wait_while`], but gives up after `timeout`, returning whether `condition`
+  /// was still true.
+  pub fn wait_timeout_while<'a, T: ?Sized, F>(&self, mut guard: MutexGuard<'a, T>, timeout: Duration, mut condition: F)
+  -> (MutexGuard<'a, T>, WaitTimeoutResult)
+  where F: FnMut(&mut T) -> bool {
+    let deadline = time::now() + timeout;
+    while condition(&mut guard) {
+      if time::now() >= deadline { return (guard, WaitTimeoutResult(true)) }
+      guard = self.wait_until(guard, Some(deadline)).0;
+    }
+    (guard, WaitTimeoutResult(false))
+  }
+
+  /// Wakes whoever's waited longest, if anyone's waiting.
+  pub fn notify_one(&self) {
+    let next = self.waiters.lock().unwrap().pop();
+    unpark_all(next);
+  }
+
+  /// Wakes everyone waiting.
+  pub fn notify_all(&self) {
+    let all = self.waiters.lock().unwrap().take();
+    unpark_all(all);
+  }
+
+  fn wait_until<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>, deadline: Option<Instant>) -> (MutexGuard<'a, T>, bool) {
+    let mutex = guard.mutex;
+    let mut waiters = self.waiters.lock().unwrap();
+    let me = waiters.push_current();
+    // We're on the list before it's unlocked, so we can't miss a notification.
+    drop(guard);
+    let (waiters, notified) = wait_for(&self.waiters, waiters, &me, deadline);
+    drop(waiters);
+    (mutex.lock(), notified)
+  }
+}
+
+impl fmt::Debug for Condvar {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("Condvar { .. }") }
+}
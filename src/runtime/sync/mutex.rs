@@ -0,0 +1,122 @@
+use super::{unpark_all, wait_for, Queue, Waiter, Waiters};
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex as StdMutex};
+
+struct MutexState {
+  locked:  bool,
+  waiters: Waiters,
+}
+
+impl MutexState {
+  /// Hands the lock to whoever's next, if anyone. Returns them, to unpark.
+  fn release(&mut self) -> Option<Arc<Waiter>> {
+    let next = self.waiters.pop();
+    // Still locked, but by them now.
+    self.locked = next.is_some();
+    next
+  }
+}
+
+impl Queue for MutexState {
+  fn waiters(&mut self) -> &mut Waiters { &mut self.waiters }
+
+  fn abandon(&mut self, _waiter: &Waiter, notified: bool) {
+    if notified { unpark_all(self.release()) }
+  }
+}
+
+/// A mutex that parks the task waiting for it, rather than its thread.
+///
+/// Unlike a `std` mutex, the guard can be held across a suspension (even if the task moves to
+/// another thread meanwhile), and nobody gets the lock ahead of someone who asked for it earlier.
+pub struct Mutex<T: ?Sized> {
+  state: StdMutex<MutexState>,
+  value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+/// Access to what's in a [`Mutex`]. Dropping it unlocks it.
+#[must_use = "dropping the guard unlocks the mutex straight away"]
+pub struct MutexGuard<'a, T: ?Sized> {
+  pub(super) mutex: &'a Mutex<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T> Mutex<T> {
+  /// An unlocked mutex holding `value`.
+  pub const fn new(value: T) -> Self {
+    Mutex { state: StdMutex::new(MutexState { locked: false, waiters: Waiters::new() }), value: UnsafeCell::new(value) }
+  }
+
+  /// What's in it.
+  pub fn into_inner(self) -> T { self.value.into_inner() }
+}
+
+impl<T: ?Sized> Mutex<T> {
+  /// Locks it, parking until it's our turn.
+  pub fn lock(&self) -> MutexGuard<'_, T> {
+    let mut state = self.state.lock().unwrap();
+    if !state.locked {
+      state.locked = true;
+      return MutexGuard { mutex: self }
+    }
+    let me = state.waiters.push_current();
+    // Whoever unlocks it hands it straight to us.
+    drop(wait_for(&self.state, state, &me, None));
+    MutexGuard { mutex: self }
+  }
+
+  /// Locks it if nobody has it right now.
+  pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+    let mut state = self.state.lock().unwrap();
+    if state.locked { return None }
+    state.locked = true;
+    Some(MutexGuard { mutex: self })
+  }
+
+  /// Is somebody holding it?
+  pub fn is_locked(&self) -> bool { self.state.lock().unwrap().locked }
+
+  /// What's in it. Having `&mut` proves nobody else has it locked.
+  pub fn get_mut(&mut self) -> &mut T { self.value.get_mut() }
+
+  fn unlock(&self) {
+    let next = self.state.lock().unwrap().release();
+    unpark_all(next);
+  }
+}
+
+impl<T: Default> Default for Mutex<T> {
+  fn default() -> Self { Mutex::new(T::default()) }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.try_lock() {
+      Some(guard) => f.debug_struct("Mutex").field("value", &&*guard).finish(),
+      None => f.write_str("Mutex { <locked> }"),
+    }
+  }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+  type Target = T;
+  fn deref(&self) -> &T { unsafe { &*self.mutex.value.get() } }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+  fn deref_mut(&mut self) -> &mut T { unsafe { &mut *self.mutex.value.get() } }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+  fn drop(&mut self) { self.mutex.unlock() }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for MutexGuard<'_, T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { fmt::Debug::fmt(&**self, f) }
+}
@@ -0,0 +1,164 @@
+use super::{unpark_all, wait_for, Queue, Waiters};
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex as StdMutex;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Progress {
+  Incomplete,
+  Running,
+  Complete,
+}
+
+struct OnceState {
+  progress: Progress,
+  waiters:  Waiters,
+}
+
+impl Queue for OnceState {
+  fn waiters(&mut self) -> &mut Waiters { &mut self.waiters }
+}
+
+/// Runs something exactly once, however many tasks try. Those that come along while it's running
+/// park until it's done.
+///
+/// If it panics, it's as if it never ran, and the next in line has a go.
+pub struct Once {
+  // So the usual case needn't lock anything.
+  done:  AtomicBool,
+  state: StdMutex<OnceState>,
+}
+
+impl Once {
+  /// A `Once` that hasn't been run.
+  pub const fn new() -> Self {
+    let state = OnceState { progress: Progress::Incomplete, waiters: Waiters::new() };
+    Once { done: AtomicBool::new(false), state: StdMutex::new(state) }
+  }
+
+  /// Runs `f` if nobody has yet, or waits for whoever's running it. Either way, it's been run
+  /// once we return.
+  pub fn call_once<F: FnOnce()>(&self, f: F) {
+    if self.is_completed() { return }
+    let mut state = self.state.lock().unwrap();
+    loop {
+      match state.progress {
+        Progress::Complete => return,
+        Progress::Running => {
+          let me = state.waiters.push_current();
+          state = wait_for(&self.state, state, &me, None).0;
+        }
+        Progress::Incomplete => {
+          state.progress = Progress::Running;
+          drop(state);
+          return self.run(f)
+        }
+      }
+    }
+  }
+
+  /// Has it been run?
+  pub fn is_completed(&self) -> bool { self.done.load(Ordering::Acquire) }
+
+  fn run<F: FnOnce()>(&self, f: F) {
+    struct Finish<'a> {
+      once:     &'a Once,
+      progress: Progress,
+    }
+    impl Drop for Finish<'_> {
+      fn drop(&mut self) {
+        let mut state = self.once.state.lock().unwrap();
+        state.progress = self.progress;
+        if self.progress == Progress::Complete { self.once.done.store(true, Ordering::Release) }
+        // If it panicked, they can fight over who has another go.
+        let everyone = state.waiters.take();
+        drop(state);
+        unpark_all(everyone);
+      }
+    }
+    let mut finish = Finish { once: self, progress: Progress::Incomplete };
+    f();
+    finish.progress = Progress::Complete;
+  }
+}
+
+impl Default for Once {
+  fn default() -> Self { Once::new() }
+}
+
+impl fmt::Debug for Once {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("Once").field("completed", &self.is_completed()).finish()
+  }
+}
+
+/// A value that's set once, by whichever task gets there first, and never changes after.
+///
+/// ```
+/// use stackle::runtime::{self, sync::OnceCell, LocalExecutor};
+///
+/// static CONFIG: OnceCell<String> = OnceCell::new();
+///
+/// let exec = LocalExecutor::new();
+/// let readers: Vec<_> = (0..3).map(|_| exec.spawn(|| {
+///   CONFIG.get_or_init(|| {
+///     // Only the first one gets here. The others park until it's done.
+///     runtime::yield_now();
+///     "loaded".to_string()
+///   }).len()
+/// })).collect();
+/// exec.run_until_idle();
+/// for reader in readers { assert_eq!(6, reader.join().unwrap()) }
+/// ```
+pub struct OnceCell<T> {
+  once:  Once,
+  value: UnsafeCell<Option<T>>,
+}
+
+unsafe impl<T: Send> Send for OnceCell<T> {}
+unsafe impl<T: Send + Sync> Sync for OnceCell<T> {}
+
+impl<T> OnceCell<T> {
+  /// An empty cell.
+  pub const fn new() -> Self { OnceCell { once: Once::new(), value: UnsafeCell::new(None) } }
+
+  /// The value, if it's been set.
+  pub fn get(&self) -> Option<&T> {
+    // Nobody writes to it once it's complete.
+    if self.once.is_completed() { unsafe { (*self.value.get()).as_ref() } } else { None }
+  }
+
+  /// The value, setting it to what `f` returns if nobody has yet, or parking while somebody's at
+  /// it.
+  pub fn get_or_init<F: FnOnce() -> T>(&self, f: F) -> &T {
+    self.once.call_once(|| unsafe { *self.value.get() = Some(f()) });
+    self.get().expect("set by call_once")
+  }
+
+  /// Sets the value, unless it's been set already, in which case you get `value` back.
+  pub fn set(&self, value: T) -> Result<(), T> {
+    let mut value = Some(value);
+    self.once.call_once(|| unsafe { *self.value.get() = value.take() });
+    match value {
+      Some(value) => Err(value),
+      None => Ok(()),
+    }
+  }
+
+  /// The value, if it's been set. Having `&mut` proves nobody's setting it.
+  pub fn get_mut(&mut self) -> Option<&mut T> { self.value.get_mut().as_mut() }
+
+  /// The value, if it's been set.
+  pub fn into_inner(self) -> Option<T> { self.value.into_inner() }
+}
+
+impl<T> Default for OnceCell<T> {
+  fn default() -> Self { OnceCell::new() }
+}
+
+impl<T: fmt::Debug> fmt::Debug for OnceCell<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_tuple("OnceCell").field(&self.get()).finish()
+  }
+}
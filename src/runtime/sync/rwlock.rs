@@ -0,0 +1,187 @@
+use super::{unpark_all, wait_for, Queue, Waiter, Waiters};
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex as StdMutex};
+
+// What each waiter wants.
+const READ:  usize = 0;
+const WRITE: usize = 1;
+
+struct RwLockState {
+  readers: usize,
+  writer:  bool,
+  waiters: Waiters,
+}
+
+impl RwLockState {
+  /// Hands the lock to whoever's next, if they can have it: a writer, or every reader up to the
+  /// next writer. Returns them, to unpark.
+  fn grant(&mut self) -> Vec<Arc<Waiter>> {
+    let mut granted = Vec::new();
+    while let Some(next) = self.waiters.front() {
+      match next.data {
+        WRITE if self.readers == 0 && !self.writer => self.writer = true,
+        READ if !self.writer => self.readers += 1,
+        _ => break,
+      }
+      granted.extend(self.waiters.pop());
+      if self.writer { break }
+    }
+    granted
+  }
+
+  fn unlock_read(&mut self) -> Vec<Arc<Waiter>> {
+    self.readers -= 1;
+    self.grant()
+  }
+
+  fn unlock_write(&mut self) -> Vec<Arc<Waiter>> {
+    self.writer = false;
+    self.grant()
+  }
+}
+
+impl Queue for RwLockState {
+  fn waiters(&mut self) -> &mut Waiters { &mut self.waiters }
+
+  fn abandon(&mut self, waiter: &Waiter, notified: bool) {
+    match (notified, waiter.data) {
+      (false, _) => (),
+      (true, READ) => unpark_all(self.unlock_read()),
+      (true, _) => unpark_all(self.unlock_write()),
+    }
+  }
+}
+
+/// A reader-writer lock that parks the task waiting for it, rather than its thread.
+///
+/// It's fair in both directions: once a writer is waiting, readers that come after it wait
+/// behind it, and when a writer's done, every reader that queued up behind it gets in together.
+pub struct RwLock<T: ?Sized> {
+  state: StdMutex<RwLockState>,
+  value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+/// Shared access to what's in a [`RwLock`]. Dropping it unlocks it.
+#[must_use = "dropping the guard unlocks the lock straight away"]
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+  lock: &'a RwLock<T>,
+}
+
+/// Exclusive access to what's in a [`RwLock`]. Dropping it unlocks it.
+#[must_use = "dropping the guard unlocks the lock straight away"]
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+  lock: &'a RwLock<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RwLockReadGuard<'_, T> {}
+unsafe impl<T: ?Sized + Sync> Sync for RwLockWriteGuard<'_, T> {}
+
+impl<T> RwLock<T> {
+  /// An unlocked lock holding `value`.
+  pub const fn new(value: T) -> Self {
+    let state = RwLockState { readers: 0, writer: false, waiters: Waiters::new() };
+    RwLock { state: StdMutex::new(state), value: UnsafeCell::new(value) }
+  }
+
+  /// What's in it.
+  pub fn into_inner(self) -> T { self.value.into_inner() }
+}
+
+impl<T: ?Sized> RwLock<T> {
+  /// Locks it for reading, parking while someone's writing or waiting to.
+  pub fn read(&self) -> RwLockReadGuard<'_, T> {
+    let mut state = self.state.lock().unwrap();
+    if !state.writer && state.waiters.is_empty() {
+      state.readers += 1;
+      return RwLockReadGuard { lock: self }
+    }
+    let me = state.waiters.push_current_with(READ);
+    drop(wait_for(&self.state, state, &me, None));
+    RwLockReadGuard { lock: self }
+  }
+
+  /// Locks it for writing, parking while anyone else has it or is ahead of us.
+  pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+    let mut state = self.state.lock().unwrap();
+    if !state.writer && state.readers == 0 {
+      state.writer = true;
+      return RwLockWriteGuard { lock: self }
+    }
+    let me = state.waiters.push_current_with(WRITE);
+    drop(wait_for(&self.state, state, &me, None));
+    RwLockWriteGuard { lock: self }
+  }
+
+  /// Locks it for reading if we can right now.
+  pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+    let mut state = self.state.lock().unwrap();
+    if state.writer || !state.waiters.is_empty() { return None }
+    state.readers += 1;
+    Some(RwLockReadGuard { lock: self })
+  }
+
+  /// Locks it for writing if we can right now.
+  pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+    let mut state = self.state.lock().unwrap();
+    if state.writer || state.readers > 0 { return None }
+    state.writer = true;
+    Some(RwLockWriteGuard { lock: self })
+  }
+
+  /// What's in it. Having `&mut` proves nobody else has it locked.
+  pub fn get_mut(&mut self) -> &mut T { self.value.get_mut() }
+}
+
+impl<T: Default> Default for RwLock<T> {
+  fn default() -> Self { RwLock::new(T::default()) }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.try_read() {
+      Some(guard) => f.debug_struct("RwLock").field("value", &&*guard).finish(),
+      None => f.write_str("RwLock { <locked> }"),
+    }
+  }
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+  type Target = T;
+  fn deref(&self) -> &T { unsafe { &*self.lock.value.get() } }
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+  type Target = T;
+  fn deref(&self) -> &T { unsafe { &*self.lock.value.get() } }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+  fn deref_mut(&mut self) -> &mut T { unsafe { &mut *self.lock.value.get() } }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+  fn drop(&mut self) {
+    let granted = self.lock.state.lock().unwrap().unlock_read();
+    unpark_all(granted);
+  }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+  fn drop(&mut self) {
+    let granted = self.lock.state.lock().unwrap().unlock_write();
+    unpark_all(granted);
+  }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLockReadGuard<'_, T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { fmt::Debug::fmt(&**self, f) }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLockWriteGuard<'_, T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { fmt::Debug::fmt(&**self, f) }
+}
@@ -0,0 +1,118 @@
+use super::{unpark_all, wait_for, Queue, Waiter, Waiters};
+use std::fmt;
+use std::sync::{Arc, Mutex as StdMutex};
+
+struct SemaphoreState {
+  permits: usize,
+  waiters: Waiters,
+}
+
+impl SemaphoreState {
+  /// Hands out what permits there are to whoever's waiting. Returns them, to unpark.
+  fn grant(&mut self) -> Vec<Arc<Waiter>> {
+    let mut granted = Vec::new();
+    while self.permits > 0 {
+      let Some(next) = self.waiters.pop() else { break };
+      self.permits -= 1;
+      granted.push(next);
+    }
+    granted
+  }
+
+  fn release(&mut self, permits: usize) -> Vec<Arc<Waiter>> {
+    self.permits += permits;
+    self.grant()
+  }
+}
+
+impl Queue for SemaphoreState {
+  fn waiters(&mut self) -> &mut Waiters { &mut self.waiters }
+
+  fn abandon(&mut self, _waiter: &Waiter, notified: bool) {
+    if notified { unpark_all(self.release(1)) }
+  }
+}
+
+/// Counts out permits, parking the tasks that want one until one's free.
+///
+/// ```
+/// use stackle::runtime::{self, sync::Semaphore, LocalExecutor};
+/// use std::rc::Rc;
+///
+/// let exec = LocalExecutor::new();
+/// // At most two at a time.
+/// let connections = Rc::new(Semaphore::new(2));
+/// for _ in 0..10 {
+///   let connections = connections.clone();
+///   exec.spawn(move || {
+///     let _permit = connections.acquire();
+///     runtime::yield_now();
+///   });
+/// }
+/// exec.run_until_idle();
+/// assert_eq!(2, connections.available_permits());
+/// ```
+pub struct Semaphore {
+  state: StdMutex<SemaphoreState>,
+}
+
+/// A permit from a [`Semaphore`], handed back when it's dropped.
+#[must_use = "dropping the permit hands it back straight away"]
+pub struct SemaphorePermit<'a> {
+  semaphore: &'a Semaphore,
+}
+
+impl Semaphore {
+  /// A semaphore with `permits` to hand out.
+  pub const fn new(permits: usize) -> Self {
+    Semaphore { state: StdMutex::new(SemaphoreState { permits, waiters: Waiters::new() }) }
+  }
+
+  /// Takes a permit, parking until it's our turn for one.
+  pub fn acquire(&self) -> SemaphorePermit<'_> {
+    let mut state = self.state.lock().unwrap();
+    if state.permits > 0 && state.waiters.is_empty() {
+      state.permits -= 1;
+      return SemaphorePermit { semaphore: self }
+    }
+    let me = state.waiters.push_current();
+    drop(wait_for(&self.state, state, &me, None));
+    SemaphorePermit { semaphore: self }
+  }
+
+  /// Takes a permit if there's one free and nobody's waiting for it.
+  pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+    let mut state = self.state.lock().unwrap();
+    if state.permits == 0 || !state.waiters.is_empty() { return None }
+    state.permits -= 1;
+    Some(SemaphorePermit { semaphore: self })
+  }
+
+  /// Adds `permits` more to hand out.
+  pub fn add_permits(&self, permits: usize) {
+    let granted = self.state.lock().unwrap().release(permits);
+    unpark_all(granted);
+  }
+
+  /// How many permits are free right now.
+  pub fn available_permits(&self) -> usize { self.state.lock().unwrap().permits }
+}
+
+impl fmt::Debug for Semaphore {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("Semaphore").field("permits", &self.available_permits()).finish()
+  }
+}
+
+impl SemaphorePermit<'_> {
+  /// Keeps the permit out of circulation for good, so the semaphore has one fewer.
+  pub fn forget(self) { std::mem::forget(self) }
+}
+
+impl Drop for SemaphorePermit<'_> {
+  fn drop(&mut self) { self.semaphore.add_permits(1) }
+}
+
+impl fmt::Debug for SemaphorePermit<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("SemaphorePermit { .. }") }
+}
@@ -0,0 +1,84 @@
+use super::{unpark_all, wait_for, Queue, Waiters};
+use std::fmt;
+use std::sync::Mutex as StdMutex;
+
+struct WaitGroupState {
+  count:   usize,
+  waiters: Waiters,
+}
+
+impl Queue for WaitGroupState {
+  fn waiters(&mut self) -> &mut Waiters { &mut self.waiters }
+}
+
+/// Waits for a number of things to be done, like Go's `sync.WaitGroup`.
+///
+/// [`add`](WaitGroup::add) however many there are to wait for, call [`done`](WaitGroup::done) as
+/// each finishes, and [`wait`](WaitGroup::wait) parks until they all have.
+///
+/// ```
+/// use stackle::runtime::{self, sync::WaitGroup, LocalExecutor};
+/// use std::rc::Rc;
+///
+/// let exec = LocalExecutor::new();
+/// let group = Rc::new(WaitGroup::new());
+/// group.add(3);
+/// for _ in 0..3 {
+///   let group = group.clone();
+///   exec.spawn(move || {
+///     runtime::yield_now();
+///     group.done();
+///   });
+/// }
+/// let waiter = exec.spawn(move || group.wait());
+/// exec.run_until_idle();
+/// assert!(waiter.is_finished());
+/// ```
+pub struct WaitGroup {
+  state: StdMutex<WaitGroupState>,
+}
+
+impl WaitGroup {
+  /// A group with nothing to wait for.
+  pub const fn new() -> Self {
+    WaitGroup { state: StdMutex::new(WaitGroupState { count: 0, waiters: Waiters::new() }) }
+  }
+
+  /// Adds `count` more things to wait for.
+  pub fn add(&self, count: usize) { self.state.lock().unwrap().count += count }
+
+  /// One of them is done. The last wakes everyone waiting.
+  ///
+  /// # Panics
+  ///
+  /// If there's nothing left to be done.
+  pub fn done(&self) {
+    let mut state = self.state.lock().unwrap();
+    state.count = state.count.checked_sub(1).expect("done called more times than added");
+    if state.count > 0 { return }
+    let everyone = state.waiters.take();
+    drop(state);
+    unpark_all(everyone);
+  }
+
+  /// Parks until everything added has been done.
+  pub fn wait(&self) {
+    let mut state = self.state.lock().unwrap();
+    if state.count == 0 { return }
+    let me = state.waiters.push_current();
+    drop(wait_for(&self.state, state, &me, None));
+  }
+
+  /// How many things are still to be done.
+  pub fn count(&self) -> usize { self.state.lock().unwrap().count }
+}
+
+impl Default for WaitGroup {
+  fn default() -> Self { WaitGroup::new() }
+}
+
+impl fmt::Debug for WaitGroup {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("WaitGroup").field("count", &self.count()).finish()
+  }
+}
@@ -0,0 +1,414 @@
+use super::stacks::{PooledStack, StackPool};
+use super::time::Clock;
+use super::wait::{wait, wait_until};
+use crate::coroutine::{Coroutine, CoroutineResult, Yielder};
+use crate::parking_lot::{self, Scheduler, Unpark};
+use std::any::Any;
+use std::cell::{Cell, RefCell, UnsafeCell};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr::null;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, Thread};
+use std::time::Instant;
+
+// A task's state. NOTIFIED goes on top of SCHEDULED or RUNNING: it's an `unpark` that the next
+// `park` gets to consume.
+const PARKED:    usize = 0;
+const NOTIFIED:  usize = 1;
+const SCHEDULED: usize = 2;
+const RUNNING:   usize = 4;
+const DONE:      usize = 8;
+
+/// What a task suspended for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Suspend {
+  Yield,
+  Park,
+}
+
+type TaskCoroutine = Coroutine<(), Suspend, (), PooledStack>;
+type Start = Box<dyn FnOnce() -> TaskCoroutine + Send>;
+
+fn task_coroutine<F, T>(stack: PooledStack, out: Arc<Mutex<Option<T>>>, f: F) -> TaskCoroutine
+where F: FnOnce() -> T + 'static, T: 'static {
+  Coroutine::new(stack, move |y: &Yielder<(), Suspend>, ()| {
+    CURRENT.with(|c| c.borrow().as_ref().expect("started outside an executor").0.yielder.set(y));
+    let result = f();
+    *out.lock().unwrap() = Some(result);
+  }).expect("a task's closure doesn't fit on its stack")
+}
+
+/// How an executor hears about tasks that have become ready.
+pub(crate) trait Schedule: Send + Sync {
+  /// Queues `task`, which was just unparked. Called from any thread.
+  fn schedule(&self, task: Task);
+
+  /// Runs the executor on this thread until nothing's ready, if that's possible from here.
+  /// Returns whether it did.
+  fn drive(self: Arc<Self>) -> bool { false }
+
+  /// Where the executor's tasks get the time from.
+  fn clock(&self) -> Clock { Clock::real() }
+}
+
+pub(crate) struct Header {
+  state:     AtomicUsize,
+  // Only touched by whoever is running the task, or finishing it.
+  coroutine: UnsafeCell<Option<TaskCoroutine>>,
+  // Makes the coroutine, for tasks that don't have one yet.
+  start:     Mutex<Option<Start>>,
+  // Set once the task starts. It lives in the coroutine's control block.
+  yielder:   Cell<*const Yielder<(), Suspend>>,
+  panic:     Mutex<Option<Box<dyn Any + Send>>>,
+  // Whoever is waiting to join us.
+  joiner:    Mutex<Option<Unparker>>,
+  // Our place in the executor's `TaskList`.
+  index:     AtomicUsize,
+  // The worker we last ran on, for executors that have several.
+  pub(crate) home: AtomicUsize,
+  // Whether something ties us to `home`, as of when we last suspended. Debug builds only.
+  tied:      AtomicBool,
+  // The outermost `timeout` that's gone off, if any, by id.
+  expired:   AtomicU64,
+  scheduler: Arc<dyn Schedule>,
+}
+
+// The coroutine (which may hold things that aren't `Send`) is only ever run, and dropped, by its
+// executor, on a thread the executor deems fit. Everything else is atomic or locked.
+unsafe impl Send for Header {}
+unsafe impl Sync for Header {}
+
+/// A handle to a task, for unparking it. It can go to other threads, wherever the task runs.
+#[derive(Clone)]
+pub struct Task(pub(crate) Arc<Header>);
+
+std::thread_local! {
+  // The task running on this thread, if any.
+  static CURRENT: RefCell<Option<Task>> = const { RefCell::new(None) };
+}
+
+pub(crate) fn current_task() -> Option<Task> { CURRENT.with(|c| c.borrow().clone()) }
+
+/// What happened when a task ran.
+pub(crate) enum Ran {
+  /// It yielded, or parked and was unparked while at it. Queue it again.
+  Ready,
+  /// It parked. Whoever unparks it will schedule it.
+  Parked,
+  /// It returned or panicked. Forget about it.
+  Finished,
+}
+
+impl Task {
+  /// Makes a task to run `f` on `stack`, reporting to `scheduler`. It starts out scheduled, so
+  /// queue it.
+  pub(crate) fn new<F, T>(stack: PooledStack, scheduler: Arc<dyn Schedule>, f: F) -> (Task, JoinHandle<T>)
+  where F: FnOnce() -> T + 'static, T: 'static {
+    let value = Arc::new(Mutex::new(None));
+    let coroutine = task_coroutine(stack, value.clone(), f);
+    Task::with(scheduler, Some(coroutine), None, value)
+  }
+
+  /// Like [`Task::new`], but the coroutine and its stack are only made, from `stacks`, when the
+  /// task first runs. So they belong to whichever thread runs it, rather than the spawner's.
+  pub(crate) fn lazy<F, T>(stacks: Arc<StackPool>, stack_size: u32, scheduler: Arc<dyn Schedule>, f: F) -> (Task, JoinHandle<T>)
+  where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+    let value = Arc::new(Mutex::new(None));
+    let out = value.clone();
+    let start = Box::new(move || task_coroutine(stacks.take(stack_size), out, f));
+    Task::with(scheduler, None, Some(start), value)
+  }
+
+  fn with<T>(scheduler: Arc<dyn Schedule>, coroutine: Option<TaskCoroutine>, start: Option<Start>, value: Arc<Mutex<Option<T>>>) -> (Task, JoinHandle<T>) {
+    let task = Task(Arc::new(Header {
+      state:     AtomicUsize::new(SCHEDULED),
+      coroutine: UnsafeCell::new(coroutine),
+      start:     Mutex::new(start),
+      yielder:   Cell::new(null()),
+      panic:     Mutex::new(None),
+      joiner:    Mutex::new(None),
+      index:     AtomicUsize::new(0),
+      home:      AtomicUsize::new(usize::MAX),
+      tied:      AtomicBool::new(false),
+      expired:   AtomicU64::new(NOT_EXPIRED),
+      scheduler,
+    }));
+    (task.clone(), JoinHandle { task, value })
+  }
+
+  /// Runs the task until it suspends or finishes. It must be scheduled.
+  pub(crate) fn run(&self) -> Ran {
+    let header = &*self.0;
+    header.state.fetch_xor(SCHEDULED ^ RUNNING, Ordering::AcqRel);
+    let previous = CURRENT.with(|c| c.replace(Some(self.clone())));
+    let coroutine = unsafe { &mut *header.coroutine.get() };
+    if coroutine.is_none() {
+      *coroutine = header.start.lock().unwrap().take().map(|start| start());
+    }
+    let coroutine = coroutine.as_mut().expect("ran a finished task");
+    let result = panic::catch_unwind(AssertUnwindSafe(|| parking_lot::with_scheduler(&Tasks, || coroutine.resume(()))));
+    CURRENT.with(|c| c.replace(previous));
+    // Before anyone can schedule it again.
+    if cfg!(debug_assertions) && result.is_ok() {
+      header.tied.store(coroutine.affinity().is_some(), Ordering::Relaxed);
+    }
+    match result {
+      Ok(CoroutineResult::Yield(Suspend::Yield)) => {
+        header.state.fetch_xor(SCHEDULED ^ RUNNING, Ordering::AcqRel);
+        Ran::Ready
+      }
+      Ok(CoroutineResult::Yield(Suspend::Park)) => {
+        match header.state.compare_exchange(RUNNING, PARKED, Ordering::AcqRel, Ordering::Acquire) {
+          Ok(_) => Ran::Parked,
+          // We were unparked on the way out, which uses up the notification.
+          Err(_) => {
+            header.state.store(SCHEDULED, Ordering::Release);
+            Ran::Ready
+          }
+        }
+      }
+      Ok(CoroutineResult::Return(())) => {
+        self.finish(None);
+        Ran::Finished
+      }
+      Err(payload) => {
+        self.finish(Some(payload));
+        Ran::Finished
+      }
+    }
+  }
+
+  /// Unwinds the task without running any more of it.
+  pub(crate) fn cancel(&self) {
+    // Nobody can schedule it from here on.
+    self.0.state.store(DONE, Ordering::Release);
+    let previous = CURRENT.with(|c| c.replace(Some(self.clone())));
+    self.finish(Some(Box::new("task cancelled")));
+    CURRENT.with(|c| c.replace(previous));
+  }
+
+  fn finish(&self, panic: Option<Box<dyn Any + Send>>) {
+    let header = &*self.0;
+    if panic.is_some() { *header.panic.lock().unwrap() = panic }
+    // Hands the stack back to the pool.
+    drop(unsafe { (*header.coroutine.get()).take() });
+    drop(header.start.lock().unwrap().take());
+    header.state.store(DONE, Ordering::Release);
+    let joiner = header.joiner.lock().unwrap().take();
+    if let Some(joiner) = joiner { joiner.unpark() }
+  }
+
+  /// Did the task hold anything that ties it to the thread it last ran on, when it suspended?
+  /// Release builds don't check.
+  pub(crate) fn tied(&self) -> bool { self.0.tied.load(Ordering::Relaxed) }
+
+  /// Wakes the task if it's parked, or else makes its next [`park`] return straight away.
+  pub fn unpark(&self) {
+    let header = &*self.0;
+    let mut state = header.state.load(Ordering::Acquire);
+    loop {
+      let next = match state {
+        PARKED => SCHEDULED,
+        DONE => return,
+        state if state & NOTIFIED != 0 => return,
+        state => state | NOTIFIED,
+      };
+      match header.state.compare_exchange_weak(state, next, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) if state == PARKED => return header.scheduler.schedule(self.clone()),
+        Ok(_) => return,
+        Err(actual) => state = actual,
+      }
+    }
+  }
+
+  fn is_done(&self) -> bool { self.0.state.load(Ordering::Acquire) == DONE }
+
+  /// The clock of the executor the task's on.
+  pub(crate) fn clock(&self) -> Clock { self.0.scheduler.clock() }
+
+  /// Makes the [`timeout`](super::time::timeout) with `id` unwind the task, next time it suspends.
+  pub(crate) fn expire(&self, id: u64) {
+    // If an outer one's gone off too, that's where we're going anyway.
+    self.0.expired.fetch_min(id, Ordering::AcqRel);
+    self.unpark();
+  }
+
+  /// Takes back [`Task::expire`], for when timeout `id` is finished with.
+  pub(crate) fn unexpire(&self, id: u64) {
+    let _ = self.0.expired.compare_exchange(id, NOT_EXPIRED, Ordering::AcqRel, Ordering::Relaxed);
+  }
+
+  // Unwinds out to a timeout that's gone off, unless we're unwinding already.
+  fn unwind_if_expired(&self) {
+    let id = self.0.expired.load(Ordering::Acquire);
+    if id != NOT_EXPIRED && !thread::panicking() { panic::resume_unwind(Box::new(Expired(id))) }
+  }
+}
+
+const NOT_EXPIRED: u64 = u64::MAX;
+
+/// What a task's unwound with when a [`timeout`](super::time::timeout) goes off.
+pub(crate) struct Expired(pub(crate) u64);
+
+impl fmt::Debug for Task {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let state = match self.0.state.load(Ordering::Relaxed) {
+      PARKED => "parked",
+      DONE => "done",
+      state if state & RUNNING != 0 => "running",
+      _ => "scheduled",
+    };
+    write!(f, "Task({:p}, {})", Arc::as_ptr(&self.0), state)
+  }
+}
+
+/// Something that can wait to be woken: a task, or a plain thread.
+#[derive(Clone, Debug)]
+pub(crate) enum Unparker {
+  Task(Task),
+  Thread(Thread),
+}
+
+impl Unparker {
+  /// Whatever we're running in.
+  pub(crate) fn current() -> Self {
+    match current_task() {
+      Some(task) => Unparker::Task(task),
+      None => Unparker::Thread(thread::current()),
+    }
+  }
+
+  pub(crate) fn unpark(&self) {
+    match self {
+      Unparker::Task(task) => task.unpark(),
+      Unparker::Thread(thread) => thread.unpark(),
+    }
+  }
+}
+
+impl Unpark for Task {
+  fn unpark(&self) { Task::unpark(self) }
+}
+
+// How tasks wait on the parking lot: installed around every run, so parking suspends the task.
+struct Tasks;
+
+impl Scheduler for Tasks {
+  fn unparker(&self) -> Box<dyn Unpark> { Box::new(Unparker::current()) }
+
+  fn park(&self, deadline: Option<Instant>) {
+    match deadline {
+      Some(deadline) => wait_until(deadline),
+      None => wait(),
+    }
+  }
+
+  fn now(&self) -> Instant { super::time::now() }
+}
+
+impl Unpark for Unparker {
+  fn unpark(&self) { Unparker::unpark(self) }
+}
+
+/// An executor's unfinished tasks, so it can cancel them when it goes away.
+#[derive(Default)]
+pub(crate) struct TaskList {
+  tasks:  Vec<Task>,
+  closed: bool,
+}
+
+impl TaskList {
+  /// Adds `task`, unless we've been closed. Cancel it if we have.
+  #[must_use]
+  pub(crate) fn insert(&mut self, task: &Task) -> bool {
+    if self.closed { return false }
+    task.0.index.store(self.tasks.len(), Ordering::Relaxed);
+    self.tasks.push(task.clone());
+    true
+  }
+
+  pub(crate) fn remove(&mut self, task: &Task) {
+    let index = task.0.index.load(Ordering::Relaxed);
+    self.tasks.swap_remove(index);
+    if let Some(moved) = self.tasks.get(index) { moved.0.index.store(index, Ordering::Relaxed) }
+  }
+
+  pub(crate) fn len(&self) -> usize { self.tasks.len() }
+
+  /// Takes every task, for cancelling, and turns away any more.
+  pub(crate) fn close(&mut self) -> Vec<Task> {
+    self.closed = true;
+    std::mem::take(&mut self.tasks)
+  }
+}
+
+fn suspend(why: Suspend) {
+  // A cancelled task unwinding through here would only be handed the cancellation again, which
+  // aborts. So it carries on unwinding instead.
+  if thread::panicking() && current_task().is_some_and(|task| task.is_done()) { return }
+  let yielder = CURRENT.with(|c| c.borrow().as_ref().map(|task| task.0.yielder.get()));
+  if let Some(yielder) = yielder { unsafe { &*yielder }.suspend(why); }
+}
+
+/// Lets other ready tasks run before we carry on. Does nothing outside of a task.
+pub fn yield_now() {
+  suspend(Suspend::Yield);
+  if let Some(task) = current_task() { task.unwind_if_expired() }
+}
+
+/// Suspends the current task until it's [unparked](Task::unpark), unless it already was since it
+/// last parked. Like [`std::thread::park`], it may also return early, so check what you're waiting
+/// for in a loop.
+///
+/// # Panics
+///
+/// Outside of a task.
+pub fn park() {
+  let task = current_task().expect("park called outside of a task");
+  let consumed = task.0.state.compare_exchange(RUNNING | NOTIFIED, RUNNING, Ordering::AcqRel, Ordering::Acquire);
+  if consumed.is_err() { suspend(Suspend::Park) }
+  task.unwind_if_expired();
+}
+
+/// The task we're running in.
+///
+/// # Panics
+///
+/// Outside of a task.
+pub fn current() -> Task { current_task().expect("current called outside of a task") }
+
+/// Owned permission to [join](JoinHandle::join) a task. Dropping it detaches the task.
+pub struct JoinHandle<T> {
+  task:  Task,
+  value: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+  /// The task we're for.
+  pub fn task(&self) -> Task { self.task.clone() }
+
+  /// Has the task finished (or panicked, or been cancelled)?
+  pub fn is_finished(&self) -> bool { self.task.is_done() }
+
+  /// Waits for the task to finish, returning what it returned or what it panicked with.
+  ///
+  /// From inside a task, this parks until it's done. From outside, it runs the task's executor if
+  /// it's a [`LocalExecutor`](super::LocalExecutor) on this thread, and blocks the thread
+  /// otherwise.
+  pub fn join(self) -> thread::Result<T> {
+    while !self.is_finished() {
+      let in_task = current_task().is_some();
+      // A local executor on this thread gets nowhere unless we run it.
+      if !in_task && self.task.0.scheduler.clone().drive() && self.is_finished() { break }
+      *self.task.0.joiner.lock().unwrap() = Some(Unparker::current());
+      if self.is_finished() { break }
+      if in_task { park() } else { thread::park() }
+    }
+    match self.task.0.panic.lock().unwrap().take() {
+      Some(payload) => Err(payload),
+      None => Ok(self.value.lock().unwrap().take().expect("finished without a value")),
+    }
+  }
+}
@@ -0,0 +1,337 @@
+//! Sleeping, timeouts and intervals for tasks.
+//!
+//! [`sleep`] parks just the task, and [`timeout`] gives up on whatever it's running after a while,
+//! unwinding it out of wherever it's parked. Outside of a task, they block the thread instead.
+//!
+//! Time comes from the executor's [`Clock`]. That's usually the real one, but a
+//! [`LocalExecutor`](super::LocalExecutor) can be given a [manual](Clock::manual) clock instead,
+//! which only moves when it's [advanced](Clock::advance), for tests that mustn't depend on how fast
+//! they run:
+//!
+//! ```
+//! use stackle::runtime::time::{self, Clock};
+//! use stackle::runtime::LocalExecutor;
+//! use std::time::Duration;
+//!
+//! let clock = Clock::manual();
+//! let exec = LocalExecutor::with_clock(clock.clone());
+//! let nap = exec.spawn(|| time::sleep(Duration::from_secs(60 * 60)));
+//! exec.run_until_idle();
+//! assert!(!nap.is_finished());
+//! clock.advance(Duration::from_secs(60 * 60));
+//! exec.run_until_idle();
+//! assert!(nap.is_finished());
+//! ```
+use super::task::{current_task, Expired, Task, Unparker};
+use super::wait::wait;
+use crate::parking_lot::Unpark;
+use std::error::Error;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod wheel;
+use wheel::{Key, Wheel};
+
+/// Where time comes from, and the timers waiting on it.
+///
+/// There's the [real](Clock::real) one, shared by everything, and [manual](Clock::manual) ones,
+/// whose time only moves when you say. Clones are the same clock.
+#[derive(Clone)]
+pub struct Clock(Arc<Shared>);
+
+struct Shared {
+  // Tick 0. Ticks are milliseconds on the real clock, but nanoseconds on manual ones, so that
+  // advancing by less than a millisecond still wakes whoever's due.
+  start:   Instant,
+  manual:  bool,
+  state:   Mutex<State>,
+  // The real clock's thread waits on this for the next timer.
+  changed: Condvar,
+}
+
+struct State {
+  wheel:   Wheel,
+  // How far a manual clock has been advanced.
+  elapsed: Duration,
+}
+
+static REAL: OnceLock<Clock> = OnceLock::new();
+
+impl Clock {
+  /// The real clock. A thread wakes tasks up as their timers go off.
+  pub fn real() -> Clock {
+    REAL.get_or_init(|| {
+      thread::Builder::new()
+        .name("stackle-timer".into())
+        .spawn(|| REAL.wait().run())
+        .expect("couldn't start the timer thread");
+      Clock::with(false)
+    }).clone()
+  }
+
+  /// A clock that starts at the present and stays there until [advanced](Clock::advance).
+  pub fn manual() -> Clock { Clock::with(true) }
+
+  fn with(manual: bool) -> Clock {
+    let state = State { wheel: Wheel::new(), elapsed: Duration::ZERO };
+    Clock(Arc::new(Shared { start: Instant::now(), manual, state: Mutex::new(state), changed: Condvar::new() }))
+  }
+
+  /// What time it is.
+  pub fn now(&self) -> Instant {
+    if !self.0.manual { return Instant::now() }
+    self.0.start + self.0.state.lock().unwrap().elapsed
+  }
+
+  /// Moves a manual clock on by `by`, waking whoever's timers go off meanwhile, in the order
+  /// they're due. They run next time their executor does.
+  ///
+  /// # Panics
+  ///
+  /// On the real clock, which moves by itself.
+  pub fn advance(&self, by: Duration) {
+    assert!(self.0.manual, "only manual clocks can be advanced");
+    let mut state = self.0.state.lock().unwrap();
+    state.elapsed += by;
+    let now = self.0.ticks(state.elapsed).0;
+    let due = state.wheel.advance(now);
+    drop(state);
+    for wake in due { wake.unpark() }
+  }
+
+  /// Unparks `wake` once it's `deadline`, unless the timer's dropped first.
+  pub(crate) fn wake_at(&self, deadline: Instant, wake: Box<dyn Unpark>) -> Timer {
+    // Rounded up, so nobody wakes early.
+    let since = deadline.saturating_duration_since(self.0.start);
+    let (tick, whole) = self.0.ticks(since);
+    let tick = tick.saturating_add(u64::from(!whole));
+    let mut state = self.0.state.lock().unwrap();
+    let next = state.wheel.next_tick();
+    match state.wheel.insert(tick, wake) {
+      Ok(key) => {
+        // The timer thread's sleeping too long.
+        if next.is_none_or(|next| tick < next) { self.0.changed.notify_one() }
+        Timer { clock: self.clone(), key: Some(key) }
+      }
+      Err(wake) => {
+        drop(state);
+        wake.unpark();
+        Timer { clock: self.clone(), key: None }
+      }
+    }
+  }
+
+  // The real clock's thread.
+  fn run(&self) {
+    let shared = &*self.0;
+    let mut state = shared.state.lock().unwrap();
+    loop {
+      let due = state.wheel.advance(shared.ticks(shared.start.elapsed()).0);
+      if !due.is_empty() {
+        drop(state);
+        for wake in due { wake.unpark() }
+        state = shared.state.lock().unwrap();
+        continue
+      }
+      state = match state.wheel.next_tick() {
+        Some(tick) => {
+          let until = (shared.start + Duration::from_millis(tick)).saturating_duration_since(Instant::now());
+          shared.changed.wait_timeout(state, until).unwrap().0
+        }
+        None => shared.changed.wait(state).unwrap(),
+      };
+    }
+  }
+}
+
+impl Shared {
+  // How many whole ticks there are in `since`, and whether that's all of it.
+  fn ticks(&self, since: Duration) -> (u64, bool) {
+    let (ticks, whole) = match self.manual {
+      true => (since.as_nanos(), true),
+      false => (since.as_millis(), since.subsec_nanos().is_multiple_of(1_000_000)),
+    };
+    (u64::try_from(ticks).unwrap_or(u64::MAX), whole)
+  }
+}
+
+impl fmt::Debug for Clock {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("Clock").field("manual", &self.0.manual).field("now", &self.now()).finish()
+  }
+}
+
+/// A pending wakeup. Dropping it cancels it, if it hasn't gone off.
+pub(crate) struct Timer {
+  clock: Clock,
+  key:   Option<Key>,
+}
+
+impl Drop for Timer {
+  fn drop(&mut self) {
+    if let Some(key) = self.key { self.clock.0.state.lock().unwrap().wheel.cancel(key) }
+  }
+}
+
+/// The clock of the task we're in, if we're in one.
+pub(crate) fn task_clock() -> Option<Clock> { current_task().map(|task| task.clock()) }
+
+/// What time it is, by our executor's clock. Outside of a task, that's the real time.
+pub fn now() -> Instant { task_clock().map_or_else(Instant::now, |clock| clock.now()) }
+
+/// Parks for `duration`. One too long to add to the present is forever.
+pub fn sleep(duration: Duration) {
+  match now().checked_add(duration) {
+    Some(deadline) => sleep_until(deadline),
+    None => loop { wait() },
+  }
+}
+
+/// Parks until `deadline`, or returns straight away if it's passed.
+///
+/// Outside of a task, this blocks the thread, running its own executors' tasks meanwhile.
+pub fn sleep_until(deadline: Instant) {
+  let clock = task_clock().unwrap_or_else(Clock::real);
+  if clock.now() >= deadline { return }
+  let _timer = clock.wake_at(deadline, Box::new(Unparker::current()));
+  while clock.now() < deadline { wait() }
+}
+
+/// [`timeout`] ran out of time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Elapsed(());
+
+impl fmt::Display for Elapsed {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { "deadline has elapsed".fmt(f) }
+}
+
+impl Error for Elapsed {}
+
+// Tells timeouts apart, older ones first.
+static NEXT_TIMEOUT: AtomicU64 = AtomicU64::new(0);
+
+// Gives a task the bad news. The wheel hands us over before it's unlocked but we're only called
+// after, by which time the timeout may have returned, so it says whether it's still running.
+struct Expire {
+  task: Task,
+  id:   u64,
+  live: Arc<Mutex<bool>>,
+}
+
+impl Unpark for Expire {
+  fn unpark(&self) {
+    let live = self.live.lock().unwrap();
+    if *live { self.task.expire(self.id) }
+  }
+}
+
+/// Runs `f`, unless it takes longer than `duration`, in which case it's cancelled and we return
+/// [`Elapsed`].
+///
+/// Cancelling works like cancelling a task: the next time `f` parks or yields (or straight away,
+/// if it's parked already), it's unwound, running its destructors. So it can only be cancelled
+/// while parked or yielding; a loop that does neither runs to the end regardless. Everything in
+/// [`runtime`](super) cleans up after itself when unwound, so a lock you were waiting for or a
+/// channel you were receiving from is fine to use afterwards.
+///
+/// ```
+/// use stackle::runtime::{channel, time, LocalExecutor};
+/// use std::time::Duration;
+///
+/// let exec = LocalExecutor::new();
+/// let (tx, rx) = channel::unbounded::<u32>();
+/// let got = exec.spawn(move || time::timeout(Duration::from_millis(10), || rx.recv()));
+/// assert!(got.join().unwrap().is_err());
+/// drop(tx);
+/// ```
+///
+/// # Panics
+///
+/// Outside of a task.
+pub fn timeout<T, F: FnOnce() -> T>(duration: Duration, f: F) -> Result<T, Elapsed> {
+  let task = current_task().expect("timeout called outside of a task");
+  let clock = task.clock();
+  let id = NEXT_TIMEOUT.fetch_add(1, Ordering::Relaxed);
+  let live = Arc::new(Mutex::new(true));
+  let expire = Expire { task: task.clone(), id, live: live.clone() };
+  // One too long to add to the present never goes off.
+  let timer = clock.now().checked_add(duration).map(|deadline| clock.wake_at(deadline, Box::new(expire)));
+  let result = panic::catch_unwind(AssertUnwindSafe(f));
+  *live.lock().unwrap() = false;
+  drop(timer);
+  // It may have gone off just as `f` finished.
+  task.unexpire(id);
+  match result {
+    Ok(value) => Ok(value),
+    Err(payload) => match payload.downcast::<Expired>() {
+      Ok(expired) if expired.0 == id => Err(Elapsed(())),
+      // An outer one's gone off, so keep going.
+      Ok(expired) => panic::resume_unwind(expired),
+      Err(payload) => panic::resume_unwind(payload),
+    },
+  }
+}
+
+/// Something that happens every so often. See [`interval`].
+#[derive(Debug)]
+pub struct Interval {
+  next:   Instant,
+  period: Duration,
+}
+
+/// Ticks every `period`, starting now.
+///
+/// ```
+/// use stackle::runtime::{time, LocalExecutor};
+/// use std::time::Duration;
+///
+/// let exec = LocalExecutor::new();
+/// let ticks = exec.spawn(|| {
+///   let mut every = time::interval(Duration::from_millis(5));
+///   let first = every.tick();
+///   every.tick();
+///   every.tick() - first
+/// });
+/// assert!(ticks.join().unwrap() >= Duration::from_millis(10));
+/// ```
+///
+/// # Panics
+///
+/// If `period` is zero.
+pub fn interval(period: Duration) -> Interval {
+  assert!(!period.is_zero(), "an interval's period can't be zero");
+  Interval { next: now(), period }
+}
+
+impl Interval {
+  /// Parks until the next tick, and returns when it was due.
+  ///
+  /// If we're late, the tick that's due goes off straight away and any others we've missed are
+  /// skipped, keeping to the same schedule rather than going off all at once.
+  pub fn tick(&mut self) -> Instant {
+    sleep_until(self.next);
+    let tick = self.next;
+    let behind = now().saturating_duration_since(tick).as_nanos() / self.period.as_nanos();
+    // A short period can be more than `u32::MAX` behind.
+    let behind = u64::try_from(behind).unwrap_or(u64::MAX);
+    self.next = tick + saturating_mul(self.period, behind.saturating_add(1));
+    tick
+  }
+
+  /// How often it ticks.
+  pub fn period(&self) -> Duration { self.period }
+}
+
+// `Duration` only multiplies by a `u32`.
+fn saturating_mul(duration: Duration, by: u64) -> Duration {
+  const NANOS_PER_SEC: u128 = 1_000_000_000;
+  let nanos = duration.as_nanos().saturating_mul(by as u128);
+  match u64::try_from(nanos / NANOS_PER_SEC) {
+    Ok(secs) => Duration::new(secs, (nanos % NANOS_PER_SEC) as u32),
+    Err(_) => Duration::MAX,
+  }
+}
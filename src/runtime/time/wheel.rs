@@ -0,0 +1,142 @@
+use crate::parking_lot::Unpark;
+
+const BITS: u32 = 6;
+const SLOTS: usize = 1 << BITS;
+const LEVELS: usize = 6;
+
+/// Pending timers, by the tick they're due on.
+///
+/// Level 0 has a slot for each of the 64 ticks in the block now's in, level 1 a slot for each
+/// 64-tick block in the 4096-tick block now's in, and so on up. A timer goes in the lowest level
+/// where its slot isn't now's, and when time gets to that slot, it moves down to a level that can
+/// tell it apart from now again, so each is touched at most once a level. That's 64⁶ ticks, over
+/// two years of milliseconds (or a minute of nanoseconds); anything later waits at the top and goes
+/// round again.
+pub(super) struct Wheel {
+  // Every timer due on or before this has gone off.
+  now:     u64,
+  levels:  [Level; LEVELS],
+  entries: Vec<Entry>,
+  free:    Vec<usize>,
+}
+
+struct Level {
+  // A bit for each slot with anything in it.
+  occupied: u64,
+  slots:    [Vec<usize>; SLOTS],
+}
+
+#[derive(Default)]
+struct Entry {
+  // Bumped every time the entry's reused, so stale keys don't cancel somebody else's timer.
+  generation: u64,
+  timer:      Option<Pending>,
+}
+
+struct Pending {
+  tick:  u64,
+  level: usize,
+  slot:  usize,
+  wake:  Box<dyn Unpark>,
+}
+
+/// Which timer, for cancelling it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) struct Key {
+  index:      usize,
+  generation: u64,
+}
+
+impl Wheel {
+  pub(super) fn new() -> Self {
+    let levels = std::array::from_fn(|_| Level { occupied: 0, slots: std::array::from_fn(|_| Vec::new()) });
+    Wheel { now: 0, levels, entries: Vec::new(), free: Vec::new() }
+  }
+
+  /// Adds a timer to `wake` on `tick`, unless it's already due, in which case you get `wake` back.
+  pub(super) fn insert(&mut self, tick: u64, wake: Box<dyn Unpark>) -> Result<Key, Box<dyn Unpark>> {
+    if tick <= self.now { return Err(wake) }
+    let index = self.free.pop().unwrap_or_else(|| {
+      self.entries.push(Entry::default());
+      self.entries.len() - 1
+    });
+    let entry = &mut self.entries[index];
+    entry.timer = Some(Pending { tick, level: 0, slot: 0, wake });
+    let key = Key { index, generation: entry.generation };
+    self.place(index);
+    Ok(key)
+  }
+
+  /// Forgets the timer, if it hasn't gone off.
+  pub(super) fn cancel(&mut self, key: Key) {
+    let entry = &self.entries[key.index];
+    if entry.generation != key.generation || entry.timer.is_none() { return }
+    let (level, slot) = { let timer = entry.timer.as_ref().unwrap(); (timer.level, timer.slot) };
+    let level = &mut self.levels[level];
+    let slots = &mut level.slots[slot];
+    let position = slots.iter().position(|&index| index == key.index).expect("timers are in their slot");
+    slots.swap_remove(position);
+    if slots.is_empty() { level.occupied &= !(1 << slot) }
+    self.release(key.index);
+  }
+
+  /// The first tick we've anything to do on: a timer going off, or some moving down a level.
+  pub(super) fn next_tick(&self) -> Option<u64> { self.next_slot().map(|(_, _, tick)| tick) }
+
+  /// Moves time on to `tick`, returning the timers that have gone off, earliest first.
+  pub(super) fn advance(&mut self, tick: u64) -> Vec<Box<dyn Unpark>> {
+    let mut due = Vec::new();
+    while let Some((level, slot, when)) = self.next_slot() {
+      if when > tick { break }
+      self.now = self.now.max(when);
+      let indices = std::mem::take(&mut self.levels[level].slots[slot]);
+      self.levels[level].occupied &= !(1 << slot);
+      for index in indices {
+        let timer = self.entries[index].timer.as_ref().expect("slots only hold pending timers");
+        if timer.tick <= self.now { due.push(self.release(index)) } else { self.place(index) }
+      }
+    }
+    self.now = self.now.max(tick);
+    due
+  }
+
+  // Files the timer at `index` in the slot for its tick, as seen from now.
+  fn place(&mut self, index: usize) {
+    let timer = self.entries[index].timer.as_mut().unwrap();
+    // Anything further off than the top level reaches goes in the slot before now's, a lap away,
+    // and comes round again from there.
+    let reach = (1 << (BITS * LEVELS as u32)) - (1 << (BITS * (LEVELS as u32 - 1)));
+    let tick = timer.tick.min(self.now + reach);
+    let differs = (self.now ^ tick) | (SLOTS as u64 - 1);
+    let level = ((63 - differs.leading_zeros()) / BITS).min(LEVELS as u32 - 1);
+    let slot = (tick >> (level * BITS)) as usize % SLOTS;
+    timer.level = level as usize;
+    timer.slot = slot;
+    let level = &mut self.levels[level as usize];
+    level.slots[slot].push(index);
+    level.occupied |= 1 << slot;
+  }
+
+  fn release(&mut self, index: usize) -> Box<dyn Unpark> {
+    let entry = &mut self.entries[index];
+    entry.generation += 1;
+    self.free.push(index);
+    entry.timer.take().expect("released a pending timer").wake
+  }
+
+  // The next slot we'll get to, and the tick it starts on. Lower levels all come before higher
+  // ones, since they're in the same block as now and the higher ones aren't.
+  fn next_slot(&self) -> Option<(usize, usize, u64)> {
+    self.levels.iter().enumerate().find(|(_, level)| level.occupied != 0).map(|(index, level)| {
+      let shift = index as u32 * BITS;
+      let here = (self.now >> shift) as u32 % SLOTS as u32;
+      let ahead = level.occupied.rotate_right(here).trailing_zeros();
+      let slot = (here + ahead) as usize % SLOTS;
+      let block = 1u64 << (shift + BITS);
+      let mut tick = (self.now & !(block - 1)) + ((slot as u64) << shift);
+      // Round the back of the wheel, into the next block.
+      if here + ahead >= SLOTS as u32 { tick += block }
+      (index, slot, tick)
+    })
+  }
+}
@@ -0,0 +1,216 @@
+//! Completion-based I/O with io_uring.
+//!
+//! Where [`net`](super::net) waits for a socket to be ready and then does the I/O itself, here the
+//! kernel does it: we submit the op, park the task, and the completion thread unparks us when it's
+//! done. That works for regular files too, which are always "ready" and so block under epoll.
+//!
+//! The kernel reads and writes our buffers while we're parked, so the ops here take them by value
+//! and hand them back with the result. If the task's unwound while it waits, by a
+//! [`timeout`](super::time::timeout) or its executor going away, the op's cancelled and the buffer
+//! stays alive until the kernel says it's finished with it.
+//!
+//! Executors find out whether the kernel has io_uring as they're built, rather than in the middle
+//! of the first task to use it, and say what they found with `uring_supported` (as in
+//! [`LocalExecutor::uring_supported`](super::LocalExecutor::uring_supported)). Otherwise we find
+//! out the first time we're used. Without it the ops fail with [`io::ErrorKind::Unsupported`], and
+//! [`File`] falls back to plain blocking calls.
+//!
+//! ```
+//! use stackle::runtime::{uring::File, LocalExecutor};
+//!
+//! let path = std::env::temp_dir().join(format!("stackle-uring-doc-{}", std::process::id()));
+//! let exec = LocalExecutor::new();
+//! let read = exec.spawn(move || {
+//!   let file = File::create(&path)?;
+//!   file.write_at(b"hello".to_vec(), 0).0?;
+//!   let (n, buf) = File::open(&path)?.read_at(Vec::with_capacity(16), 0);
+//!   std::fs::remove_file(&path)?;
+//!   n.map(|_| buf)
+//! });
+//! assert_eq!(b"hello".to_vec(), read.join().unwrap().unwrap());
+//! ```
+mod ring;
+
+use ring::{result, ring, Sqe, Timespec};
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::time::Duration;
+
+// An offset that means "wherever the file's at", for files, and is the only one sockets and pipes
+// take.
+const CURRENT: u64 = u64::MAX;
+
+/// Has the kernel got io_uring, with the ops we use (5.6 or later)? It can be missing, or turned
+/// off by `kernel.io_uring_disabled` or a seccomp filter. We set up the ring the first time anyone
+/// asks, which building an executor does.
+pub fn is_supported() -> bool { ring().is_some() }
+
+fn unsupported() -> io::Error { io::Error::new(io::ErrorKind::Unsupported, "io_uring isn't supported") }
+
+/// Reads into `buf`'s spare capacity at `offset`, extending it by however much was read. Returns
+/// that and `buf` back.
+pub fn read_at(fd: BorrowedFd<'_>, buf: Vec<u8>, offset: u64) -> (io::Result<usize>, Vec<u8>) {
+  let Some(ring) = ring() else { return (Err(unsupported()), buf) };
+  let mut buf = buf;
+  let sqe = Sqe {
+    opcode: ring::OP_READ,
+    fd:     fd.as_raw_fd(),
+    off:    offset,
+    addr:   buf.spare_capacity_mut().as_mut_ptr() as u64,
+    len:    (buf.capacity() - buf.len()).min(u32::MAX as usize) as u32,
+    ..Sqe::default()
+  };
+  let (res, mut buf) = ring.run_op(sqe, buf);
+  let read = result(res).map(|n| n as usize);
+  // The kernel's filled in that much.
+  if let Ok(n) = read { unsafe { buf.set_len(buf.len() + n) } }
+  (read, buf)
+}
+
+/// Like [`read_at`], from wherever `fd` is at, for sockets, pipes and the like.
+pub fn read(fd: BorrowedFd<'_>, buf: Vec<u8>) -> (io::Result<usize>, Vec<u8>) { read_at(fd, buf, CURRENT) }
+
+/// Writes `buf` at `offset`, returning how much was written, which might not be all of it, and
+/// `buf` back.
+pub fn write_at(fd: BorrowedFd<'_>, buf: Vec<u8>, offset: u64) -> (io::Result<usize>, Vec<u8>) {
+  let Some(ring) = ring() else { return (Err(unsupported()), buf) };
+  let sqe = Sqe {
+    opcode: ring::OP_WRITE,
+    fd:     fd.as_raw_fd(),
+    off:    offset,
+    addr:   buf.as_ptr() as u64,
+    len:    buf.len().min(u32::MAX as usize) as u32,
+    ..Sqe::default()
+  };
+  let (res, buf) = ring.run_op(sqe, buf);
+  (result(res).map(|n| n as usize), buf)
+}
+
+/// Like [`write_at`], wherever `fd` is at.
+pub fn write(fd: BorrowedFd<'_>, buf: Vec<u8>) -> (io::Result<usize>, Vec<u8>) { write_at(fd, buf, CURRENT) }
+
+/// The next connection to the listening socket `fd`, close-on-exec. Blocking or not, it's the
+/// kernel that waits for it.
+pub fn accept(fd: BorrowedFd<'_>) -> io::Result<OwnedFd> {
+  let ring = ring().ok_or_else(unsupported)?;
+  let sqe = Sqe { opcode: ring::OP_ACCEPT, fd: fd.as_raw_fd(), op_flags: libc::SOCK_CLOEXEC as u32, ..Sqe::default() };
+  let fd = result(ring.run_op(sqe, ()).0)?;
+  Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+/// Opens `path` with `open(2)`'s `flags`, and `mode` if it's created. `O_CLOEXEC` is added for you.
+pub fn open(path: &Path, flags: libc::c_int, mode: libc::mode_t) -> io::Result<OwnedFd> {
+  let ring = ring().ok_or_else(unsupported)?;
+  let path = CString::new(path.as_os_str().as_bytes())
+    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path can't contain a nul"))?;
+  let sqe = Sqe {
+    opcode:   ring::OP_OPENAT,
+    fd:       libc::AT_FDCWD,
+    addr:     path.as_ptr() as u64,
+    len:      mode,
+    op_flags: (flags | libc::O_CLOEXEC) as u32,
+    ..Sqe::default()
+  };
+  let fd = result(ring.run_op(sqe, path).0)?;
+  Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+/// Flushes what's been written to `fd` to the disk, and its metadata too unless `data_only`.
+pub fn fsync(fd: BorrowedFd<'_>, data_only: bool) -> io::Result<()> {
+  let ring = ring().ok_or_else(unsupported)?;
+  let op_flags = if data_only { ring::FSYNC_DATASYNC } else { 0 };
+  let sqe = Sqe { opcode: ring::OP_FSYNC, fd: fd.as_raw_fd(), op_flags, ..Sqe::default() };
+  result(ring.run_op(sqe, ()).0).map(drop)
+}
+
+/// Parks for `duration`, timed by the kernel rather than our clock, so a manual
+/// [`Clock`](super::time::Clock) doesn't come into it.
+pub fn sleep(duration: Duration) -> io::Result<()> {
+  let ring = ring().ok_or_else(unsupported)?;
+  let time = Box::new(Timespec { sec: duration.as_secs() as i64, nsec: duration.subsec_nanos() as i64 });
+  let sqe = Sqe { opcode: ring::OP_TIMEOUT, fd: -1, addr: &*time as *const Timespec as u64, len: 1, ..Sqe::default() };
+  match result(ring.run_op(sqe, time).0) {
+    Err(err) if err.raw_os_error() == Some(libc::ETIME) => Ok(()),
+    Err(err) => Err(err),
+    Ok(_) => Ok(()),
+  }
+}
+
+/// A file whose reads and writes park just the task, with io_uring doing the I/O. Without it,
+/// they're plain blocking calls.
+pub struct File {
+  inner: fs::File,
+}
+
+impl File {
+  /// Opens `path` for reading.
+  pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> { Self::open_with(path.as_ref(), libc::O_RDONLY) }
+
+  /// Opens `path` for writing, creating it if it's not there and truncating it if it is.
+  pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+    Self::open_with(path.as_ref(), libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC)
+  }
+
+  fn open_with(path: &Path, flags: libc::c_int) -> io::Result<Self> {
+    if !is_supported() {
+      let inner = match flags {
+        libc::O_RDONLY => fs::File::open(path)?,
+        _ => fs::File::create(path)?,
+      };
+      return Ok(File { inner })
+    }
+    Ok(File { inner: fs::File::from(open(path, flags, 0o666)?) })
+  }
+
+  /// Takes over a `std` file.
+  pub fn from_std(inner: fs::File) -> Self { File { inner } }
+
+  /// Hands back the `std` file.
+  pub fn into_std(self) -> fs::File { self.inner }
+
+  /// Reads into `buf`'s spare capacity, from `offset`. See [`read_at`].
+  pub fn read_at(&self, buf: Vec<u8>, offset: u64) -> (io::Result<usize>, Vec<u8>) {
+    if is_supported() { return read_at(self.as_fd(), buf, offset) }
+    let mut buf = buf;
+    let len = buf.len();
+    buf.resize(buf.capacity(), 0);
+    let read = self.inner.read_at(&mut buf[len..], offset);
+    buf.truncate(len + *read.as_ref().unwrap_or(&0));
+    (read, buf)
+  }
+
+  /// Writes `buf` at `offset`. See [`write_at`].
+  pub fn write_at(&self, buf: Vec<u8>, offset: u64) -> (io::Result<usize>, Vec<u8>) {
+    if is_supported() { return write_at(self.as_fd(), buf, offset) }
+    let written = self.inner.write_at(&buf, offset);
+    (written, buf)
+  }
+
+  /// Flushes the contents and metadata to disk.
+  pub fn sync_all(&self) -> io::Result<()> {
+    if is_supported() { fsync(self.as_fd(), false) } else { self.inner.sync_all() }
+  }
+
+  /// Flushes just the contents to disk.
+  pub fn sync_data(&self) -> io::Result<()> {
+    if is_supported() { fsync(self.as_fd(), true) } else { self.inner.sync_data() }
+  }
+}
+
+impl AsFd for File {
+  fn as_fd(&self) -> BorrowedFd<'_> { self.inner.as_fd() }
+}
+
+impl AsRawFd for File {
+  fn as_raw_fd(&self) -> RawFd { self.inner.as_raw_fd() }
+}
+
+impl fmt::Debug for File {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { self.inner.fmt(f) }
+}
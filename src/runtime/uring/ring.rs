@@ -0,0 +1,358 @@
+use crate::runtime::reactor::cvt;
+use crate::runtime::task::Unparker;
+use crate::runtime::wait::wait;
+use std::any::Any;
+use std::collections::HashMap;
+use std::io;
+use std::mem::size_of;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+
+// From <linux/io_uring.h>.
+pub(super) const OP_FSYNC:        u8 = 3;
+pub(super) const OP_TIMEOUT:      u8 = 11;
+pub(super) const OP_ACCEPT:       u8 = 13;
+const OP_ASYNC_CANCEL:            u8 = 14;
+pub(super) const OP_OPENAT:       u8 = 18;
+pub(super) const OP_READ:         u8 = 22;
+pub(super) const OP_WRITE:        u8 = 23;
+pub(super) const FSYNC_DATASYNC:  u32 = 1;
+
+const FEAT_SINGLE_MMAP:   u32 = 1 << 0;
+const FEAT_NODROP:        u32 = 1 << 1;
+const FEAT_SUBMIT_STABLE: u32 = 1 << 2;
+// Came with READ, WRITE and OPENAT, in 5.6, so it tells us they're there too.
+const FEAT_RW_CUR_POS:    u32 = 1 << 3;
+const NEEDED: u32 = FEAT_SINGLE_MMAP | FEAT_NODROP | FEAT_SUBMIT_STABLE | FEAT_RW_CUR_POS;
+
+const ENTER_GETEVENTS: u32 = 1;
+const OFF_SQ_RING: libc::off_t = 0;
+const OFF_SQES:    libc::off_t = 0x1000_0000;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqOffsets {
+  head:         u32,
+  tail:         u32,
+  ring_mask:    u32,
+  ring_entries: u32,
+  flags:        u32,
+  dropped:      u32,
+  array:        u32,
+  resv1:        u32,
+  user_addr:    u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqOffsets {
+  head:         u32,
+  tail:         u32,
+  ring_mask:    u32,
+  ring_entries: u32,
+  overflow:     u32,
+  cqes:         u32,
+  flags:        u32,
+  resv1:        u32,
+  user_addr:    u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+  sq_entries:     u32,
+  cq_entries:     u32,
+  flags:          u32,
+  sq_thread_cpu:  u32,
+  sq_thread_idle: u32,
+  features:       u32,
+  wq_fd:          u32,
+  resv:           [u32; 3],
+  sq_off:         SqOffsets,
+  cq_off:         CqOffsets,
+}
+
+/// A submission queue entry, as the kernel wants it.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub(super) struct Sqe {
+  pub(super) opcode:       u8,
+  pub(super) flags:        u8,
+  pub(super) ioprio:       u16,
+  pub(super) fd:           i32,
+  /// The offset, or a second address.
+  pub(super) off:          u64,
+  pub(super) addr:         u64,
+  pub(super) len:          u32,
+  /// The op's own flags.
+  pub(super) op_flags:     u32,
+  pub(super) user_data:    u64,
+  pub(super) buf_index:    u16,
+  pub(super) personality:  u16,
+  pub(super) splice_fd_in: i32,
+  pub(super) addr3:        u64,
+  pub(super) pad:          u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Cqe {
+  user_data: u64,
+  res:       i32,
+  flags:     u32,
+}
+
+/// The `timespec` the kernel wants for timeouts.
+#[repr(C)]
+pub(super) struct Timespec {
+  pub(super) sec:  i64,
+  pub(super) nsec: i64,
+}
+
+struct Mmap {
+  ptr: *mut u8,
+  len: usize,
+}
+
+impl Mmap {
+  fn new(fd: &OwnedFd, len: usize, offset: libc::off_t) -> io::Result<Self> {
+    let prot = libc::PROT_READ | libc::PROT_WRITE;
+    let ptr = unsafe { libc::mmap(null_mut(), len, prot, libc::MAP_SHARED | libc::MAP_POPULATE, fd.as_raw_fd(), offset) };
+    if ptr == libc::MAP_FAILED { return Err(io::Error::last_os_error()) }
+    Ok(Mmap { ptr: ptr as *mut u8, len })
+  }
+
+  fn at<T>(&self, offset: u32) -> *mut T { unsafe { self.ptr.add(offset as usize) as *mut T } }
+}
+
+impl Drop for Mmap {
+  fn drop(&mut self) { unsafe { libc::munmap(self.ptr as *mut _, self.len) }; }
+}
+
+/// An io_uring instance, shared by everyone. We submit from whichever thread wants something done,
+/// and a thread of its own reaps the completions and wakes whoever's waiting for them.
+pub(super) struct Ring {
+  fd:    OwnedFd,
+  // Where the kernel reads and writes what's shared with us.
+  _maps: (Mmap, Mmap),
+  sq:    Mutex<Submissions>,
+  cq:    Completions,
+  // What's in flight, by `user_data`.
+  ops:   Mutex<HashMap<u64, Arc<Op>>>,
+  next:  AtomicU64,
+}
+
+struct Submissions {
+  head:  *const AtomicU32,
+  tail:  *const AtomicU32,
+  mask:  u32,
+  array: *mut u32,
+  sqes:  *mut Sqe,
+}
+
+// Only the completion thread touches these.
+struct Completions {
+  head: *const AtomicU32,
+  tail: *const AtomicU32,
+  mask: u32,
+  cqes: *const Cqe,
+}
+
+// The pointers are into our mmaps, which live as long as we do, and the submission side's locked.
+unsafe impl Send for Ring {}
+unsafe impl Sync for Ring {}
+
+// The `user_data` of cancellations, which nobody waits for.
+const CANCELLATION: u64 = u64::MAX;
+
+struct Op {
+  state: Mutex<OpState>,
+  // Whether its result is a new fd, which somebody has to close.
+  opens: bool,
+}
+
+struct OpState {
+  result:    Option<i32>,
+  waiter:    Option<Unparker>,
+  // Whatever the kernel's reading or writing: buffers, paths and so on. It's ours again once the
+  // op's complete, and until then nobody else gets it, even if whoever submitted it gives up.
+  resources: Option<Box<dyn Any + Send>>,
+  // Whoever submitted it gave up before it completed, so they won't see the result.
+  abandoned: bool,
+}
+
+impl Op {
+  // For a result nobody's going to see.
+  fn discard(&self, result: i32) {
+    if self.opens && result >= 0 { unsafe { libc::close(result) }; }
+  }
+}
+
+static RING: OnceLock<Option<Ring>> = OnceLock::new();
+
+/// The ring, unless the kernel hasn't got io_uring, or not enough of it. We find out once, the
+/// first time anyone asks, which is usually an executor being built.
+pub(super) fn ring() -> Option<&'static Ring> {
+  RING.get_or_init(|| {
+    let ring = Ring::new(256).ok()?;
+    thread::Builder::new().name("stackle-uring".into()).spawn(|| {
+      RING.wait().as_ref().expect("started once the ring's up").run()
+    }).ok()?;
+    Some(ring)
+  }).as_ref()
+}
+
+impl Ring {
+  fn new(entries: u32) -> io::Result<Self> {
+    let mut params = Params::default();
+    let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, entries, &mut params as *mut Params) };
+    let fd = cvt(fd as libc::c_int)?;
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    if params.features & NEEDED != NEEDED { return Err(io::ErrorKind::Unsupported.into()) }
+    let (sq, cq) = (&params.sq_off, &params.cq_off);
+    // Both rings share the one mapping.
+    let sq_len = sq.array as usize + params.sq_entries as usize * size_of::<u32>();
+    let cq_len = cq.cqes as usize + params.cq_entries as usize * size_of::<Cqe>();
+    let rings = Mmap::new(&fd, sq_len.max(cq_len), OFF_SQ_RING)?;
+    let sqes = Mmap::new(&fd, params.sq_entries as usize * size_of::<Sqe>(), OFF_SQES)?;
+    let submissions = Submissions {
+      head:  rings.at(sq.head),
+      tail:  rings.at(sq.tail),
+      mask:  unsafe { *rings.at::<u32>(sq.ring_mask) },
+      array: rings.at(sq.array),
+      sqes:  sqes.at(0),
+    };
+    let completions = Completions {
+      head: rings.at(cq.head),
+      tail: rings.at(cq.tail),
+      mask: unsafe { *rings.at::<u32>(cq.ring_mask) },
+      cqes: rings.at(cq.cqes),
+    };
+    Ok(Ring {
+      fd,
+      _maps: (rings, sqes),
+      sq: Mutex::new(submissions),
+      cq: completions,
+      ops: Mutex::new(HashMap::new()),
+      next: AtomicU64::new(0),
+    })
+  }
+
+  /// Submits `sqe`, which may point into `resources`, and parks until it's complete. Returns the
+  /// result, and `resources` back.
+  ///
+  /// If we're unwound while we wait, say by a [`timeout`](crate::runtime::time::timeout), the op's
+  /// cancelled, and `resources` is dropped once the kernel's done with it.
+  pub(super) fn run_op<R: Any + Send>(&self, mut sqe: Sqe, resources: R) -> (i32, R) {
+    let token = self.next.fetch_add(1, Ordering::Relaxed);
+    sqe.user_data = token;
+    let resources: Box<dyn Any + Send> = Box::new(resources);
+    let state = OpState { result: None, waiter: None, resources: Some(resources), abandoned: false };
+    let op = Arc::new(Op { state: Mutex::new(state), opens: matches!(sqe.opcode, OP_ACCEPT | OP_OPENAT) });
+    self.ops.lock().unwrap().insert(token, op.clone());
+    self.push(sqe);
+
+    struct Cancel<'a> {
+      ring:  &'a Ring,
+      op:    &'a Op,
+      token: u64,
+    }
+    impl Drop for Cancel<'_> {
+      fn drop(&mut self) {
+        let mut state = self.op.state.lock().unwrap();
+        // Whichever of us and the completion thread comes second deals with the result.
+        if let Some(result) = state.result { return self.op.discard(result) }
+        state.abandoned = true;
+        drop(state);
+        let cancel = Sqe { opcode: OP_ASYNC_CANCEL, fd: -1, addr: self.token, user_data: CANCELLATION, ..Sqe::default() };
+        // We're likely unwinding, so a panic would abort. If the kernel won't take the
+        // cancellation, the op's left abandoned to finish by itself, which does no harm but wait.
+        let _ = self.ring.try_push(cancel);
+      }
+    }
+    let cancel = Cancel { ring: self, op: &op, token };
+    let mut state = op.state.lock().unwrap();
+    let result = loop {
+      if let Some(result) = state.result { break result }
+      state.waiter = Some(Unparker::current());
+      drop(state);
+      wait();
+      state = op.state.lock().unwrap();
+    };
+    std::mem::forget(cancel);
+    let resources = state.resources.take().expect("taken once");
+    (result, *resources.downcast().expect("the same type back"))
+  }
+
+  fn push(&self, sqe: Sqe) {
+    if let Err(err) = self.try_push(sqe) { panic!("io_uring_enter failed: {}", err) }
+  }
+
+  // Like `push`, but says why the kernel wouldn't take it, rather than panicking.
+  fn try_push(&self, sqe: Sqe) -> io::Result<()> {
+    let sq = self.sq.lock().unwrap();
+    let tail = unsafe { &*sq.tail }.load(Ordering::Relaxed);
+    // We submit each as we go, so there's always room.
+    debug_assert!(tail.wrapping_sub(unsafe { &*sq.head }.load(Ordering::Acquire)) <= sq.mask);
+    let index = tail & sq.mask;
+    unsafe {
+      *sq.sqes.add(index as usize) = sqe;
+      *sq.array.add(index as usize) = index;
+      (*sq.tail).store(tail.wrapping_add(1), Ordering::Release);
+    }
+    loop {
+      match self.enter(1, 0, 0) {
+        Ok(_) => return Ok(()),
+        // Busy reaping completions. They'll be gone in a moment.
+        Err(err) if matches!(err.raw_os_error(), Some(libc::EINTR | libc::EAGAIN | libc::EBUSY)) => thread::yield_now(),
+        Err(err) => return Err(err),
+      }
+    }
+  }
+
+  fn enter(&self, submit: u32, wait_for: u32, flags: u32) -> io::Result<libc::c_int> {
+    let result = unsafe {
+      libc::syscall(libc::SYS_io_uring_enter, self.fd.as_raw_fd(), submit, wait_for, flags, null_mut::<libc::c_void>(), 0usize)
+    };
+    cvt(result as libc::c_int)
+  }
+
+  // The completion thread.
+  fn run(&self) {
+    let cq = &self.cq;
+    loop {
+      if let Err(err) = self.enter(0, 1, ENTER_GETEVENTS) {
+        if err.raw_os_error() != Some(libc::EINTR) { panic!("io_uring_enter failed: {}", err) }
+      }
+      let (head, tail) = unsafe { ((*cq.head).load(Ordering::Relaxed), (*cq.tail).load(Ordering::Acquire)) };
+      // They're free-running, so the tail may have wrapped past zero.
+      let done: Vec<Cqe> = (0..tail.wrapping_sub(head))
+        .map(|i| unsafe { *cq.cqes.add((head.wrapping_add(i) & cq.mask) as usize) })
+        .collect();
+      unsafe { (*cq.head).store(tail, Ordering::Release) };
+      for cqe in done {
+        if cqe.user_data == CANCELLATION { continue }
+        let Some(op) = self.ops.lock().unwrap().remove(&cqe.user_data) else { continue };
+        let mut state = op.state.lock().unwrap();
+        state.result = Some(cqe.res);
+        if state.abandoned { op.discard(cqe.res) }
+        let waiter = state.waiter.take();
+        drop(state);
+        // If they gave up, this was the last reference, and the resources go with it.
+        drop(op);
+        if let Some(waiter) = waiter { waiter.unpark() }
+      }
+    }
+  }
+}
+
+/// Turns a completion's result into what it means.
+pub(super) fn result(res: i32) -> io::Result<u32> {
+  if res < 0 { Err(io::Error::from_raw_os_error(-res)) } else { Ok(res as u32) }
+}
+
+// What the kernel expects.
+const _: () = assert!(size_of::<Sqe>() == 64 && size_of::<Cqe>() == 16 && size_of::<Params>() == 120);
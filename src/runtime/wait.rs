@@ -0,0 +1,114 @@
+use super::local::drive_owned;
+use super::task::{current_task, park, Unparker};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+/// Someone waiting in a [`Waiters`].
+pub(crate) struct Waiter {
+  unparker: Unparker,
+  // Set when they're taken off the queue to be woken, under whatever lock guards the queue.
+  notified: AtomicBool,
+  /// Whatever the queue's owner wants to know about them, like what they're waiting for.
+  pub(crate) data: usize,
+}
+
+impl Waiter {
+  /// Have they been taken off the queue to be woken? Ask under the queue's lock.
+  pub(crate) fn notified(&self) -> bool { self.notified.load(Ordering::Relaxed) }
+
+  pub(crate) fn unpark(&self) { self.unparker.unpark() }
+}
+
+/// Whoever is waiting for something, to be woken in the order they started waiting.
+#[derive(Default)]
+pub(crate) struct Waiters(VecDeque<Arc<Waiter>>);
+
+impl Waiters {
+  pub(crate) const fn new() -> Self { Waiters(VecDeque::new()) }
+
+  /// Adds whatever we're running in. Do it under the same lock as the check that made us wait,
+  /// then [`wait`] once it's released.
+  pub(crate) fn push_current(&mut self) -> Arc<Waiter> { self.push_current_with(0) }
+
+  /// Like [`Waiters::push_current`], but with some `data` for whoever wakes us.
+  pub(crate) fn push_current_with(&mut self, data: usize) -> Arc<Waiter> {
+    let me = Arc::new(Waiter { unparker: Unparker::current(), notified: AtomicBool::new(false), data });
+    self.0.push_back(me.clone());
+    me
+  }
+
+  /// Forgets `waiter`, if it's still here. For when [`wait`] returned without us waking it.
+  pub(crate) fn remove(&mut self, waiter: &Arc<Waiter>) {
+    if let Some(index) = self.0.iter().position(|w| Arc::ptr_eq(w, waiter)) { self.0.remove(index); }
+  }
+
+  /// The one that's waited longest, if we're going to wake them.
+  pub(crate) fn front(&self) -> Option<&Waiter> { self.0.front().map(|w| &**w) }
+
+  /// The one that's waited longest, now notified. Unpark them once the lock's released.
+  pub(crate) fn pop(&mut self) -> Option<Arc<Waiter>> {
+    let waiter = self.0.pop_front()?;
+    waiter.notified.store(true, Ordering::Relaxed);
+    Some(waiter)
+  }
+
+  /// Everyone, now notified. Unpark them once the lock's released.
+  pub(crate) fn take(&mut self) -> VecDeque<Arc<Waiter>> {
+    let all = std::mem::take(&mut self.0);
+    for waiter in &all { waiter.notified.store(true, Ordering::Relaxed) }
+    all
+  }
+
+  pub(crate) fn is_empty(&self) -> bool { self.0.is_empty() }
+}
+
+/// Blocks until unparked: just the task, if we're in one, or else the thread. May return early.
+///
+/// A thread runs its own executors' ready tasks first, since nobody else can and what it's waiting
+/// for may well be one of them. They unpark it when they have more to do, which brings it back
+/// here to run them again.
+pub(crate) fn wait() {
+  if current_task().is_some() { return park() }
+  drive_owned();
+  thread::park()
+}
+
+/// Like [`wait`], but returns by `deadline` whether we've been unparked or not.
+pub(crate) fn wait_until(deadline: Instant) {
+  match current_task() {
+    Some(task) => {
+      let _timer = task.clock().wake_at(deadline, Box::new(Unparker::Task(task.clone())));
+      park();
+    }
+    None => {
+      drive_owned();
+      thread::park_timeout(deadline.saturating_duration_since(Instant::now()));
+    }
+  }
+}
+
+/// Like [`wait`], for `waiter` in the queue that `queue` picks out of what `lock` guards. If we're
+/// unwound meanwhile, say by a [`timeout`](super::time::timeout), it's taken off the queue, and a
+/// wakeup it got on the way goes to whoever's next instead.
+pub(crate) fn wait_in<S>(lock: &Mutex<S>, waiter: &Arc<Waiter>, queue: fn(&mut S) -> &mut Waiters) {
+  struct Leave<'a, S> {
+    lock:   &'a Mutex<S>,
+    waiter: &'a Arc<Waiter>,
+    queue:  fn(&mut S) -> &mut Waiters,
+  }
+  impl<S> Drop for Leave<'_, S> {
+    fn drop(&mut self) {
+      let mut state = self.lock.lock().unwrap();
+      let queue = (self.queue)(&mut state);
+      let next = if self.waiter.notified() { queue.pop() } else { queue.remove(self.waiter); None };
+      drop(state);
+      if let Some(next) = next { next.unpark() }
+    }
+  }
+  let leave = Leave { lock, waiter, queue };
+  wait();
+  std::mem::forget(leave);
+}
@@ -1,12 +1,27 @@
 /// # Safety
 ///
 /// * `end()` must return an appropriately aligned pointer.
+/// * If `size()` is overridden, that many bytes below `end()` must be ours to use.
 /// * The stack is expected to be flanked by a guard page or never to overflow.
 pub unsafe trait Stack {
   /// Returns a pointer to the end of the stack's memory, i.e. the first byte after the stack.
   fn end(&self) -> *mut usize;
+
+  /// How many bytes of stack there are below `end()`, not counting any guard page.
+  ///
+  /// Defaults to `usize::MAX`, for "don't know", in which case nothing can check that a
+  /// coroutine fits and it's on you.
+  fn size(&self) -> usize { usize::MAX }
+}
+
+unsafe impl<S: Stack + ?Sized> Stack for &mut S {
+  fn end(&self) -> *mut usize { (**self).end() }
+  fn size(&self) -> usize { (**self).size() }
 }
 
+mod static_stack;
+pub use static_stack::*;
+
 #[cfg(any(feature="alloc", feature="std"))]
 mod allocator;
 #[cfg(any(feature="alloc", feature="std"))]
@@ -15,14 +15,14 @@ pub struct AllocatorStack {
   // https://agner.org/optimize/calling_conventions.pdf
   target_arch="x86_64",
   // https://github.com/riscv-collab/riscv-gcc/issues/61
-  target_arch="riscv",   target_arch="riscv64",
+  target_arch="riscv32", target_arch="riscv64",
   // https://en.wikipedia.org/wiki/X86_calling_conventions#cdecl
   all(target_arch="x86", unix),                 
 ))]
 const ALIGN: usize = 16;
 
 // https://community.arm.com/arm-community-blogs/b/architectures-and-processors-blog/posts/using-the-stack-in-aarch32-and-aarch64
-#[cfg(any(target_arch="arm"))]
+#[cfg(target_arch="arm")]
 const ALIGN: usize = 8;
 
 // https://agner.org/optimize/calling_conventions.pdf
@@ -34,9 +34,10 @@ impl AllocatorStack {
   ///
   /// # Safety
   ///
-  /// It's actually the drop that's unsafe:
+  /// It's mostly the drop that's unsafe:
   /// * You promise not to drop it while it's being used.
   /// * Ideally, if it has been used, unwind it first.
+  /// * There's no guard page, so nothing run on it may overflow it.
   pub unsafe fn new(size: u32) -> AllocatorStack {
     let layout =  Layout::from_size_align_unchecked(size as usize, ALIGN);
     let start = alloc(layout);
@@ -44,6 +45,9 @@ impl AllocatorStack {
   }
 }
 
+// We own the memory outright, so it may go wherever we do.
+unsafe impl Send for AllocatorStack {}
+
 impl Drop for AllocatorStack {
   fn drop(&mut self) {
     let layout = unsafe { Layout::from_size_align_unchecked(self.size as usize, ALIGN) };
@@ -55,6 +59,8 @@ unsafe impl super::Stack for AllocatorStack {
   fn end(&self) -> *mut usize {
     unsafe { self.start.offset(self.size as isize)}.cast()
   }
+
+  fn size(&self) -> usize { self.size as usize }
 }
 
 /// A const-sized GlobalAlloc-allocated stack
@@ -65,9 +71,10 @@ impl<const SIZE: u32> AllocatorStackConst<SIZE> {
   ///
   /// # Safety
   ///
-  /// It's actually the drop that's unsafe:
+  /// It's mostly the drop that's unsafe:
   /// * You promise not to drop it while it's being used.
   /// * Ideally, if it has been used, unwind it first.
+  /// * There's no guard page, so nothing run on it may overflow it.
   pub unsafe fn new() -> Self {
     let layout =  Layout::from_size_align_unchecked(SIZE as usize, ALIGN);
     let start = alloc(layout);
@@ -75,6 +82,8 @@ impl<const SIZE: u32> AllocatorStackConst<SIZE> {
   }
 }
 
+unsafe impl<const SIZE: u32> Send for AllocatorStackConst<SIZE> {}
+
 impl<const SIZE: u32> Drop for AllocatorStackConst<SIZE> {
   fn drop(&mut self) {
     unsafe { libc::munmap(self.0 as *mut _, SIZE as usize) };
@@ -85,4 +94,6 @@ unsafe impl<const SIZE: u32> super::Stack for AllocatorStackConst<SIZE> {
   fn end(&self) -> *mut usize {
     unsafe { self.0.offset(SIZE as isize)}.cast()
   }
+
+  fn size(&self) -> usize { SIZE as usize }
 }
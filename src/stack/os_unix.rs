@@ -28,6 +28,8 @@ unsafe impl Stack for ParanoidStack {
     let size = self.size + self.page;
     unsafe { self.start.add(size as usize)}.cast()
   }
+
+  fn size(&self) -> usize { self.size as usize }
 }
 
 impl ParanoidStack {
@@ -67,6 +69,9 @@ impl ParanoidStack {
   }
 }
 
+// We own the mapping outright, so it may go wherever we do.
+unsafe impl Send for ParanoidStack {}
+
 impl Drop for ParanoidStack {
   fn drop(&mut self) {
     let size = self.size + self.page + self.page;
@@ -98,6 +103,8 @@ unsafe impl Stack for SafeStack {
     let size = self.size + self.page;
     unsafe { self.start.offset(size as isize)}.cast()
   }
+
+  fn size(&self) -> usize { self.size as usize }
 }
 
 impl SafeStack {
@@ -130,6 +137,8 @@ impl SafeStack {
   }
 }
 
+unsafe impl Send for SafeStack {}
+
 impl Drop for SafeStack {
   fn drop(&mut self) {
     let size = self.size + self.page;
@@ -171,7 +180,7 @@ const GUARD_FLAGS: c_int = MAP_ANONYMOUS | MAP_PRIVATE;
 #[cfg(target_os="freebsd")] // sounds like this is faster? not entirely sure.
 const GUARD_FLAGS: c_int = MAP_ANONYMOUS | MAP_PRIVATE | libc::MAP_GUARD;
 
-#[cfg(any(target_os="dragonflybsd", target_os="freebsd", target_os="linux", target_os="netbsd", target_os="openbsd"))]
+#[cfg(any(target_os="dragonfly", target_os="freebsd", target_os="linux", target_os="netbsd", target_os="openbsd"))]
 const STACK_FLAGS: c_int = MAP_ANONYMOUS | MAP_PRIVATE | MAP_FIXED | libc::MAP_STACK;
-#[cfg(not(any(target_os="dragonflybsd", target_os="freebsd", target_os="linux", target_os="netbsd", target_os="openbsd")))]
+#[cfg(not(any(target_os="dragonfly", target_os="freebsd", target_os="linux", target_os="netbsd", target_os="openbsd")))]
 const STACK_FLAGS: c_int = MAP_ANONYMOUS | MAP_PRIVATE | MAP_FIXED;
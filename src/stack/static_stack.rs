@@ -0,0 +1,30 @@
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+
+/// A const-sized stack that lives wherever you put it. Needs no allocator, so it's handy for
+/// `no_std`, but there's no guard page either, so it must never overflow. That's on you, which is
+/// why making one is `unsafe`.
+///
+/// Pass it by `&mut` to anything that wants a [`Stack`](super::Stack) it can own. It isn't `Sync`,
+/// so it can't be a plain `static`. Keep it in a local, a struct or a `static mut` you only ever
+/// take one `&mut` of (through `addr_of_mut!`).
+#[repr(C, align(16))]
+pub struct StaticStack<const SIZE: usize>(UnsafeCell<[MaybeUninit<u8>; SIZE]>);
+
+impl<const SIZE: usize> StaticStack<SIZE> {
+  /// # Safety
+  ///
+  /// Nothing run on it may use more than `SIZE` bytes of stack, red zone and signal handlers
+  /// included. There's no guard page to catch it if it does.
+  pub const unsafe fn new() -> Self {
+    StaticStack(UnsafeCell::new([MaybeUninit::uninit(); SIZE]))
+  }
+}
+
+unsafe impl<const SIZE: usize> super::Stack for StaticStack<SIZE> {
+  fn end(&self) -> *mut usize {
+    unsafe { self.0.get().cast::<u8>().add(SIZE) }.cast()
+  }
+
+  fn size(&self) -> usize { SIZE }
+}
@@ -1,20 +1,61 @@
 mod arch;
 pub use arch::*;
 
-use core::mem::ManuallyDrop;
+#[cfg(unix)]
+mod signal;
+#[cfg(unix)]
+pub use signal::*;
+
+mod suspended;
+pub use suspended::*;
+
+use core::mem::{align_of, size_of, ManuallyDrop, MaybeUninit};
+use core::ptr::null_mut;
 
 pub type InitFn =  unsafe extern "C" fn(*mut usize, *const u8);
 
+/// The entrypoint of a prepared stack. It receives the paused stack and the argument from the
+/// first switch to it as well as a pointer to the top of its stack.
+pub type PreparedFn = unsafe extern "C" fn(*mut usize, usize, *mut u8);
+
+/// A function to be run on top of a stack by [`switch_ontop`]. It receives the paused stack and the
+/// argument and its return value is what the resumed context's `switch` returns.
+pub type OntopFn = unsafe extern "C" fn(*mut usize, usize) -> Switch;
+
 #[repr(C)]
 pub struct Switch {
   pub stack: *mut usize,
   pub arg:   usize,
 }
 
+#[repr(C)]
+pub struct Switch2 {
+  pub stack: *mut usize,
+  pub arg:   usize,
+  pub arg2:  usize,
+}
+
+#[repr(C)]
+pub struct Switch3 {
+  pub stack: *mut usize,
+  pub arg:   usize,
+  pub arg2:  usize,
+  pub arg3:  usize,
+}
+
+/// The result of a [`switch_with`].
+pub struct SwitchWith<T> {
+  pub stack: *mut usize,
+  pub value: T,
+}
+
 /// Moves the closure onto the new stack and calls it.
 ///
 /// Closure receives the paused stack to return to as well as the first input (a usize).
 ///
+/// Backtraces taken on the new stack stop at its trampoline, even with `chained-backtraces`, which
+/// only chains prepared stacks (see `link_closure_prepared_chained`).
+///
 /// # Safety
 ///
 /// * Stack must be the end address of a properly aligned stack.
@@ -30,9 +71,199 @@ where F: FnOnce(*mut usize, usize) {
   link_detached(bootstrap_closure::<F>, f, stack)
 }
 
+/// Moves the closure onto the top of the new stack and prepares it to be called by the first
+/// switch. Unlike [`link_closure_detached`], nothing runs until then.
+///
+/// Closure receives the paused stack to return to as well as the first input (a usize).
+///
+/// # Safety
+///
+/// * Stack must be the end address of a properly aligned stack.
+/// * One of:
+///   * Stack must be allocated with a guard page OR
+///   * Stack must never overflow (including red zone and signal space)
+/// * Never return from the closure, escape it.
+/// * Never unwind from the closure, catch any unwinding panic and escape.
+#[inline(always)]
+pub unsafe fn link_closure_prepared<F>(stack: *mut usize, closure: F) -> *mut usize
+where F: FnOnce(*mut usize, usize) {
+  // 16 keeps every arch we support happy.
+  let align = align_of::<F>().max(16);
+  let top = (stack as usize - size_of::<F>()) & !(align - 1);
+  (top as *mut F).write(closure);
+  link_prepared(start_prepared::<F>, top as *mut usize)
+}
+
+/// The most that [`link_closure_prepared`] (or `link_closure_prepared_chained`) writes below the
+/// closure, on any arch.
+pub(crate) const PREPARED_RESERVE: usize = 64;
+
+/// Like [`link_closure_prepared`], but backtraces taken on the new stack carry on into whatever
+/// stack `*parent` points to when they are taken (e.g. whoever last resumed it).
+///
+/// There's no detached equivalent: [`link_closure_detached`]'s trampoline has nowhere to find a
+/// parent.
+///
+/// # Safety
+///
+/// As [`link_closure_prepared`], plus `parent` must outlive the stack and always hold a stack
+/// paused by `switch` (or null, for nothing) while code on the new stack runs.
+#[cfg(feature="chained-backtraces")]
+#[inline(always)]
+pub unsafe fn link_closure_prepared_chained<F>(
+  stack: *mut usize, closure: F, parent: *const *mut usize
+) -> *mut usize
+where F: FnOnce(*mut usize, usize) {
+  let align = align_of::<F>().max(16);
+  let top = (stack as usize - size_of::<F>()) & !(align - 1);
+  (top as *mut F).write(closure);
+  link_prepared_chained(start_prepared::<F>, top as *mut usize, parent)
+}
+
+/// Reserves the 16 bytes below `stack` for a pointer to the parent's paused stack pointer, where
+/// the prepared trampoline's CFI expects to find it. Returns the new top of the stack.
+#[cfg(feature="chained-backtraces")]
+#[inline(always)]
+pub(crate) unsafe fn link_parent(stack: *mut usize, parent: *const *mut usize) -> *mut usize {
+  let link = stack.cast::<u8>().sub(16).cast::<usize>();
+  link.write(parent as usize);
+  link
+}
+
+/// The parent of a stack with no parent. It points into a run of zeroes, so unwinders find a null
+/// return address and stop there.
+#[cfg(feature="chained-backtraces")]
+pub(crate) fn orphan() -> *const *mut usize { &ORPHAN.0 }
+
+#[cfg(feature="chained-backtraces")]
+static ORPHAN_FRAME: [usize; 8] = [0; 8];
+
+#[cfg(feature="chained-backtraces")]
+struct Orphan(*mut usize);
+
+// It's never written through.
+#[cfg(feature="chained-backtraces")]
+unsafe impl Sync for Orphan {}
+
+#[cfg(feature="chained-backtraces")]
+static ORPHAN: Orphan = Orphan(ORPHAN_FRAME.as_ptr().wrapping_add(4) as *mut usize);
+
+/// The value a new stack's frame pointer starts with. Normally null, which ends the frame-pointer
+/// chain right there.
+///
+/// With `frame-pointers`, it points at a static frame record instead, so frame-pointer unwinders
+/// (e.g. `perf --call-graph=fp`) see every stack as having been called from [`stackle_coroutine`].
+// Some arches only read it from asm.
+pub(crate) struct RootFp(#[allow(dead_code)] pub(crate) *const usize);
+
+// It's never written through.
+unsafe impl Sync for RootFp {}
+
+#[cfg(not(feature="frame-pointers"))]
+pub(crate) static ROOT_FP: RootFp = RootFp(core::ptr::null());
+
+// riscv frame pointers point just above the record rather than at it.
+#[cfg(all(feature="frame-pointers", any(target_arch="riscv32", target_arch="riscv64")))]
+pub(crate) static ROOT_FP: RootFp = RootFp(ROOT_RECORD.0.as_ptr().wrapping_add(2).cast());
+
+#[cfg(all(feature="frame-pointers", not(any(target_arch="riscv32", target_arch="riscv64"))))]
+pub(crate) static ROOT_FP: RootFp = RootFp(ROOT_RECORD.0.as_ptr().cast());
+
+#[cfg(feature="frame-pointers")]
+struct RootRecord([*const u8; 2]);
+
+#[cfg(feature="frame-pointers")]
+unsafe impl Sync for RootRecord {}
+
+// A null frame pointer, then a return address a little way into `stackle_coroutine` so that
+// profilers attribute it there even if they step back to find the call.
+#[cfg(feature="frame-pointers")]
+static ROOT_RECORD: RootRecord = RootRecord([
+  core::ptr::null(),
+  ((stackle_coroutine as fn() -> !) as *const u8).wrapping_add(4),
+]);
+
+/// Never called, it just names the root of every stack's frame-pointer chain in profiles.
+#[cfg(feature="frame-pointers")]
+#[inline(never)]
+pub fn stackle_coroutine() -> ! { panic!("stackle_coroutine is only a name for profilers") }
+
+unsafe extern "C" fn start_prepared<F>(stack: *mut usize, arg: usize, closure: *mut u8)
+where F: FnOnce(*mut usize, usize) {
+  let f = closure.cast::<F>().read();
+  f(stack, arg);
+}
+
 unsafe extern "C" fn bootstrap_closure<F>(stack: *mut usize, closure: *const u8)
 where F: FnOnce(*mut usize, usize) {
   let f = closure.cast::<F>().read();
   let switch = switch(stack, 0);
   f(switch.stack, switch.arg);
 }
+
+/// Pauses the current stack context and resumes another, moving a `T` across.
+///
+/// A `T` that fits in three words (and is no more aligned than a word) travels in registers via
+/// [`switch`], [`switch2`] or [`switch3`]. Anything bigger stays where it is on our (paused) stack
+/// and the other side receives a pointer to it, which it reads from immediately.
+///
+/// # Safety
+///
+/// Behaviour is undefined if:
+/// * The stack was not paused correctly
+/// * The other side is not also using `switch_with::<T>` (the same `T`!).
+#[inline(always)]
+pub unsafe fn switch_with<T>(stack: *mut usize, value: T) -> SwitchWith<T> {
+  const WORD: usize = size_of::<usize>();
+  if size_of::<T>() > 3 * WORD || align_of::<T>() > align_of::<usize>() {
+    // Pointer handoff. Our frame outlives the read because we're paused until they switch back.
+    let value = ManuallyDrop::new(value);
+    let ret = switch(stack, (&value as *const ManuallyDrop<T>) as usize);
+    let value = (ret.arg as *const T).read();
+    return SwitchWith { stack: ret.stack, value };
+  }
+  // Padding and unused words stay uninitialised all the way through the registers.
+  let mut words = [MaybeUninit::<usize>::uninit(); 3];
+  words.as_mut_ptr().cast::<T>().write(value);
+  let [a, b, c] = words;
+  let (stack, words) = if size_of::<T>() <= WORD {
+    let (stack, [a]) = switch_uninit(stack, [a]);
+    (stack, [a, b, c])
+  } else if size_of::<T>() <= 2 * WORD {
+    let (stack, [a, b]) = switch2_uninit(stack, [a, b]);
+    (stack, [a, b, c])
+  } else {
+    switch3_uninit(stack, [a, b, c])
+  };
+  let value = words.as_ptr().cast::<T>().read();
+  SwitchWith { stack, value }
+}
+
+/// Switches to `target` for the last time, dropping `stack_owner` once we are safely off the stack
+/// it owns (presumably the one we're running on).
+///
+/// The resumed context's `switch` returns a null stack, there being nothing left to resume.
+///
+/// # Safety
+///
+/// Behaviour is undefined if:
+/// * The target stack was not paused correctly
+/// * Anything on the current stack is used again. Its frames are not unwound, so anything in them
+///   that needs dropping is leaked.
+pub unsafe fn exit_to<S>(target: *mut usize, arg: usize, stack_owner: S) -> ! {
+  // This lives on the stack we're leaving. `exit_ontop` moves it out before it's freed.
+  let exit = ManuallyDrop::new(Exit { arg, stack_owner });
+  switch_ontop(target, (&exit as *const ManuallyDrop<Exit<S>>) as usize, exit_ontop::<S>);
+  unreachable!("an exited stack was resumed")
+}
+
+struct Exit<S> {
+  arg: usize,
+  stack_owner: S,
+}
+
+unsafe extern "C" fn exit_ontop<S>(_paused: *mut usize, exit: usize) -> Switch {
+  let Exit { arg, stack_owner } = (exit as *const Exit<S>).read();
+  drop(stack_owner);
+  Switch { stack: null_mut(), arg }
+}
@@ -36,6 +36,8 @@ pub use x86_64_unix::*;
 
 #[cfg(all(
   not(target_arch="aarch64"),
+  not(target_arch="riscv32"),
+  not(target_arch="riscv64"),
   not(all(target_arch="x86_64", unix)),
   not(all(target_arch="x86",    unix)),
 ))]
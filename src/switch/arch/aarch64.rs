@@ -6,8 +6,22 @@
 //! * `sp` must be aligned by 16 at all times at which it is used to read/write data.
 //! * We cannot rely on there being a red zone below `sp`. There's a 2 word one on windows but it
 //!   sounds like the compiler might play with it (sometimes?) so we'd better not risk it.
-use crate::switch::{InitFn, Switch};
+//! * `x19` is callee-saved, but llvm reserves it for itself so we can't just tell it we clobber it.
+//!   We spill it next to the frame instead.
+//!
+//! Every function here pauses us the same way, leaving a 32 byte frame at the paused stack pointer:
+//!
+//! | paused rel | data           |
+//! |------------|----------------|
+//! | +24        | padding        |
+//! | +16        | x19            |
+//! | +8         | return address |
+//! | 0          | frame pointer  |
+use crate::switch::{InitFn, OntopFn, PreparedFn, Switch, Switch2, Switch3};
+#[cfg(feature="chained-backtraces")]
+use crate::switch::{link_parent, orphan};
 use core::arch::asm;
+use core::mem::MaybeUninit;
 
 /// Links a coroutine with a new stack, starting a new call stack at a trampoline.
 ///
@@ -24,47 +38,49 @@ pub unsafe extern "C" fn link_detached(
   asm!(
     // step 1: state preservation. we must spill our state to the stack so we may be resumed.
     // adr = generate pc-relative address, 2f = forward reference to label 2.
-    "adr lr, 2f",             // set the link register to the end of this function.
-    // stp = store pair of registers, [sp, #-16] = predecrement sp by 16. implies sp -= 16
-    "stp fp, lr, [sp, #-16]", // push the frame pointer and return address to the stack
-    // our stack should now look like this:
-    // | new sp rel | old sp rel | data           |
-    // |------------|------------|----------------|
-    // | +8         | -8         | return address |
-    // | 0          | -16        | frame pointer  |
-
-    // step 2: set up the trampoline frame in the new stack
-    "stp x0, x3, [x2, #-16]" // push fun and trampoline to the stack. implies x2 -= 16
-    // the new stack should now look like this:
-    // | new x2 rel | old x2 rel | data                |
-    // |------------|------------|---------------------|
-    // | +8         | -8         | trampoline function |
-    // | 0          | -16        | entrypoint function |
-
-    // step 3: setting up parameters
-    "mov sp, x0", // current stack pointer -> arg 1, overwriting 'fun'.
+    "adr lr, 2f",              // set the link register to the end of this function.
+    // str/stp = store (pair of) registers, [sp, #-16]! = predecrement sp by 16. implies sp -= 16
+    "str x19, [sp, #-16]!",    // push x19 (and some padding)
+    "stp fp, lr, [sp, #-16]!", // push the frame pointer and return address to the stack
+
+    // step 2: setting up parameters
+    "mov x0, sp", // current stack pointer -> arg 1.
     // argument layout should now be:
     // | register | value                  |
     // |----------|------------------------|
     // | x0       | paused stack pointer   |
     // | x1       | arg (untouched)        |
-    
-    // step 4: calling trampoline on the new stack.
-    "mov xzr, lr", // zero out the link register (meaning "top of call chain")
-    "mov x2, sp",  // set the correct stack pointer
-    "bx x3",       // switch to trampoline
-    
+    // | x3       | fun (untouched)        |
+
+    // step 3: calling trampoline on the new stack.
+    "mov sp, x2",  // set the correct stack pointer
+    #[cfg(feature="frame-pointers")]
+    "adrp x9, {root}", // the root of the call chain
+    #[cfg(feature="frame-pointers")]
+    "ldr fp, [x9, :lo12:{root}]",
+    #[cfg(not(feature="frame-pointers"))]
+    "mov fp, xzr", // zero out the frame pointer (meaning "top of call chain")
+    "mov lr, xzr", // and the link register
+    "br  x4",      // switch to trampoline
+
     // End of function, as taken in first instruction. register layout should now be:
     // | register | value                   |
     // |----------|-------------------------|
     // | x1       | arg                     |
     // | x2       | paused stack pointer    |
-
     "2:",
-    inout("x0") fun => _,
+    out("x0") _,
     inout("x1") arg => _,
     inout("x2") stack,
-    inout("x3") trampoline => _,
+    inout("x3") fun => _,
+    inout("x4") trampoline => _,
+    #[cfg(feature="frame-pointers")]
+    root = sym crate::switch::ROOT_FP,
+    // callee-saved registers that whoever we switch to is free to use.
+    out("x20") _, out("x21") _, out("x22") _, out("x23") _, out("x24") _,
+    out("x25") _, out("x26") _, out("x27") _, out("x28") _,
+    out("v8") _, out("v9") _, out("v10") _, out("v11") _,
+    out("v12") _, out("v13") _, out("v14") _, out("v15") _,
     clobber_abi("C")
   );
   stack
@@ -72,56 +88,342 @@ pub unsafe extern "C" fn link_detached(
 
 /// Pauses the current stack context and resumes another.
 ///
-/// If the pointer points to an `extern "C"` function then 
-///
 /// # Safety
 ///
 /// Behaviour is undefined if:
 /// * The stack was not paused correctly
 #[inline(always)]
-pub unsafe extern "C" fn switch(mut stack: *mut usize, mut arg: usize) -> Switch {
+pub unsafe extern "C" fn switch(stack: *mut usize, arg: usize) -> Switch {
+  let (stack, [arg]) = switch_uninit(stack, [MaybeUninit::new(arg)]);
+  Switch { stack, arg: arg.assume_init() }
+}
+
+/// [`switch`], but the word may be uninitialised (e.g. padding), for
+/// [`switch_with`](crate::switch::switch_with).
+#[inline(always)]
+pub(crate) unsafe fn switch_uninit(
+  mut stack: *mut usize, [mut arg]: [MaybeUninit<usize>; 1]
+) -> (*mut usize, [MaybeUninit<usize>; 1]) {
   asm!(
     // step 1: state preservation. we must spill our state to the stack so we may be resumed.
-    // adr = generate pc-relative address, 2f = forward reference to label 2.
-    "adr lr, 2f",             // set the link register to the end of this function.
-    // stp = store pair of registers, [sp, #-16] = predecrement sp by 16. implies sp -= 16
-    "stp fp, lr, [sp, #-16]", // push the frame pointer and return address to the stack
-    // our stack should now look like this:
-    // | new sp rel | old sp rel | data           |
-    // |------------|------------|----------------|
-    // | +8         | -8         | return address |
-    // | 0          | -16        | frame pointer  |
-
-    // step 2: set parameters stacks
-    "mov sp, x2", // save current stack pointer into x2
+    "adr lr, 2f",              // set the link register to the end of this function.
+    "str x19, [sp, #-16]!",    // push x19 (and some padding). implies sp -= 16
+    "stp fp, lr, [sp, #-16]!", // push the frame pointer and return address. implies sp -= 16
+    "mov x2, sp",              // paused stack pointer -> x2
     // argument layout should now be:
     // | register | value                |
     // |----------|----------------------|
     // | x1       | arg (untouched)      |
     // | x2       | paused stack pointer |
 
-    // step 3: state restoration (inverse of preservation) and branching
-    // ldp = load pair of registers, #16 = postincrement sp by 16, implies sp += 16
-    "ldp fp, lr, [x0], #16" // load the frame pointer and return address from the stack
-    "mov x0, sp",           // load new stack pointer
-    "bx lr",                // branch to the return address.
+    // step 2: state restoration (inverse of preservation) and branching
+    // ldp = load pair of registers
+    "ldp fp, lr, [x0]",        // load the frame pointer and return address from the new stack
+    "ldr x19, [x0, #16]",      // load x19
+    "add sp, x0, #32",         // release the frame
+    "br  lr",                  // branch to the return address.
+
+    // End of function, as taken in first instruction. register layout should now be:
+    // | register | value                   |
+    // |----------|-------------------------|
+    // | x1       | arg                     |
+    // | x2       | paused stack pointer    |
+    "2:",
+    inout("x0") stack => _,
+    inout("x1") arg,
+    out("x2") stack,
+    // callee-saved registers that whoever we switch to is free to use.
+    out("x20") _, out("x21") _, out("x22") _, out("x23") _, out("x24") _,
+    out("x25") _, out("x26") _, out("x27") _, out("x28") _,
+    out("v8") _, out("v9") _, out("v10") _, out("v11") _,
+    out("v12") _, out("v13") _, out("v14") _, out("v15") _,
+    clobber_abi("C")
+  );
+  (stack, [arg])
+}
+
+/// Like [`switch`], but carries a second word in `x3`.
+///
+/// The context being resumed must be expecting two words (i.e. paused in `switch2`), otherwise the
+/// second word is simply lost.
+///
+/// # Safety
+///
+/// Behaviour is undefined if:
+/// * The stack was not paused correctly
+#[inline(always)]
+pub unsafe extern "C" fn switch2(stack: *mut usize, arg: usize, arg2: usize) -> Switch2 {
+  let (stack, [arg, arg2]) = switch2_uninit(stack, [MaybeUninit::new(arg), MaybeUninit::new(arg2)]);
+  Switch2 { stack, arg: arg.assume_init(), arg2: arg2.assume_init() }
+}
+
+/// [`switch2`], but the words may be uninitialised (e.g. padding), for
+/// [`switch_with`](crate::switch::switch_with).
+#[inline(always)]
+pub(crate) unsafe fn switch2_uninit(
+  mut stack: *mut usize, [mut arg, mut arg2]: [MaybeUninit<usize>; 2]
+) -> (*mut usize, [MaybeUninit<usize>; 2]) {
+  asm!(
+    // step 1: state preservation, exactly as `switch` does.
+    "adr lr, 2f",              // set the link register to the end of this function.
+    "str x19, [sp, #-16]!",    // push x19 (and some padding). implies sp -= 16
+    "stp fp, lr, [sp, #-16]!", // push the frame pointer and return address. implies sp -= 16
+    "mov x2, sp",              // paused stack pointer -> x2
+    // step 2: state restoration and branching
+    "ldp fp, lr, [x0]",        // load the frame pointer and return address from the new stack
+    "ldr x19, [x0, #16]",      // load x19
+    "add sp, x0, #32",         // release the frame
+    "br  lr",                  // branch to the return address.
+
+    // End of function, as taken in first instruction. register layout should now be:
+    // | register | value                   |
+    // |----------|-------------------------|
+    // | x1       | arg                     |
+    // | x2       | paused stack pointer    |
+    // | x3       | arg2                    |
+    "2:",
+    inout("x0") stack => _,
+    inout("x1") arg,
+    out("x2") stack,
+    inout("x3") arg2,
+    // callee-saved registers that whoever we switch to is free to use.
+    out("x20") _, out("x21") _, out("x22") _, out("x23") _, out("x24") _,
+    out("x25") _, out("x26") _, out("x27") _, out("x28") _,
+    out("v8") _, out("v9") _, out("v10") _, out("v11") _,
+    out("v12") _, out("v13") _, out("v14") _, out("v15") _,
+    clobber_abi("C")
+  );
+  (stack, [arg, arg2])
+}
+
+/// Like [`switch`], but carries a second word in `x3` and a third in `x4`.
+///
+/// The context being resumed must be expecting three words (i.e. paused in `switch3`), otherwise
+/// the extra words are simply lost.
+///
+/// # Safety
+///
+/// Behaviour is undefined if:
+/// * The stack was not paused correctly
+#[inline(always)]
+pub unsafe extern "C" fn switch3(
+  stack: *mut usize, arg: usize, arg2: usize, arg3: usize
+) -> Switch3 {
+  let (stack, [arg, arg2, arg3]) = switch3_uninit(stack, [MaybeUninit::new(arg), MaybeUninit::new(arg2), MaybeUninit::new(arg3)]);
+  Switch3 { stack, arg: arg.assume_init(), arg2: arg2.assume_init(), arg3: arg3.assume_init() }
+}
+
+/// [`switch3`], but the words may be uninitialised (e.g. padding), for
+/// [`switch_with`](crate::switch::switch_with).
+#[inline(always)]
+pub(crate) unsafe fn switch3_uninit(
+  mut stack: *mut usize, [mut arg, mut arg2, mut arg3]: [MaybeUninit<usize>; 3]
+) -> (*mut usize, [MaybeUninit<usize>; 3]) {
+  asm!(
+    // step 1: state preservation, exactly as `switch` does.
+    "adr lr, 2f",              // set the link register to the end of this function.
+    "str x19, [sp, #-16]!",    // push x19 (and some padding). implies sp -= 16
+    "stp fp, lr, [sp, #-16]!", // push the frame pointer and return address. implies sp -= 16
+    "mov x2, sp",              // paused stack pointer -> x2
+    // step 2: state restoration and branching
+    "ldp fp, lr, [x0]",        // load the frame pointer and return address from the new stack
+    "ldr x19, [x0, #16]",      // load x19
+    "add sp, x0, #32",         // release the frame
+    "br  lr",                  // branch to the return address.
+
+    // End of function, as taken in first instruction. register layout should now be:
+    // | register | value                   |
+    // |----------|-------------------------|
+    // | x1       | arg                     |
+    // | x2       | paused stack pointer    |
+    // | x3       | arg2                    |
+    // | x4       | arg3                    |
+    "2:",
+    inout("x0") stack => _,
+    inout("x1") arg,
+    out("x2") stack,
+    inout("x3") arg2,
+    inout("x4") arg3,
+    // callee-saved registers that whoever we switch to is free to use.
+    out("x20") _, out("x21") _, out("x22") _, out("x23") _, out("x24") _,
+    out("x25") _, out("x26") _, out("x27") _, out("x28") _,
+    out("v8") _, out("v9") _, out("v10") _, out("v11") _,
+    out("v12") _, out("v13") _, out("v14") _, out("v15") _,
+    clobber_abi("C")
+  );
+  (stack, [arg, arg2, arg3])
+}
+
+/// Like [`switch`], but also preserves our floating-point control state (FPCR) while
+/// we're paused, so whatever we switch to can change rounding modes and the like without it leaking
+/// back into us.
+///
+/// Only the caller is protected: the state is saved above the paused frame and restored when we are
+/// resumed, so the other side may be paused however it likes.
+///
+/// # Safety
+///
+/// Behaviour is undefined if:
+/// * The stack was not paused correctly
+#[inline(always)]
+pub unsafe extern "C" fn switch_full(mut stack: *mut usize, mut arg: usize) -> Switch {
+  asm!(
+    // save the control state where `switch_ontop` won't trample it while we're paused.
+    "mrs x9, fpcr",
+    "str x9, [sp, #-16]!",     // implies sp -= 16
+    // state preservation, exactly as `switch` does.
+    "adr lr, 2f",              // set the link register to the end of this function.
+    "str x19, [sp, #-16]!",    // push x19 (and some padding). implies sp -= 16
+    "stp fp, lr, [sp, #-16]!", // push the frame pointer and return address. implies sp -= 16
+    "mov x2, sp",              // paused stack pointer -> x2
+    // state restoration and branching
+    "ldp fp, lr, [x0]",        // load the frame pointer and return address from the new stack
+    "ldr x19, [x0, #16]",      // load x19
+    "add sp, x0, #32",         // release the frame
+    "br  lr",                  // branch to the return address.
 
     // End of function, as taken in first instruction. register layout should now be:
     // | register | value                   |
     // |----------|-------------------------|
     // | x1       | arg                     |
     // | x2       | paused stack pointer    |
-    "2:", 
+    "2:",
+    // we have been resumed, put our control state back.
+    "ldr x9, [sp], #16",       // implies sp += 16
+    "msr fpcr, x9",
+    inout("x0") stack => _,
+    inout("x1") arg,
+    out("x2") stack,
+    // callee-saved registers that whoever we switch to is free to use.
+    out("x20") _, out("x21") _, out("x22") _, out("x23") _, out("x24") _,
+    out("x25") _, out("x26") _, out("x27") _, out("x28") _,
+    out("v8") _, out("v9") _, out("v10") _, out("v11") _,
+    out("v12") _, out("v13") _, out("v14") _, out("v15") _,
+    clobber_abi("C")
+  );
+  Switch { stack, arg }
+}
+
+/// Pauses the current stack context, then calls `fun(paused, arg)` on top of `stack` before resuming
+/// it. Whatever `fun` returns is what the resumed context's `switch` returns.
+///
+/// This is how you do things to a context that it can't do for itself, like freeing the stack it
+/// was running on or injecting work into it.
+///
+/// # Safety
+///
+/// Behaviour is undefined if:
+/// * The stack was not paused correctly
+/// * `fun` unwinds or switches away (it runs below the paused frame, which will be resumed).
+#[inline(always)]
+pub unsafe extern "C" fn switch_ontop(mut stack: *mut usize, mut arg: usize, fun: OntopFn) -> Switch {
+  asm!(
+    // step 1: state preservation, exactly as `switch` does.
+    "adr lr, 2f",              // set the link register to the end of this function.
+    "str x19, [sp, #-16]!",    // push x19 (and some padding). implies sp -= 16
+    "stp fp, lr, [sp, #-16]!", // push the frame pointer and return address. implies sp -= 16
+    "mov x2, sp",              // paused stack pointer -> x2
+
+    // step 2: switch stacks. the saved state is above sp, so `fun` can't trample it.
+    "mov sp, x0",
+
+    // step 3: call fun(paused, arg). x1 is still arg.
+    "mov x0, x2",
+    "blr x3",
+    // the returned `Switch` is in x0:x1. x1 is already where it needs to be.
+    "mov x2, x0",
+
+    // step 4: state restoration (as `switch` does) and branching
+    "ldp fp, lr, [sp]",        // load the frame pointer and return address
+    "ldr x19, [sp, #16]",      // load x19
+    "add sp, sp, #32",         // release the frame
+    "br  lr",                  // branch to the return address.
+
+    // End of function, as taken in first instruction. register layout should now be:
+    // | register | value                   |
+    // |----------|-------------------------|
+    // | x1       | arg                     |
+    // | x2       | paused stack pointer    |
+    "2:",
     inout("x0") stack => _,
     inout("x1") arg,
     out("x2") stack,
+    inout("x3") fun => _,
+    // callee-saved registers that whoever we switch to is free to use.
+    out("x20") _, out("x21") _, out("x22") _, out("x23") _, out("x24") _,
+    out("x25") _, out("x26") _, out("x27") _, out("x28") _,
+    out("v8") _, out("v9") _, out("v10") _, out("v11") _,
+    out("v12") _, out("v13") _, out("v14") _, out("v15") _,
     clobber_abi("C")
   );
   Switch { stack, arg }
 }
 
+/// Prepares a new stack so that the first `switch` to it calls `fun(paused, arg, stack)`, without
+/// running anything now.
+///
+/// Whatever `fun` needs (probably a closure) should already have been written at `stack`.
+///
+/// # Safety
+///
+/// * `stack` must be a 16-byte aligned pointer into a region with enough room below it.
+/// * `stack` must either have a guard page allocated or not overflow.
+/// * `fun` must never return.
+#[inline(always)]
+pub unsafe fn link_prepared(fun: PreparedFn, stack: *mut usize) -> *mut usize {
+  #[cfg(feature="chained-backtraces")]
+  let stack = link_parent(stack, orphan());
+  prepare_frame(fun, stack)
+}
+
+/// Like [`link_prepared`], but backtraces continue into the stack `*parent` holds when they're
+/// taken. `fun` receives the original `stack` as its third argument.
+///
+/// # Safety
+///
+/// As [`link_prepared`], plus `parent` must always hold a paused stack while the new stack runs.
+#[cfg(feature="chained-backtraces")]
+#[inline(always)]
+pub unsafe fn link_prepared_chained(fun: PreparedFn, stack: *mut usize, parent: *const *mut usize) -> *mut usize {
+  prepare_frame(fun, link_parent(stack, parent))
+}
+
+#[inline(always)]
+unsafe fn prepare_frame(fun: PreparedFn, stack: *mut usize) -> *mut usize {
+  let entry = prepared_trampoline as unsafe extern "C" fn() as usize;
+  // We just have to write out what `switch` would have saved, smuggling the function in through
+  // the frame pointer for the prepared trampoline to find.
+  let frame = stack.sub(4);
+  frame.write(fun as usize);                        // frame pointer
+  frame.add(1).write(entry);                        // return address
+  frame.add(2).write(0);                            // x19
+  // the new stack should now look like this:
+  // | frame rel | data                         |
+  // |-----------|------------------------------|
+  // | +32       | whatever the caller put here |
+  // | +16       | x19                          |
+  // | +8        | prepared trampoline          |
+  // | 0         | entrypoint function          |
+  frame
+}
+
+/// The return address and frame pointer that `switch` saved when it paused `stack`.
+#[cfg(feature="backtrace")]
+#[inline(always)]
+pub(crate) unsafe fn paused_frame(stack: *mut usize) -> (usize, *const usize) {
+  (stack.add(1).read(), stack.read() as *const usize)
+}
+
+/// Where the frame record (the caller's frame pointer, then the return address) that `fp` points
+/// to starts.
+#[cfg(feature="backtrace")]
+#[inline(always)]
+pub(crate) fn frame_record(fp: *const usize) -> *const usize {
+  fp
+}
+
 /* Trampoline function (terminates the call chain, becoming the first frame):
- * - called with an artificial frame.
+ * - jumped to by `link_detached`, so x0 = paused stack pointer, x1 = arg and x3 = the function.
  * - calls the function in a new frame.
  * - expects that function never to return.
  */
@@ -134,8 +436,66 @@ core::arch::global_asm!(
   ".align 16",             // put it at the start of a quadword to increase fetch perf.
   "trampoline:",
   ".cfi_startproc simple", // function prologue
+  ".cfi_def_cfa sp, 0",    // unwinders need a cfa, even just to stop here.
   ".cfi_undefined lr",     // stop unwinding at this frame
   ".cfi_undefined fp",     // stop the call chain at this frame (for gdb)
-  "bl sp",                 // call the function in a new stack frame.
+  "blr x3",                // call the function in a new stack frame.
+  "brk #1",                // it's not allowed to return.
   ".cfi_endproc"           // function epilogue
 );
+
+/* Prepared trampoline (the first frame of a prepared stack):
+ * - jumped to by the first switch, so x1 = arg and x2 = paused stack pointer.
+ * - fp holds the function and sp points at whatever the linker left there for it.
+ * - expects that function never to return.
+ */
+extern "C" {
+    fn prepared_trampoline();
+}
+
+#[cfg(not(feature="chained-backtraces"))]
+core::arch::global_asm!(
+  ".global prepared_trampoline",
+  ".align 16",
+  "prepared_trampoline:",
+  ".cfi_startproc simple",
+  ".cfi_def_cfa sp, 0",    // unwinders need a cfa, even just to stop here.
+  ".cfi_undefined lr",     // stop unwinding at this frame
+  ".cfi_undefined fp",     // stop the call chain at this frame (for gdb)
+  "mov x0, x2",            // paused stack pointer -> arg 1, x1 is already arg 2
+  "mov x2, sp",            // the top of the stack -> arg 3
+  "mov x9, fp",            // the function
+  "adrp x10, {root}",      // the root of the call chain (null, unless `frame-pointers`)
+  "ldr fp, [x10, :lo12:{root}]",
+  "mov lr, xzr",           // zero the link register
+  "blr x9",                // call the function in a new stack frame.
+  "brk #1",                // it's not allowed to return.
+  ".cfi_endproc",
+  root = sym crate::switch::ROOT_FP
+);
+
+// Chained, [sp] points at wherever the parent's paused stack pointer is kept. We describe our
+// caller as living there: `switch` saved its frame right at it, same as it would in a call.
+#[cfg(feature="chained-backtraces")]
+core::arch::global_asm!(
+  ".global prepared_trampoline",
+  ".align 16",
+  "prepared_trampoline:",
+  ".cfi_startproc simple",
+  // cfa = **sp + 32 (DW_CFA_def_cfa_expression: DW_OP_breg31 0, DW_OP_deref, DW_OP_deref,
+  // DW_OP_plus_uconst 32)
+  ".cfi_escape 0x0f, 6, 0x8f, 0x00, 0x06, 0x06, 0x23, 0x20",
+  ".cfi_offset x19, -16",   // where `switch` saved them
+  ".cfi_offset lr, -24",
+  ".cfi_offset fp, -32",
+  "mov x0, x2",            // paused stack pointer -> arg 1, x1 is already arg 2
+  "add x2, sp, #16",       // the top of the stack, above the link -> arg 3
+  "mov x9, fp",            // the function
+  "adrp x10, {root}",      // the root of the call chain (null, unless `frame-pointers`)
+  "ldr fp, [x10, :lo12:{root}]",
+  "mov lr, xzr",           // zero the link register
+  "blr x9",                // call the function in a new stack frame.
+  "brk #1",                // it's not allowed to return.
+  ".cfi_endproc",
+  root = sym crate::switch::ROOT_FP
+);
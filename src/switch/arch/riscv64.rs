@@ -3,8 +3,22 @@
 //! * `sp` must always be 16-byte aligned.
 //! * No red zone under the stack pointer.
 //! * Too many callee-push registers, what were they thinking?
-use crate::switch::{InitFn, Switch};
+//! * `s1` is one of them, but llvm reserves it for itself so we can't just tell it we clobber it.
+//!   We spill it next to the frame instead.
+//!
+//! Every function here pauses us the same way, leaving a 32 byte frame at the paused stack pointer:
+//!
+//! | paused rel | data           |
+//! |------------|----------------|
+//! | +24        | padding        |
+//! | +16        | s1             |
+//! | +8         | return address |
+//! | 0          | frame pointer  |
+use crate::switch::{InitFn, OntopFn, PreparedFn, Switch, Switch2, Switch3};
+#[cfg(feature="chained-backtraces")]
+use crate::switch::{link_parent, orphan};
 use core::arch::asm;
+use core::mem::MaybeUninit;
 
 /// Links a coroutine with a new stack, starting a new call stack at a trampoline.
 ///
@@ -21,54 +35,58 @@ pub unsafe extern "C" fn link_detached(
   asm!(
     // step 1: state preservation. we must spill our state to the stack so we may be resumed.
     // addi = add immediate
-    "addi  sp, sp, -16",       // sp = sp - 16 (reserve space on the stack)
-    // auipc - add upper immediate to program counter, i.e. make a pc-rel addr absolute.
-    "auipc ra, %pcrel_lo(2f)", // ra = endofthisfunction
+    "addi sp, sp, -32", // sp = sp - 32 (reserve space on the stack)
+    // lla = load local address (an auipc/addi pair)
+    "lla  ra, 2f",      // ra = endofthisfunction
     // sd = store double (64 bit)
-    "sd    ra, 8(sp)",         // *(sp+8) = ra (save return address)
-    "sd    fp, 0(sp)",         // *sp = fp (save frame pointer)
-    // our stack should now look like this:
-    // | new sp rel | old sp rel | data           |
-    // |------------|------------|----------------|
-    // | +8         | -8         | return address |
-    // | 0          | -16        | frame pointer  |
-
-    "sd a3,  -8(a2)", // *(a2-8) = a3 (store trampoline)
-    "sd a0, -16(a2)", // *(a2-16) = a0 (push fun)
-    // the new stack should now look like this:
-    // | a2 rel | data                |
-    // |--------|---------------------|
-    // | -8     | trampoline function |
-    // | -16    | entrypoint function |
-
-    // step 3: setting up parameters
-    // mv = move (actually a shortcut for `addi r10, sp, 0`)
-    "mv a0, sp", // a0 = sp (current stack pointer -> arg 1, overwriting 'fun').
+    "sd   ra, 8(sp)",  // *(sp+8) = ra (save return address)
+    "sd   fp, 0(sp)",  // *sp = fp (save frame pointer)
+    "sd   s1, 16(sp)", // *(sp+16) = s1
+
+    // step 2: setting up parameters
+    // mv = move (actually a shortcut for `addi a0, sp, 0`)
+    "mv   a0, sp",      // a0 = sp (current stack pointer -> arg 1, overwriting 'fun').
     // argument layout should now be:
     // | register | value                  |
     // |----------|------------------------|
     // | a0       | paused stack pointer   |
     // | a1       | arg (untouched)        |
-    
-    // step 4: calling trampoline on the new stack.
+    // | a3       | fun (untouched)        |
+
+    // step 3: calling trampoline on the new stack.
+    "mv   sp, a2",      // sp = a2 (set the correct stack pointer)
     // these are both ways of terminating the call chain
-    "mv   zero, ra",      // ra = 0 (no return address)
-    "mv   zero, fp",      // fp = 0 (no frame pointer)
-    "addi sp,   a2, -16", // sp = a2 - 16 (set the correct stack pointer0)
-    // j = jump (actually a shorthand for `jalr zero, a3`)
-    "j    a3",            // transfer to trampoline
-    
+    "mv   ra, zero",    // ra = 0 (no return address)
+    #[cfg(feature="frame-pointers")]
+    "la   t1, {root}",  // fp = the root of the call chain
+    #[cfg(feature="frame-pointers")]
+    "ld   fp, 0(t1)",
+    #[cfg(not(feature="frame-pointers"))]
+    "mv   fp, zero",    // fp = 0 (no frame pointer)
+    "jr   a4",          // transfer to trampoline
+
     // End of function, as taken in first instruction. register layout should now be:
     // | register | value                   |
     // |----------|-------------------------|
     // | a1       | arg                     |
     // | a2       | paused stack pointer    |
-
     "2:",
-    inout("a0") fun => _,
+    out("a0") _,
     inout("a1") arg => _,
     inout("a2") stack,
-    inout("a3") trampoline => _,
+    inout("a3") fun => _,
+    inout("a4") trampoline => _,
+    #[cfg(feature="frame-pointers")]
+    root = sym crate::switch::ROOT_FP,
+    // callee-saved registers that whoever we switch to is free to use.
+    out("s2") _, out("s3") _, out("s4") _, out("s5") _, out("s6") _,
+    out("s7") _, out("s8") _, out("s9") _, out("s10") _, out("s11") _,
+    #[cfg(target_feature="f")] out("fs0") _, #[cfg(target_feature="f")] out("fs1") _,
+    #[cfg(target_feature="f")] out("fs2") _, #[cfg(target_feature="f")] out("fs3") _,
+    #[cfg(target_feature="f")] out("fs4") _, #[cfg(target_feature="f")] out("fs5") _,
+    #[cfg(target_feature="f")] out("fs6") _, #[cfg(target_feature="f")] out("fs7") _,
+    #[cfg(target_feature="f")] out("fs8") _, #[cfg(target_feature="f")] out("fs9") _,
+    #[cfg(target_feature="f")] out("fs10") _, #[cfg(target_feature="f")] out("fs11") _,
     clobber_abi("C")
   );
   stack
@@ -76,57 +94,391 @@ pub unsafe extern "C" fn link_detached(
 
 /// Pauses the current stack context and resumes another.
 ///
-/// If the pointer points to an `extern "C"` function then 
-///
 /// # Safety
 ///
 /// Behaviour is undefined if:
 /// * The stack was not paused correctly
 #[inline(always)]
-pub unsafe extern "C" fn switch(mut stack: *mut usize, mut arg: usize) -> Switch {
+pub unsafe extern "C" fn switch(stack: *mut usize, arg: usize) -> Switch {
+  let (stack, [arg]) = switch_uninit(stack, [MaybeUninit::new(arg)]);
+  Switch { stack, arg: arg.assume_init() }
+}
+
+/// [`switch`], but the word may be uninitialised (e.g. padding), for
+/// [`switch_with`](crate::switch::switch_with).
+#[inline(always)]
+pub(crate) unsafe fn switch_uninit(
+  mut stack: *mut usize, [mut arg]: [MaybeUninit<usize>; 1]
+) -> (*mut usize, [MaybeUninit<usize>; 1]) {
   asm!(
     // step 1: state preservation. we must spill our state to the stack so we may be resumed.
-    "addi  sp,     sp, -16",   // sp = sp - 16 (reserve space on the stack)
-    "auipc ra, %pcrel_lo(2f)", // ra = endofthisfunction
-    "sd    ra,   8(sp)",       // *(sp+8) = ra (save return address)
-    "sd    fp,   0(sp)",       // *sp = fp (save frame pointer)
-    // our stack should now look like this:
-    // | new sp rel | old sp rel | data           |
-    // |------------|------------|----------------|
-    // | +8         | -8         | return address |
-    // | 0          | -16        | frame pointer  |
-
-    // step 2: set parameters stacks
-    "mv sp, a2", // a2 = sp (save current stack pointer)
+    "addi sp, sp, -32", // sp = sp - 32 (reserve space on the stack)
+    "lla  ra, 2f",      // ra = endofthisfunction
+    "sd   ra, 8(sp)",  // *(sp+8) = ra (save return address)
+    "sd   fp, 0(sp)",  // *sp = fp (save frame pointer)
+    "sd   s1, 16(sp)", // *(sp+16) = s1
+    "mv   a2, sp",      // a2 = sp (save current stack pointer)
     // argument layout should now be:
     // | register | value                |
     // |----------|----------------------|
     // | a1       | arg (untouched)      |
     // | a2       | paused stack pointer |
 
-    // step 3: state restoration (inverse of preservation) and branching
+    // step 2: state restoration (inverse of preservation) and branching
     // ld = load double (64 bit)
-    "ld   fp, 0(a0)",     // fp = *a0 (load the frame pointer)
-    "ld   ra, 8(a0)",     // ra = *(a0 + 8) (load the return address)
-    "addi sp,   a0, 16",  // sp = r10 + 16 (set new sp but release the frame)
-    "j    ra",            // transfer control back to the return address
+    "ld   fp, 0(a0)",  // fp = *a0 (load the frame pointer)
+    "ld   ra, 8(a0)",  // ra = *(a0 + 8) (load the return address)
+    "ld   s1, 16(a0)", // s1 = *(a0 + 16)
+    "addi sp, a0, 32",  // sp = a0 + 32 (set new sp but release the frame)
+    "jr   ra",          // transfer control back to the return address
+
+    // End of function, as taken in first instruction. register layout should now be:
+    // | register | value                   |
+    // |----------|-------------------------|
+    // | a1       | arg                     |
+    // | a2       | paused stack pointer    |
+    "2:",
+    inout("a0") stack => _,
+    inout("a1") arg,
+    out("a2")   stack,
+    // callee-saved registers that whoever we switch to is free to use.
+    out("s2") _, out("s3") _, out("s4") _, out("s5") _, out("s6") _,
+    out("s7") _, out("s8") _, out("s9") _, out("s10") _, out("s11") _,
+    #[cfg(target_feature="f")] out("fs0") _, #[cfg(target_feature="f")] out("fs1") _,
+    #[cfg(target_feature="f")] out("fs2") _, #[cfg(target_feature="f")] out("fs3") _,
+    #[cfg(target_feature="f")] out("fs4") _, #[cfg(target_feature="f")] out("fs5") _,
+    #[cfg(target_feature="f")] out("fs6") _, #[cfg(target_feature="f")] out("fs7") _,
+    #[cfg(target_feature="f")] out("fs8") _, #[cfg(target_feature="f")] out("fs9") _,
+    #[cfg(target_feature="f")] out("fs10") _, #[cfg(target_feature="f")] out("fs11") _,
+    clobber_abi("C")
+  );
+  (stack, [arg])
+}
+
+/// Like [`switch`], but carries a second word in `a3`.
+///
+/// The context being resumed must be expecting two words (i.e. paused in `switch2`), otherwise the
+/// second word is simply lost.
+///
+/// # Safety
+///
+/// Behaviour is undefined if:
+/// * The stack was not paused correctly
+#[inline(always)]
+pub unsafe extern "C" fn switch2(stack: *mut usize, arg: usize, arg2: usize) -> Switch2 {
+  let (stack, [arg, arg2]) = switch2_uninit(stack, [MaybeUninit::new(arg), MaybeUninit::new(arg2)]);
+  Switch2 { stack, arg: arg.assume_init(), arg2: arg2.assume_init() }
+}
+
+/// [`switch2`], but the words may be uninitialised (e.g. padding), for
+/// [`switch_with`](crate::switch::switch_with).
+#[inline(always)]
+pub(crate) unsafe fn switch2_uninit(
+  mut stack: *mut usize, [mut arg, mut arg2]: [MaybeUninit<usize>; 2]
+) -> (*mut usize, [MaybeUninit<usize>; 2]) {
+  asm!(
+    // step 1: state preservation, exactly as `switch` does.
+    "addi sp, sp, -32", // sp = sp - 32 (reserve space on the stack)
+    "lla  ra, 2f",      // ra = endofthisfunction
+    "sd   ra, 8(sp)",  // *(sp+8) = ra (save return address)
+    "sd   fp, 0(sp)",  // *sp = fp (save frame pointer)
+    "sd   s1, 16(sp)", // *(sp+16) = s1
+    "mv   a2, sp",      // a2 = sp (save current stack pointer)
+    // step 2: state restoration and branching
+    "ld   fp, 0(a0)",  // fp = *a0 (load the frame pointer)
+    "ld   ra, 8(a0)",  // ra = *(a0 + 8) (load the return address)
+    "ld   s1, 16(a0)", // s1 = *(a0 + 16)
+    "addi sp, a0, 32",  // sp = a0 + 32 (set new sp but release the frame)
+    "jr   ra",          // transfer control back to the return address
+
+    // End of function, as taken in first instruction. register layout should now be:
+    // | register | value                   |
+    // |----------|-------------------------|
+    // | a1       | arg                     |
+    // | a2       | paused stack pointer    |
+    // | a3       | arg2                    |
+    "2:",
+    inout("a0") stack => _,
+    inout("a1") arg,
+    out("a2")   stack,
+    inout("a3") arg2,
+    // callee-saved registers that whoever we switch to is free to use.
+    out("s2") _, out("s3") _, out("s4") _, out("s5") _, out("s6") _,
+    out("s7") _, out("s8") _, out("s9") _, out("s10") _, out("s11") _,
+    #[cfg(target_feature="f")] out("fs0") _, #[cfg(target_feature="f")] out("fs1") _,
+    #[cfg(target_feature="f")] out("fs2") _, #[cfg(target_feature="f")] out("fs3") _,
+    #[cfg(target_feature="f")] out("fs4") _, #[cfg(target_feature="f")] out("fs5") _,
+    #[cfg(target_feature="f")] out("fs6") _, #[cfg(target_feature="f")] out("fs7") _,
+    #[cfg(target_feature="f")] out("fs8") _, #[cfg(target_feature="f")] out("fs9") _,
+    #[cfg(target_feature="f")] out("fs10") _, #[cfg(target_feature="f")] out("fs11") _,
+    clobber_abi("C")
+  );
+  (stack, [arg, arg2])
+}
+
+/// Like [`switch`], but carries a second word in `a3` and a third in `a4`.
+///
+/// The context being resumed must be expecting three words (i.e. paused in `switch3`), otherwise
+/// the extra words are simply lost.
+///
+/// # Safety
+///
+/// Behaviour is undefined if:
+/// * The stack was not paused correctly
+#[inline(always)]
+pub unsafe extern "C" fn switch3(
+  stack: *mut usize, arg: usize, arg2: usize, arg3: usize
+) -> Switch3 {
+  let (stack, [arg, arg2, arg3]) = switch3_uninit(stack, [MaybeUninit::new(arg), MaybeUninit::new(arg2), MaybeUninit::new(arg3)]);
+  Switch3 { stack, arg: arg.assume_init(), arg2: arg2.assume_init(), arg3: arg3.assume_init() }
+}
+
+/// [`switch3`], but the words may be uninitialised (e.g. padding), for
+/// [`switch_with`](crate::switch::switch_with).
+#[inline(always)]
+pub(crate) unsafe fn switch3_uninit(
+  mut stack: *mut usize, [mut arg, mut arg2, mut arg3]: [MaybeUninit<usize>; 3]
+) -> (*mut usize, [MaybeUninit<usize>; 3]) {
+  asm!(
+    // step 1: state preservation, exactly as `switch` does.
+    "addi sp, sp, -32", // sp = sp - 32 (reserve space on the stack)
+    "lla  ra, 2f",      // ra = endofthisfunction
+    "sd   ra, 8(sp)",  // *(sp+8) = ra (save return address)
+    "sd   fp, 0(sp)",  // *sp = fp (save frame pointer)
+    "sd   s1, 16(sp)", // *(sp+16) = s1
+    "mv   a2, sp",      // a2 = sp (save current stack pointer)
+    // step 2: state restoration and branching
+    "ld   fp, 0(a0)",  // fp = *a0 (load the frame pointer)
+    "ld   ra, 8(a0)",  // ra = *(a0 + 8) (load the return address)
+    "ld   s1, 16(a0)", // s1 = *(a0 + 16)
+    "addi sp, a0, 32",  // sp = a0 + 32 (set new sp but release the frame)
+    "jr   ra",          // transfer control back to the return address
+
+    // End of function, as taken in first instruction. register layout should now be:
+    // | register | value                   |
+    // |----------|-------------------------|
+    // | a1       | arg                     |
+    // | a2       | paused stack pointer    |
+    // | a3       | arg2                    |
+    // | a4       | arg3                    |
+    "2:",
+    inout("a0") stack => _,
+    inout("a1") arg,
+    out("a2")   stack,
+    inout("a3") arg2,
+    inout("a4") arg3,
+    // callee-saved registers that whoever we switch to is free to use.
+    out("s2") _, out("s3") _, out("s4") _, out("s5") _, out("s6") _,
+    out("s7") _, out("s8") _, out("s9") _, out("s10") _, out("s11") _,
+    #[cfg(target_feature="f")] out("fs0") _, #[cfg(target_feature="f")] out("fs1") _,
+    #[cfg(target_feature="f")] out("fs2") _, #[cfg(target_feature="f")] out("fs3") _,
+    #[cfg(target_feature="f")] out("fs4") _, #[cfg(target_feature="f")] out("fs5") _,
+    #[cfg(target_feature="f")] out("fs6") _, #[cfg(target_feature="f")] out("fs7") _,
+    #[cfg(target_feature="f")] out("fs8") _, #[cfg(target_feature="f")] out("fs9") _,
+    #[cfg(target_feature="f")] out("fs10") _, #[cfg(target_feature="f")] out("fs11") _,
+    clobber_abi("C")
+  );
+  (stack, [arg, arg2, arg3])
+}
+
+/// Like [`switch`], but also preserves our floating-point control state (the rounding mode in `fcsr`) while
+/// we're paused, so whatever we switch to can change rounding modes and the like without it leaking
+/// back into us.
+///
+/// Only the caller is protected: the state is saved above the paused frame and restored when we are
+/// resumed, so the other side may be paused however it likes.
+///
+/// # Safety
+///
+/// Behaviour is undefined if:
+/// * The stack was not paused correctly
+#[cfg(target_feature="f")]
+#[inline(always)]
+pub unsafe extern "C" fn switch_full(mut stack: *mut usize, mut arg: usize) -> Switch {
+  asm!(
+    // save the control state where `switch_ontop` won't trample it while we're paused.
+    "addi sp, sp, -16",
+    "frrm t0",          // t0 = rounding mode
+    "sd   t0, 0(sp)",
+    // state preservation, exactly as `switch` does.
+    "addi sp, sp, -32", // sp = sp - 32 (reserve space on the stack)
+    "lla  ra, 2f",      // ra = endofthisfunction
+    "sd   ra, 8(sp)",  // *(sp+8) = ra (save return address)
+    "sd   fp, 0(sp)",  // *sp = fp (save frame pointer)
+    "sd   s1, 16(sp)", // *(sp+16) = s1
+    "mv   a2, sp",      // a2 = sp (save current stack pointer)
+    // state restoration and branching
+    "ld   fp, 0(a0)",  // fp = *a0 (load the frame pointer)
+    "ld   ra, 8(a0)",  // ra = *(a0 + 8) (load the return address)
+    "ld   s1, 16(a0)", // s1 = *(a0 + 16)
+    "addi sp, a0, 32",  // sp = a0 + 32 (set new sp but release the frame)
+    "jr   ra",          // transfer control back to the return address
+
+    // End of function, as taken in first instruction. register layout should now be:
+    // | register | value                   |
+    // |----------|-------------------------|
+    // | a1       | arg                     |
+    // | a2       | paused stack pointer    |
+    "2:",
+    // we have been resumed, put our control state back.
+    "ld   t0, 0(sp)",
+    "fsrm t0",
+    "addi sp, sp, 16",
+    inout("a0") stack => _,
+    inout("a1") arg,
+    out("a2")   stack,
+    // callee-saved registers that whoever we switch to is free to use.
+    out("s2") _, out("s3") _, out("s4") _, out("s5") _, out("s6") _,
+    out("s7") _, out("s8") _, out("s9") _, out("s10") _, out("s11") _,
+    #[cfg(target_feature="f")] out("fs0") _, #[cfg(target_feature="f")] out("fs1") _,
+    #[cfg(target_feature="f")] out("fs2") _, #[cfg(target_feature="f")] out("fs3") _,
+    #[cfg(target_feature="f")] out("fs4") _, #[cfg(target_feature="f")] out("fs5") _,
+    #[cfg(target_feature="f")] out("fs6") _, #[cfg(target_feature="f")] out("fs7") _,
+    #[cfg(target_feature="f")] out("fs8") _, #[cfg(target_feature="f")] out("fs9") _,
+    #[cfg(target_feature="f")] out("fs10") _, #[cfg(target_feature="f")] out("fs11") _,
+    clobber_abi("C")
+  );
+  Switch { stack, arg }
+}
+
+/// Without floating point, there's no control state to preserve, so this is just [`switch`].
+///
+/// # Safety
+///
+/// Behaviour is undefined if:
+/// * The stack was not paused correctly
+#[cfg(not(target_feature="f"))]
+#[inline(always)]
+pub unsafe extern "C" fn switch_full(stack: *mut usize, arg: usize) -> Switch { switch(stack, arg) }
+
+/// Pauses the current stack context, then calls `fun(paused, arg)` on top of `stack` before resuming
+/// it. Whatever `fun` returns is what the resumed context's `switch` returns.
+///
+/// This is how you do things to a context that it can't do for itself, like freeing the stack it
+/// was running on or injecting work into it.
+///
+/// # Safety
+///
+/// Behaviour is undefined if:
+/// * The stack was not paused correctly
+/// * `fun` unwinds or switches away (it runs below the paused frame, which will be resumed).
+#[inline(always)]
+pub unsafe extern "C" fn switch_ontop(mut stack: *mut usize, mut arg: usize, fun: OntopFn) -> Switch {
+  asm!(
+    // step 1: state preservation, exactly as `switch` does.
+    "addi sp, sp, -32", // sp = sp - 32 (reserve space on the stack)
+    "lla  ra, 2f",      // ra = endofthisfunction
+    "sd   ra, 8(sp)",  // *(sp+8) = ra (save return address)
+    "sd   fp, 0(sp)",  // *sp = fp (save frame pointer)
+    "sd   s1, 16(sp)", // *(sp+16) = s1
+    "mv   a2, sp",      // a2 = sp (save current stack pointer)
+
+    // step 2: switch stacks. the saved state is above sp, so `fun` can't trample it.
+    "mv   sp, a0",
+
+    // step 3: call fun(paused, arg). a1 is still arg.
+    "mv   a0, a2",
+    "jalr a3",
+    // the returned `Switch` is in a0:a1. a1 is already where it needs to be.
+    "mv   a2, a0",
+
+    // step 4: state restoration (as `switch` does) and branching
+    "ld   fp, 0(sp)",  // fp = *sp (load the frame pointer)
+    "ld   ra, 8(sp)",  // ra = *(sp + 8) (load the return address)
+    "ld   s1, 16(sp)", // s1 = *(sp + 16)
+    "addi sp, sp, 32",  // release the frame
+    "jr   ra",          // transfer control back to the return address
 
     // End of function, as taken in first instruction. register layout should now be:
     // | register | value                   |
     // |----------|-------------------------|
     // | a1       | arg                     |
     // | a2       | paused stack pointer    |
-    "2:", 
+    "2:",
     inout("a0") stack => _,
     inout("a1") arg,
     out("a2")   stack,
+    inout("a3") fun => _,
+    // callee-saved registers that whoever we switch to is free to use.
+    out("s2") _, out("s3") _, out("s4") _, out("s5") _, out("s6") _,
+    out("s7") _, out("s8") _, out("s9") _, out("s10") _, out("s11") _,
+    #[cfg(target_feature="f")] out("fs0") _, #[cfg(target_feature="f")] out("fs1") _,
+    #[cfg(target_feature="f")] out("fs2") _, #[cfg(target_feature="f")] out("fs3") _,
+    #[cfg(target_feature="f")] out("fs4") _, #[cfg(target_feature="f")] out("fs5") _,
+    #[cfg(target_feature="f")] out("fs6") _, #[cfg(target_feature="f")] out("fs7") _,
+    #[cfg(target_feature="f")] out("fs8") _, #[cfg(target_feature="f")] out("fs9") _,
+    #[cfg(target_feature="f")] out("fs10") _, #[cfg(target_feature="f")] out("fs11") _,
     clobber_abi("C")
   );
   Switch { stack, arg }
 }
 
+/// Prepares a new stack so that the first `switch` to it calls `fun(paused, arg, stack)`, without
+/// running anything now.
+///
+/// Whatever `fun` needs (probably a closure) should already have been written at `stack`.
+///
+/// # Safety
+///
+/// * `stack` must be a 16-byte aligned pointer into a region with enough room below it.
+/// * `stack` must either have a guard page allocated or not overflow.
+/// * `fun` must never return.
+#[inline(always)]
+pub unsafe fn link_prepared(fun: PreparedFn, stack: *mut usize) -> *mut usize {
+  #[cfg(feature="chained-backtraces")]
+  let stack = link_parent(stack, orphan());
+  prepare_frame(fun, stack)
+}
+
+/// Like [`link_prepared`], but backtraces continue into the stack `*parent` holds when they're
+/// taken. `fun` receives the original `stack` as its third argument.
+///
+/// # Safety
+///
+/// As [`link_prepared`], plus `parent` must always hold a paused stack while the new stack runs.
+#[cfg(feature="chained-backtraces")]
+#[inline(always)]
+pub unsafe fn link_prepared_chained(fun: PreparedFn, stack: *mut usize, parent: *const *mut usize) -> *mut usize {
+  prepare_frame(fun, link_parent(stack, parent))
+}
+
+#[inline(always)]
+unsafe fn prepare_frame(fun: PreparedFn, stack: *mut usize) -> *mut usize {
+  let entry = prepared_trampoline as unsafe extern "C" fn() as usize;
+  // We just have to write out what `switch` would have saved, smuggling the function in through
+  // the frame pointer for the prepared trampoline to find.
+  let frame = stack.cast::<u8>().sub(32).cast::<usize>();
+  frame.write(fun as usize);                        // frame pointer
+  frame.add(1).write(entry);                        // return address
+  frame.add(2).write(0);                            // s1
+  // the new stack should now look like this:
+  // | frame rel | data                         |
+  // |-----------|------------------------------|
+  // | +32       | whatever the caller put here |
+  // | +2*XLEN   | s1                           |
+  // | +XLEN     | prepared trampoline          |
+  // | 0         | entrypoint function          |
+  frame
+}
+
+/// The return address and frame pointer that `switch` saved when it paused `stack`.
+#[cfg(feature="backtrace")]
+#[inline(always)]
+pub(crate) unsafe fn paused_frame(stack: *mut usize) -> (usize, *const usize) {
+  (stack.add(1).read(), stack.read() as *const usize)
+}
+
+/// Where the frame record (the caller's frame pointer, then the return address) that `fp` points
+/// to starts.
+#[cfg(feature="backtrace")]
+#[inline(always)]
+pub(crate) fn frame_record(fp: *const usize) -> *const usize {
+  // frame pointers point just above the record.
+  fp.wrapping_sub(2)
+}
+
 /* Trampoline function (terminates the call chain, becoming the first frame):
- * - called with an artificial frame.
+ * - jumped to by `link_detached`, so a0 = paused stack pointer, a1 = arg and a3 = the function.
  * - calls the function in a new frame.
  * - expects that function never to return.
  */
@@ -139,8 +491,64 @@ core::arch::global_asm!(
   ".align 16",             // put it at the start of a quadword to increase fetch perf.
   "trampoline:",
   ".cfi_startproc simple", // function prologue
+  ".cfi_def_cfa sp, 0",    // unwinders need a cfa, even just to stop here.
   ".cfi_undefined ra",     // stop unwinding at this frame
   ".cfi_undefined fp",     // stop the call chain at this frame (for gdb)
-  "call sp",               // call the function in a new stack frame.
+  "jalr a3",               // call the function in a new stack frame.
+  "unimp",                 // it's not allowed to return.
   ".cfi_endproc"           // function epilogue
 );
+
+/* Prepared trampoline (the first frame of a prepared stack):
+ * - jumped to by the first switch, so a1 = arg and a2 = paused stack pointer.
+ * - fp holds the function and sp points at whatever the linker left there for it.
+ * - expects that function never to return.
+ */
+extern "C" {
+    fn prepared_trampoline();
+}
+
+#[cfg(not(feature="chained-backtraces"))]
+core::arch::global_asm!(
+  ".global prepared_trampoline",
+  ".align 16",
+  "prepared_trampoline:",
+  ".cfi_startproc simple",
+  ".cfi_def_cfa sp, 0",    // unwinders need a cfa, even just to stop here.
+  ".cfi_undefined ra",     // stop unwinding at this frame
+  ".cfi_undefined fp",     // stop the call chain at this frame (for gdb)
+  "mv   a0, a2",           // paused stack pointer -> arg 1, a1 is already arg 2
+  "mv   a2, sp",           // the top of the stack -> arg 3
+  "mv   t0, fp",           // the function
+  "la   t1, {root}",       // the root of the call chain (null, unless `frame-pointers`)
+  "ld   fp, 0(t1)",
+  "jalr t0",               // call the function in a new stack frame.
+  "unimp",                 // it's not allowed to return.
+  ".cfi_endproc",
+  root = sym crate::switch::ROOT_FP
+);
+
+// Chained, [sp] points at wherever the parent's paused stack pointer is kept. We describe our
+// caller as living there: `switch` saved its frame right at it, same as it would in a call.
+#[cfg(feature="chained-backtraces")]
+core::arch::global_asm!(
+  ".global prepared_trampoline",
+  ".align 16",
+  "prepared_trampoline:",
+  ".cfi_startproc simple",
+  // cfa = **sp + 32 (DW_CFA_def_cfa_expression: DW_OP_breg2 0, DW_OP_deref, DW_OP_deref,
+  // DW_OP_plus_uconst 32)
+  ".cfi_escape 0x0f, 6, 0x72, 0x00, 0x06, 0x06, 0x23, 0x20",
+  ".cfi_offset s1, -16",     // where `switch` saved them
+  ".cfi_offset ra, -24",
+  ".cfi_offset fp, -32",
+  "mv   a0, a2",           // paused stack pointer -> arg 1, a1 is already arg 2
+  "addi a2, sp, 16",       // the top of the stack, above the link -> arg 3
+  "mv   t0, fp",           // the function
+  "la   t1, {root}",       // the root of the call chain (null, unless `frame-pointers`)
+  "ld   fp, 0(t1)",
+  "jalr t0",               // call the function in a new stack frame.
+  "unimp",                 // it's not allowed to return.
+  ".cfi_endproc",
+  root = sym crate::switch::ROOT_FP
+);
@@ -7,8 +7,13 @@
 //! * `sp` ought to be aligned to 16 bytes when making a function call. This is poorly enforced, but
 //!   if you don't it's liable to confuse some software and may decrease performance.
 //! * There is a 128-byte red zone below the stack we can use for leaf function storage.
-use crate::switch::{InitFn, Switch};
+//! * `r12`-`r15` are callee-saved. Whoever we switch to may trash them, so we mark them clobbered
+//!   and let the compiler spill them only if it was actually using them.
+use crate::switch::{InitFn, OntopFn, PreparedFn, Switch, Switch2, Switch3, ROOT_FP};
+#[cfg(feature="chained-backtraces")]
+use crate::switch::{link_parent, orphan};
 use core::arch::asm;
+use core::mem::MaybeUninit;
 
 /// Links a coroutine with a new stack, starting a new call stack at a trampoline.
 ///
@@ -57,6 +62,9 @@ pub unsafe extern "C" fn link_detached(
     
     // step 4: calling trampoline on the new stack.
     "xor rbx, rbx",        // zero out rbx (reset nefarious porpoise state)
+    #[cfg(feature="frame-pointers")]
+    "mov rbp, [rip + {root}]", // the root of the call chain
+    #[cfg(not(feature="frame-pointers"))]
     "xor rbp, rbp",        // zero out rbp (meaning "top of call chain")
     "lea rsp, [rdx - 16]", // set the correct stack pointer
     "jmp rcx",             // switch to trampoline
@@ -72,6 +80,10 @@ pub unsafe extern "C" fn link_detached(
     inout("rsi") arg => _,
     inout("rdx") stack,
     inout("rcx") trampoline => _,
+    #[cfg(feature="frame-pointers")]
+    root = sym ROOT_FP,
+    // callee-saved registers that whoever we switch to is free to use.
+    out("r12") _, out("r13") _, out("r14") _, out("r15") _,
     clobber_abi("C")
   );
   stack
@@ -84,7 +96,17 @@ pub unsafe extern "C" fn link_detached(
 /// Behaviour is undefined if:
 /// * The stack was not paused correctly
 #[inline(always)]
-pub unsafe extern "C" fn switch(mut stack: *mut usize, mut arg: usize) -> Switch {
+pub unsafe extern "C" fn switch(stack: *mut usize, arg: usize) -> Switch {
+  let (stack, [arg]) = switch_uninit(stack, [MaybeUninit::new(arg)]);
+  Switch { stack, arg: arg.assume_init() }
+}
+
+/// [`switch`], but the word may be uninitialised (e.g. padding), for
+/// [`switch_with`](crate::switch::switch_with).
+#[inline(always)]
+pub(crate) unsafe fn switch_uninit(
+  mut stack: *mut usize, [mut arg]: [MaybeUninit<usize>; 1]
+) -> (*mut usize, [MaybeUninit<usize>; 1]) {
   asm!(
     // spill to stack
     "lea rax, [rip + 2f]", // calculate address of end of this function with forward ref
@@ -122,11 +144,305 @@ pub unsafe extern "C" fn switch(mut stack: *mut usize, mut arg: usize) -> Switch
     out("rdx") stack,
     out("rcx") _,
     out("rax") _,
+    // callee-saved registers that whoever we switch to is free to use.
+    out("r12") _, out("r13") _, out("r14") _, out("r15") _,
+    clobber_abi("C")
+  );
+  (stack, [arg])
+}
+
+/// Like [`switch`], but carries a second word in `rcx`.
+///
+/// The context being resumed must be expecting two words (i.e. paused in `switch2`), otherwise the
+/// second word is simply lost.
+///
+/// # Safety
+///
+/// Behaviour is undefined if:
+/// * The stack was not paused correctly
+#[inline(always)]
+pub unsafe extern "C" fn switch2(stack: *mut usize, arg: usize, arg2: usize) -> Switch2 {
+  let (stack, [arg, arg2]) = switch2_uninit(stack, [MaybeUninit::new(arg), MaybeUninit::new(arg2)]);
+  Switch2 { stack, arg: arg.assume_init(), arg2: arg2.assume_init() }
+}
+
+/// [`switch2`], but the words may be uninitialised (e.g. padding), for
+/// [`switch_with`](crate::switch::switch_with).
+#[inline(always)]
+pub(crate) unsafe fn switch2_uninit(
+  mut stack: *mut usize, [mut arg, mut arg2]: [MaybeUninit<usize>; 2]
+) -> (*mut usize, [MaybeUninit<usize>; 2]) {
+  asm!(
+    // spill to stack, exactly as `switch` does.
+    "lea rax, [rip + 2f]",
+    "mov [rsp - 8],  rax",
+    "mov [rsp - 16], rbp",
+    "mov [rsp - 24], rbx",
+    // switch stacks
+    "mov rdx, rsp",
+    "mov rsp, rdi",
+    // restore and branch
+    "mov rbx, [rdi - 24]",
+    "mov rbp, [rdi - 16]",
+    "mov rax, [rdi - 8]",
+    "jmp rax",
+
+    // our internal calling convention is this:
+    // | register | value                   |
+    // |----------|-------------------------|
+    // | rsi      | arg                     |
+    // | rcx      | arg2                    |
+    // | rdx      | paused stack pointer    |
+    "2:",
+    inout("rdi") stack => _,
+    inout("rsi") arg,
+    inout("rcx") arg2,
+    out("rdx") stack,
+    out("rax") _,
+    // callee-saved registers that whoever we switch to is free to use.
+    out("r12") _, out("r13") _, out("r14") _, out("r15") _,
+    clobber_abi("C")
+  );
+  (stack, [arg, arg2])
+}
+
+/// Like [`switch`], but carries a second word in `rcx` and a third in `r8`.
+///
+/// The context being resumed must be expecting three words (i.e. paused in `switch3`), otherwise
+/// the extra words are simply lost.
+///
+/// # Safety
+///
+/// Behaviour is undefined if:
+/// * The stack was not paused correctly
+#[inline(always)]
+pub unsafe extern "C" fn switch3(
+  stack: *mut usize, arg: usize, arg2: usize, arg3: usize
+) -> Switch3 {
+  let (stack, [arg, arg2, arg3]) = switch3_uninit(stack, [MaybeUninit::new(arg), MaybeUninit::new(arg2), MaybeUninit::new(arg3)]);
+  Switch3 { stack, arg: arg.assume_init(), arg2: arg2.assume_init(), arg3: arg3.assume_init() }
+}
+
+/// [`switch3`], but the words may be uninitialised (e.g. padding), for
+/// [`switch_with`](crate::switch::switch_with).
+#[inline(always)]
+pub(crate) unsafe fn switch3_uninit(
+  mut stack: *mut usize, [mut arg, mut arg2, mut arg3]: [MaybeUninit<usize>; 3]
+) -> (*mut usize, [MaybeUninit<usize>; 3]) {
+  asm!(
+    // spill to stack, exactly as `switch` does.
+    "lea rax, [rip + 2f]",
+    "mov [rsp - 8],  rax",
+    "mov [rsp - 16], rbp",
+    "mov [rsp - 24], rbx",
+    // switch stacks
+    "mov rdx, rsp",
+    "mov rsp, rdi",
+    // restore and branch
+    "mov rbx, [rdi - 24]",
+    "mov rbp, [rdi - 16]",
+    "mov rax, [rdi - 8]",
+    "jmp rax",
+
+    // our internal calling convention is this:
+    // | register | value                   |
+    // |----------|-------------------------|
+    // | rsi      | arg                     |
+    // | rcx      | arg2                    |
+    // | r8       | arg3                    |
+    // | rdx      | paused stack pointer    |
+    "2:",
+    inout("rdi") stack => _,
+    inout("rsi") arg,
+    inout("rcx") arg2,
+    inout("r8")  arg3,
+    out("rdx") stack,
+    out("rax") _,
+    // callee-saved registers that whoever we switch to is free to use.
+    out("r12") _, out("r13") _, out("r14") _, out("r15") _,
+    clobber_abi("C")
+  );
+  (stack, [arg, arg2, arg3])
+}
+
+/// Like [`switch`], but also preserves our floating-point control state (MXCSR and the x87 control word) while
+/// we're paused, so whatever we switch to can change rounding modes and the like without it leaking
+/// back into us.
+///
+/// Only the caller is protected: the state is saved above the paused frame and restored when we are
+/// resumed, so the other side may be paused however it likes.
+///
+/// # Safety
+///
+/// Behaviour is undefined if:
+/// * The stack was not paused correctly
+#[inline(always)]
+pub unsafe extern "C" fn switch_full(mut stack: *mut usize, mut arg: usize) -> Switch {
+  asm!(
+    // save the control state where `switch_ontop` won't trample it while we're paused.
+    "sub rsp, 16",
+    "stmxcsr dword ptr [rsp]",
+    "fnstcw  word ptr [rsp + 4]",
+    // spill to stack, exactly as `switch` does.
+    "lea rax, [rip + 2f]",
+    "mov [rsp - 8],  rax",
+    "mov [rsp - 16], rbp",
+    "mov [rsp - 24], rbx",
+    // switch stacks
+    "mov rdx, rsp",
+    "mov rsp, rdi",
+    // restore and branch
+    "mov rbx, [rdi - 24]",
+    "mov rbp, [rdi - 16]",
+    "mov rax, [rdi - 8]",
+    "jmp rax",
+
+    // our internal calling convention is this:
+    // | register | value                   |
+    // |----------|-------------------------|
+    // | rsi      | arg                     |
+    // | rdx      | paused stack pointer    |
+    "2:",
+    // we have been resumed, put our control state back.
+    "ldmxcsr dword ptr [rsp]",
+    "fldcw   word ptr [rsp + 4]",
+    "add rsp, 16",
+    inout("rdi") stack => _,
+    inout("rsi") arg,
+    out("rdx") stack,
+    out("rax") _,
+    // callee-saved registers that whoever we switch to is free to use.
+    out("r12") _, out("r13") _, out("r14") _, out("r15") _,
+    clobber_abi("C")
+  );
+  Switch { stack, arg }
+}
+
+/// Pauses the current stack context, then calls `fun(paused, arg)` on top of `stack` before resuming
+/// it. Whatever `fun` returns is what the resumed context's `switch` returns.
+///
+/// This is how you do things to a context that it can't do for itself, like freeing the stack it
+/// was running on or injecting work into it.
+///
+/// # Safety
+///
+/// Behaviour is undefined if:
+/// * The stack was not paused correctly
+/// * `fun` unwinds or switches away (it runs below the paused frame, which will be resumed).
+#[inline(always)]
+pub unsafe extern "C" fn switch_ontop(mut stack: *mut usize, mut arg: usize, fun: OntopFn) -> Switch {
+  asm!(
+    // step 1: spill to stack, exactly as `switch` does.
+    "lea rax, [rip + 2f]",
+    "mov [rsp - 8],  rax",
+    "mov [rsp - 16], rbp",
+    "mov [rsp - 24], rbx",
+
+    // step 2: switch stacks, stepping over the saved state so `fun` can't trample it.
+    "mov rdx, rsp",        // save current stack pointer into rdx
+    "lea rsp, [rdi - 32]", // 32 rather than 24 to keep the stack aligned for the call
+    // the new stack should now look like this:
+    // | rdi rel | data           |
+    // |---------|----------------|
+    // | -8      | return address |
+    // | -16     | frame pointer  |
+    // | -24     | llvm obscurity |
+    // | -32     | (padding)      |
+
+    // step 3: call fun(paused, arg). rsi is still arg.
+    "mov rdi, rdx",
+    "call rcx",
+    // the returned `Switch` is in rax:rdx. rearrange it into our internal calling convention.
+    "mov rsi, rdx",
+    "mov rdx, rax",
+
+    // step 4: state restoration (as `switch` does) and branching
+    "lea rsp, [rsp + 32]",
+    "mov rbx, [rsp - 24]",
+    "mov rbp, [rsp - 16]",
+    "mov rax, [rsp - 8]",
+    "jmp rax",
+
+    // our internal calling convention is this:
+    // | register | value                   |
+    // |----------|-------------------------|
+    // | rsi      | arg                     |
+    // | rdx      | paused stack pointer    |
+    "2:",
+    inout("rdi") stack => _,
+    inout("rsi") arg,
+    inout("rcx") fun => _,
+    out("rdx") stack,
+    out("rax") _,
+    // callee-saved registers that whoever we switch to is free to use.
+    out("r12") _, out("r13") _, out("r14") _, out("r15") _,
     clobber_abi("C")
   );
   Switch { stack, arg }
 }
 
+/// Prepares a new stack so that the first `switch` to it calls `fun(paused, arg, stack)`, without
+/// running anything now.
+///
+/// Whatever `fun` needs (probably a closure) should already have been written at `stack`.
+///
+/// # Safety
+///
+/// * `stack` must be a 16-byte aligned pointer into a region with enough room below it.
+/// * `stack` must either have a guard page allocated or not overflow.
+/// * `fun` must never return.
+#[inline(always)]
+pub unsafe fn link_prepared(fun: PreparedFn, stack: *mut usize) -> *mut usize {
+  #[cfg(feature="chained-backtraces")]
+  let stack = link_parent(stack, orphan());
+  prepare_frame(fun, stack)
+}
+
+/// Like [`link_prepared`], but backtraces continue into the stack `*parent` holds when they're
+/// taken. `fun` receives the original `stack` as its third argument.
+///
+/// # Safety
+///
+/// As [`link_prepared`], plus `parent` must always hold a paused stack while the new stack runs.
+#[cfg(feature="chained-backtraces")]
+#[inline(always)]
+pub unsafe fn link_prepared_chained(fun: PreparedFn, stack: *mut usize, parent: *const *mut usize) -> *mut usize {
+  prepare_frame(fun, link_parent(stack, parent))
+}
+
+#[inline(always)]
+unsafe fn prepare_frame(fun: PreparedFn, stack: *mut usize) -> *mut usize {
+  let entry = prepared_trampoline as unsafe extern "C" fn() as usize;
+  // We just have to write out what `switch` would have saved, picking registers that the
+  // prepared trampoline will find things in.
+  stack.sub(1).write(entry);                        // return address
+  stack.sub(2).write(ROOT_FP.0 as usize);           // frame pointer (the root of the call chain)
+  stack.sub(3).write(fun as usize);                 // rbx
+  // the new stack should now look like this:
+  // | rsp rel | data                         |
+  // |---------|------------------------------|
+  // | +0      | whatever the caller put here |
+  // | -8      | prepared trampoline          |
+  // | -16     | root frame pointer           |
+  // | -24     | entrypoint function          |
+  stack
+}
+
+/// The return address and frame pointer that `switch` saved when it paused `stack`.
+#[cfg(feature="backtrace")]
+#[inline(always)]
+pub(crate) unsafe fn paused_frame(stack: *mut usize) -> (usize, *const usize) {
+  (stack.sub(1).read(), stack.sub(2).read() as *const usize)
+}
+
+/// Where the frame record (the caller's frame pointer, then the return address) that `fp` points
+/// to starts.
+#[cfg(feature="backtrace")]
+#[inline(always)]
+pub(crate) fn frame_record(fp: *const usize) -> *const usize {
+  fp
+}
+
 /* Trampoline function (terminates the call chain, becoming the first frame):
  * - called with an artificial frame.
  * - calls the function in a new frame.
@@ -141,8 +457,54 @@ core::arch::global_asm!(
   ".align 16",             // put it at the start of a quadword to increase fetch perf.
   "trampoline:",
   ".cfi_startproc simple", // function prologue
+  ".cfi_def_cfa rsp, 16",  // unwinders need a cfa, even just to stop here.
   ".cfi_undefined rip",    // stop unwinding at this frame
   ".cfi_undefined rsp",    // stop the call chain at this frame (for gdb)
   "call [rsp]",            // call the function in a new stack frame.
   ".cfi_endproc"           // function epilogue
 );
+
+/* Prepared trampoline (the first frame of a prepared stack):
+ * - jumped to by the first switch, so rsi = arg and rdx = paused stack pointer.
+ * - rbx holds the function and rsp points at whatever the linker left there for it.
+ * - expects that function never to return.
+ */
+extern "C" {
+    fn prepared_trampoline();
+}
+
+#[cfg(not(feature="chained-backtraces"))]
+core::arch::global_asm!(
+  ".global prepared_trampoline",
+  ".align 16",
+  "prepared_trampoline:",
+  ".cfi_startproc simple",
+  ".cfi_def_cfa rsp, 0",   // unwinders need a cfa, even just to stop here.
+  ".cfi_undefined rip",    // stop unwinding at this frame
+  ".cfi_undefined rsp",    // stop the call chain at this frame (for gdb)
+  "mov rdi, rdx",          // paused stack pointer -> arg 1, rsi is already arg 2
+  "mov rdx, rsp",          // the top of the stack -> arg 3
+  "call rbx",              // call the function in a new stack frame.
+  "ud2",                   // it's not allowed to return.
+  ".cfi_endproc"
+);
+
+// Chained, [rsp] points at wherever the parent's paused stack pointer is kept. We describe our
+// caller as living there: `switch` saved its frame just below it, same as it would in a call.
+#[cfg(feature="chained-backtraces")]
+core::arch::global_asm!(
+  ".global prepared_trampoline",
+  ".align 16",
+  "prepared_trampoline:",
+  ".cfi_startproc simple",
+  // cfa = **rsp (DW_CFA_def_cfa_expression: DW_OP_breg7 0, DW_OP_deref, DW_OP_deref)
+  ".cfi_escape 0x0f, 4, 0x77, 0x00, 0x06, 0x06",
+  ".cfi_offset rip, -8",   // where `switch` saved them
+  ".cfi_offset rbp, -16",
+  ".cfi_offset rbx, -24",
+  "mov rdi, rdx",          // paused stack pointer -> arg 1, rsi is already arg 2
+  "lea rdx, [rsp + 16]",   // the top of the stack, above the link -> arg 3
+  "call rbx",              // call the function in a new stack frame.
+  "ud2",                   // it's not allowed to return.
+  ".cfi_endproc"
+);
@@ -1,7 +1,10 @@
 //! x86 is a bit limited on registers, so we have to be slightly creative. We use the fastcall ABI
 //! to get two parameters into registers and the third goes on the stack.
-use crate::switch::{InitFn, Switch};
+use crate::switch::{InitFn, OntopFn, PreparedFn, Switch, Switch2, Switch3, ROOT_FP};
+#[cfg(feature="chained-backtraces")]
+use crate::switch::{link_parent, orphan};
 use core::arch::asm;
+use core::mem::MaybeUninit;
 
 /// Links a coroutine with a new stack, starting a new call stack at a trampoline.
 ///
@@ -16,105 +19,512 @@ pub unsafe extern "fastcall" fn link_detached(
   mut stack: *mut usize, // the end of a stack region.
 ) -> *mut usize {
   // Step 1: setting up the new stack. We do this in rust space to reduce register pressure.
-  unsafe {
-    stack.sub(1).write(trampoline as usize);
-    stack.sub(2).write(fun as usize);
-  }
-  // the new stack should now look like this:
-  // | end rel | data                |
-  // |---------|---------------------|
-  // | -4      | trampoline function |
-  // | -8      | entrypoint function |
+  unsafe { stack.sub(2).write(fun as usize); }
+  // Where step 4 finds the root of the call chain. We've no registers to spare, and no
+  // eip-relative addressing to load it with.
+  #[cfg(feature="frame-pointers")]
+  unsafe { stack.sub(1).write(ROOT_FP.0 as usize); }
   asm!(
+    // there's no eip-relative addressing, so we let `call` work out the resume address for us.
+    "call 3f",             // push the address of label 2 and jump to the switch
+    "2:",
+    "jmp 4f",              // we have been resumed, skip over the switch.
+    "3:",
+    "pop ecx",             // ecx = resume address
+    // step 2: spill to stack, exactly as `switch` does.
+    "lea esp, [esp - 24]", // make space for the 4 words on the stack
+    "mov [esp + 12], esi", // save esi (llvm won't let us clobber it)
+    "mov [esp + 8],  ebp", // save the frame pointer (we aren't allowed to clobber it)
+    "mov [esp + 4],  ebx", // save llvm's nefarious porpoises register (no clobbering)
+    "mov [esp],      ecx", // save the resume address
+    "lea ecx, [esp + 12]", // paused stack pointer -> ecx
+
+    // step 3: the trampoline's arguments go on the new stack.
+    "mov [eax - 16], ecx", // paused stack pointer -> arg 1
+    "mov [eax - 12], edx", // arg -> arg 2
+    // the new stack should now look like this:
+    // | end rel | data                 |
+    // |---------|----------------------|
+    // | -4      | root frame pointer   |
+    // | -8      | entrypoint function  |
+    // | -12     | arg                  |
+    // | -16     | paused stack pointer |
+
+    // step 4: calling trampoline on the new stack.
+    #[cfg(feature="frame-pointers")]
+    "mov ebp, [eax - 4]",  // the root of the call chain
+    #[cfg(not(feature="frame-pointers"))]
+    "xor ebp, ebp",        // zero out ebp (meaning "top of call chain")
+    "lea esp, [eax - 16]", // set the correct stack pointer
+    "jmp edi",             // switch to trampoline
 
-    // step 2: state preservation. we must spill our state to the stack so we may be resumed.
-    "lea esp, [esp - 12]", // make space for the 3 words on the stack
-    "mov [esp - 4],  ebp", // save the frame pointer in case it is used
-    "mov [esp - 8],  ebx", // save llvm's nefarious porpoises register.
-    "lea ecx, [eip + 2f]", // calculate address of end of this function with forward ref
-    "mov [esp - 12], ecx", // save end of function as the return address
+    // End of function, as taken in first instruction. register layout should now be:
+    // our internal calling convention is this:
+    // | register | value                   |
+    // |----------|-------------------------|
+    // | edx      | arg                     |
+    // | eax      | paused stack pointer    |
+    "4:",
+    inout("edx") arg => _,
+    inout("eax") stack,
+    inout("edi") trampoline => _,
+    clobber_abi("fastcall")
+  );
+  stack
+}
+
+/// Pauses the current stack context and resumes another.
+///
+/// # Safety
+///
+/// Behaviour is undefined if:
+/// * The stack was not paused correctly
+#[inline(always)]
+pub unsafe extern "fastcall" fn switch(stack: *mut usize, arg: usize) -> Switch {
+  let (stack, [arg]) = switch_uninit(stack, [MaybeUninit::new(arg)]);
+  Switch { stack, arg: arg.assume_init() }
+}
+
+/// [`switch`], but the word may be uninitialised (e.g. padding), for
+/// [`switch_with`](crate::switch::switch_with).
+#[inline(always)]
+pub(crate) unsafe fn switch_uninit(
+  mut stack: *mut usize, [mut arg]: [MaybeUninit<usize>; 1]
+) -> (*mut usize, [MaybeUninit<usize>; 1]) {
+  asm!(
+    // there's no eip-relative addressing, so we let `call` work out the resume address for us.
+    "call 3f",             // push the address of label 2 and jump to the switch
+    "2:",
+    "jmp 4f",              // we have been resumed, skip over the switch.
+    "3:",
+    "pop eax",             // eax = resume address
+    // spill to stack. the paused stack pointer is 12 below where we started.
+    "lea esp, [esp - 24]", // make space for the 4 words on the stack
+    "mov [esp + 12], esi", // save esi (llvm won't let us clobber it)
+    "mov [esp + 8],  ebp", // save the frame pointer (we aren't allowed to clobber it)
+    "mov [esp + 4],  ebx", // save llvm's nefarious porpoises register (no clobbering)
+    "mov [esp],      eax", // save the resume address
+    "lea eax, [esp + 12]", // paused stack pointer -> eax
     // our stack should now look like this:
-    // | esp rel | data           |
+    // | eax rel | data           |
     // |---------|----------------|
+    // | 0       | esi            |
     // | -4      | frame pointer  |
     // | -8      | llvm obscurity |
     // | -12     | return address |
 
-    // step 3: setting up parameters
-    "mov ecx, esp", // current stack pointer -> arg 1
+    // state restoration (inverse of preservation) and branching
+    "mov esi, [ecx]",
+    "mov ebx, [ecx - 8]",
+    "mov ebp, [ecx - 4]",
+    "lea esp, [ecx + 12]", // reset the stack pointer
+    "jmp [ecx - 12]",
 
-    // argument layout should now be:
+    // our internal calling convention is this:
     // | register | value                   |
     // |----------|-------------------------|
-    // | rdi      | fun (closure parameter) |
-    // | rsi      | rsp (our stack pointer) |
-    
-    // step 4: calling trampoline on the new stack.
-    "xor ebx, ebx",        // zero out rbx
-    "xor ebp, ebp",        // zero out rbp (meaning "top of call chain")
-    "lea esp, [eax - 16]", // set the correct stack pointer
-    "jmp [eax - 8]",       // switch to trampoline
-    
-    // End of function, as taken in first instruction. register layout should now be:
+    // | edx      | arg                     |
+    // | eax      | paused stack pointer    |
+    "4:",
+    inout("ecx") stack => _,
+    inout("edx") arg,
+    out("eax") stack,
+    out("edi") _,
+    clobber_abi("fastcall")
+  );
+  (stack, [arg])
+}
+
+/// Like [`switch`], but carries a second word in `edi`.
+///
+/// The context being resumed must be expecting two words (i.e. paused in `switch2`), otherwise the
+/// second word is simply lost.
+///
+/// # Safety
+///
+/// Behaviour is undefined if:
+/// * The stack was not paused correctly
+#[inline(always)]
+pub unsafe extern "fastcall" fn switch2(stack: *mut usize, arg: usize, arg2: usize) -> Switch2 {
+  let (stack, [arg, arg2]) = switch2_uninit(stack, [MaybeUninit::new(arg), MaybeUninit::new(arg2)]);
+  Switch2 { stack, arg: arg.assume_init(), arg2: arg2.assume_init() }
+}
+
+/// [`switch2`], but the words may be uninitialised (e.g. padding), for
+/// [`switch_with`](crate::switch::switch_with).
+#[inline(always)]
+pub(crate) unsafe fn switch2_uninit(
+  mut stack: *mut usize, [mut arg, mut arg2]: [MaybeUninit<usize>; 2]
+) -> (*mut usize, [MaybeUninit<usize>; 2]) {
+  asm!(
+    // there's no eip-relative addressing, so we let `call` work out the resume address for us.
+    "call 3f",             // push the address of label 2 and jump to the switch
+    "2:",
+    "jmp 4f",              // we have been resumed, skip over the switch.
+    "3:",
+    "pop eax",             // eax = resume address
+    // spill to stack. the paused stack pointer is 12 below where we started, as for `switch`.
+    "lea esp, [esp - 24]", // make space for the 4 words on the stack
+    "mov [esp + 12], esi", // save esi (llvm won't let us clobber it)
+    "mov [esp + 8],  ebp", // save the frame pointer (we aren't allowed to clobber it)
+    "mov [esp + 4],  ebx", // save llvm's nefarious porpoises register (no clobbering)
+    "mov [esp],      eax", // save the resume address
+    "lea eax, [esp + 12]", // paused stack pointer -> eax
+    // our stack should now look like this:
+    // | eax rel | data           |
+    // |---------|----------------|
+    // | 0       | esi            |
+    // | -4      | frame pointer  |
+    // | -8      | llvm obscurity |
+    // | -12     | return address |
+
+    // state restoration and branching
+    "mov esi, [ecx]",
+    "mov ebx, [ecx - 8]",
+    "mov ebp, [ecx - 4]",
+    "lea esp, [ecx + 12]", // reset the stack pointer
+    "jmp [ecx - 12]",
+
     // our internal calling convention is this:
     // | register | value                   |
     // |----------|-------------------------|
     // | edx      | arg                     |
+    // | edi      | arg2                    |
     // | eax      | paused stack pointer    |
+    "4:",
+    inout("ecx") stack => _,
+    inout("edx") arg,
+    inout("edi") arg2,
+    out("eax") stack,
+    clobber_abi("fastcall")
+  );
+  (stack, [arg, arg2])
+}
 
+/// Like [`switch`], but carries a second word in `edi` and a third in `ecx`.
+///
+/// We're out of registers, so the third word travels on the paused stack just below the frame and
+/// is picked up by the resumed side.
+///
+/// The context being resumed must be expecting three words (i.e. paused in `switch3`), otherwise
+/// the extra words are simply lost.
+///
+/// # Safety
+///
+/// Behaviour is undefined if:
+/// * The stack was not paused correctly
+#[inline(always)]
+pub unsafe extern "fastcall" fn switch3(
+  stack: *mut usize, arg: usize, arg2: usize, arg3: usize
+) -> Switch3 {
+  let (stack, [arg, arg2, arg3]) = switch3_uninit(stack, [MaybeUninit::new(arg), MaybeUninit::new(arg2), MaybeUninit::new(arg3)]);
+  Switch3 { stack, arg: arg.assume_init(), arg2: arg2.assume_init(), arg3: arg3.assume_init() }
+}
+
+/// [`switch3`], but the words may be uninitialised (e.g. padding), for
+/// [`switch_with`](crate::switch::switch_with).
+#[inline(always)]
+pub(crate) unsafe fn switch3_uninit(
+  mut stack: *mut usize, [mut arg, mut arg2, mut arg3]: [MaybeUninit<usize>; 3]
+) -> (*mut usize, [MaybeUninit<usize>; 3]) {
+  asm!(
+    "call 3f",             // push the address of label 2 and jump to the switch
     "2:",
-    inout("edx") arg => _,
-    inout("eax") stack,
+    "mov ecx, [eax - 16]", // we have been resumed, pick up the third word from the paused stack.
+    "jmp 4f",              // skip over the switch.
+    "3:",
+    // spill to stack. the paused stack pointer is 12 below where we started, as for `switch`.
+    "lea esp, [esp - 24]", // make space for the 5 words on the stack
+    "mov [esp],      eax", // save the third word
+    "mov [esp + 16], esi", // save esi (llvm won't let us clobber it)
+    "mov [esp + 12], ebp", // save the frame pointer (we aren't allowed to clobber it)
+    "mov [esp + 8],  ebx", // save llvm's nefarious porpoises register (no clobbering)
+    "mov eax, [esp + 24]", // eax = resume address, as pushed by the call
+    "mov [esp + 4],  eax", // save the resume address
+    "lea eax, [esp + 16]", // paused stack pointer -> eax
+    // our stack should now look like this:
+    // | eax rel | data           |
+    // |---------|----------------|
+    // | 0       | esi            |
+    // | -4      | frame pointer  |
+    // | -8      | llvm obscurity |
+    // | -12     | return address |
+    // | -16     | third word     |
+
+    // state restoration and branching
+    "mov esi, [ecx]",
+    "mov ebx, [ecx - 8]",
+    "mov ebp, [ecx - 4]",
+    "lea esp, [ecx + 12]", // reset the stack pointer
+    "jmp [ecx - 12]",
+    "4:",
+    inout("ecx") stack => arg3,
+    inout("edx") arg,
+    inout("edi") arg2,
+    inout("eax") arg3 => stack,
     clobber_abi("fastcall")
   );
-  stack
+  (stack, [arg, arg2, arg3])
 }
 
-/// Pauses the current stack context and resumes another.
+/// Like [`switch`], but also preserves our floating-point control state (MXCSR and the x87 control word) while
+/// we're paused, so whatever we switch to can change rounding modes and the like without it leaking
+/// back into us.
+///
+/// Only the caller is protected: the state is saved above the paused frame and restored when we are
+/// resumed, so the other side may be paused however it likes.
 ///
 /// # Safety
 ///
 /// Behaviour is undefined if:
 /// * The stack was not paused correctly
+#[cfg(target_feature="sse")]
 #[inline(always)]
-pub unsafe extern "fastcall" fn switch(mut stack: *mut usize, mut arg: usize) -> Switch {
+pub unsafe extern "fastcall" fn switch_full(mut stack: *mut usize, mut arg: usize) -> Switch {
   asm!(
-    // spill to stack
-    "lea esp, [esp - 12]", // make space for the 3 words on the stack
-    "mov [esp - 4],  ebp", // save the frame pointer (we aren't allowed to clobber it)
-    "mov [esp - 8],  ebx", // save llvm's nefarious porpoises register (no clobbering)
-    "lea eax, [eip + 2f]", // calculate address of end of this function with forward ref
-    "mov [esp - 12], eax", // save end of function as the return address
-
-    // switch stacks
-    "mov eax, esp",        // save current stack pointer into eax
-    "mov esp, ecx",        // load new stack pointer
-
-    // step 2: state restoration (inverse of preservation) and branching
-    "mov eax, [ecx - 12]",
-    "mov ebp, [ecx - 8]",
-    "mov eax, [ecx - 4]",
-    "lea esp, [esp + 12]", // reset the stack pointer
-    "jmp eax",
+    // save the control state where it'll be at the top of the stack when we're resumed, rather
+    // than below it where a signal handler could trample it.
+    "sub esp, 16",
+    "stmxcsr dword ptr [esp]",
+    "fnstcw  word ptr [esp + 4]",
+    "call 3f",             // push the address of label 2 and jump to the switch
+    "2:",
+    // we have been resumed, put our control state back.
+    "ldmxcsr dword ptr [esp]",
+    "fldcw   word ptr [esp + 4]",
+    "add esp, 16",
+    "jmp 4f",              // skip over the switch.
+    "3:",
+    "pop eax",             // eax = resume address
+    // spill to stack, exactly as `switch2` does.
+    "lea esp, [esp - 24]",
+    "mov [esp + 12], esi",
+    "mov [esp + 8],  ebp",
+    "mov [esp + 4],  ebx",
+    "mov [esp],      eax",
+    "lea eax, [esp + 12]", // paused stack pointer -> eax
+
+    // state restoration and branching
+    "mov esi, [ecx]",
+    "mov ebx, [ecx - 8]",
+    "mov ebp, [ecx - 4]",
+    "lea esp, [ecx + 12]", // reset the stack pointer
+    "jmp [ecx - 12]",
 
     // our internal calling convention is this:
     // | register | value                   |
     // |----------|-------------------------|
     // | edx      | arg                     |
     // | eax      | paused stack pointer    |
+    "4:",
+    inout("ecx") stack => _,
+    inout("edx") arg,
+    out("eax") stack,
+    out("edi") _,
+    clobber_abi("fastcall")
+  );
+  Switch { stack, arg }
+}
+
+/// Like [`switch`], but also preserves our floating-point control state (the x87 control word) while
+/// we're paused, so whatever we switch to can change rounding modes and the like without it leaking
+/// back into us.
+///
+/// Only the caller is protected: the state is saved above the paused frame and restored when we are
+/// resumed, so the other side may be paused however it likes.
+///
+/// # Safety
+///
+/// Behaviour is undefined if:
+/// * The stack was not paused correctly
+#[cfg(not(target_feature="sse"))]
+#[inline(always)]
+pub unsafe extern "fastcall" fn switch_full(mut stack: *mut usize, mut arg: usize) -> Switch {
+  asm!(
+    // save the control state where it'll be at the top of the stack when we're resumed, rather
+    // than below it where a signal handler could trample it.
+    "sub esp, 16",
+    "fnstcw  word ptr [esp + 4]",
+    "call 3f",             // push the address of label 2 and jump to the switch
+    "2:",
+    // we have been resumed, put our control state back.
+    "fldcw   word ptr [esp + 4]",
+    "add esp, 16",
+    "jmp 4f",              // skip over the switch.
+    "3:",
+    "pop eax",             // eax = resume address
+    // spill to stack, exactly as `switch2` does.
+    "lea esp, [esp - 24]",
+    "mov [esp + 12], esi",
+    "mov [esp + 8],  ebp",
+    "mov [esp + 4],  ebx",
+    "mov [esp],      eax",
+    "lea eax, [esp + 12]", // paused stack pointer -> eax
+
+    // state restoration and branching
+    "mov esi, [ecx]",
+    "mov ebx, [ecx - 8]",
+    "mov ebp, [ecx - 4]",
+    "lea esp, [ecx + 12]", // reset the stack pointer
+    "jmp [ecx - 12]",
 
-    // the end of the function, always called into by resume()
-    "2:", 
+    // our internal calling convention is this:
+    // | register | value                   |
+    // |----------|-------------------------|
+    // | edx      | arg                     |
+    // | eax      | paused stack pointer    |
+    "4:",
     inout("ecx") stack => _,
-    inout("edx") arg => _,
+    inout("edx") arg,
+    out("eax") stack,
+    out("edi") _,
+    clobber_abi("fastcall")
+  );
+  Switch { stack, arg }
+}
+
+/// Pauses the current stack context, then calls `fun(paused, arg)` on top of `stack` before resuming
+/// it. Whatever `fun` returns is what the resumed context's `switch` returns.
+///
+/// This is how you do things to a context that it can't do for itself, like freeing the stack it
+/// was running on or injecting work into it.
+///
+/// # Safety
+///
+/// Behaviour is undefined if:
+/// * The stack was not paused correctly
+/// * `fun` unwinds or switches away (it runs below the paused frame, which will be resumed).
+#[inline(always)]
+pub unsafe extern "fastcall" fn switch_ontop(mut stack: *mut usize, mut arg: usize, fun: OntopFn) -> Switch {
+  asm!(
+    "call 3f",             // push the address of label 2 and jump to the switch
+    "2:",
+    "jmp 4f",              // we have been resumed, skip over the switch.
+    "3:",
+    "pop eax",             // eax = resume address
+    // step 1: spill to stack, exactly as `switch2` does.
+    "lea esp, [esp - 24]",
+    "mov [esp + 12], esi",
+    "mov [esp + 8],  ebp",
+    "mov [esp + 4],  ebx",
+    "mov [esp],      eax",
+    "lea eax, [esp + 12]", // paused stack pointer -> eax
+
+    // step 2: switch stacks, stepping over the saved state so `fun` can't trample it. we need
+    // room for three arguments and the 8 bytes of `Switch` that `fun` returns through a pointer.
+    "lea esp, [ecx - 36]",
+    // the new stack should now look like this:
+    // | esp rel | ecx rel | data                 |
+    // |---------|---------|----------------------|
+    // | +36     | 0       | esi                  |
+    // | +32     | -4      | frame pointer        |
+    // | +28     | -8      | llvm obscurity       |
+    // | +24     | -12     | return address       |
+    // | +12     | -24     | `Switch` return slot |
+    // | +8      | -28     | arg                  |
+    // | +4      | -32     | paused stack pointer |
+    // | 0       | -36     | return slot pointer  |
+
+    // step 3: call fun(paused, arg), which pops the return slot pointer on its way out.
+    "mov [esp + 8], edx",
+    "mov [esp + 4], eax",
+    "lea eax, [esp + 12]",
+    "mov [esp],     eax",
+    "call edi",
+    "mov eax, [esp + 8]",  // returned stack
+    "mov edx, [esp + 12]", // returned arg
+    "lea ecx, [esp + 32]", // the new stack pointer, as it was passed in
+
+    // step 4: state restoration (as `switch` does) and branching
+    "mov esi, [ecx]",
+    "mov ebx, [ecx - 8]",
+    "mov ebp, [ecx - 4]",
+    "lea esp, [ecx + 12]", // reset the stack pointer
+    "jmp [ecx - 12]",
+
+    // our internal calling convention is this:
+    // | register | value                   |
+    // |----------|-------------------------|
+    // | edx      | arg                     |
+    // | eax      | paused stack pointer    |
+    "4:",
+    inout("ecx") stack => _,
+    inout("edx") arg,
+    inout("edi") fun => _,
     out("eax") stack,
     clobber_abi("fastcall")
   );
   Switch { stack, arg }
 }
 
+/// Prepares a new stack so that the first `switch` to it calls `fun(paused, arg, stack)`, without
+/// running anything now.
+///
+/// Whatever `fun` needs (probably a closure) should already have been written at `stack`.
+///
+/// # Safety
+///
+/// * `stack` must be a 16-byte aligned pointer into a region with enough room below it.
+/// * `stack` must either have a guard page allocated or not overflow.
+/// * `fun` must never return.
+#[inline(always)]
+pub unsafe fn link_prepared(fun: PreparedFn, stack: *mut usize) -> *mut usize {
+  #[cfg(feature="chained-backtraces")]
+  let stack = link_parent(stack, orphan());
+  prepare_frame(fun, stack)
+}
+
+/// Like [`link_prepared`], but backtraces continue into the stack `*parent` holds when they're
+/// taken. `fun` receives the original `stack` as its third argument.
+///
+/// # Safety
+///
+/// As [`link_prepared`], plus `parent` must always hold a paused stack while the new stack runs.
+#[cfg(feature="chained-backtraces")]
+#[inline(always)]
+pub unsafe fn link_prepared_chained(fun: PreparedFn, stack: *mut usize, parent: *const *mut usize) -> *mut usize {
+  prepare_frame(fun, link_parent(stack, parent))
+}
+
+#[inline(always)]
+unsafe fn prepare_frame(fun: PreparedFn, stack: *mut usize) -> *mut usize {
+  let entry = prepared_trampoline as unsafe extern "C" fn() as usize;
+  // We just have to write out what `switch` would have saved, picking registers that the
+  // prepared trampoline will find things in. `switch` resets the stack pointer to 12 above the
+  // paused stack pointer, so that's where we put it.
+  let paused = stack.sub(3);
+  paused.write(0);                                   // esi
+  paused.sub(1).write(ROOT_FP.0 as usize);           // frame pointer (the root of the call chain)
+  paused.sub(2).write(fun as usize);                 // ebx
+  paused.sub(3).write(entry);                        // return address
+  // the new stack should now look like this:
+  // | paused rel | data                         |
+  // |------------|------------------------------|
+  // | +12        | whatever the caller put here |
+  // | 0          | esi                          |
+  // | -4         | root frame pointer           |
+  // | -8         | entrypoint function          |
+  // | -12        | prepared trampoline          |
+  paused
+}
+
+/// The return address and frame pointer that `switch` saved when it paused `stack`.
+#[cfg(feature="backtrace")]
+#[inline(always)]
+pub(crate) unsafe fn paused_frame(stack: *mut usize) -> (usize, *const usize) {
+  (stack.sub(3).read(), stack.sub(1).read() as *const usize)
+}
+
+/// Where the frame record (the caller's frame pointer, then the return address) that `fp` points
+/// to starts.
+#[cfg(feature="backtrace")]
+#[inline(always)]
+pub(crate) fn frame_record(fp: *const usize) -> *const usize {
+  fp
+}
+
 /* Trampoline function (terminates the call chain, becoming the first frame):
- * - called with an artificial frame.
+ * - jumped to by `link_detached`, with the function and its arguments on the new stack.
  * - calls the function in a new frame.
  * - expects that function never to return.
  */
@@ -127,8 +537,65 @@ core::arch::global_asm!(
   ".align 16",             // put it at the start of a quadword to increase fetch perf.
   "trampoline:",
   ".cfi_startproc simple", // function prologue
+  ".cfi_def_cfa esp, 16",  // unwinders need a cfa, even just to stop here.
   ".cfi_undefined eip",    // stop unwinding at this frame
   ".cfi_undefined esp",    // stop the call chain at this frame (for gdb)
-  "call [esp]",            // call the function in a new stack frame.
+  "call [esp + 8]",        // call the function in a new stack frame, its arguments are at esp.
+  "ud2",                   // it's not allowed to return.
   ".cfi_endproc"           // function epilogue
 );
+
+/* Prepared trampoline (the first frame of a prepared stack):
+ * - jumped to by the first switch, so edx = arg and eax = paused stack pointer.
+ * - ebx holds the function and esp points at whatever the linker left there for it.
+ * - expects that function never to return.
+ */
+extern "C" {
+    fn prepared_trampoline();
+}
+
+#[cfg(not(feature="chained-backtraces"))]
+core::arch::global_asm!(
+  ".global prepared_trampoline",
+  ".align 16",
+  "prepared_trampoline:",
+  ".cfi_startproc simple",
+  ".cfi_def_cfa esp, 0",   // unwinders need a cfa, even just to stop here.
+  ".cfi_undefined eip",    // stop unwinding at this frame
+  ".cfi_undefined esp",    // stop the call chain at this frame (for gdb)
+  "mov ecx, esp",          // the top of the stack
+  "sub esp, 4",            // keep the stack aligned for the call
+  "push ecx",              // arg 3
+  "push edx",              // arg 2
+  "push eax",              // arg 1
+  "call ebx",              // call the function in a new stack frame.
+  "ud2",                   // it's not allowed to return.
+  ".cfi_endproc"
+);
+
+// Chained, [esp] points at wherever the parent's paused stack pointer is kept. We describe our
+// caller as living there: `switch` saved its frame just below it and resumes 12 above it.
+#[cfg(feature="chained-backtraces")]
+core::arch::global_asm!(
+  ".global prepared_trampoline",
+  ".align 16",
+  "prepared_trampoline:",
+  ".cfi_startproc simple",
+  // cfa = **esp + 12 (DW_CFA_def_cfa_expression: DW_OP_breg4 0, DW_OP_deref, DW_OP_deref,
+  // DW_OP_plus_uconst 12)
+  ".cfi_escape 0x0f, 6, 0x74, 0x00, 0x06, 0x06, 0x23, 0x0c",
+  ".cfi_offset eip, -24",   // where `switch` saved them
+  ".cfi_offset ebx, -20",
+  ".cfi_offset ebp, -16",
+  ".cfi_offset esi, -12",
+  "lea ecx, [esp + 16]",   // the top of the stack, above the link
+  "sub esp, 4",            // keep the stack aligned for the call
+  "push ecx",              // arg 3
+  "push edx",              // arg 2
+  "push eax",              // arg 1
+  // the link is 16 further up now.
+  ".cfi_escape 0x0f, 6, 0x74, 0x10, 0x06, 0x06, 0x23, 0x0c",
+  "call ebx",              // call the function in a new stack frame.
+  "ud2",                   // it's not allowed to return.
+  ".cfi_endproc"
+);
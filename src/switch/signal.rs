@@ -0,0 +1,81 @@
+//! Signal masks that belong to a context rather than the thread, like `swapcontext` gives you.
+//!
+//! Plain `switch` leaves the thread's signal mask alone, so one context blocking a signal blocks it
+//! for everyone on the thread. If that matters, keep a [`SigMask`] per context and switch to it
+//! with [`switch_masked`]. It costs two `pthread_sigmask` calls per round trip and nothing at all
+//! if you don't use it.
+use super::{switch, Switch};
+use core::fmt;
+use core::mem::MaybeUninit;
+use core::ptr::null_mut;
+use libc::{c_int, sigset_t};
+
+/// A set of blocked signals.
+#[derive(Clone, Copy)]
+pub struct SigMask(sigset_t);
+
+impl fmt::Debug for SigMask {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let mut set = f.debug_set();
+    for sig in 1..65 {
+      if self.contains(sig) { set.entry(&sig); }
+    }
+    set.finish()
+  }
+}
+
+impl SigMask {
+  /// A mask blocking nothing.
+  pub fn empty() -> Self {
+    let mut set = MaybeUninit::uninit();
+    unsafe { libc::sigemptyset(set.as_mut_ptr()) };
+    SigMask(unsafe { set.assume_init() })
+  }
+
+  /// A mask blocking everything that can be blocked.
+  pub fn full() -> Self {
+    let mut set = MaybeUninit::uninit();
+    unsafe { libc::sigfillset(set.as_mut_ptr()) };
+    SigMask(unsafe { set.assume_init() })
+  }
+
+  /// The calling thread's current mask.
+  pub fn current() -> Self {
+    let mut set = Self::empty();
+    unsafe { libc::pthread_sigmask(libc::SIG_SETMASK, null_mut(), &mut set.0) };
+    set
+  }
+
+  /// Blocks `sig`. Invalid signal numbers are ignored.
+  pub fn add(&mut self, sig: c_int) { unsafe { libc::sigaddset(&mut self.0, sig) }; }
+
+  /// Unblocks `sig`. Invalid signal numbers are ignored.
+  pub fn remove(&mut self, sig: c_int) { unsafe { libc::sigdelset(&mut self.0, sig) }; }
+
+  /// Is `sig` blocked?
+  pub fn contains(&self, sig: c_int) -> bool { unsafe { libc::sigismember(&self.0, sig) == 1 } }
+
+  /// Makes this the thread's mask, returning the old one.
+  pub fn apply(&self) -> SigMask {
+    let mut old = Self::empty();
+    unsafe { libc::pthread_sigmask(libc::SIG_SETMASK, &self.0, &mut old.0) };
+    old
+  }
+}
+
+/// Like [`switch`], but the target runs with `mask` as the thread's signal mask.
+///
+/// When we are resumed, we get our own mask back and `mask` is updated to whatever the target left
+/// in place, ready for next time. Keep one mask per context and it behaves as if each had its own.
+///
+/// # Safety
+///
+/// Behaviour is undefined if:
+/// * The stack was not paused correctly
+#[inline(always)]
+pub unsafe fn switch_masked(stack: *mut usize, arg: usize, mask: &mut SigMask) -> Switch {
+  let ours = mask.apply();
+  let ret = switch(stack, arg);
+  *mask = ours.apply();
+  ret
+}
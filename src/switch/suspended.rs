@@ -0,0 +1,100 @@
+//! A safe, linear handle to a paused context.
+//!
+//! A raw paused stack pointer can be copied and resumed twice, which corrupts the stack. A
+//! [`Suspended`] can't be copied: [`Suspended::switch`] consumes it and hands back a fresh one for
+//! whoever resumed us. Its lifetime is that of the stack it lives on, so it can't outlive it either.
+//!
+//! ```
+//! use stackle::{stack::*, switch::Suspended};
+//!
+//! let mut stack = SafeStack::new(16 * 1024, PageSize::get().unwrap()).unwrap();
+//! let adder = Suspended::link_detached(&mut stack, |mut back, mut n| {
+//!   loop { (back, n) = back.switch(n + 1) }
+//! });
+//! let (adder, n) = adder.switch(1);
+//! assert_eq!(2, n);
+//! # drop(adder);
+//! ```
+//!
+//! Resuming a context twice doesn't compile:
+//!
+//! ```compile_fail
+//! # use stackle::{stack::*, switch::Suspended};
+//! # let mut stack = SafeStack::new(16 * 1024, PageSize::get().unwrap()).unwrap();
+//! let adder = Suspended::link_detached(&mut stack, |mut back, mut n| {
+//!   loop { (back, n) = back.switch(n + 1) }
+//! });
+//! adder.switch(1);
+//! adder.switch(2);
+//! ```
+//!
+//! Nor does keeping one after its stack is gone:
+//!
+//! ```compile_fail
+//! # use stackle::{stack::*, switch::Suspended};
+//! let adder;
+//! {
+//!   let mut stack = SafeStack::new(16 * 1024, PageSize::get().unwrap()).unwrap();
+//!   adder = Suspended::link_detached(&mut stack, |mut back, mut n| {
+//!     loop { (back, n) = back.switch(n + 1) }
+//!   });
+//! }
+//! adder.switch(1);
+//! ```
+use super::{link_closure_detached, switch};
+use crate::stack::Stack;
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+
+/// A paused context on a stack borrowed for `'stack`. Resume it with [`Suspended::switch`].
+///
+/// Dropping one simply abandons the context: nothing on its stack is dropped.
+#[repr(transparent)]
+#[derive(Debug)]
+pub struct Suspended<'stack> {
+  stack:   NonNull<usize>,
+  _marker: PhantomData<&'stack mut ()>,
+}
+
+impl<'stack> Suspended<'stack> {
+  /// Prepares `fun` to run on `stack` when first switched to, receiving the context that did so
+  /// and the argument it passed.
+  ///
+  /// When `fun` returns, we switch to the context it returns for the last time. Resuming the
+  /// finished context after that aborts.
+  pub fn link_detached<S, F>(stack: &'stack mut S, fun: F) -> Self
+  where S: Stack + ?Sized, F: FnOnce(Suspended<'stack>, usize) -> (Suspended<'stack>, usize) + 'stack {
+    let paused = unsafe {
+      link_closure_detached(stack.end(), move |paused, arg| {
+        let (next, arg) = fun(Suspended::from_raw(paused), arg);
+        next.switch(arg);
+        // This unwinds into the trampoline, which aborts.
+        panic!("resumed a finished context")
+      })
+    };
+    unsafe { Suspended::from_raw(paused) }
+  }
+
+  /// Pauses the current context and resumes this one, passing it `arg`. Returns the context that
+  /// resumed us and the argument it passed.
+  #[inline(always)]
+  pub fn switch(self, arg: usize) -> (Suspended<'stack>, usize) {
+    let ret = unsafe { switch(self.stack.as_ptr(), arg) };
+    (unsafe { Suspended::from_raw(ret.stack) }, ret.arg)
+  }
+
+  /// Gives up the handle for the raw paused stack pointer.
+  #[inline(always)]
+  pub fn into_raw(self) -> *mut usize { self.stack.as_ptr() }
+
+  /// Wraps a raw paused stack pointer.
+  ///
+  /// # Safety
+  ///
+  /// * `stack` must be a context paused by `switch` that nothing else will resume.
+  /// * The stack it lives on must outlive `'stack`.
+  #[inline(always)]
+  pub unsafe fn from_raw(stack: *mut usize) -> Self {
+    Suspended { stack: NonNull::new_unchecked(stack), _marker: PhantomData }
+  }
+}
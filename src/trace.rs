@@ -0,0 +1,78 @@
+//! Backtraces of paused stacks, for finding out where something is stuck.
+//!
+//! We start from the return address and frame pointer that `switch` saved and follow the
+//! frame-pointer chain from there, so you'll only get more than the first frame out of code built
+//! with frame pointers (`-C force-frame-pointers=yes`). This is slow, resolving symbols especially.
+//! Don't use it anywhere hot.
+use crate::switch::{frame_record, paused_frame};
+use core::ffi::c_void;
+use core::fmt;
+use core::mem::size_of;
+
+/// The return addresses on a paused stack, innermost first.
+pub struct PausedBacktrace {
+  ips: Vec<*mut c_void>,
+}
+
+impl PausedBacktrace {
+  /// Walks the stack paused at `stack`, which ends at `end`.
+  ///
+  /// We only follow frame records further up the stack than the last and below `end`, so a
+  /// missing frame pointer ends the trace early (or adds some nonsense frames) rather than taking
+  /// us off into unmapped memory.
+  ///
+  /// # Safety
+  ///
+  /// * `stack` must have been paused by `switch` and must stay paused while we walk it.
+  /// * Everything between `stack` and `end` must be readable.
+  pub unsafe fn capture(stack: *mut usize, end: *mut usize) -> Self {
+    let (ip, mut fp) = paused_frame(stack);
+    let mut ips = vec![ip as *mut c_void];
+    let mut floor = stack as usize;
+    loop {
+      let record = frame_record(fp);
+      let start = record as usize;
+      if start < floor || start + 2 * size_of::<usize>() > end as usize || !start.is_multiple_of(size_of::<usize>()) {
+        break
+      }
+      let ip = record.add(1).read();
+      if ip == 0 { break }
+      ips.push(ip as *mut c_void);
+      fp = record.read() as *const usize;
+      floor = start + 2 * size_of::<usize>();
+    }
+    PausedBacktrace { ips }
+  }
+
+  /// The return addresses we found, innermost first.
+  pub fn ips(&self) -> &[*mut c_void] { &self.ips }
+}
+
+// Laid out like `std::backtrace::Backtrace`.
+impl fmt::Display for PausedBacktrace {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    for (i, ip) in self.ips.iter().enumerate() {
+      let mut resolved = false;
+      let mut result = Ok(());
+      backtrace::resolve(*ip, |symbol| {
+        resolved = true;
+        if result.is_err() { return }
+        result = match symbol.name() {
+          Some(name) => writeln!(f, "{:4}: {:#}", i, name),
+          None => writeln!(f, "{:4}: <unknown>", i),
+        }.and_then(|()| match (symbol.filename(), symbol.lineno(), symbol.colno()) {
+          (Some(file), Some(line), Some(col)) => writeln!(f, "             at {}:{}:{}", file.display(), line, col),
+          (Some(file), Some(line), None) => writeln!(f, "             at {}:{}", file.display(), line),
+          _ => Ok(()),
+        });
+      });
+      result?;
+      if !resolved { writeln!(f, "{:4}: <unknown>", i)? }
+    }
+    Ok(())
+  }
+}
+
+impl fmt::Debug for PausedBacktrace {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { fmt::Display::fmt(self, f) }
+}
@@ -0,0 +1,222 @@
+use stackle::runtime::channel::{self, broadcast, oneshot, RecvError, TryRecvError, TrySendError};
+use stackle::runtime::{self, Executor, LocalExecutor};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+#[test]
+fn full_channels_park_the_sender() {
+  let exec = LocalExecutor::new();
+  let (tx, rx) = channel::channel(2);
+  let log = Rc::new(RefCell::new(Vec::new()));
+  let sent = log.clone();
+  exec.spawn(move || for i in 0..4 {
+    tx.send(i).unwrap();
+    sent.borrow_mut().push(format!("sent {}", i));
+  });
+  let received = log.clone();
+  exec.spawn(move || {
+    runtime::yield_now();
+    while let Ok(i) = rx.recv() { received.borrow_mut().push(format!("got {}", i)) }
+  });
+  assert_eq!(0, exec.run_until_idle());
+  assert_eq!(
+    vec!["sent 0", "sent 1", "got 0", "got 1", "sent 2", "sent 3", "got 2", "got 3"],
+    *log.borrow()
+  );
+}
+
+#[test]
+fn try_variants_dont_wait() {
+  let (tx, rx) = channel::channel(1);
+  assert_eq!(Err(TryRecvError::Empty), rx.try_recv());
+  tx.try_send(1).unwrap();
+  assert!(matches!(tx.try_send(2), Err(TrySendError::Full(2))));
+  assert_eq!(Ok(1), rx.try_recv());
+  drop(tx);
+  assert_eq!(Err(TryRecvError::Closed), rx.try_recv());
+
+  let (tx, rx) = channel::unbounded();
+  for i in 0..100 { tx.try_send(i).unwrap() }
+  drop(rx);
+  assert!(tx.is_closed());
+  assert_eq!(Err(TrySendError::Closed(100)), tx.try_send(100));
+}
+
+#[test]
+fn receivers_drain_closed_channels() {
+  let exec = LocalExecutor::new();
+  let (tx, rx) = channel::unbounded();
+  for i in 0..3 {
+    let tx = tx.clone();
+    exec.spawn(move || tx.send(i).unwrap());
+  }
+  drop(tx);
+  let all = exec.spawn(move || {
+    let mut all: Vec<_> = rx.iter().collect();
+    all.sort();
+    (all, rx.recv())
+  });
+  exec.run_until_idle();
+  assert_eq!((vec![0, 1, 2], Err(RecvError)), all.join().unwrap());
+}
+
+#[test]
+fn dropping_the_receiver_wakes_senders() {
+  let exec = LocalExecutor::new();
+  let (tx, rx) = channel::channel(1);
+  let sender = exec.spawn(move || {
+    tx.send("kept").unwrap();
+    tx.send("returned").unwrap_err().0
+  });
+  exec.run_until_idle();
+  assert!(!sender.is_finished());
+  drop(rx);
+  assert_eq!("returned", sender.join().unwrap());
+}
+
+#[test]
+fn channels_cross_workers() {
+  let exec = Executor::new(4);
+  let (tx, rx) = channel::channel(4);
+  for i in 0..8 {
+    let tx = tx.clone();
+    unsafe { exec.spawn(move || for j in 0..100 { tx.send(i * 100 + j).unwrap() }) };
+  }
+  drop(tx);
+  let sum = unsafe { exec.spawn(move || rx.iter().sum::<usize>()) };
+  assert_eq!((0..800).sum::<usize>(), sum.join().unwrap());
+}
+
+#[test]
+fn oneshots() {
+  let exec = LocalExecutor::new();
+  let (tx, rx) = oneshot::channel();
+  let reply = exec.spawn(move || rx.recv());
+  exec.spawn(move || {
+    runtime::yield_now();
+    tx.send("pong").unwrap();
+  });
+  exec.run_until_idle();
+  assert_eq!(Ok("pong"), reply.join().unwrap());
+
+  let (tx, rx) = oneshot::channel::<()>();
+  drop(tx);
+  assert_eq!(Err(RecvError), rx.recv());
+
+  let (tx, mut rx) = oneshot::channel();
+  assert_eq!(Err(TryRecvError::Empty), rx.try_recv());
+  tx.send(5).unwrap();
+  assert_eq!(Ok(5), rx.try_recv());
+  assert_eq!(Err(TryRecvError::Closed), rx.try_recv());
+
+  let (tx, rx) = oneshot::channel();
+  drop(rx);
+  assert!(tx.is_closed());
+  assert_eq!(7, tx.send(7).unwrap_err().0);
+}
+
+#[test]
+fn broadcasts_reach_everyone() {
+  let exec = LocalExecutor::new();
+  let (tx, rx) = broadcast::channel(2);
+  let listeners: Vec<_> = (0..3).map(|_| {
+    let mut rx = rx.clone();
+    exec.spawn(move || {
+      let mut got = Vec::new();
+      while let Ok(i) = rx.recv() { got.push(i) }
+      got
+    })
+  }).collect();
+  drop(rx);
+  exec.spawn(move || for i in 0..5 { tx.send(i).unwrap() });
+  exec.run_until_idle();
+  for listener in listeners { assert_eq!(vec![0, 1, 2, 3, 4], listener.join().unwrap()) }
+}
+
+#[test]
+fn slow_broadcast_receivers_hold_senders_up() {
+  let (tx, mut slow) = broadcast::channel(2);
+  let mut fast = tx.subscribe();
+  tx.try_send(1).unwrap();
+  tx.try_send(2).unwrap();
+  assert_eq!(Ok(1), fast.try_recv());
+  assert_eq!(Ok(2), fast.try_recv());
+  assert!(matches!(tx.try_send(3), Err(TrySendError::Full(3))));
+  assert_eq!(Ok(1), slow.try_recv());
+  tx.try_send(3).unwrap();
+  // Dropping the slow one lets go of what it never read.
+  drop(slow);
+  tx.try_send(4).unwrap();
+  let mut late = tx.subscribe();
+  assert_eq!(Err(TryRecvError::Empty), late.try_recv());
+  assert_eq!(Ok(3), fast.try_recv());
+  assert_eq!(Ok(4), fast.try_recv());
+  drop((fast, late));
+  assert_eq!(0, tx.receiver_count());
+  assert!(matches!(tx.try_send(5), Err(TrySendError::Closed(5))));
+}
+
+#[test]
+fn threads_feed_tasks_and_back() {
+  let exec = LocalExecutor::new();
+  let (tx, rx) = channel::channel(1);
+  let (back, results) = channel::unbounded();
+  let feeder = std::thread::spawn(move || {
+    for i in 0..10 { tx.send(i).unwrap() }
+    drop(tx);
+    results.iter().sum::<i32>()
+  });
+  let echo = exec.spawn(move || for i in &rx { back.send(i * 2).unwrap() });
+  // Joining runs the executor whenever the feeder wakes the echo.
+  echo.join().unwrap();
+  assert_eq!(90, feeder.join().unwrap());
+}
+
+#[test]
+fn blocking_threads_run_their_own_tasks() {
+  let exec = LocalExecutor::new();
+  let (tx, rx) = channel::channel(1);
+  exec.spawn(move || for i in 0..5 { tx.send(i).unwrap() });
+  // Nobody's running the executor but us, and we're busy waiting for it.
+  assert_eq!(vec![0, 1, 2, 3, 4], rx.iter().collect::<Vec<_>>());
+
+  let (tx, rx) = channel::channel(1);
+  let sum = exec.spawn(move || rx.iter().sum::<i32>());
+  for i in 0..5 { tx.send(i).unwrap() }
+  drop(tx);
+  assert_eq!(10, sum.join().unwrap());
+}
+
+#[test]
+fn threads_and_workers_talk_both_ways() {
+  let exec = Executor::new(2);
+  let (requests, incoming) = channel::channel::<(i32, oneshot::Sender<i32>)>(2);
+  unsafe { exec.spawn(move || for (n, reply) in &incoming {
+    runtime::yield_now();
+    reply.send(n * n).unwrap();
+  })};
+  let clients: Vec<_> = (0..4).map(|t| {
+    let requests = requests.clone();
+    std::thread::spawn(move || (0..25).map(|i| {
+      let (reply, response) = oneshot::channel();
+      requests.send((t * 25 + i, reply)).unwrap();
+      response.recv().unwrap()
+    }).sum::<i32>())
+  }).collect();
+  drop(requests);
+  let total: i32 = clients.into_iter().map(|c| c.join().unwrap()).sum();
+  assert_eq!((0..100).map(|n| n * n).sum::<i32>(), total);
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[test]
+fn threads_wake_pinned_workers() {
+  use stackle::runtime::ThreadPerCore;
+  let cpus = runtime::available_cpus().unwrap();
+  let workers = ThreadPerCore::new(&cpus[..1]).unwrap();
+  let (tx, rx) = channel::channel(1);
+  let sum = workers.spawn_on(0, move || rx.iter().sum::<u64>());
+  for i in 0..1000 { tx.send(i).unwrap() }
+  drop(tx);
+  assert_eq!(499500, sum.join().unwrap());
+}
@@ -0,0 +1,227 @@
+use stackle::{coroutine::*, stack::*};
+use std::cell::Cell;
+use std::mem::size_of;
+use std::rc::Rc;
+
+fn safe_stack() -> SafeStack {
+  let p = PageSize::get().unwrap();
+  SafeStack::new(64 * 1024, p).unwrap()
+}
+
+#[test]
+fn handle_is_a_pointer() {
+  assert_eq!(size_of::<usize>(), size_of::<Coroutine<(), (), (), SafeStack>>());
+  assert_eq!(size_of::<usize>(), size_of::<Option<Coroutine<(), (), (), SafeStack>>>());
+}
+
+#[test]
+fn yields_and_returns() {
+  let mut c = Coroutine::new(safe_stack(), |y: &Yielder<usize, usize>, mut input| {
+    for _ in 0..10 {
+      input = y.suspend(input + 1);
+    }
+    "done"
+  }).unwrap();
+  assert!(!c.started());
+  for i in 0..10 {
+    assert_eq!(CoroutineResult::Yield(i + 1), c.resume(i));
+  }
+  assert_eq!(CoroutineResult::Return("done"), c.resume(0));
+  assert!(c.done());
+}
+
+#[test]
+fn static_stack() {
+  let mut stack = unsafe { StaticStack::<{ 64 * 1024 }>::new() };
+  let mut c = Coroutine::new(&mut stack, |y: &Yielder<(), usize>, ()| {
+    y.suspend(1);
+    2
+  }).unwrap();
+  assert_eq!(CoroutineResult::Yield(1), c.resume(()));
+  assert_eq!(CoroutineResult::Return(2), c.resume(()));
+}
+
+#[test]
+fn too_small_a_stack() {
+  // The closure alone is bigger than the whole stack.
+  let mut stack = unsafe { StaticStack::<{ 16 * 1024 }>::new() };
+  let big = [7u8; 32 * 1024];
+  let err = Coroutine::new(&mut stack, move |_: &Yielder<(), u8>, ()| big[0]).err().unwrap();
+  assert_eq!(16 * 1024, err.size);
+  assert!(err.needed > 32 * 1024);
+  // Whereas a small one fits.
+  let mut c = Coroutine::new(&mut stack, |_: &Yielder<(), u8>, ()| 7).unwrap();
+  assert_eq!(CoroutineResult::Return(7), c.resume(()));
+}
+
+#[test]
+fn panics_propagate() {
+  let mut c = Coroutine::new(safe_stack(), |_: &Yielder<(), ()>, ()| -> () { panic!("oops") })
+    .unwrap();
+  let err = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| c.resume(()))).unwrap_err();
+  assert_eq!(Some(&"oops"), err.downcast_ref::<&str>());
+  assert!(c.done());
+}
+
+struct DropFlag(Rc<Cell<bool>>);
+
+impl Drop for DropFlag {
+  fn drop(&mut self) { self.0.set(true) }
+}
+
+#[test]
+fn drop_unstarted() {
+  let dropped = Rc::new(Cell::new(false));
+  let flag = DropFlag(dropped.clone());
+  let c = Coroutine::new(safe_stack(), move |_: &Yielder<(), ()>, ()| drop(flag)).unwrap();
+  assert!(!dropped.get());
+  drop(c);
+  assert!(dropped.get());
+}
+
+#[test]
+fn drop_suspended_unwinds() {
+  let dropped = Rc::new(Cell::new(false));
+  let flag = DropFlag(dropped.clone());
+  let mut c = Coroutine::new(safe_stack(), move |y: &Yielder<(), ()>, ()| {
+    let _flag = flag;
+    loop { y.suspend(()) }
+  }).unwrap();
+  assert_eq!(CoroutineResult::Yield(()), c.resume(()));
+  assert!(!dropped.get());
+  drop(c);
+  assert!(dropped.get());
+}
+
+#[cfg(feature="chained-backtraces")]
+#[inline(never)]
+fn resumed_from_here(c: &mut Coroutine<(), (), String, SafeStack>) -> String {
+  match c.resume(()) {
+    CoroutineResult::Return(trace) => trace,
+    CoroutineResult::Yield(()) => unreachable!(),
+  }
+}
+
+#[cfg(feature="chained-backtraces")]
+#[test]
+fn backtrace_reaches_resumer() {
+  let mut c = Coroutine::new(safe_stack(), |_: &Yielder<(), ()>, ()| {
+    std::backtrace::Backtrace::force_capture().to_string()
+  }).unwrap();
+  let trace = resumed_from_here(&mut c);
+  assert!(trace.contains("resumed_from_here"), "{}", trace);
+}
+
+#[cfg(feature="backtrace")]
+#[inline(never)]
+fn parked_here(y: &Yielder<(), ()>) { y.suspend(()) }
+
+#[cfg(feature="backtrace")]
+#[test]
+fn suspended_backtrace() {
+  let mut c = Coroutine::new(safe_stack(), |y: &Yielder<(), ()>, ()| parked_here(y)).unwrap();
+  assert!(c.backtrace().is_some());
+  assert_eq!(CoroutineResult::Yield(()), c.resume(()));
+  let trace = c.backtrace().unwrap().to_string();
+  // Without frame pointers, we only know where it switched, which may have been inlined.
+  assert!(trace.contains("suspend") || trace.contains("parked_here"), "{}", trace);
+  assert_eq!(CoroutineResult::Return(()), c.resume(()));
+  assert!(c.backtrace().is_none());
+}
+
+#[cfg(unix)]
+#[test]
+fn signal_masks_stay_put() {
+  use stackle::switch::SigMask;
+  let sig = libc::SIGUSR1;
+  let mut c = Coroutine::new(safe_stack(), move |y: &Yielder<(), bool>, ()| {
+    let mut mask = SigMask::current();
+    mask.add(sig);
+    mask.apply();
+    y.suspend(true);
+    SigMask::current().contains(sig)
+  }).unwrap();
+  let mut mask = SigMask::current();
+  assert!(!mask.contains(sig));
+  assert_eq!(CoroutineResult::Yield(true), c.resume_masked((), &mut mask));
+  // it blocked the signal for itself, not for us.
+  assert!(!SigMask::current().contains(sig));
+  assert!(mask.contains(sig));
+  assert_eq!(CoroutineResult::Return(true), c.resume_masked((), &mut mask));
+  assert!(!SigMask::current().contains(sig));
+}
+
+#[test]
+fn scoped_borrows_locals() {
+  let mut seen = Vec::new();
+  let numbers = [1, 2, 3];
+  stackle::scope(|s| {
+    let mut c = s.spawn(safe_stack(), |y: &Yielder<(), &usize>, ()| {
+      for n in &numbers { y.suspend(n) }
+      numbers.len()
+    }).unwrap();
+    while let CoroutineResult::Yield(n) = c.resume(()) { seen.push(*n) }
+    assert!(c.done());
+  });
+  assert_eq!(vec![1, 2, 3], seen);
+}
+
+struct Count<'a>(&'a Cell<usize>);
+
+impl Drop for Count<'_> {
+  fn drop(&mut self) { self.0.set(self.0.get() + 1) }
+}
+
+#[test]
+fn scope_unwinds_leaked_coroutines() {
+  let dropped = Cell::new(0);
+  stackle::scope(|s| {
+    for _ in 0..3 {
+      let mut c = s.spawn(safe_stack(), |y: &Yielder<(), ()>, ()| {
+        let _count = Count(&dropped);
+        loop { y.suspend(()) }
+      }).unwrap();
+      assert_eq!(CoroutineResult::Yield(()), c.resume(()));
+      std::mem::forget(c);
+    }
+    assert_eq!(0, dropped.get());
+  });
+  assert_eq!(3, dropped.get());
+}
+
+#[test]
+fn migrates_between_threads() {
+  let mut c = SendCoroutine::new(safe_stack(), |y: &Yielder<usize, usize>, mut n| {
+    // lives on the coroutine's stack across both threads.
+    let mut total = Vec::new();
+    loop {
+      total.push(n);
+      n = y.suspend(total.iter().sum());
+    }
+  }).unwrap();
+  assert_eq!(CoroutineResult::Yield(1), c.resume(1));
+  let moving = unsafe { c.migrate() }.ok().unwrap();
+  let mut c = std::thread::spawn(move || {
+    let mut c = moving.arrive();
+    assert_eq!(CoroutineResult::Yield(3), c.resume(2));
+    unsafe { c.migrate() }.ok().unwrap()
+  }).join().unwrap().arrive();
+  assert_eq!(CoroutineResult::Yield(6), c.resume(3));
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn bound_guards_block_migration() {
+  use stackle::local::Affinity;
+  use std::sync::{Arc, Mutex};
+  let m = Arc::new(Mutex::new(0));
+  let mut c = SendCoroutine::new(safe_stack(), move |y: &Yielder<(), ()>, ()| {
+    let _held = stackle::local::bound(m.lock().unwrap());
+    y.suspend(());
+  }).unwrap();
+  c.resume(());
+  let Err((mut c, why)) = (unsafe { c.migrate() }) else { panic!("migrated with a guard held") };
+  assert_eq!(Affinity::ThreadBound, why);
+  assert_eq!(CoroutineResult::Return(()), c.resume(()));
+  assert!(unsafe { c.migrate() }.is_ok());
+}
@@ -0,0 +1,191 @@
+use stackle::{coroutine::*, fiber_local, stack::*};
+use std::cell::Cell;
+use std::rc::Rc;
+
+fn safe_stack() -> SafeStack {
+  let p = PageSize::get().unwrap();
+  SafeStack::new(64 * 1024, p).unwrap()
+}
+
+fiber_local! {
+  static COUNTER: Cell<usize> = Cell::new(0);
+  #[inherit]
+  static TRACE: Cell<u64> = Cell::new(0);
+  static SHARED: Rc<Cell<usize>> = Rc::new(Cell::new(0));
+}
+
+#[test]
+fn values_belong_to_the_coroutine() {
+  COUNTER.with(|c| c.set(100));
+  let mut a = Coroutine::new(safe_stack(), |y: &Yielder<(), usize>, ()| {
+    loop { y.suspend(COUNTER.with(|c| { c.set(c.get() + 1); c.get() })) }
+  }).unwrap();
+  let mut b = Coroutine::new(safe_stack(), |y: &Yielder<(), usize>, ()| {
+    loop { y.suspend(COUNTER.with(|c| { c.set(c.get() + 10); c.get() })) }
+  }).unwrap();
+  assert_eq!(CoroutineResult::Yield(1), a.resume(()));
+  assert_eq!(CoroutineResult::Yield(10), b.resume(()));
+  assert_eq!(CoroutineResult::Yield(2), a.resume(()));
+  assert_eq!(CoroutineResult::Yield(20), b.resume(()));
+  assert_eq!(100, COUNTER.with(Cell::get));
+}
+
+#[test]
+fn inherited_into_children() {
+  TRACE.with(|t| t.set(7));
+  COUNTER.with(|c| c.set(7));
+  let mut parent = Coroutine::new(safe_stack(), |_: &Yielder<(), ()>, ()| {
+    TRACE.with(|t| t.set(t.get() + 1));
+    let mut child = Coroutine::new(safe_stack(), |_: &Yielder<(), ()>, ()| {
+      (TRACE.with(Cell::get), COUNTER.with(Cell::get))
+    }).unwrap();
+    match child.resume(()) {
+      CoroutineResult::Return(values) => values,
+      CoroutineResult::Yield(()) => unreachable!(),
+    }
+  }).unwrap();
+  // only the inherited key comes along, and it's a copy.
+  assert_eq!(CoroutineResult::Return((8, 0)), parent.resume(()));
+  assert_eq!(7, TRACE.with(Cell::get));
+}
+
+struct DropFlag(Rc<Cell<bool>>);
+
+impl Drop for DropFlag {
+  fn drop(&mut self) { self.0.set(true) }
+}
+
+fiber_local! {
+  static FLAG: Cell<Option<DropFlag>> = Cell::new(None);
+}
+
+#[test]
+fn destroyed_on_completion() {
+  let dropped = Rc::new(Cell::new(false));
+  let flag = DropFlag(dropped.clone());
+  let mut c = Coroutine::new(safe_stack(), move |y: &Yielder<(), ()>, ()| {
+    FLAG.with(|f| f.set(Some(flag)));
+    y.suspend(());
+  }).unwrap();
+  assert_eq!(CoroutineResult::Yield(()), c.resume(()));
+  assert!(!dropped.get());
+  assert_eq!(CoroutineResult::Return(()), c.resume(()));
+  assert!(dropped.get());
+}
+
+#[test]
+fn destroyed_on_cancellation() {
+  let dropped = Rc::new(Cell::new(false));
+  let flag = DropFlag(dropped.clone());
+  let mut c = Coroutine::new(safe_stack(), move |y: &Yielder<(), ()>, ()| {
+    FLAG.with(|f| f.set(Some(flag)));
+    loop { y.suspend(()) }
+  }).unwrap();
+  assert_eq!(CoroutineResult::Yield(()), c.resume(()));
+  drop(c);
+  assert!(dropped.get());
+}
+
+#[cfg(target_os="linux")]
+fn errno() -> i32 { unsafe { *libc::__errno_location() } }
+
+#[cfg(target_os="linux")]
+fn set_errno(value: i32) { unsafe { *libc::__errno_location() = value } }
+
+#[cfg(target_os="linux")]
+#[test]
+fn errno_travels_with_the_coroutine() {
+  stackle::local::ERRNO.register();
+  let mut c = Coroutine::new(safe_stack(), |y: &Yielder<(), ()>, ()| {
+    set_errno(libc::EAGAIN);
+    y.suspend(());
+    errno()
+  }).unwrap();
+  set_errno(0);
+  assert_eq!(CoroutineResult::Yield(()), c.resume(()));
+  assert_eq!(0, errno());
+  set_errno(libc::ENOENT);
+  assert_eq!(CoroutineResult::Return(libc::EAGAIN), c.resume(()));
+  assert_eq!(libc::ENOENT, errno());
+}
+
+thread_local! {
+  static LEGACY: Cell<usize> = const { Cell::new(0) };
+}
+
+static LEGACY_SLOT: stackle::local::TlsSlot = stackle::local::TlsSlot::new(
+  || LEGACY.with(Cell::get),
+  |value| LEGACY.with(|l| l.set(value)),
+);
+
+#[test]
+fn registered_slots_travel_with_the_coroutine() {
+  LEGACY_SLOT.register();
+  LEGACY_SLOT.register();
+  LEGACY.with(|l| l.set(1));
+  let mut c = Coroutine::new(safe_stack(), |y: &Yielder<(), usize>, ()| {
+    // we start with our first resumer's value.
+    let first = LEGACY.with(Cell::get);
+    LEGACY.with(|l| l.set(2));
+    y.suspend(first);
+    LEGACY.with(Cell::get)
+  }).unwrap();
+  assert_eq!(CoroutineResult::Yield(1), c.resume(()));
+  assert_eq!(1, LEGACY.with(Cell::get));
+  LEGACY.with(|l| l.set(3));
+  assert_eq!(CoroutineResult::Return(2), c.resume(()));
+  assert_eq!(3, LEGACY.with(Cell::get));
+}
+
+thread_local! {
+  static LATE: Cell<usize> = const { Cell::new(0) };
+}
+
+static LATE_SLOT: stackle::local::TlsSlot = stackle::local::TlsSlot::new(
+  || LATE.with(Cell::get),
+  |value| LATE.with(|l| l.set(value)),
+);
+
+#[test]
+fn slots_registered_mid_coroutine_go_back_to_the_resumers() {
+  LATE.with(|l| l.set(1));
+  let mut outer = Coroutine::new(safe_stack(), |y: &Yielder<(), usize>, ()| {
+    let mut inner = Coroutine::new(safe_stack(), |y: &Yielder<(), ()>, ()| {
+      LATE_SLOT.register();
+      LATE.with(|l| l.set(2));
+      y.suspend(());
+      LATE.with(Cell::get)
+    }).unwrap();
+    assert_eq!(CoroutineResult::Yield(()), inner.resume(()));
+    let seen = LATE.with(Cell::get);
+    LATE.with(|l| l.set(5));
+    y.suspend(seen);
+    match inner.resume(()) { CoroutineResult::Return(n) => n, _ => unreachable!() }
+  }).unwrap();
+  assert_eq!(CoroutineResult::Yield(1), outer.resume(()));
+  assert_eq!(1, LATE.with(Cell::get));
+  LATE.with(|l| l.set(3));
+  assert_eq!(CoroutineResult::Return(2), outer.resume(()));
+  assert_eq!(3, LATE.with(Cell::get));
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn values_block_migration() {
+  TRACE.with(|t| t.set(7));
+  let mut c = SendCoroutine::new(safe_stack(), |y: &Yielder<(), ()>, ()| {
+    y.suspend(());
+    // `Send` values (and inherited copies) don't stop us.
+    COUNTER.with(|c| c.set(1));
+    y.suspend(());
+    SHARED.with(|s| s.set(1));
+    y.suspend(());
+  }).unwrap();
+  c.resume(());
+  let mut c = unsafe { c.migrate() }.ok().unwrap().arrive();
+  c.resume(());
+  let mut c = unsafe { c.migrate() }.ok().unwrap().arrive();
+  c.resume(());
+  let Err((_, why)) = (unsafe { c.migrate() }) else { panic!("migrated with an Rc") };
+  assert_eq!(stackle::local::Affinity::FiberLocals, why);
+}
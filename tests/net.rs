@@ -0,0 +1,156 @@
+#![cfg(any(target_os="linux", target_os="android"))]
+
+use stackle::runtime::net::{TcpListener, TcpStream, UdpSocket, UnixListener, UnixStream};
+use stackle::runtime::{time, Executor, LocalExecutor};
+use std::cell::Cell;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::Shutdown;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+fn echo(mut stream: impl Read + Write) {
+  let mut buf = [0; 64];
+  loop {
+    let n = stream.read(&mut buf).unwrap();
+    if n == 0 { break }
+    stream.write_all(&buf[..n]).unwrap();
+  }
+}
+
+#[test]
+fn tcp_echoes_on_one_thread() {
+  let exec = LocalExecutor::new();
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = listener.local_addr().unwrap();
+  exec.spawn(move || for stream in listener.incoming().take(3) {
+    stackle::runtime::spawn(move || echo(stream.unwrap()));
+  });
+  let clients: Vec<_> = (0..3).map(|i| exec.spawn(move || {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut lines = BufReader::new(&stream);
+    let mut got = Vec::new();
+    for line in 0..3 {
+      (&stream).write_all(format!("{} {}\n", i, line).as_bytes()).unwrap();
+      let mut echoed = String::new();
+      lines.read_line(&mut echoed).unwrap();
+      got.push(echoed);
+    }
+    stream.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(0, stream.read(&mut [0; 8]).unwrap());
+    got
+  })).collect();
+  for (i, client) in clients.into_iter().enumerate() {
+    let expected: Vec<_> = (0..3).map(|line| format!("{} {}\n", i, line)).collect();
+    assert_eq!(expected, client.join().unwrap());
+  }
+}
+
+#[test]
+fn threads_talk_to_workers() {
+  let exec = Executor::new(2);
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = listener.local_addr().unwrap();
+  let server = unsafe { exec.spawn(move || {
+    let (stream, peer) = listener.accept().unwrap();
+    assert_eq!(peer, stream.peer_addr().unwrap());
+    echo(stream);
+  })};
+  // Plain threads, which just block: one writing more than fits in the buffers, one reading it
+  // all back.
+  let stream = Arc::new(TcpStream::connect(addr).unwrap());
+  let reader = stream.clone();
+  let reading = std::thread::spawn(move || {
+    let mut back = Vec::new();
+    (&*reader).read_to_end(&mut back).unwrap();
+    back
+  });
+  let big: Vec<u8> = (0..1 << 20).map(|i| i as u8).collect();
+  (&*stream).write_all(&big).unwrap();
+  stream.shutdown(Shutdown::Write).unwrap();
+  assert!(big == reading.join().unwrap());
+  server.join().unwrap();
+}
+
+#[test]
+fn refused_connections_fail() {
+  let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+  let exec = LocalExecutor::new();
+  let refused = exec.spawn(move || TcpStream::connect(addr).map(drop));
+  assert_eq!(io::ErrorKind::ConnectionRefused, refused.join().unwrap().unwrap_err().kind());
+}
+
+#[test]
+fn udp_ping_pong() {
+  let exec = LocalExecutor::new();
+  let (ping, pong) = (UdpSocket::bind("127.0.0.1:0").unwrap(), UdpSocket::bind("127.0.0.1:0").unwrap());
+  let ping_addr = ping.local_addr().unwrap();
+  let pong_addr = pong.local_addr().unwrap();
+  exec.spawn(move || {
+    let mut buf = [0; 16];
+    for _ in 0..3 {
+      let (n, from) = pong.recv_from(&mut buf).unwrap();
+      assert_eq!(ping_addr, from);
+      pong.send_to(&buf[..n], from).unwrap();
+    }
+  });
+  let got = exec.spawn(move || {
+    ping.connect(pong_addr).unwrap();
+    (0..3u8).map(|i| {
+      ping.send(&[i; 4]).unwrap();
+      let mut buf = [0; 16];
+      let n = ping.recv(&mut buf).unwrap();
+      buf[..n].to_vec()
+    }).collect::<Vec<_>>()
+  });
+  assert_eq!(vec![vec![0; 4], vec![1; 4], vec![2; 4]], got.join().unwrap());
+}
+
+#[test]
+fn unix_streams() {
+  let path = std::env::temp_dir().join(format!("stackle-net-{}.sock", std::process::id()));
+  let _ = std::fs::remove_file(&path);
+  let exec = LocalExecutor::new();
+  let listener = UnixListener::bind(&path).unwrap();
+  exec.spawn(move || echo(listener.accept().unwrap().0));
+  let connecting = path.clone();
+  let echoed = exec.spawn(move || {
+    let mut stream = UnixStream::connect(connecting).unwrap();
+    stream.write_all(b"over a file").unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut echoed = String::new();
+    stream.read_to_string(&mut echoed).unwrap();
+    echoed
+  });
+  assert_eq!("over a file", echoed.join().unwrap());
+  std::fs::remove_file(&path).unwrap();
+
+  let (a, mut b) = UnixStream::pair().unwrap();
+  exec.spawn(move || echo(a));
+  b.write_all(b"paired").unwrap();
+  let mut buf = [0; 6];
+  b.read_exact(&mut buf).unwrap();
+  assert_eq!(b"paired", &buf);
+}
+
+#[test]
+fn timeouts_cancel_reads() {
+  let exec = LocalExecutor::new();
+  let (a, b) = UnixStream::pair().unwrap();
+  let timed_out = Rc::new(Cell::new(false));
+  let told = timed_out.clone();
+  let reader = exec.spawn(move || {
+    let mut buf = [0; 5];
+    let early = time::timeout(Duration::from_millis(10), || (&a).read(&mut buf));
+    told.set(early.is_err());
+    // The stream's fine after, and hears about what's written later.
+    let n = (&a).read(&mut buf).unwrap();
+    buf[..n].to_vec()
+  });
+  while !timed_out.get() {
+    exec.run_until_idle();
+    std::thread::sleep(Duration::from_millis(1));
+  }
+  (&b).write_all(b"later").unwrap();
+  assert_eq!(b"later".to_vec(), reader.join().unwrap());
+}
@@ -0,0 +1,245 @@
+use stackle::coroutine::{Coroutine, CoroutineResult, Yielder};
+use stackle::parking_lot::{self, ParkResult, Scheduler, Unpark, UnparkResult};
+use stackle::runtime::time::{self, Clock};
+use stackle::runtime::{Executor, LocalExecutor};
+use stackle::stack::{PageSize, SafeStack};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// The sort of thing you'd build on the lot: set once, waited on by anyone.
+#[derive(Default)]
+struct Event(AtomicBool);
+
+impl Event {
+  fn key(&self) -> usize { self as *const Self as usize }
+
+  fn is_set(&self) -> bool { self.0.load(Ordering::Acquire) }
+
+  fn wait_until(&self, deadline: Option<Instant>) -> bool {
+    while !self.is_set() {
+      if parking_lot::park(self.key(), || !self.is_set(), || (), deadline) == ParkResult::TimedOut { return false }
+    }
+    true
+  }
+
+  fn set(&self) -> usize {
+    self.0.store(true, Ordering::Release);
+    parking_lot::unpark_all(self.key())
+  }
+}
+
+#[test]
+fn tasks_and_threads_park_alike() {
+  let exec = Executor::new(2);
+  let event = Arc::new(Event::default());
+  let tasks: Vec<_> = (0..4).map(|_| {
+    let event = event.clone();
+    unsafe { exec.spawn(move || event.wait_until(None)) }
+  }).collect();
+  let threads: Vec<_> = (0..2).map(|_| {
+    let event = event.clone();
+    std::thread::spawn(move || event.wait_until(None))
+  }).collect();
+  // Parked tasks leave the workers free for more.
+  let other = unsafe { exec.spawn(|| 1 + 1) };
+  assert_eq!(2, other.join().unwrap());
+  std::thread::sleep(Duration::from_millis(20));
+  event.set();
+  for task in tasks { assert!(task.join().unwrap()) }
+  for thread in threads { assert!(thread.join().unwrap()) }
+}
+
+#[test]
+fn tasks_on_one_thread_wake_each_other() {
+  let exec = LocalExecutor::new();
+  let event = Rc::new(Event::default());
+  let waiters: Vec<_> = (0..3).map(|_| {
+    let event = event.clone();
+    exec.spawn(move || event.wait_until(None))
+  }).collect();
+  exec.run_until_idle();
+  let setter = exec.spawn(move || event.set());
+  exec.run_until_idle();
+  assert_eq!(3, setter.join().unwrap());
+  for waiter in waiters { assert!(waiter.join().unwrap()) }
+}
+
+#[test]
+fn invalid_parks_return_straight_away() {
+  let key = 12345;
+  let slept = Cell::new(false);
+  assert_eq!(ParkResult::Invalid, parking_lot::park(key, || false, || slept.set(true), None));
+  assert!(!slept.get());
+  assert_eq!(UnparkResult::default(), parking_lot::unpark_one(key));
+}
+
+#[test]
+fn parks_time_out() {
+  let event = Event::default();
+  let start = Instant::now();
+  assert!(!event.wait_until(Some(start + Duration::from_millis(20))));
+  assert!(start.elapsed() >= Duration::from_millis(20));
+
+  let exec = LocalExecutor::new();
+  let timed_out = exec.spawn(|| {
+    let event = Event::default();
+    let start = Instant::now();
+    (event.wait_until(Some(start + Duration::from_millis(20))), start.elapsed())
+  });
+  let (set, waited) = timed_out.join().unwrap();
+  assert!(!set);
+  assert!(waited >= Duration::from_millis(20));
+  // Nobody's left on the queue.
+  assert_eq!(0, parking_lot::unpark_all(event.key()));
+}
+
+#[test]
+fn unpark_one_goes_in_order() {
+  let exec = LocalExecutor::new();
+  let key = Box::into_raw(Box::new(0u8)) as usize;
+  let woken = Rc::new(RefCell::new(Vec::new()));
+  for i in 0..3 {
+    let woken = woken.clone();
+    exec.spawn(move || {
+      let before = Rc::new(Cell::new(false));
+      let result = parking_lot::park(key, || true, || before.set(true), None);
+      assert!(before.get());
+      woken.borrow_mut().push((i, result));
+    });
+  }
+  exec.run_until_idle();
+  let mut results = Vec::new();
+  for _ in 0..4 {
+    results.push(parking_lot::unpark_one(key));
+    exec.run_until_idle();
+  }
+  let (one, last, none) = (UnparkResult { unparked: true, have_more: true }, UnparkResult { unparked: true, have_more: false }, UnparkResult::default());
+  assert_eq!(vec![one, one, last, none], results);
+  assert_eq!(vec![(0, ParkResult::Unparked), (1, ParkResult::Unparked), (2, ParkResult::Unparked)], *woken.borrow());
+  drop(unsafe { Box::from_raw(key as *mut u8) });
+}
+
+#[test]
+fn cancelled_tasks_leave_the_queue() {
+  let event = Event::default();
+  let key = event.key();
+  let exec = LocalExecutor::new();
+  exec.spawn(move || parking_lot::park(key, || true, || (), None));
+  exec.run_until_idle();
+  drop(exec);
+  assert_eq!(0, event.set());
+}
+
+#[test]
+fn timeouts_go_by_the_task_clock() {
+  let clock = Clock::manual();
+  let exec = LocalExecutor::with_clock(clock.clone());
+  let event = Rc::new(Event::default());
+  let waiter = event.clone();
+  let handle = exec.spawn(move || waiter.wait_until(Some(time::now() + Duration::from_secs(60))));
+  exec.run_until_idle();
+  assert!(!handle.is_finished());
+  // A minute early, going by the real time.
+  clock.advance(Duration::from_secs(60));
+  exec.run_until_idle();
+  assert!(handle.is_finished());
+  assert!(!handle.join().unwrap());
+}
+
+// And a lock, which hands itself over one waiter at a time.
+#[derive(Default)]
+struct Lock(AtomicBool);
+
+impl Lock {
+  fn key(&self) -> usize { self as *const Self as usize }
+
+  fn lock(&self) {
+    while self.0.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+      parking_lot::park(self.key(), || self.0.load(Ordering::Relaxed), || (), None);
+    }
+  }
+
+  fn unlock(&self) {
+    self.0.store(false, Ordering::Release);
+    parking_lot::unpark_one(self.key());
+  }
+}
+
+#[test]
+fn unwound_waiters_pass_their_wakeup_on() {
+  let clock = Clock::manual();
+  let exec = LocalExecutor::with_clock(clock.clone());
+  let lock = Rc::new(Lock(AtomicBool::new(true)));
+  let first = lock.clone();
+  let first = exec.spawn(move || time::timeout(Duration::from_secs(1), || first.lock()).is_ok());
+  let second = lock.clone();
+  let second = exec.spawn(move || { second.lock(); second.unlock() });
+  exec.run_until_idle();
+  // Wakes the first, whose time's up before it gets to run.
+  lock.unlock();
+  clock.advance(Duration::from_secs(1));
+  exec.run_until_idle();
+  assert!(!first.join().unwrap());
+  assert!(second.is_finished());
+}
+
+// A scheduler of our own: round robin over coroutines, which suspend to park. It needn't bother
+// tracking who's been unparked, since parks may return whenever they like.
+struct RoundRobin;
+
+type Yield = Yielder<(), ()>;
+
+std::thread_local! {
+  static YIELDER: Cell<*const Yield> = const { Cell::new(std::ptr::null()) };
+}
+
+static NUDGES: AtomicUsize = AtomicUsize::new(0);
+
+struct Nudge;
+
+impl Unpark for Nudge {
+  fn unpark(&self) { NUDGES.fetch_add(1, Ordering::Relaxed); }
+}
+
+impl Scheduler for RoundRobin {
+  fn unparker(&self) -> Box<dyn Unpark> { Box::new(Nudge) }
+
+  fn park(&self, _deadline: Option<Instant>) {
+    let yielder = YIELDER.with(Cell::get);
+    unsafe { &*yielder }.suspend(());
+    // The others have had a go meanwhile, and set theirs.
+    YIELDER.with(|c| c.set(yielder));
+  }
+}
+
+#[test]
+fn other_schedulers_plug_in() {
+  let stack = || SafeStack::new(64 * 1024, PageSize::get().unwrap()).unwrap();
+  let event = Rc::new(Event::default());
+  let mut coroutines: Vec<Coroutine<(), (), bool, SafeStack>> = Vec::new();
+  for _ in 0..2 {
+    let event = event.clone();
+    coroutines.push(Coroutine::new(stack(), move |y: &Yield, ()| {
+      YIELDER.with(|c| c.set(y));
+      event.wait_until(None)
+    }).unwrap());
+  }
+  let setter = event.clone();
+  coroutines.push(Coroutine::new(stack(), move |_: &Yield, ()| setter.set() == 2).unwrap());
+
+  let mut done = Vec::new();
+  while !coroutines.is_empty() {
+    coroutines.retain_mut(|c| match parking_lot::with_scheduler(&RoundRobin, || c.resume(())) {
+      CoroutineResult::Yield(()) => true,
+      CoroutineResult::Return(ok) => {
+        done.push(ok);
+        false
+      }
+    });
+  }
+  assert_eq!(vec![true, true, true], done);
+  assert_eq!(2, NUDGES.load(Ordering::Relaxed));
+}
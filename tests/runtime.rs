@@ -0,0 +1,299 @@
+use stackle::runtime::{self, LocalExecutor};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+#[test]
+fn tasks_take_turns() {
+  let exec = LocalExecutor::new();
+  let log = Rc::new(RefCell::new(Vec::new()));
+  for name in ["a", "b"] {
+    let log = log.clone();
+    exec.spawn(move || {
+      for i in 0..3 {
+        log.borrow_mut().push(format!("{}{}", name, i));
+        runtime::yield_now();
+      }
+    });
+  }
+  assert_eq!(0, exec.run_until_idle());
+  assert_eq!(vec!["a0", "b0", "a1", "b1", "a2", "b2"], *log.borrow());
+}
+
+#[test]
+fn park_waits_for_unpark() {
+  let exec = LocalExecutor::new();
+  let woken = Rc::new(Cell::new(false));
+  let flag = woken.clone();
+  let sleeper = exec.spawn(move || {
+    while !flag.get() { runtime::park() }
+    "awake"
+  });
+  assert_eq!(1, exec.run_until_idle());
+  assert!(!sleeper.is_finished());
+  // a spurious unpark just goes round the loop again.
+  sleeper.task().unpark();
+  assert_eq!(1, exec.run_until_idle());
+  woken.set(true);
+  sleeper.task().unpark();
+  assert_eq!(0, exec.run_until_idle());
+  assert_eq!("awake", sleeper.join().unwrap());
+}
+
+#[test]
+fn unpark_before_park_is_remembered() {
+  let exec = LocalExecutor::new();
+  let handle = exec.spawn(|| {
+    runtime::current().unpark();
+    runtime::park();
+  });
+  assert_eq!(0, exec.run_until_idle());
+  assert!(handle.is_finished());
+}
+
+#[test]
+fn tasks_join_each_other() {
+  let exec = LocalExecutor::new();
+  let outer = exec.spawn(|| {
+    let inner = runtime::spawn(|| {
+      for _ in 0..5 { runtime::yield_now() }
+      21
+    });
+    inner.join().unwrap() * 2
+  });
+  assert_eq!(42, outer.join().unwrap());
+}
+
+#[test]
+fn panics_go_to_the_joiner() {
+  let exec = LocalExecutor::new();
+  let handle = exec.spawn(|| -> () { panic!("oops") });
+  let payload = handle.join().unwrap_err();
+  assert_eq!(Some(&"oops"), payload.downcast_ref::<&str>());
+}
+
+#[test]
+fn huge_stacks_are_mapped_to_size() {
+  // Too big to round up to a power of two in a u32. Only the top page or so gets touched.
+  let exec = LocalExecutor::new();
+  let handle = exec.spawn_sized((1 << 31) + 1, || 42);
+  assert_eq!(42, handle.join().unwrap());
+}
+
+struct DropFlag(Rc<Cell<bool>>);
+
+impl Drop for DropFlag {
+  fn drop(&mut self) { self.0.set(true) }
+}
+
+#[test]
+fn dropping_the_executor_cancels_tasks() {
+  let dropped = Rc::new(Cell::new(false));
+  let flag = DropFlag(dropped.clone());
+  let exec = LocalExecutor::new();
+  let handle = exec.spawn(move || {
+    let _flag = flag;
+    loop { runtime::park() }
+  });
+  assert_eq!(1, exec.run_until_idle());
+  drop(exec);
+  assert!(dropped.get());
+  assert!(handle.join().is_err());
+}
+
+mod pool {
+  use stackle::fiber_local;
+  use stackle::runtime::{self, Executor};
+  use std::cell::Cell;
+  use std::collections::HashSet;
+  use std::rc::Rc;
+  use std::sync::atomic::{AtomicBool, Ordering};
+  use std::sync::{Arc, Mutex};
+  use std::time::Duration;
+
+  #[test]
+  fn spreads_over_workers() {
+    let exec = Executor::new(4);
+    let threads = Arc::new(Mutex::new(HashSet::new()));
+    let handles: Vec<_> = (0..8).map(|_| {
+      let threads = threads.clone();
+      unsafe { exec.spawn(move || {
+        // hog the worker, so the others have to pick up the rest.
+        std::thread::sleep(Duration::from_millis(20));
+        threads.lock().unwrap().insert(std::thread::current().id());
+      })}
+    }).collect();
+    for handle in handles { handle.join().unwrap() }
+    assert!(threads.lock().unwrap().len() > 1);
+  }
+
+  #[test]
+  fn woken_from_another_thread() {
+    let exec = Executor::new(2);
+    let ready = Arc::new(AtomicBool::new(false));
+    let flag = ready.clone();
+    let handle = unsafe { exec.spawn(move || {
+      while !flag.load(Ordering::Acquire) { runtime::park() }
+      "woken"
+    })};
+    std::thread::sleep(Duration::from_millis(10));
+    assert!(!handle.is_finished());
+    ready.store(true, Ordering::Release);
+    handle.task().unpark();
+    assert_eq!("woken", handle.join().unwrap());
+  }
+
+  #[test]
+  fn tasks_join_and_spawn_each_other() {
+    let exec = Executor::new(3);
+    let spawner = exec.handle();
+    let outer = unsafe { exec.spawn(move || {
+      let inner: Vec<_> = (0..10).map(|i| spawner.spawn(move || {
+        runtime::yield_now();
+        i
+      })).collect();
+      inner.into_iter().map(|h| h.join().unwrap()).sum::<usize>()
+    })};
+    assert_eq!(45, outer.join().unwrap());
+  }
+
+  #[test]
+  fn sized_stacks() {
+    let exec = Executor::new(1);
+    let handle = unsafe { exec.spawn_sized(4 << 20, || {
+      let big = [1u8; 2 << 20];
+      std::hint::black_box(&big).iter().map(|&b| b as usize).sum::<usize>()
+    })};
+    assert_eq!(2 << 20, handle.join().unwrap());
+  }
+
+  #[test]
+  fn panics_go_to_the_joiner() {
+    let exec = Executor::new(2);
+    let handle = unsafe { exec.spawn::<_, ()>(|| panic!("oops")) };
+    let payload = handle.join().unwrap_err();
+    assert_eq!(Some(&"oops"), payload.downcast_ref::<&str>());
+  }
+
+  #[test]
+  fn shutdown_cancels_parked_tasks() {
+    struct DropFlag(Arc<AtomicBool>);
+    impl Drop for DropFlag {
+      fn drop(&mut self) {
+        // Neither of these gets us cancelled a second time.
+        runtime::park();
+        runtime::yield_now();
+        self.0.store(true, Ordering::Release)
+      }
+    }
+    let dropped = Arc::new(AtomicBool::new(false));
+    let flag = DropFlag(dropped.clone());
+    let exec = Executor::new(2);
+    let handle = unsafe { exec.spawn::<_, ()>(move || {
+      let _flag = flag;
+      loop { runtime::park() }
+    })};
+    std::thread::sleep(Duration::from_millis(10));
+    drop(exec);
+    assert!(dropped.load(Ordering::Acquire));
+    assert!(handle.join().is_err());
+  }
+
+  fiber_local! {
+    // Not `Send`, so it ties whoever touches it to their worker.
+    static TOUCHED: Rc<Cell<bool>> = Rc::new(Cell::new(false));
+  }
+
+  #[cfg(debug_assertions)]
+  #[test]
+  fn tied_tasks_stay_put() {
+    let exec = Executor::new(4);
+    let busy: Vec<_> = (0..8).map(|_| unsafe { exec.spawn(|| {
+      for _ in 0..200 { runtime::yield_now() }
+    })}).collect();
+    let tied = unsafe { exec.spawn(|| {
+      TOUCHED.with(|t| t.set(true));
+      let home = std::thread::current().id();
+      (0..200).all(|_| {
+        runtime::yield_now();
+        std::thread::current().id() == home
+      })
+    })};
+    assert!(tied.join().unwrap());
+    for handle in busy { handle.join().unwrap() }
+  }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod per_core {
+  use stackle::runtime::{self, ThreadPerCore};
+  use std::rc::Rc;
+  use std::sync::atomic::{AtomicBool, Ordering};
+  use std::sync::Arc;
+  use std::time::Duration;
+
+  fn cpus(n: usize) -> Vec<usize> {
+    runtime::available_cpus().unwrap().into_iter().cycle().take(n).collect()
+  }
+
+  #[test]
+  fn tasks_stay_on_their_core() {
+    let workers = ThreadPerCore::new(&cpus(2)).unwrap();
+    let handles: Vec<_> = (0..8).map(|i| workers.spawn_on(i % 2, || {
+      let home = Rc::new(std::thread::current().id());
+      (0..50).all(|_| {
+        runtime::yield_now();
+        std::thread::current().id() == *home
+      })
+    })).collect();
+    for handle in handles { assert!(handle.join().unwrap()) }
+  }
+
+  #[test]
+  fn workers_are_pinned() {
+    let cpu = runtime::available_cpus().unwrap()[0];
+    let workers = ThreadPerCore::new(&[cpu]).unwrap();
+    let on = workers.spawn_on(0, || unsafe { libc::sched_getcpu() });
+    assert_eq!(cpu as i32, on.join().unwrap());
+  }
+
+  #[test]
+  fn cores_spawn_on_each_other() {
+    let workers = ThreadPerCore::new(&cpus(2)).unwrap();
+    let other = workers.handle(1);
+    let outer = workers.spawn_on(0, move || {
+      let inner = other.spawn(|| std::thread::current().name().map(String::from));
+      let local = runtime::spawn(|| 1);
+      (inner.join().unwrap(), local.join().unwrap())
+    });
+    let (name, one) = outer.join().unwrap();
+    assert!(name.unwrap().starts_with("stackle-core-"));
+    assert_eq!(1, one);
+  }
+
+  #[test]
+  fn dropping_cancels_parked_tasks() {
+    struct DropFlag(Arc<AtomicBool>);
+    impl Drop for DropFlag {
+      fn drop(&mut self) { self.0.store(true, Ordering::Release) }
+    }
+    let dropped = Arc::new(AtomicBool::new(false));
+    let flag = DropFlag(dropped.clone());
+    let workers = ThreadPerCore::new(&cpus(1)).unwrap();
+    let handle = workers.spawn_on::<_, ()>(0, move || {
+      let _flag = flag;
+      loop { runtime::park() }
+    });
+    std::thread::sleep(Duration::from_millis(10));
+    let late = workers.handle(0);
+    drop(workers);
+    assert!(dropped.load(Ordering::Acquire));
+    assert!(handle.join().is_err());
+    // Nobody's there to run it, so it's cancelled rather than left hanging.
+    assert!(late.spawn(|| ()).join().is_err());
+  }
+
+  #[test]
+  fn bad_cpus_are_an_error() {
+    assert!(ThreadPerCore::new(&[usize::MAX]).is_err());
+  }
+}
@@ -1,4 +1,6 @@
-use stackle::{*, stack::*, switch::*};
+use stackle::{stack::*, switch::*};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 fn adder(stack: *mut usize, arg: usize) {
   let mut ret = Switch { stack, arg };
@@ -29,9 +31,8 @@ fn allocator_adding_closure() {
     // this is just to give us something to move so it's a real closure
     let thing = (42usize, 42usize);
     let s = AllocatorStack::new(8192);
-    let c = link_closure_detached(s.end(), |stack, arg| {
+    let c = link_closure_detached(s.end(), move |stack, arg| {
       // check our moved thing is still what we set it to.
-      let thing = thing;
       assert_eq!(thing.0, 42);
       assert_eq!(thing.1, 42);
       let mut ret = Switch { stack, arg };
@@ -71,9 +72,8 @@ fn paranoid_adding_closure() {
     let thing = (42usize, 42usize);
     let p = PageSize::get().unwrap();
     let s = ParanoidStack::new(8192, p).unwrap();
-    let c = link_closure_detached(s.end(), |stack, arg| {
+    let c = link_closure_detached(s.end(), move |stack, arg| {
       // check our moved thing is still what we set it to.
-      let thing = thing;
       assert_eq!(thing.0, 42);
       assert_eq!(thing.1, 42);
       let mut ret = Switch { stack, arg };
@@ -113,9 +113,8 @@ fn safe_adding_closure() {
     let thing = (42usize, 42usize);
     let p = PageSize::get().unwrap();
     let s = SafeStack::new(8192, p).unwrap();
-    let c = link_closure_detached(s.end(), |stack, arg| {
+    let c = link_closure_detached(s.end(), move |stack, arg| {
       // check our moved thing is still what we set it to.
-      let thing = thing;
       assert_eq!(thing.0, 42);
       assert_eq!(thing.1, 42);
       let mut ret = Switch { stack, arg };
@@ -130,3 +129,209 @@ fn safe_adding_closure() {
     }
   }
 }
+
+#[test]
+fn safe_multi_word() {
+  unsafe {
+    let p = PageSize::get().unwrap();
+    let s = SafeStack::new(8192, p).unwrap();
+    let c = link_closure_detached(s.end(), |stack, _arg| {
+      let mut ret = switch2(stack, 0, 0);
+      loop {
+        let r = switch3(ret.stack, ret.arg + 1, ret.arg2 + 2, ret.arg + ret.arg2);
+        ret = Switch2 { stack: r.stack, arg: r.arg, arg2: r.arg2 };
+      }
+    });
+    let mut ret = switch2(c, 0, 0);
+    for i in 0..1000 {
+      let r = switch2(ret.stack, i, i * 2);
+      assert_eq!(i + 1, r.arg);
+      assert_eq!(i * 2 + 2, r.arg2);
+      ret = r;
+    }
+    // three words, the last being the sum of what we sent.
+    let r = switch3(ret.stack, 5, 7, 0);
+    assert_eq!((6, 9), (r.arg, r.arg2));
+  }
+}
+
+fn typed_echo<T: Clone>(stack: *mut usize, _arg: usize) {
+  unsafe {
+    let mut ret = switch_with::<Option<T>>(stack, None);
+    loop {
+      let value = ret.value.clone();
+      ret = switch_with(ret.stack, value);
+    }
+  }
+}
+
+#[test]
+fn safe_switch_with() {
+  unsafe {
+    let p = PageSize::get().unwrap();
+    // fits in registers
+    let s = SafeStack::new(8192, p).unwrap();
+    let c = link_closure_detached(s.end(), typed_echo::<String>);
+    let mut ret = switch_with::<Option<String>>(c, None);
+    for i in 0..100 {
+      ret = switch_with(ret.stack, Some(i.to_string()));
+      assert_eq!(Some(i.to_string()), ret.value);
+    }
+    // fits in registers, with padding
+    let s = SafeStack::new(8192, p).unwrap();
+    let c = link_closure_detached(s.end(), typed_echo::<(u8, u16)>);
+    let mut ret = switch_with::<Option<(u8, u16)>>(c, None);
+    for i in 0..100 {
+      ret = switch_with(ret.stack, Some((i, i as u16 * 3)));
+      assert_eq!(Some((i, i as u16 * 3)), ret.value);
+    }
+    // doesn't fit in registers
+    let s = SafeStack::new(8192, p).unwrap();
+    let c = link_closure_detached(s.end(), typed_echo::<[u64; 8]>);
+    let mut ret = switch_with::<Option<[u64; 8]>>(c, None);
+    for i in 0..100 {
+      ret = switch_with(ret.stack, Some([i; 8]));
+      assert_eq!(Some([i; 8]), ret.value);
+    }
+  }
+}
+
+static ONTOP_LOCAL: AtomicUsize = AtomicUsize::new(0);
+
+unsafe extern "C" fn times_ten(stack: *mut usize, arg: usize) -> Switch {
+  // we should be running on the coroutine's stack, not the test thread's.
+  let local = 0u8;
+  ONTOP_LOCAL.store(&local as *const u8 as usize, Ordering::Relaxed);
+  Switch { stack, arg: arg * 10 }
+}
+
+#[test]
+fn safe_switch_ontop() {
+  unsafe {
+    let p = PageSize::get().unwrap();
+    let s = SafeStack::new(8192, p).unwrap();
+    let c = link_closure_detached(s.end(), adder);
+    let mut ret = Switch { stack: c, arg: 0 };
+    for i in 0..100 {
+      ret = switch_ontop(ret.stack, i, times_ten);
+      assert_eq!(i * 10 + 1, ret.arg);
+      let local = ONTOP_LOCAL.load(Ordering::Relaxed);
+      assert!(local < s.end() as usize && local > s.end() as usize - 8192);
+    }
+    // and it still works normally afterwards.
+    ret = switch(ret.stack, 41);
+    assert_eq!(42, ret.arg);
+  }
+}
+
+struct Flagged<S>(S, Arc<AtomicBool>);
+
+unsafe impl<S: Stack> Stack for Flagged<S> {
+  fn end(&self) -> *mut usize { self.0.end() }
+  fn size(&self) -> usize { self.0.size() }
+}
+
+impl<S> Drop for Flagged<S> {
+  fn drop(&mut self) { self.1.store(true, Ordering::SeqCst) }
+}
+
+#[test]
+fn safe_exit_to() {
+  unsafe {
+    let p = PageSize::get().unwrap();
+    let dropped = Arc::new(AtomicBool::new(false));
+    let s = Flagged(SafeStack::new(8192, p).unwrap(), dropped.clone());
+    let end = s.end();
+    let c = link_closure_detached(end, move |stack, arg| {
+      let ret = switch(stack, arg + 1);
+      exit_to(ret.stack, ret.arg + 1, s);
+    });
+    let ret = switch(c, 1);
+    assert_eq!(2, ret.arg);
+    assert!(!dropped.load(Ordering::SeqCst));
+    let ret = switch(ret.stack, 41);
+    assert_eq!(42, ret.arg);
+    assert!(ret.stack.is_null());
+    assert!(dropped.load(Ordering::SeqCst));
+  }
+}
+
+#[test]
+fn safe_prepared_closure() {
+  unsafe {
+    let thing = (42usize, 42usize);
+    let started = Arc::new(AtomicBool::new(false));
+    let started2 = started.clone();
+    let p = PageSize::get().unwrap();
+    let s = SafeStack::new(8192, p).unwrap();
+    let c = link_closure_prepared(s.end(), move |stack, arg| {
+      started2.store(true, Ordering::SeqCst);
+      assert_eq!(thing.0, 42);
+      assert_eq!(thing.1, 42);
+      let mut ret = Switch { stack, arg };
+      loop {
+        ret = switch(ret.stack, ret.arg + 1);
+      }
+    });
+    // nothing runs until we switch to it.
+    assert!(!started.load(Ordering::SeqCst));
+    let mut ret = Switch { stack: c, arg: 0 };
+    for i in 0..1000 {
+      ret = switch(ret.stack, i);
+      assert_eq!(i + 1, ret.arg);
+    }
+    assert!(started.load(Ordering::SeqCst));
+  }
+}
+
+#[cfg(any(target_arch="x86_64", target_arch="aarch64"))]
+mod fenv {
+  extern "C" {
+    pub fn fegetround() -> i32;
+    pub fn fesetround(mode: i32) -> i32;
+  }
+  #[cfg(target_arch="x86_64")]
+  pub const FE_UPWARD: i32 = 0x800;
+  #[cfg(target_arch="aarch64")]
+  pub const FE_UPWARD: i32 = 0x400000;
+}
+
+#[cfg(any(target_arch="x86_64", target_arch="aarch64"))]
+#[test]
+fn switch_full_keeps_rounding_mode() {
+  unsafe {
+    let p = PageSize::get().unwrap();
+    let s = SafeStack::new(8192, p).unwrap();
+    let c = link_closure_prepared(s.end(), |stack, arg| {
+      let mut ret = Switch { stack, arg };
+      loop {
+        fenv::fesetround(fenv::FE_UPWARD);
+        ret = switch(ret.stack, ret.arg + 1);
+      }
+    });
+    let mode = fenv::fegetround();
+    assert_ne!(fenv::FE_UPWARD, mode);
+    let ret = switch_full(c, 1);
+    assert_eq!(2, ret.arg);
+    assert_eq!(mode, fenv::fegetround());
+    // plain `switch` lets it leak.
+    switch(ret.stack, 2);
+    assert_eq!(fenv::FE_UPWARD, fenv::fegetround());
+    fenv::fesetround(mode);
+  }
+}
+
+#[test]
+fn suspended_handles() {
+  let p = PageSize::get().unwrap();
+  let mut s = SafeStack::new(16 * 1024, p).unwrap();
+  let c = Suspended::link_detached(&mut s, |back, n| {
+    let (back, n) = back.switch(n + 1);
+    // returning switches to `back` for the last time.
+    (back, n * 10)
+  });
+  let (c, n) = c.switch(1);
+  assert_eq!(2, n);
+  let (_finished, n) = c.switch(3);
+  assert_eq!(30, n);
+}
@@ -0,0 +1,216 @@
+use stackle::runtime::sync::{Barrier, Condvar, Mutex, Once, OnceCell, RwLock, Semaphore, WaitGroup};
+use stackle::runtime::{self, Executor, LocalExecutor};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[test]
+fn mutexes_go_in_turn() {
+  let exec = LocalExecutor::new();
+  let lock = Rc::new(Mutex::new(Vec::new()));
+  for name in ["a", "b", "c"] {
+    let lock = lock.clone();
+    exec.spawn(move || for _ in 0..2 {
+      let mut log = lock.lock();
+      log.push(name);
+      // Everyone else queues up behind us meanwhile.
+      runtime::yield_now();
+      drop(log);
+      runtime::yield_now();
+    });
+  }
+  assert_eq!(0, exec.run_until_idle());
+  assert_eq!(vec!["a", "b", "c", "a", "b", "c"], *lock.lock());
+}
+
+#[test]
+fn mutexes_across_workers() {
+  let exec = Executor::new(4);
+  let count = Arc::new(Mutex::new(0));
+  let handles: Vec<_> = (0..8).map(|_| {
+    let count = count.clone();
+    unsafe { exec.spawn(move || for _ in 0..100 {
+      let mut count = count.lock();
+      let was = *count;
+      runtime::yield_now();
+      *count = was + 1;
+    })}
+  }).collect();
+  for handle in handles { handle.join().unwrap() }
+  assert_eq!(800, *count.lock());
+}
+
+#[test]
+fn writers_hold_up_later_readers() {
+  let exec = LocalExecutor::new();
+  let lock = Rc::new(RwLock::new(()));
+  let log = Rc::new(RefCell::new(Vec::new()));
+  for (name, write) in [("r1", false), ("r2", false), ("w", true), ("r3", false)] {
+    let (lock, log) = (lock.clone(), log.clone());
+    exec.spawn(move || {
+      let _guard = if write { Err(lock.write()) } else { Ok(lock.read()) };
+      log.borrow_mut().push(format!("{} in", name));
+      runtime::yield_now();
+      log.borrow_mut().push(format!("{} out", name));
+    });
+  }
+  exec.run_until_idle();
+  assert_eq!(vec!["r1 in", "r2 in", "r1 out", "r2 out", "w in", "w out", "r3 in", "r3 out"], *log.borrow());
+  assert!(lock.try_write().is_some());
+}
+
+#[test]
+fn condvars_wake_waiters() {
+  let exec = LocalExecutor::new();
+  let pair = Rc::new((Mutex::new(Vec::new()), Condvar::new()));
+  let consumer = {
+    let pair = pair.clone();
+    exec.spawn(move || {
+      let (queue, ready) = &*pair;
+      let mut got = Vec::new();
+      while got.len() < 3 {
+        let mut queue = ready.wait_while(queue.lock(), |q| q.is_empty());
+        got.append(&mut queue);
+      }
+      got
+    })
+  };
+  exec.spawn(move || for i in 0..3 {
+    pair.0.lock().push(i);
+    pair.1.notify_one();
+    runtime::yield_now();
+  });
+  exec.run_until_idle();
+  assert_eq!(vec![0, 1, 2], consumer.join().unwrap());
+}
+
+#[test]
+fn condvars_time_out() {
+  let exec = LocalExecutor::new();
+  let pair = Rc::new((Mutex::new(false), Condvar::new()));
+  let waiter = {
+    let pair = pair.clone();
+    exec.spawn(move || {
+      let (flag, cond) = &*pair;
+      let (_, result) = cond.wait_timeout(flag.lock(), Duration::from_millis(20));
+      let timed_out = result.timed_out();
+      let (flag, result) = cond.wait_timeout_while(flag.lock(), Duration::from_secs(10), |set| !*set);
+      (timed_out, result.timed_out(), *flag)
+    })
+  };
+  exec.spawn(move || {
+    // Let the first wait time out before we set it.
+    std::thread::sleep(Duration::from_millis(40));
+    runtime::yield_now();
+    *pair.0.lock() = true;
+    pair.1.notify_all();
+  });
+  assert_eq!((true, false, true), waiter.join().unwrap());
+}
+
+#[test]
+fn semaphores_limit_concurrency() {
+  let exec = LocalExecutor::new();
+  let permits = Rc::new(Semaphore::new(2));
+  let (inside, most) = (Rc::new(Cell::new(0)), Rc::new(Cell::new(0)));
+  for _ in 0..6 {
+    let (permits, inside, most) = (permits.clone(), inside.clone(), most.clone());
+    exec.spawn(move || {
+      let _permit = permits.acquire();
+      inside.set(inside.get() + 1);
+      most.set(most.get().max(inside.get()));
+      runtime::yield_now();
+      inside.set(inside.get() - 1);
+    });
+  }
+  exec.run_until_idle();
+  assert_eq!(2, most.get());
+  assert_eq!(2, permits.available_permits());
+  permits.try_acquire().unwrap().forget();
+  assert_eq!(1, permits.available_permits());
+}
+
+#[test]
+fn barriers_release_everyone_with_one_leader() {
+  let exec = LocalExecutor::new();
+  let barrier = Rc::new(Barrier::new(3));
+  let leaders = Rc::new(Cell::new(0));
+  let log = Rc::new(RefCell::new(Vec::new()));
+  for i in 0..3 {
+    let (barrier, leaders, log) = (barrier.clone(), leaders.clone(), log.clone());
+    exec.spawn(move || for round in 0..2 {
+      for _ in 0..i { runtime::yield_now() }
+      log.borrow_mut().push(round);
+      if barrier.wait().is_leader() { leaders.set(leaders.get() + 1) }
+    });
+  }
+  exec.run_until_idle();
+  assert_eq!(2, leaders.get());
+  assert_eq!(vec![0, 0, 0, 1, 1, 1], *log.borrow());
+}
+
+#[test]
+fn once_runs_once_unless_it_panics() {
+  let exec = LocalExecutor::new();
+  let once = Rc::new(Once::new());
+  let runs = Rc::new(Cell::new(0));
+  let handles: Vec<_> = (0..3).map(|i| {
+    let (once, runs) = (once.clone(), runs.clone());
+    exec.spawn(move || once.call_once(|| {
+      runs.set(runs.get() + 1);
+      runtime::yield_now();
+      // The others are waiting, and the next of them gets a go.
+      if i == 0 { panic!("oops") }
+    }))
+  }).collect();
+  exec.run_until_idle();
+  let results: Vec<_> = handles.into_iter().map(|h| h.join().is_ok()).collect();
+  assert_eq!(vec![false, true, true], results);
+  assert_eq!(2, runs.get());
+  assert!(once.is_completed());
+
+  let cell = OnceCell::new();
+  assert_eq!(None, cell.get());
+  assert_eq!(Ok(()), cell.set(1));
+  assert_eq!(Err(2), cell.set(2));
+  assert_eq!(&1, cell.get_or_init(|| 3));
+}
+
+#[test]
+fn threads_wait_for_task_groups() {
+  let exec = Executor::new(2);
+  let group = Arc::new(WaitGroup::new());
+  let done = Arc::new(AtomicUsize::new(0));
+  group.add(10);
+  for _ in 0..10 {
+    let (group, done) = (group.clone(), done.clone());
+    unsafe { exec.spawn(move || {
+      runtime::yield_now();
+      done.fetch_add(1, Ordering::Relaxed);
+      group.done();
+    })};
+  }
+  group.wait();
+  assert_eq!(10, done.load(Ordering::Relaxed));
+  assert_eq!(0, group.count());
+}
+
+#[test]
+fn cancelled_waiters_pass_the_lock_on() {
+  let lock = Arc::new(Mutex::new(0));
+  let held = lock.lock();
+  let (first, second) = (LocalExecutor::new(), LocalExecutor::new());
+  let (a, b) = (lock.clone(), lock.clone());
+  first.spawn(move || *a.lock() += 1);
+  let got = second.spawn(move || *b.lock() += 10);
+  first.run_until_idle();
+  second.run_until_idle();
+  // Handed to the first, which never gets to run before it's cancelled.
+  drop(held);
+  drop(first);
+  second.run_until_idle();
+  assert!(got.is_finished());
+  assert_eq!(10, *lock.lock());
+}
@@ -0,0 +1,244 @@
+use stackle::runtime::sync::Mutex;
+use stackle::runtime::time::{self, Clock};
+use stackle::runtime::{self, channel, Executor, LocalExecutor};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+const MS: Duration = Duration::from_millis(1);
+
+#[test]
+fn sleeping_parks_just_the_task() {
+  let exec = LocalExecutor::new();
+  let start = Instant::now();
+  let sleeper = exec.spawn(|| {
+    time::sleep(30 * MS);
+    time::now()
+  });
+  let busy = Rc::new(Cell::new(0));
+  let counter = busy.clone();
+  exec.spawn(move || for _ in 0..10 {
+    counter.set(counter.get() + 1);
+    runtime::yield_now();
+  });
+  exec.run_until_idle();
+  assert_eq!(10, busy.get());
+  assert!(sleeper.join().unwrap() >= start + 30 * MS);
+  assert!(start.elapsed() >= 30 * MS);
+}
+
+#[test]
+fn threads_sleep_too() {
+  let start = Instant::now();
+  time::sleep(20 * MS);
+  assert!(start.elapsed() >= 20 * MS);
+  time::sleep_until(start);
+}
+
+#[test]
+fn manual_clocks_only_move_when_told() {
+  let clock = Clock::manual();
+  let exec = LocalExecutor::with_clock(clock.clone());
+  let start = clock.now();
+  let woke = Rc::new(RefCell::new(Vec::new()));
+  for ms in [30, 10, 20, 10_000] {
+    let woke = woke.clone();
+    exec.spawn(move || {
+      time::sleep(ms * MS);
+      woke.borrow_mut().push((ms, time::now() - start));
+    });
+  }
+  exec.run_until_idle();
+  clock.advance(15 * MS);
+  exec.run_until_idle();
+  assert_eq!(vec![(10, 15 * MS)], *woke.borrow());
+  clock.advance(15 * MS);
+  exec.run_until_idle();
+  assert_eq!(vec![(10, 15 * MS), (20, 30 * MS), (30, 30 * MS)], woke.borrow()[..]);
+  assert_eq!(1, exec.run_until_idle());
+  clock.advance(Duration::from_secs(10));
+  assert_eq!(0, exec.run_until_idle());
+  assert_eq!(start + Duration::from_millis(10_030), clock.now());
+}
+
+#[test]
+fn manual_clocks_move_by_less_than_a_millisecond() {
+  let clock = Clock::manual();
+  let exec = LocalExecutor::with_clock(clock.clone());
+  let short = exec.spawn(|| time::sleep(Duration::from_micros(500)));
+  let long = exec.spawn(|| time::sleep(Duration::from_micros(900)));
+  exec.run_until_idle();
+  clock.advance(Duration::from_micros(500));
+  exec.run_until_idle();
+  assert!(short.is_finished());
+  assert!(!long.is_finished());
+  clock.advance(Duration::from_micros(400));
+  exec.run_until_idle();
+  assert!(long.is_finished());
+}
+
+#[test]
+fn far_off_timers_go_off_on_time() {
+  let clock = Clock::manual();
+  let exec = LocalExecutor::with_clock(clock.clone());
+  let start = clock.now();
+  let hour = Duration::from_secs(60 * 60);
+  // Manual clocks tick by the nanosecond, so the wheel's levels start down here.
+  let ns = Duration::from_nanos;
+  let delays = [ns(1), ns(63), ns(64), ns(65), ns(4095), ns(4097), ns(262_145), MS, 63 * MS, 64 * MS, 65 * MS, 4095 * MS, 4097 * MS, Duration::from_secs(7), hour, 24 * hour, 30 * 24 * hour, 3 * 365 * 24 * hour];
+  let woke = Rc::new(RefCell::new(Vec::new()));
+  for delay in delays {
+    let woke = woke.clone();
+    exec.spawn(move || {
+      time::sleep(delay);
+      woke.borrow_mut().push(time::now() - start);
+    });
+  }
+  exec.run_until_idle();
+  // In uneven steps, each a little under the gap to the next timer, so each wakes on the first
+  // step that reaches it.
+  let mut expected = Vec::new();
+  for delay in delays {
+    while clock.now() - start < delay {
+      let step = (delay - (clock.now() - start)).mul_f64(0.7).max(ns(1));
+      clock.advance(step);
+      exec.run_until_idle();
+      let late = clock.now() - start >= delay;
+      assert_eq!(late, woke.borrow().len() > expected.len(), "{:?} at {:?}", delay, clock.now() - start);
+    }
+    expected.push(clock.now() - start);
+  }
+  assert_eq!(expected, *woke.borrow());
+}
+
+#[test]
+fn forever_is_too_long_to_add() {
+  let clock = Clock::manual();
+  let exec = LocalExecutor::with_clock(clock.clone());
+  let (tx, rx) = channel::unbounded();
+  let patient = exec.spawn(move || time::timeout(Duration::MAX, || rx.recv()));
+  let forever = exec.spawn(|| time::sleep(Duration::MAX));
+  assert_eq!(2, exec.run_until_idle());
+  tx.send(42).unwrap();
+  clock.advance(100 * 365 * 24 * Duration::from_secs(60 * 60));
+  assert_eq!(1, exec.run_until_idle());
+  assert_eq!(Ok(Ok(42)), patient.join().unwrap());
+  assert!(!forever.is_finished());
+}
+
+#[test]
+fn timeouts_cancel_what_they_time() {
+  let exec = LocalExecutor::new();
+  let lock = Rc::new(Mutex::new(0));
+  let held = lock.clone();
+  let holder = exec.spawn(move || {
+    let mut guard = held.lock();
+    time::sleep(30 * MS);
+    *guard += 1;
+  });
+  let (waiting, unwound) = (lock.clone(), Rc::new(Cell::new(false)));
+  let dropped = unwound.clone();
+  let impatient = exec.spawn(move || {
+    struct Mark(Rc<Cell<bool>>);
+    impl Drop for Mark {
+      fn drop(&mut self) { self.0.set(true) }
+    }
+    let got = time::timeout(5 * MS, || {
+      let _mark = Mark(dropped);
+      *waiting.lock() += 10;
+    });
+    // The lock's none the worse for us giving up on it.
+    *waiting.lock() += 100;
+    got
+  });
+  assert!(impatient.join().unwrap().is_err());
+  holder.join().unwrap();
+  assert!(unwound.get());
+  assert_eq!(101, *lock.lock());
+}
+
+#[test]
+fn timeouts_leave_channels_working() {
+  let exec = LocalExecutor::new();
+  let (tx, rx) = channel::channel(1);
+  tx.send(0).unwrap();
+  // Both wait for room, in turn. The first gives up, and mustn't take the second's turn with it.
+  let (first, second) = (tx.clone(), tx.clone());
+  let impatient = exec.spawn(move || time::timeout(5 * MS, || first.send(1)));
+  let patient = exec.spawn(move || time::timeout(Duration::from_secs(10), || second.send(2)));
+  exec.run_until_idle();
+  assert!(impatient.join().unwrap().is_err());
+  assert_eq!(Ok(0), rx.recv());
+  assert_eq!(Ok(2), rx.recv());
+  assert_eq!(Ok(Ok(())), patient.join().unwrap());
+  drop(tx);
+  assert!(rx.recv().is_err());
+}
+
+#[test]
+fn outer_timeouts_win() {
+  let exec = Executor::new(2);
+  let nested = unsafe { exec.spawn(|| {
+    let start = Instant::now();
+    let outer = time::timeout(10 * MS, || {
+      let inner = time::timeout(Duration::from_secs(10), || time::sleep(Duration::from_secs(10)));
+      unreachable!("the inner timeout returned {:?}", inner)
+    });
+    // Neither goes off if we're quick enough.
+    let quick = time::timeout(Duration::from_secs(10), || time::timeout(Duration::from_secs(10), || 7));
+    (outer.is_err(), start.elapsed(), quick)
+  })};
+  let (timed_out, took, quick) = nested.join().unwrap();
+  assert!(timed_out);
+  assert!(took >= 10 * MS && took < Duration::from_secs(5));
+  assert_eq!(Ok(Ok(7)), quick);
+}
+
+#[test]
+fn intervals_keep_time() {
+  let clock = Clock::manual();
+  let exec = LocalExecutor::with_clock(clock.clone());
+  let start = clock.now();
+  let ticks = Rc::new(RefCell::new(Vec::new()));
+  let log = ticks.clone();
+  exec.spawn(move || {
+    let mut every = time::interval(10 * MS);
+    loop {
+      let tick = every.tick();
+      log.borrow_mut().push(tick - start);
+    }
+  });
+  exec.run_until_idle();
+  for _ in 0..3 {
+    clock.advance(10 * MS);
+    exec.run_until_idle();
+  }
+  // Fall behind, and the late tick goes off, but the one we missed altogether doesn't.
+  clock.advance(25 * MS);
+  exec.run_until_idle();
+  clock.advance(5 * MS);
+  exec.run_until_idle();
+  let ms = |ms| ms * MS;
+  assert_eq!(vec![ms(0), ms(10), ms(20), ms(30), ms(40), ms(60)], *ticks.borrow());
+}
+
+#[test]
+fn intervals_skip_however_many_they_missed() {
+  let clock = Clock::manual();
+  let exec = LocalExecutor::with_clock(clock.clone());
+  let handle = exec.spawn(|| {
+    let mut every = time::interval(Duration::from_nanos(1));
+    let first = every.tick();
+    // Five billion ticks late, more than a `u32` counts.
+    time::sleep(Duration::from_secs(5));
+    every.tick();
+    every.tick() - first
+  });
+  exec.run_until_idle();
+  clock.advance(Duration::from_secs(5));
+  exec.run_until_idle();
+  assert!(!handle.is_finished());
+  clock.advance(MS);
+  exec.run_until_idle();
+  assert_eq!(Duration::new(5, 1), handle.join().unwrap());
+}
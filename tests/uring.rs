@@ -0,0 +1,167 @@
+#![cfg(any(target_os="linux", target_os="android"))]
+
+use stackle::runtime::net::TcpListener;
+use stackle::runtime::uring::{self, File};
+use stackle::runtime::time::{self, Clock};
+use stackle::runtime::{Executor, LocalExecutor};
+use std::cell::Cell;
+use std::io::{Read, Write};
+use std::os::fd::AsFd;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+const MS: Duration = Duration::from_millis(1);
+
+fn temp(name: &str) -> std::path::PathBuf {
+  std::env::temp_dir().join(format!("stackle-uring-{}-{}", name, std::process::id()))
+}
+
+#[test]
+fn files_round_trip() {
+  // With io_uring or without, File works the same.
+  let path = temp("files");
+  let exec = LocalExecutor::new();
+  let writing = path.clone();
+  let read = exec.spawn(move || {
+    let file = File::create(&writing).unwrap();
+    let (written, _) = file.write_at(b"hello".to_vec(), 0);
+    assert_eq!(5, written.unwrap());
+    let (written, _) = file.write_at(b", world".to_vec(), 5);
+    assert_eq!(7, written.unwrap());
+    file.sync_all().unwrap();
+    // Read onto the end of what's already in the buffer.
+    let mut buf = Vec::with_capacity(64);
+    buf.push(b'>');
+    let (read, buf) = File::open(&writing).unwrap().read_at(buf, 7);
+    assert_eq!(5, read.unwrap());
+    buf
+  });
+  assert_eq!(b">world".to_vec(), read.join().unwrap());
+  assert_eq!("hello, world", std::fs::read_to_string(&path).unwrap());
+  std::fs::remove_file(&path).unwrap();
+  assert!(File::open(&path).is_err());
+}
+
+#[test]
+fn executors_find_out_up_front() {
+  let exec = LocalExecutor::new();
+  assert_eq!(uring::is_supported(), exec.uring_supported());
+  assert_eq!(exec.uring_supported(), Executor::new(1).uring_supported());
+}
+
+#[test]
+fn many_tasks_read_at_once() {
+  if !uring::is_supported() { return }
+  let path = temp("many");
+  let data: Vec<u8> = (0..1 << 16).map(|i| (i / 256) as u8).collect();
+  std::fs::write(&path, &data).unwrap();
+  let exec = Executor::new(2);
+  let readers: Vec<_> = (0..64u64).map(|i| {
+    let path = path.clone();
+    unsafe { exec.spawn(move || {
+      let (read, buf) = File::open(&path).unwrap().read_at(Vec::with_capacity(256), i * 1024);
+      assert_eq!(256, read.unwrap());
+      buf
+    })}
+  }).collect();
+  for (i, reader) in readers.into_iter().enumerate() {
+    assert_eq!(vec![(i * 4) as u8; 256], reader.join().unwrap());
+  }
+  std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn accepts_connections() {
+  if !uring::is_supported() { return }
+  let exec = LocalExecutor::new();
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = listener.local_addr().unwrap();
+  let server = exec.spawn(move || {
+    let mut stream = std::net::TcpStream::from(uring::accept(listener.as_fd()).unwrap());
+    let mut got = String::new();
+    stream.read_to_string(&mut got).unwrap();
+    got
+  });
+  let mut client = std::net::TcpStream::connect(addr).unwrap();
+  client.write_all(b"accepted").unwrap();
+  drop(client);
+  assert_eq!("accepted", server.join().unwrap());
+}
+
+#[test]
+fn cancelled_accepts_close_what_they_accept() {
+  if !uring::is_supported() { return }
+  let clock = Clock::manual();
+  let exec = LocalExecutor::with_clock(clock.clone());
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = listener.local_addr().unwrap();
+  let accepting = exec.spawn(move || {
+    let accepted = time::timeout(10 * MS, || uring::accept(listener.as_fd()));
+    assert!(accepted.is_err());
+    listener
+  });
+  exec.run_until_idle();
+  let mut client = std::net::TcpStream::connect(addr).unwrap();
+  // Accepted by now, but the task gives up before it gets to see it.
+  std::thread::sleep(20 * MS);
+  clock.advance(10 * MS);
+  drop(accepting.join().unwrap());
+  client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+  match client.read(&mut [0; 1]) {
+    Ok(0) => (),
+    Err(err) if err.kind() == std::io::ErrorKind::ConnectionReset => (),
+    other => panic!("the accepted end's still open: {:?}", other),
+  }
+}
+
+#[test]
+fn kernel_timeouts_park_just_the_task() {
+  if !uring::is_supported() { return }
+  let exec = LocalExecutor::new();
+  let order = Rc::new(std::cell::RefCell::new(Vec::new()));
+  let (slow, fast) = (order.clone(), order.clone());
+  let start = Instant::now();
+  let sleeper = exec.spawn(move || {
+    uring::sleep(Duration::from_millis(30)).unwrap();
+    slow.borrow_mut().push("slept");
+  });
+  exec.spawn(move || fast.borrow_mut().push("ran"));
+  sleeper.join().unwrap();
+  assert!(start.elapsed() >= Duration::from_millis(30));
+  assert_eq!(vec!["ran", "slept"], *order.borrow());
+}
+
+#[test]
+fn cancelled_reads_keep_their_buffers() {
+  if !uring::is_supported() { return }
+  let exec = LocalExecutor::new();
+  let (a, b) = std::os::unix::net::UnixStream::pair().unwrap();
+  let timed_out = Rc::new(Cell::new(false));
+  let told = timed_out.clone();
+  let reader = exec.spawn(move || {
+    // The buffer's gone from our stack as soon as we unwind, but the op still owns it.
+    let early = time::timeout(Duration::from_millis(10), || uring::read(a.as_fd(), Vec::with_capacity(8)));
+    told.set(early.is_err());
+    // The cancelled read doesn't eat what's written later, either.
+    let (read, buf) = uring::read(a.as_fd(), Vec::with_capacity(8));
+    read.unwrap();
+    buf
+  });
+  while !timed_out.get() {
+    exec.run_until_idle();
+    std::thread::sleep(Duration::from_millis(1));
+  }
+  (&b).write_all(b"later").unwrap();
+  assert_eq!(b"later".to_vec(), reader.join().unwrap());
+}
+
+#[test]
+fn threads_use_it_too() {
+  if !uring::is_supported() { return }
+  let (a, mut b) = std::os::unix::net::UnixStream::pair().unwrap();
+  let (written, buf) = uring::write(a.as_fd(), b"from a thread".to_vec());
+  assert_eq!(buf.len(), written.unwrap());
+  let mut got = vec![0; buf.len()];
+  b.read_exact(&mut got).unwrap();
+  assert_eq!(buf, got);
+}